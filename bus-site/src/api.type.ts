@@ -13,10 +13,22 @@ export type StopDeparture = {
     status?: string,
     _timestamp: DateTime,
     seq?: number,
-    then_headsign?: string
+    then_headsign?: string,
+    headway?: number,
+    wheelchair?: number,
+    parent?: string,
+    transfers?: StopTransfer[],
+    occupancy?: string
+}
+
+export type StopTransfer = {
+    to: string,
+    type: number,
+    min_time?: number
 }
 
 export type StopAlert = {
+    id: string,
     header?: string,
     description?: string,
     url?: string
@@ -26,7 +38,20 @@ export type StopInfo = {
     id: number,
     name: string,
     locality_name: string,
-    locality_code: string
+    locality_code: string,
+    stop_area?: string
+}
+
+export type NearbyStop = {
+    id: number,
+    name: string,
+    locality_code: string,
+    locality_name: string,
+    stance: string,
+    indicator?: string,
+    lat: number,
+    long: number,
+    distance: number
 }
 
 export type StanceInfo = {
@@ -65,7 +90,11 @@ export type ServiceStopData = {
     long: number,
     lat: number,
     status?: string,
-    seq: number
+    seq: number,
+    parent?: string,
+    wheelchair?: number,
+    transfers?: StopTransfer[],
+    headway?: number
 }
 
 export type ServiceBranch = {
@@ -83,9 +112,11 @@ export type ServiceBranch = {
         pct?: number,
         on_previous: boolean,
         vehicle?: {
+            id?: string,
             license?: string,
             name?: string,
-            occupancy_pct?: number
+            occupancy_pct?: number,
+            occupancy?: string
         }
     },
     route: string,
@@ -95,7 +126,9 @@ export type ServiceBranch = {
 export type ServiceInfo = {
     code: string,
     dest: string,
-    cancelled: boolean
+    cancelled: boolean,
+    diverted?: boolean,
+    tracking?: "no_tracking" | "possibly_cancelled"
 }
 
 export type ServiceData = {
@@ -117,7 +150,9 @@ export type LinkedService = {
     trip_id: string,
     service_name: string,
     location: string,
-    dep_time: string
+    dep_time: string,
+    confidence: number,
+    observed?: boolean
 }
 
 export type SearchResult = {
@@ -970,6 +1005,22 @@ export type SiriSource = {
     TimeOfCommunication: string;
 }
 
+export type DatasetMetadata = {
+    built?: string,
+    schema_version?: number,
+    sources: SourceMetadata[]
+}
+
+export type SourceMetadata = {
+    name: string,
+    downloaded?: string,
+    imported?: string,
+    hash?: string,
+    routes: number,
+    trips: number,
+    services: number
+}
+
 export type StopsQuery = {
     name: string,
     display_name: string,
@@ -984,7 +1035,11 @@ export type StopsQuery = {
     long: number,
     lat: number,
     seq: number,
-    full_loc: string
+    full_loc: string,
+    parent?: string,
+    wheelchair?: number,
+    transfers?: StopTransfer[],
+    headway?: number
 }
 
 // stops.name, stops.name as display_name, stops.locality, indicator as ind, arrival_time as arr,
@@ -6,9 +6,10 @@ import { LatLng, LatLngBounds } from "../../../leaflet/geo/index.js";
 const queryStmt = db.prepare(
     "SELECT name,parent,qualifier,locality,station FROM stops_search WHERE stops_search MATCH ? || '*' ORDER BY rank*priority LIMIT 5 OFFSET ?")
 const locStmt = db.prepare(
-    `SELECT s.name, s.locality_name AS parent, qualifier, s.locality FROM stances
+    `SELECT s.name, s.locality_name AS parent, qualifier, s.locality FROM stances_rtree r
+                INNER JOIN stances on stances.rowid = r.id
                 INNER JOIN main.stops s on s.id = stances.stop INNER JOIN main.localities l on l.code = s.locality
-                 WHERE stances.lat >= ? AND stances.long >= ? AND stances.lat <= ? AND stances.long <= ?
+                 WHERE r.max_lat >= ? AND r.max_long >= ? AND r.min_lat <= ? AND r.min_long <= ?
                 GROUP BY s.id ORDER BY pow(AVG(stances.lat)-?,2)+pow(AVG(stances.long)-?,2) LIMIT 5 OFFSET ?`
 )
 
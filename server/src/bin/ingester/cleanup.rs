@@ -1,9 +1,11 @@
 use rusqlite::Connection;
 use std::error::Error;
 use std::fs;
+use BusBoardsServer::GTFSResponder;
 use crate::localities::{load_localities_json, Localities, Stance};
+use crate::sources::{Source, SourceFormat, source_filter};
 
-fn clean_arrivals(db: &mut Connection) -> Result<(), Box<dyn Error>> {
+fn clean_arrivals(db: &mut Connection, scope: &str) -> Result<(), Box<dyn Error>> {
     println!("Cleaning up arrivals");
     let localities: Localities = load_localities_json();
     let mut arrival_bays: Vec<&Stance> = Vec::new();
@@ -18,7 +20,7 @@ fn clean_arrivals(db: &mut Connection) -> Result<(), Box<dyn Error>> {
       INNER JOIN stop_times arrival on arrival.stop_sequence=departure.stop_sequence-1 AND arrival.stop_id IN ({arrival_list})
       INNER JOIN stances st2 on st2.code = arrival.stop_id
       INNER JOIN trips t on t.trip_id = departure.trip_id
-    WHERE st1.stop == st2.stop AND departure.trip_id=arrival.trip_id;");
+    WHERE st1.stop == st2.stop AND departure.trip_id=arrival.trip_id AND {scope};");
 
     let arrivals: Vec<ArrivalsSelectResult> = {
         let mut stmt = db.prepare(select_all.as_str())?;
@@ -49,10 +51,22 @@ struct ArrivalsSelectResult {
     dep_id: i32
 }
 
+/// Move stops without departures to dropped_stops, restoring dropped stops which are served again.
+/// Stops are only rebuilt when NaPTAN changes, so a stop dropped by one import may be needed by a later one
 fn clean_stops(conn: &Connection) -> Result<(), rusqlite::Error> {
     println!("Cleaning up stops");
     conn.pragma_update(None, "foreign_keys", "OFF")?;
-    conn.execute("DELETE FROM stops WHERE stops.id NOT IN (SELECT DISTINCT stances.stop FROM stop_times INNER JOIN stances ON stances.code=stop_id) AND (NOT EXISTS(SELECT 1 FROM stances WHERE stances.stop=stops.id AND crs IS NOT NULL));", [])?;
+    let served = |stop: &str| format!("EXISTS(SELECT 1 FROM stances INNER JOIN stop_times ON stop_times.stop_id=stances.code WHERE stances.stop={stop})");
+    conn.execute(format!("INSERT INTO stops (id, name, locality, locality_name, stop_area)
+                          SELECT id, name, locality, locality_name, stop_area FROM dropped_stops WHERE {}", served("dropped_stops.id")).as_str(), [])?;
+    conn.execute("DELETE FROM dropped_stops WHERE id IN (SELECT id FROM stops)", [])?;
+    let unused = format!("NOT {} AND (NOT EXISTS(SELECT 1 FROM stances WHERE stances.stop=stops.id AND crs IS NOT NULL))", served("stops.id"));
+    conn.execute(format!("INSERT INTO dropped_stops (id, name, locality, locality_name, stop_area)
+                          SELECT id, name, locality, locality_name, stop_area FROM stops WHERE {unused}").as_str(), [])?;
+    conn.execute(format!("DELETE FROM stops WHERE {unused};").as_str(), [])?;
+    conn.execute("DELETE FROM validation_issues WHERE check_name='dropped_stop'", [])?;
+    conn.execute("INSERT INTO validation_issues (check_name, source, agency_id, item, detail)
+                  SELECT 'dropped_stop', 'NaPTAN', NULL, id, name || ', ' || coalesce(locality_name, locality) FROM dropped_stops", [])?;
     println!("Rebuilding stops_search");
     // Rebuild stops_search table
     conn.execute("DROP TABLE IF EXISTS stops_search;", [])?;
@@ -70,45 +84,52 @@ fn reset_polar() -> Result<(), Box<dyn Error>> {
 }
 
 // new BODS version creates awful route destinations - overwrite this (at the sacrifice of some properly set names)
-fn patch_bods(conn: &mut Connection) -> rusqlite::Result<usize> {
+// TransXChange sources carry real timing points and destinations, so only GTFS sources are patched
+fn patch_bods(conn: &mut Connection, sources: &[&Source]) -> rusqlite::Result<usize> {
     println!("Patching BODS route display + timepoint names");
     // patch Ember route short names - use E1/E3 etc. vs "Ember"
-    conn.execute("UPDATE routes SET route_short_name=substr(route_id, 2) WHERE agency_id='Ember'", [])?;
+    conn.execute(format!("UPDATE routes SET route_short_name=substr(route_id, 2) WHERE agency_id='Ember' AND {}", source_filter("route_id", sources)).as_str(), [])?;
+    let scope = source_filter("trip_id", sources);
+    let trips_scope = source_filter("trips.trip_id", sources);
+    // BODS GTFS vehicle journeys are V... after the prefix of the source with BODS realtime trips
+    let bods_journeys = |column: &str| sources.iter().find(|source| source.realtime == Some(GTFSResponder::BODS))
+        .map_or("0".to_string(), |source| format!("substr({column}, 1, {}) = '{}V'", source.prefix.len() + 1, source.prefix.replace('\'', "''")));
     // new BODS breaks timing points in Scotland - attempt to redefine some
     // create a timing point where a dwell occurs
     conn.execute(
-        r#"UPDATE stop_times SET timepoint = 1
+        format!(r#"UPDATE stop_times SET timepoint = 1
                   WHERE arrival_time <> departure_time
                     AND stop_id LIKE '6%'
-                    AND trip_id LIKE 'V%'"#, []
+                    AND {}
+                    AND {scope}"#, bods_journeys("trip_id")).as_str(), []
     )?;
     // create a timing point at each change of locality (change min/max depending on direction to try and maintain consistency)
     conn.execute(
-        r#"UPDATE stop_times SET timepoint=1 FROM 
+        format!(r#"UPDATE stop_times SET timepoint=1 FROM 
               (SELECT t.trip_id AS tid, CASE WHEN t.direction_id=0 THEN min(stop_sequence) ELSE max(stop_sequence) END AS mss
                 FROM stop_times
                          INNER JOIN trips t ON stop_times.trip_id=t.trip_id
                          INNER JOIN stances sta ON sta.code=stop_times.stop_id
                          INNER JOIN stops stop ON sta.stop = stop.id
-                WHERE t.trip_id LIKE 'V%'
+                WHERE {}
                   AND stop_times.stop_id LIKE '6%'
+                  AND {}
                 GROUP BY t.trip_id, locality)
-            WHERE stop_times.trip_id=tid AND stop_sequence=mss"#, []
+            WHERE stop_times.trip_id=tid AND stop_sequence=mss"#, bods_journeys("t.trip_id"), source_filter("t.trip_id", sources)).as_str(), []
     )?;
     // create a timing point based on keywords in stop name
     conn.execute(
-        r#"UPDATE stop_times SET timepoint=1 FROM
+        format!(r#"UPDATE stop_times SET timepoint=1 FROM
                 (SELECT st.trip_id AS tid, st.stop_sequence AS mss FROM stops
                     INNER JOIN stances s on stops.id = s.stop
                     INNER JOIN stop_times st ON s.code = st.stop_id
                 WHERE (stops.name='Rail Station' OR stops.name='Bus Station'
-                    OR stops.name LIKE '%Hospital%' OR stops.name LIKE '%Infirmary%') AND stop_id LIKE '6%' AND trip_id LIKE 'V%')
-                WHERE stop_times.trip_id=tid AND stop_sequence=mss"#, []
+                    OR stops.name LIKE '%Hospital%' OR stops.name LIKE '%Infirmary%') AND stop_id LIKE '6%' AND {} AND {scope})
+                WHERE stop_times.trip_id=tid AND stop_sequence=mss"#, bods_journeys("trip_id")).as_str(), []
     )?;
     // fix route dests - both for special cases and non-special cases
-    // long-term fix will be using TxC
     conn.execute(
-       r#"UPDATE trips SET trip_headsign=(SELECT CASE
+       format!(r#"UPDATE trips SET trip_headsign=(SELECT CASE
                WHEN original = '' THEN new_name
                WHEN original = dest_stop_name THEN new_name
                WHEN original LIKE new_name || ' ' || new_name || ' Hospital%' THEN substr(original, length(new_name) + 2)
@@ -132,13 +153,14 @@ fn patch_bods(conn: &mut Connection) -> rusqlite::Result<usize> {
              WHERE r.agency_id LIKE 'OP%' AND r.agency_id NOT IN ('OP5050', 'OP564', 'OP5051', 'OP545', 'OP563')
                  AND (origin_loc <> dest_loc OR original IS NULL)
                  AND NOT (original LIKE '%Airport%' AND new_name NOT LIKE '%Airport')
+                 AND {trips_scope}
              ) AS trips_subquery
-             WHERE trips.trip_id = trips_subquery.trip_id"#, []
+             WHERE trips.trip_id = trips_subquery.trip_id"#).as_str(), []
     )?;
     
     // patch some long distance coach names
     conn.execute(
-        r#"UPDATE trips SET trip_headsign=(SELECT CASE
+        format!(r#"UPDATE trips SET trip_headsign=(SELECT CASE
                     WHEN original = 'Tokyngton' THEN 'Wembley Stadium'
                     WHEN original = 'Centenary Square' THEN 'Birmingham'
                     WHEN original = 'Penglais' THEN 'Aberystwyth University'
@@ -155,29 +177,33 @@ fn patch_bods(conn: &mut Connection) -> rusqlite::Result<usize> {
                   INNER JOIN main.routes r on r.route_id = trips.route_id
                   INNER JOIN main.stop_times origin on (trips.trip_id = origin.trip_id AND (SELECT min(stop_sequence) FROM stop_times WHERE trip_id=trips.trip_id)=origin.stop_sequence)
                   INNER JOIN main.stop_times dest on (trips.trip_id = dest.trip_id AND (SELECT max(stop_sequence) FROM stop_times WHERE trip_id=trips.trip_id)=dest.stop_sequence)
-                WHERE agency_id IN ('OP5050', 'OP564', 'OP5051', 'OP545', 'OP563') AND NOT instr(trip_headsign, 'Airport')) AS trip_subquery
-                WHERE trips.trip_id=trip_subquery.trip_id"#, [])
+                WHERE agency_id IN ('OP5050', 'OP564', 'OP5051', 'OP545', 'OP563') AND NOT instr(trip_headsign, 'Airport') AND {trips_scope}) AS trip_subquery
+                WHERE trips.trip_id=trip_subquery.trip_id"#).as_str(), [])
 }
 
-fn remove_traveline_ember(conn: &mut Connection) -> rusqlite::Result<usize> {
+fn remove_traveline_ember(conn: &mut Connection, sources: &[&Source]) -> rusqlite::Result<usize> {
     println!("Removing Ember TNDS data");
-    delete_operator(conn, "OP965")?;
-    delete_operator(conn, "OP8058")
+    delete_operator(conn, "OP965", sources)?;
+    delete_operator(conn, "OP8058", sources)
 }
 
-fn delete_operator(conn: &mut Connection, agency_id: &str) -> rusqlite::Result<usize> {
-    conn.execute("DELETE FROM stop_times WHERE trip_id=(SELECT trip_id FROM trips INNER JOIN main.routes r on r.route_id = trips.route_id WHERE r.agency_id=?)", [agency_id])?;
-    conn.execute("DELETE FROM trips WHERE trip_id=(SELECT trip_id FROM trips INNER JOIN main.routes r on r.route_id = trips.route_id WHERE r.agency_id=?)", [agency_id])?;
-    conn.execute("DELETE FROM routes WHERE agency_id=?", [agency_id])?;
-    conn.execute("DELETE FROM agency WHERE agency_id=?", [agency_id])
+/// Delete an operator's routes and trips imported from the given sources, and the operator once it has no routes left
+fn delete_operator(conn: &mut Connection, agency_id: &str, sources: &[&Source]) -> rusqlite::Result<usize> {
+    let operator_trips = format!("SELECT trip_id FROM trips INNER JOIN main.routes r on r.route_id = trips.route_id WHERE r.agency_id=? AND {}", source_filter("r.route_id", sources));
+    conn.execute(format!("DELETE FROM stop_times WHERE trip_id IN ({operator_trips})").as_str(), [agency_id])?;
+    conn.execute(format!("DELETE FROM trips WHERE trip_id IN ({operator_trips})").as_str(), [agency_id])?;
+    conn.execute(format!("DELETE FROM routes WHERE agency_id=? AND {}", source_filter("route_id", sources)).as_str(), [agency_id])?;
+    conn.execute("DELETE FROM agency WHERE agency_id=?1 AND NOT EXISTS (SELECT 1 FROM routes WHERE agency_id=?1)", [agency_id])
 }
 
-fn clean_flix(conn: &mut Connection) -> rusqlite::Result<usize> {
+fn clean_flix(conn: &mut Connection, sources: &[&Source]) -> rusqlite::Result<usize> {
     println!("Cleaning Flix data");
-    conn.execute("UPDATE trips SET trip_id=replace(trip_id, '#', '-') WHERE EXISTS (SELECT agency_id FROM routes WHERE routes.route_id=trips.route_id AND agency_id='FLIXBUS-eu')", [])?;
-    conn.execute("UPDATE routes SET route_short_name=replace(replace(route_short_name,'UK',''), 'FlixBus ', '') WHERE agency_id='FLIXBUS-eu'", [])?;
-    conn.execute(r#"
-        DELETE FROM routes WHERE routes.agency_id='FLIXBUS-eu' AND routes.route_id NOT IN (SELECT trips.route_id FROM trips
+    let trips = source_filter("trips.trip_id", sources);
+    let routes = source_filter("routes.route_id", sources);
+    conn.execute(format!("UPDATE trips SET trip_id=replace(trip_id, '#', '-') WHERE EXISTS (SELECT agency_id FROM routes WHERE routes.route_id=trips.route_id AND agency_id='FLIXBUS-eu') AND {trips}").as_str(), [])?;
+    conn.execute(format!("UPDATE routes SET route_short_name=replace(replace(route_short_name,'UK',''), 'FlixBus ', '') WHERE agency_id='FLIXBUS-eu' AND {routes}").as_str(), [])?;
+    conn.execute(format!(r#"
+        DELETE FROM routes WHERE routes.agency_id='FLIXBUS-eu' AND {routes} AND routes.route_id NOT IN (SELECT trips.route_id FROM trips
            INNER JOIN routes r on trips.route_id = r.route_id
            INNER JOIN stop_times origin on trips.trip_id = origin.trip_id AND origin.stop_sequence=(SELECT min(stop_sequence) FROM stop_times WHERE trip_id=trips.trip_id)
            INNER JOIN stances origin_stance on origin_stance.code = origin.stop_id
@@ -186,9 +212,9 @@ fn clean_flix(conn: &mut Connection) -> rusqlite::Result<usize> {
            INNER JOIN stances dest_stance on dest_stance.code = dest.stop_id
            INNER JOIN stops dest_stop on dest_stop.id = dest_stance.stop
         WHERE r.agency_id='FLIXBUS-eu' AND (origin_stop.locality<>'Europe' OR dest_stop.locality<>'Europe'));
-    "#, [])?;
+    "#).as_str(), [])?;
     conn.execute("UPDATE agency SET agency_name='FlixBus' WHERE agency_id='FLIXBUS-eu'", [])?;
-    conn.execute(r#"
+    conn.execute(format!(r#"
     UPDATE trips SET trip_headsign=(
         SELECT CASE WHEN dest_loc_name = 'Europe' THEN dest_name
         WHEN dest_loc_name = 'Centenary Square' THEN 'Birmingham'
@@ -200,18 +226,20 @@ fn clean_flix(conn: &mut Connection) -> rusqlite::Result<usize> {
                                 INNER JOIN main.stops dest_stop on dest_stop.id = st.stop
              ) AS trip_subquery
         WHERE trips.trip_id=trip_subquery.trip_id
-    ) WHERE (SELECT agency_id FROM routes WHERE trips.route_id=routes.route_id)='FLIXBUS-eu'
-    "#, [])?;
-    delete_operator(conn, "OP5051")?;
-    delete_operator(conn, "FLIXTRAIN-eu")
+    ) WHERE (SELECT agency_id FROM routes WHERE trips.route_id=routes.route_id)='FLIXBUS-eu' AND {trips}
+    "#).as_str(), [])?;
+    delete_operator(conn, "OP5051", sources)?;
+    delete_operator(conn, "FLIXTRAIN-eu", sources)
 }
 
-pub fn cleanup(conn: &mut Connection) -> Result<(), Box<dyn Error>> {
-    clean_arrivals(conn).expect("Clean arrivals");
-    clean_flix(conn).expect("Clean Flix");
+/// Clean up data imported from the given sources
+pub fn cleanup(conn: &mut Connection, sources: &[&Source]) -> Result<(), Box<dyn Error>> {
+    clean_arrivals(conn, source_filter("departure.trip_id", sources).as_str()).expect("Clean arrivals");
+    clean_flix(conn, sources).expect("Clean Flix");
     clean_stops(conn).expect("Clean stops");
     reset_polar().expect("Reset Polar");
-    patch_bods(conn).expect("Display name + timing point patching");
-    remove_traveline_ember(conn).expect("Patch Ember");
+    let gtfs_sources: Vec<&Source> = sources.iter().copied().filter(|s| s.format == SourceFormat::GTFS).collect();
+    patch_bods(conn, &gtfs_sources).expect("Display name + timing point patching");
+    remove_traveline_ember(conn, sources).expect("Patch Ember");
     Ok(())
 }
@@ -0,0 +1,203 @@
+use std::str::FromStr;
+
+use crate::sources::Source;
+
+pub const USAGE: &str = "Usage: ingester [options]
+
+Builds the database in a staging copy and swaps it in once every stage has run.
+With no options, every stage is run for the sources which have changed since the last import.
+The stances, localities and stops stages are skipped unless NaPTAN or the stop overrides have changed.
+
+Options:
+  --stage <stage,...>    Only run the given stages against a copy of the existing database.
+                         cleanup and linking only run along with the sources stage, as they change newly imported data
+  --from <stage>         Run the given stage and every stage after it
+  --sources <name,...>   Only import the given sources (by name or prefix), even if unchanged
+  --force                Re-import sources even if they have not changed
+  --resume               Continue a failed run from its staging database, skipping completed stages
+  --in-place             Write directly to the live database instead of a staging copy
+  --help                 Show this message
+
+Stages, in order:
+  stances, localities, stops, sources, indexes, cleanup, patterns, linking, observed, segments, noc, validate
+
+If no sources have changed, only the patterns stage (for any trips missing patterns), the observed stage
+(to learn links from realtime vehicle observations) and the segments stage (to learn running times) are run.";
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Stage {
+    Stances,
+    Localities,
+    Stops,
+    Sources,
+    Indexes,
+    Cleanup,
+    Patterns,
+    Linking,
+    Observed,
+    Segments,
+    Noc,
+    Validate
+}
+
+/// Stages building stances and stops from NaPTAN, which are skipped when NaPTAN and the stop overrides are unchanged
+pub const NAPTAN_STAGES: [Stage; 3] = [Stage::Stances, Stage::Localities, Stage::Stops];
+
+pub const STAGES: [Stage; 12] = [
+    Stage::Stances, Stage::Localities, Stage::Stops, Stage::Sources, Stage::Indexes,
+    Stage::Cleanup, Stage::Patterns, Stage::Linking, Stage::Observed, Stage::Segments, Stage::Noc, Stage::Validate
+];
+
+impl Stage {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Stage::Stances => "stances",
+            Stage::Localities => "localities",
+            Stage::Stops => "stops",
+            Stage::Sources => "sources",
+            Stage::Indexes => "indexes",
+            Stage::Cleanup => "cleanup",
+            Stage::Patterns => "patterns",
+            Stage::Linking => "linking",
+            Stage::Observed => "observed",
+            Stage::Segments => "segments",
+            Stage::Noc => "noc",
+            Stage::Validate => "validate"
+        }
+    }
+}
+
+impl FromStr for Stage {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        STAGES.iter().find(|stage| stage.name() == s).copied()
+            .ok_or(format!("Unknown stage '{s}'"))
+    }
+}
+
+#[derive(Default)]
+pub struct Args {
+    /// Stages to run - None runs every stage
+    pub stages: Option<Vec<Stage>>,
+    /// Source names or prefixes to import - None imports every enabled source
+    pub sources: Option<Vec<String>>,
+    pub force: bool,
+    pub resume: bool,
+    pub in_place: bool,
+    pub help: bool
+}
+
+pub fn parse_args(mut args: impl Iterator<Item=String>) -> Result<Args, String> {
+    let mut parsed = Args::default();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--stage" | "--stages" => {
+                let value = args.next().ok_or("--stage needs a list of stages")?;
+                let mut stages = value.split(',').map(Stage::from_str).collect::<Result<Vec<Stage>, String>>()?;
+                // always run stages in pipeline order
+                stages.sort_by_key(|stage| STAGES.iter().position(|s| s == stage));
+                stages.dedup();
+                parsed.stages = Some(stages);
+            }
+            "--from" => {
+                let stage = Stage::from_str(args.next().ok_or("--from needs a stage")?.as_str())?;
+                parsed.stages = Some(STAGES.iter().skip_while(|s| **s != stage).copied().collect());
+            }
+            "--sources" => {
+                let value = args.next().ok_or("--sources needs a list of sources")?;
+                parsed.sources = Some(value.split(',').map(|s| s.to_string()).collect());
+            }
+            "--force" => parsed.force = true,
+            "--resume" => parsed.resume = true,
+            "--in-place" => parsed.in_place = true,
+            "--help" | "-h" => parsed.help = true,
+            _ => return Err(format!("Unknown argument '{arg}'"))
+        }
+    }
+    if parsed.resume && parsed.in_place {
+        return Err("--resume cannot be used with --in-place".to_string());
+    }
+    // cleanup patches and linking relinks whatever was imported - on data imported by an earlier run this would repeat it
+    // (a resumed run already imported its sources)
+    if let Some(stages) = parsed.stages.as_ref().filter(|stages| !parsed.resume && !stages.contains(&Stage::Sources)) {
+        if let Some(stage) = stages.iter().find(|stage| matches!(stage, Stage::Cleanup | Stage::Linking)) {
+            return Err(format!("The {} stage can only run with the sources stage - use --from sources, or --resume to finish a failed run", stage.name()));
+        }
+    }
+    Ok(parsed)
+}
+
+/// Enabled sources to import - those named (by name or prefix) in --sources, or all of them
+pub fn select_sources<'a>(names: Option<&Vec<String>>, enabled: &[&'a Source]) -> Result<Vec<&'a Source>, String> {
+    let Some(names) = names else { return Ok(enabled.to_vec()) };
+    if let Some(name) = names.iter().find(|name| !enabled.iter().any(|source| &source.name == *name || &source.prefix == *name)) {
+        return Err(format!("Unknown or disabled source '{name}'"));
+    }
+    Ok(enabled.iter().copied().filter(|source| names.iter().any(|name| &source.name == name || &source.prefix == name)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &str) -> Result<Args, String> {
+        parse_args(args.split_whitespace().map(str::to_string))
+    }
+
+    fn source(name: &str, prefix: &str) -> Source {
+        Source { name: name.to_string(), prefix: prefix.to_string(), ..Default::default() }
+    }
+
+    #[test]
+    fn stages_run_in_pipeline_order() {
+        let args = parse("--stage noc,patterns,noc").unwrap();
+        assert_eq!(args.stages, Some(vec![Stage::Patterns, Stage::Noc]));
+        let args = parse("--from segments").unwrap();
+        assert_eq!(args.stages, Some(vec![Stage::Segments, Stage::Noc, Stage::Validate]));
+        assert_eq!(parse("").unwrap().stages, None);
+    }
+
+    #[test]
+    fn unknown_stages_are_rejected() {
+        assert_eq!(parse("--stage patterns,routes").err(), Some("Unknown stage 'routes'".to_string()));
+        assert!(parse("--from").is_err());
+        assert!(parse("--stage").is_err());
+        assert!(parse("--verbose").is_err());
+    }
+
+    #[test]
+    fn import_stages_need_sources_stage() {
+        assert!(parse("--stage cleanup").is_err());
+        assert!(parse("--stage linking,noc").is_err());
+        assert!(parse("--from cleanup").is_err());
+        assert!(parse("--stage sources,cleanup,linking").is_ok());
+        assert!(parse("--from sources").is_ok());
+        assert!(parse("--resume --stage linking").is_ok());
+    }
+
+    #[test]
+    fn resume_flags() {
+        let args = parse("--resume").unwrap();
+        assert!(args.resume && !args.in_place);
+        assert!(parse("--resume --in-place").is_err());
+    }
+
+    #[test]
+    fn sources_by_name_or_prefix() {
+        let (bods, ember, flix) = (source("BODS", "B"), source("Ember", "E"), source("Flix", "F"));
+        let enabled = [&bods, &ember, &flix];
+        let args = parse("--sources Ember,F").unwrap();
+        let selected = select_sources(args.sources.as_ref(), &enabled).unwrap();
+        assert_eq!(selected.iter().map(|source| source.name.as_str()).collect::<Vec<_>>(), vec!["Ember", "Flix"]);
+        assert_eq!(select_sources(None, &enabled).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn unknown_sources_are_rejected() {
+        let bods = source("BODS", "B");
+        let args = parse("--sources BODS,Megabus").unwrap();
+        assert_eq!(select_sources(args.sources.as_ref(), &[&bods]).err(), Some("Unknown or disabled source 'Megabus'".to_string()));
+        assert!(parse("--sources").is_err());
+    }
+}
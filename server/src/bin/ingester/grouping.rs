@@ -1,7 +1,8 @@
 use std::collections::HashMap;
 use std::error::Error;
 use std::fs::File;
-use std::io::BufWriter;
+use std::io::{copy, BufWriter};
+use std::path::PathBuf;
 
 use itertools::Itertools;
 use polars::datatypes::AnyValue;
@@ -11,19 +12,42 @@ use polars::io::SerReader;
 use polars::prelude::{as_struct, coalesce, col, CsvReadOptions, DataFrameJoinOps, DataType, Field, IntoLazy, JoinArgs, JoinType, lit, Literal, NamedFrom, not, NULL, Schema, SchemaRef, SmartString, StringNameSpaceImpl, when};
 use polars::series::Series;
 use regex::{Regex, RegexBuilder};
+use sha2::{Digest, Sha256};
 use tower_http::compression::Predicate;
 
 use BusBoardsServer::download_if_old;
 
-use crate::localities::{Localities, Stance};
-use crate::locality_changes::{LOCALITY_CHANGES, MANUAL_ARRIVALS, MANUAL_RENAMES, NAPTAN_OVERRIDES};
+use crate::localities::{download_nptg, Localities, Stance};
+use crate::stop_areas::{load_stop_areas, write_stop_areas};
+use crate::locality_changes::{load_overrides, override_paths, validate_overrides, StopOverrides};
+
+/// Key of the stance inputs' hash in the file_hashes table
+pub const NAPTAN_HASH_KEY: &str = "naptan";
+const STOPS_PATH: &str = "Stops.csv";
+const CRS_PATH: &str = "crs.csv";
+
+fn download_stops() -> Result<File, Box<dyn Error>> {
+    download_if_old("https://naptan.api.dft.gov.uk/v1/access-nodes?dataFormat=csv", STOPS_PATH)
+}
+
+/// Hash of the NaPTAN stops, NPTG localities, CRS codes and stop overrides that stances are grouped from
+pub fn hash_naptan() -> Result<String, Box<dyn Error>> {
+    let mut hasher = Sha256::new();
+    copy(&mut download_stops()?, &mut hasher)?;
+    copy(&mut download_nptg()?, &mut hasher)?;
+    for path in [PathBuf::from(CRS_PATH)].into_iter().chain(override_paths()?) {
+        copy(&mut File::open(path)?, &mut hasher)?;
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
 
 pub fn group_stances() -> Result<Localities, Box<dyn Error>> {
-    download_if_old("https://naptan.api.dft.gov.uk/v1/access-nodes?dataFormat=csv", "Stops.csv")?;
-    let mut df = load_csv("Stops.csv")?;
+    download_stops()?;
+    let mut df = load_csv(STOPS_PATH)?;
+    let overrides = load_overrides()?;
 
     println!("Grouping stances");
-    let mut groupings = group_data(&mut df)?;
+    let mut groupings = group_data(&mut df, &overrides)?;
     println!("Fixing groupings");
     fix_groupings(&mut groupings)?;
     println!("Writing to localities.json");
@@ -31,9 +55,9 @@ pub fn group_stances() -> Result<Localities, Box<dyn Error>> {
     Ok(groupings)
 }
 
-fn group_data(df: &mut DataFrame) -> Result<Localities, Box<dyn Error>> {
+fn group_data(df: &mut DataFrame, overrides: &StopOverrides) -> Result<Localities, Box<dyn Error>> {
     println!("Loading CSV");
-    let crs = load_csv("crs.csv")?;
+    let crs = load_csv(CRS_PATH)?;
     let mut df = df.join(&crs, ["ATCOCode"], ["ATCOCode"], JoinArgs::new(JoinType::Left))?;
 
     println!("Converting coordinates");
@@ -54,12 +78,14 @@ fn group_data(df: &mut DataFrame) -> Result<Localities, Box<dyn Error>> {
         .collect()?;
 
     println!("Standardising synonyms");
-    let df = standardise_synonyms(df)?;
+    let df = standardise_synonyms(df, overrides)?;
 
     println!("Grouping data");
     let location_vals = df.select(["NptgLocalityCode", "CommonName", "ATCOCode", "Indicator", "Street", "Lat", "Lon", "Arrival", "CrsRef"])?
         .into_struct("loc");
-    let location_data: Vec<(&str, &str, Stance)> = location_vals.into_iter().map(|s|
+    let stop_areas = load_stop_areas()?;
+    write_stop_areas(&stop_areas)?;
+    let mut location_data: Vec<(&str, &str, Stance)> = location_vals.into_iter().map(|s|
             (s[0].get_str().unwrap(), s[1].get_str().unwrap(), Stance {
                 atco_code: s[2].get_str().unwrap().to_string(),
                 lat: s[5].try_extract().unwrap(),
@@ -73,8 +99,10 @@ fn group_data(df: &mut DataFrame) -> Result<Localities, Box<dyn Error>> {
                     }
                 },
                 crs: s[8].get_str().map(|s| s.to_string()),
+                stop_area: s[2].get_str().and_then(|atco| stop_areas.stance_area(atco)),
             }
     )).collect();
+    group_by_stop_area(&mut location_data);
 
     let mut data: Localities = HashMap::new();
     location_data.iter().for_each(|(k0, k1, stance)| {
@@ -88,7 +116,30 @@ fn group_data(df: &mut DataFrame) -> Result<Localities, Box<dyn Error>> {
     Ok(data)
 }
 
-fn standardise_synonyms(df: DataFrame) -> Result<DataFrame, Box<dyn Error>> {
+/// Stop areas are the primary grouping key - move all stances in a stop area into the
+/// (locality, name) group most of its stances have. Stances without a stop area keep their name-based group.
+fn group_by_stop_area<'a>(location_data: &mut [(&'a str, &'a str, Stance)]) {
+    let mut area_groups: HashMap<String, HashMap<(&'a str, &'a str), usize>> = HashMap::new();
+    for (locality, name, stance) in location_data.iter() {
+        if let Some(area) = &stance.stop_area {
+            *area_groups.entry(area.clone()).or_default().entry((*locality, *name)).or_default() += 1;
+        }
+    }
+    let area_keys: HashMap<String, (&'a str, &'a str)> = area_groups.into_iter()
+        .filter_map(|(area, groups)| {
+            let key = groups.into_iter().max_by(|(a_key, a), (b_key, b)| a.cmp(b).then(b_key.cmp(a_key)))?.0;
+            Some((area, key))
+        })
+        .collect();
+    for (locality, name, stance) in location_data.iter_mut() {
+        if let Some(&(area_locality, area_name)) = stance.stop_area.as_ref().and_then(|area| area_keys.get(area)) {
+            *locality = area_locality;
+            *name = area_name;
+        }
+    }
+}
+
+fn standardise_synonyms(df: DataFrame, overrides: &StopOverrides) -> Result<DataFrame, Box<dyn Error>> {
     let df = df.lazy()
         // Denote an arrival bay
         .with_column(
@@ -112,6 +163,8 @@ fn standardise_synonyms(df: DataFrame) -> Result<DataFrame, Box<dyn Error>> {
             .alias("CommonName")
     ).collect()?;
 
+    validate_overrides(overrides, &df)?;
+
     // Create manual fixes to locality code, parent and name
     let changes_schema = Schema::from_iter([
         Field::new("NptgLocalityCode", DataType::String),
@@ -130,20 +183,20 @@ fn standardise_synonyms(df: DataFrame) -> Result<DataFrame, Box<dyn Error>> {
     ]);
 
     let changes_df = DataFrame::from_rows_iter_and_schema(
-        LOCALITY_CHANGES.map(|c| Row::new(vec![
-            AnyValue::String(c.0), AnyValue::String(c.1), AnyValue::String(c.2),
-            c.3.map_or(AnyValue::Null, |cc| AnyValue::String(cc))
-        ])).iter(), &changes_schema
+        overrides.locality_changes.iter().map(|c| Row::new(vec![
+            AnyValue::String(c.locality.as_str()), AnyValue::String(c.name.as_str()), AnyValue::String(c.new_locality.as_str()),
+            c.parent_name.as_ref().map_or(AnyValue::Null, |cc| AnyValue::String(cc.as_str()))
+        ])).collect_vec().iter(), &changes_schema
     )?;
     /*let renames_df = DataFrame::from_rows_iter_and_schema(
-        MANUAL_RENAMES.map(|c| Row::new(vec![
-            AnyValue::String(c.0), AnyValue::String(c.1), AnyValue::String(c.2)
-        ])).iter(), &renames_schema
+        overrides.renames.iter().map(|c| Row::new(vec![
+            AnyValue::String(c.locality.as_str()), AnyValue::String(c.pattern.as_str()), AnyValue::String(c.name.as_str())
+        ])).collect_vec().iter(), &renames_schema
     )?;*/
     let naptan_df = DataFrame::from_rows_iter_and_schema(
-        NAPTAN_OVERRIDES.map(|c| Row::new(vec![
-            AnyValue::String(c.0), AnyValue::String(c.1)
-        ])).iter(), &naptan_schema
+        overrides.naptan_overrides.iter().map(|c| Row::new(vec![
+            AnyValue::String(c.locality.as_str()), AnyValue::String(c.new_locality.as_str())
+        ])).collect_vec().iter(), &naptan_schema
     )?;
 
     let df = df.lazy()
@@ -163,7 +216,7 @@ fn standardise_synonyms(df: DataFrame) -> Result<DataFrame, Box<dyn Error>> {
             coalesce(&[col("NewNptgLocalityCode"), col("NewNptgLocalityCode2"), col("NptgLocalityCode")]).alias("NptgLocalityCode"),
             coalesce(&[col("NewParentLocalityName"), col("ParentLocalityName")]).alias("ParentLocalityName"),
             /*coalesce(&[col("NewCommonName"), col("CommonName")]).alias("CommonName"),*/
-            when(col("ATCOCode").is_in(lit(Series::new("ATCOCode", MANUAL_ARRIVALS))))
+            when(col("ATCOCode").is_in(lit(Series::new("ATCOCode", overrides.arrivals.clone()))))
                 .then(true)
                 .otherwise(col("Arrival"))
                 .alias("Arrival")
@@ -173,11 +226,11 @@ fn standardise_synonyms(df: DataFrame) -> Result<DataFrame, Box<dyn Error>> {
     
     let mut df = df.lazy();
     let mut when_expr = when(false).then(lit("blah")).when(false).then(lit("blah"));
-    for (loc, old_name, new_name) in MANUAL_RENAMES {
+    for rename in &overrides.renames {
         when_expr = when_expr.when(
-            col("NptgLocalityCode").eq(lit(loc))
-                .and(col("CommonName").str().contains(lit(old_name), true))
-        ).then(col("CommonName").str().replace(lit(old_name), lit(new_name), false));
+            col("NptgLocalityCode").eq(lit(rename.locality.as_str()))
+                .and(col("CommonName").str().contains(lit(rename.pattern.as_str()), true))
+        ).then(col("CommonName").str().replace(lit(rename.pattern.as_str()), lit(rename.name.as_str()), false));
     }
     let df = df.with_column(
         when_expr.otherwise(col("CommonName")).alias("CommonName")
@@ -2,47 +2,111 @@ use std::{fs, iter};
 use std::collections::HashMap;
 use std::error::Error;
 use std::fs::File;
-use std::io::BufReader;
+use std::io::{BufReader, copy};
 use std::path::Path;
 use std::time::Instant;
 
 use geo_types::Coord;
 use itertools::Itertools;
 use memmap::Mmap;
-use phf::phf_map;
 use piz::read::{as_tree, DirectoryContents, FileTree, ZipArchive};
 use rusqlite::{Connection, params_from_iter};
 use serde::Deserialize;
+use sha2::{Digest, Sha256};
 
-use BusBoardsServer::download_if_old;
+use crate::metadata::record_source;
+use crate::sources::{open_source, Source, SourceFormat, source_filter};
+use crate::txc::import_txc;
 
-use crate::sources::Source;
+/// Download a source if needed and return the SHA-256 hash of its zip
+pub fn hash_source(source: &Source) -> Result<String, Box<dyn Error>> {
+    if let Some(dir) = Path::new(&source.path).parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut file = open_source(source)?;
+    let mut hasher = Sha256::new();
+    copy(&mut file, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Get the hash of a source's zip (keyed by its path), or of other inputs, from when it was last imported
+pub fn get_stored_hash(db: &Connection, key: &str) -> Option<String> {
+    db.query_row("SELECT hash FROM file_hashes WHERE source=?", [key], |row| row.get(0)).ok()
+}
 
-pub fn process_source(db: &mut Connection, source: &Source, overrides: &Overrides) -> Result<(), Box<dyn Error>> {
-    // Download source
-    fs::create_dir_all("gtfs")?;
-    download_if_old(source.url, source.path)?;
+pub fn store_hash(db: &Connection, key: &str, hash: &str) -> rusqlite::Result<usize> {
+    db.execute("REPLACE INTO file_hashes (source, hash) VALUES (?, ?)", [key, hash])
+}
+
+pub fn process_source(db: &mut Connection, source: &Source, overrides: &Overrides, hash: &str) -> Result<(), Box<dyn Error>> {
+    // Remove previously imported data for this source
+    delete_source(db, source)?;
     // Import zip
-    import_zip(db, source, overrides)
+    match source.format {
+        SourceFormat::GTFS => import_zip(db, source, overrides)?,
+        SourceFormat::TransXChange => import_txc(db, source)?
+    }
+    store_hash(db, source.path.as_str(), hash)?;
+    record_source(db, source, hash)?;
+    Ok(())
+}
+
+/// Delete all rows carrying the prefix of a source
+pub fn delete_source(db: &mut Connection, source: &Source) -> Result<(), Box<dyn Error>> {
+    println!("Removing existing data for {}", source.name);
+    let trips = source_filter("trip_id", &[source]);
+    let routes = source_filter("route_id", &[source]);
+    let services = source_filter("service_id", &[source]);
+    let shapes = source_filter("shape_id", &[source]);
+    let links_from = source_filter("\"from\"", &[source]);
+    let links_to = source_filter("\"to\"", &[source]);
+    let polar = source_filter("gtfs", &[source]);
+    let lothian = source_filter("route", &[source]);
+    db.execute("DELETE FROM file_hashes WHERE source=?", [&source.path])?;
+    db.execute("DELETE FROM source_metadata WHERE prefix=?", [&source.prefix])?;
+    db.execute("DELETE FROM transfers WHERE source=?", [&source.prefix])?;
+    db.execute("DELETE FROM stance_attributes WHERE source=?", [&source.prefix])?;
+    db.execute_batch(format!(r#"
+        DELETE FROM links WHERE {links_from} OR {links_to};
+        DELETE FROM replaced_links WHERE {links_from} OR {links_to};
+        DELETE FROM polar WHERE {polar};
+        DELETE FROM lothian WHERE {lothian};
+        DELETE FROM trip_patterns WHERE {trips};
+        DELETE FROM frequencies WHERE {trips};
+        DELETE FROM headways WHERE {trips};
+        DELETE FROM stop_times WHERE {trips};
+        DELETE FROM trips WHERE {trips};
+        DELETE FROM routes WHERE {routes};
+        DELETE FROM calendar_dates WHERE {services};
+        DELETE FROM calendar WHERE {services};
+        DELETE FROM shapes WHERE {shapes};
+    "#).as_str())?;
+    Ok(())
 }
 
 fn import_zip(db: &mut Connection, source: &Source, overrides: &Overrides) -> Result<(), Box<dyn Error>> {
-    let path = Path::new(source.path);
+    let path = Path::new(&source.path);
     let timer = Instant::now();
-    // each entry: file name, SQL insert stmt, each field ordered by index, map of defaults to use, bool for if prefix should be added to field
+    // each entry: file name, SQL insert stmt, each field ordered by index, bool for if prefix should be added to field
     let imports: Imports = [
         ("agency.txt", "REPLACE INTO agency (agency_id, agency_name, agency_url, agency_timezone, agency_lang) VALUES (?, ?, ?, ?, ?)",
-         vec!["agency_id", "agency_name", "agency_url", "agency_timezone", "agency_lang"], phf_map! {}, false),
+         vec!["agency_id", "agency_name", "agency_url", "agency_timezone", "agency_lang"], false),
         ("routes.txt", "REPLACE INTO routes (route_id, agency_id, route_short_name, route_long_name, route_type) VALUES (?6||?1, ?2, ?3, ?4, ?5)",
-         vec!["route_id", "agency_id", "route_short_name", "route_long_name", "route_type"], phf_map! {"agency_id" => "Ember"}, true),
+         vec!["route_id", "agency_id", "route_short_name", "route_long_name", "route_type"], true),
         ("calendar.txt", "REPLACE INTO calendar (service_id, start_date, end_date, validity) VALUES (?11||?1, cast(?2 as integer), ?3, (?4 + (?5 << 1) + (?6 << 2) + (?7 << 3) + (?8 << 4) + (?9 << 5) + (?10 << 6)))",
-         vec!["service_id", "start_date", "end_date", "monday", "tuesday", "wednesday", "thursday", "friday", "saturday", "sunday"], phf_map! {}, true),
+         vec!["service_id", "start_date", "end_date", "monday", "tuesday", "wednesday", "thursday", "friday", "saturday", "sunday"], true),
         ("calendar_dates.txt", "REPLACE INTO calendar_dates (service_id, date, exception_type) VALUES (?4||?1, ?2, ?3)",
-         vec!["service_id", "date", "exception_type"], phf_map! {}, true),
+         vec!["service_id", "date", "exception_type"], true),
         ("trips.txt", "REPLACE INTO trips (route_id, service_id, trip_id, trip_headsign, shape_id, direction_id, block_id) VALUES (?8||?1, ?8||?2, ?8||?3, ?4, ?8||?5, ?6, ?8||?7)",
-         vec!["route_id", "service_id", "trip_id", "trip_headsign", "shape_id", "direction_id", "block_id"], phf_map! {}, true),
+         vec!["route_id", "service_id", "trip_id", "trip_headsign", "shape_id", "direction_id", "block_id"], true),
         ("stop_times.txt", "REPLACE INTO stop_times (trip_id, arrival_time, departure_time, stop_id, stop_sequence, timepoint, stop_headsign, pickup_type, drop_off_type) VALUES (?10||?1, substr(?2, 1, 2)*3600+substr(?2, 4, 2)*60+substr(?2, 7, 2), substr(?3, 1, 2)*3600+substr(?3, 4, 2)*60+substr(?3, 7, 2), ?4, ?5, ?6, NULLIF(?7, ''), ?8, ?9)",
-         vec!["trip_id", "arrival_time", "departure_time", "stop_id", "stop_sequence", "timepoint", "stop_headsign", "pickup_type", "drop_off_type"], phf_map! {}, true)
+         vec!["trip_id", "arrival_time", "departure_time", "stop_id", "stop_sequence", "timepoint", "stop_headsign", "pickup_type", "drop_off_type"], true),
+        ("stops.txt", "REPLACE INTO stance_attributes (code, parent_station, wheelchair_boarding, source) SELECT ?1, ?2, ?3, ?4 WHERE ?2 IS NOT NULL OR cast(coalesce(?3, 0) as integer) <> 0",
+         vec!["stop_id", "parent_station", "wheelchair_boarding"], true),
+        ("frequencies.txt", "REPLACE INTO frequencies (trip_id, start_time, end_time, headway_secs, exact_times) VALUES (?6||?1, substr(?2, 1, 2)*3600+substr(?2, 4, 2)*60+substr(?2, 7, 2), substr(?3, 1, 2)*3600+substr(?3, 4, 2)*60+substr(?3, 7, 2), ?4, coalesce(?5, 0))",
+         vec!["trip_id", "start_time", "end_time", "headway_secs", "exact_times"], true),
+        ("transfers.txt", "INSERT INTO transfers (from_stop_id, to_stop_id, transfer_type, min_transfer_time, from_trip_id, to_trip_id, source) VALUES (?1, ?2, coalesce(?3, 0), ?4, ?7||?5, ?7||?6, ?7)",
+         vec!["from_stop_id", "to_stop_id", "transfer_type", "min_transfer_time", "from_trip_id", "to_trip_id"], true)
     ];
 
     let zip_file = File::open(path)?;
@@ -51,20 +115,55 @@ fn import_zip(db: &mut Connection, source: &Source, overrides: &Overrides) -> Re
     let dir = as_tree(archive.entries())?;
     let file_name = path.file_name().unwrap().to_str().unwrap();
 
-    for (subfile_name, stmt, indexes, defaults, add_prefix) in imports {
+    for (subfile_name, stmt, indexes, add_prefix) in imports {
+        if OPTIONAL_FILES.contains(&subfile_name) && dir.lookup(subfile_name).is_err() {
+            continue;
+        }
         println!("Importing {} for {}", subfile_name, file_name);
-        import_txt_file(&archive, &dir, subfile_name, db, stmt, &indexes, &defaults, if add_prefix { Some(source.prefix) } else { None }, overrides).expect(subfile_name);
+        import_txt_file(&archive, &dir, subfile_name, db, stmt, &indexes, source.defaults.get(subfile_name), if add_prefix { Some(source.prefix.as_str()) } else { None }, overrides).expect(subfile_name);
     }
 
     println!("Importing shapes.txt for {}", file_name);
     import_shapes(&archive, Some(source.prefix.to_string()), db)?;
 
+    expand_frequencies(db, source)?;
+
     println!("{}s to import {}", timer.elapsed().as_secs(), file_name);
 
     Ok(())
 }
 
-fn import_txt_file(archive: &ZipArchive, dir: &DirectoryContents, file_name: &str, db: &mut Connection, stmt_str: &str, indexes: &Vec<&str>, defaults: &phf::Map<&str, &str>, prefix: Option<&str>, overrides: &Overrides) -> Result<(), Box<dyn Error>> {
+/// Replace frequency-based template trips with a concrete trip for each departure
+fn expand_frequencies(db: &mut Connection, source: &Source) -> rusqlite::Result<()> {
+    let scope = source_filter("trip_id", &[source]);
+    let departures = format!(r#"
+        WITH RECURSIVE departures(trip_id, departure, end_time, headway, exact_times) AS (
+            SELECT trip_id, start_time, end_time, headway_secs, exact_times FROM frequencies WHERE headway_secs > 0 AND {scope}
+            UNION ALL
+            SELECT trip_id, departure + headway, end_time, headway, exact_times FROM departures WHERE departure + headway < end_time
+        )"#);
+    let tx = db.transaction()?;
+    tx.execute(format!(r#"{departures}
+        INSERT INTO trips (trip_id, route_id, service_id, trip_headsign, shape_id, direction_id, block_id)
+        SELECT t.trip_id || '-' || d.departure, route_id, service_id, trip_headsign, shape_id, direction_id, NULL
+            FROM departures d INNER JOIN trips t ON t.trip_id = d.trip_id"#).as_str(), [])?;
+    tx.execute(format!(r#"{departures}
+        INSERT INTO headways (trip_id, headway_secs, exact_times)
+        SELECT trip_id || '-' || departure, headway, exact_times FROM departures"#).as_str(), [])?;
+    tx.execute(format!(r#"{departures}
+        INSERT INTO stop_times (trip_id, arrival_time, departure_time, stop_id, stop_headsign, stop_sequence, timepoint, drop_off_type, pickup_type)
+        SELECT st.trip_id || '-' || d.departure, st.arrival_time - origin.departure_time + d.departure, st.departure_time - origin.departure_time + d.departure,
+               st.stop_id, st.stop_headsign, st.stop_sequence, st.timepoint, st.drop_off_type, st.pickup_type
+            FROM departures d
+                INNER JOIN stop_times st ON st.trip_id = d.trip_id
+                INNER JOIN stop_times origin ON origin.trip_id = d.trip_id AND origin.stop_sequence = (SELECT min(stop_sequence) FROM stop_times WHERE trip_id = d.trip_id)"#).as_str(), [])?;
+    tx.execute_batch(format!(r#"
+        DELETE FROM stop_times WHERE trip_id IN (SELECT trip_id FROM frequencies WHERE {scope});
+        DELETE FROM trips WHERE trip_id IN (SELECT trip_id FROM frequencies WHERE {scope});"#).as_str())?;
+    tx.commit()
+}
+
+fn import_txt_file(archive: &ZipArchive, dir: &DirectoryContents, file_name: &str, db: &mut Connection, stmt_str: &str, indexes: &Vec<&str>, defaults: Option<&Defaults>, prefix: Option<&str>, overrides: &Overrides) -> Result<(), Box<dyn Error>> {
     let file = dir.lookup(file_name)?;
     let stream_reader = BufReader::new(archive.read(file)?);
     let mut rdr = csv::Reader::from_reader(stream_reader);
@@ -85,7 +184,7 @@ fn import_txt_file(archive: &ZipArchive, dir: &DirectoryContents, file_name: &st
                     .map(|hdr_name| {
                         let located_value = unsafe { std::str::from_utf8_unchecked(headers.get(hdr_name).and_then(|i| record.get(*i)).unwrap_or(EMPTY_SLICE)) };
                         if located_value.is_empty() {
-                            defaults.get(hdr_name).map(|str| *str)
+                            defaults.and_then(|d| d.get(*hdr_name)).map(|str| str.as_str())
                         } else {
                             Some(overrides.get(&hdr_name.to_string()).and_then(|o| o.get(located_value).map(|s| s.as_str()))
                                 .unwrap_or(located_value))
@@ -142,5 +241,7 @@ struct ShapeFileRecord {
 }
 
 const EMPTY_SLICE: &[u8] = &[];
-type Imports<'a, 'b> = [(&'a str, &'a str, Vec<&'a str>, phf::Map<&'a str, &'a str>, bool); 6];
-type Overrides = HashMap<String, HashMap<String, String>>;
+type Imports<'a> = [(&'a str, &'a str, Vec<&'a str>, bool); 9];
+const OPTIONAL_FILES: [&str; 3] = ["stops.txt", "frequencies.txt", "transfers.txt"];
+type Defaults = HashMap<String, String>;
+pub type Overrides = HashMap<String, HashMap<String, String>>;
@@ -1,6 +1,7 @@
 use std::collections::HashMap;
 use std::error::Error;
-use std::fs::File;
+use std::fs;
+use std::path::Path;
 use std::sync::Mutex;
 use futures::StreamExt;
 
@@ -12,19 +13,16 @@ use piz::ZipArchive;
 use polars::export::rayon::iter::IntoParallelRefIterator;
 use polars::export::rayon::iter::ParallelIterator;
 use rusqlite::{Connection, params};
-use rusqlite::Error::QueryReturnedNoRows;
 use rusqlite::functions::FunctionFlags;
 use serde::{Deserialize, Serialize};
 
-use BusBoardsServer::download_if_old;
 use crate::open_db;
-use crate::sources::{Source, StopAddType};
+use crate::sources::{open_source, Source, StopAddType};
+use crate::validation::record_issue;
 
 pub fn map_external_gtfs_stops(db: &mut Connection, db_path: &str, source: &Source) -> Result<HashMap<String, String>, Box<dyn Error>> {
     println!("Mapping stops for {}", source.name);
-    download_if_old(source.url, source.path)?;
-
-    let zip_file = File::open(source.path)?;
+    let zip_file = open_source(source)?;
     let mapping = unsafe { Mmap::map(&zip_file)? };
     let archive = ZipArchive::new(&mapping)?;
     let dir = as_tree(archive.entries())?;
@@ -32,8 +30,11 @@ pub fn map_external_gtfs_stops(db: &mut Connection, db_path: &str, source: &Sour
     let file = dir.lookup("stops.txt")?;
     let mut rdr = csv::Reader::from_reader(archive.read(file)?);
 
+    let manual_mappings = load_manual_mappings(source)?;
     let map = HashMap::new();
     let map_mutex = Mutex::new(map);
+    let review_mutex = Mutex::new(Vec::new());
+    db.execute("DELETE FROM validation_issues WHERE check_name IN ('low_confidence_stop_match', 'distant_stop_match') AND source=?", [&source.name])?;
     db.execute("INSERT OR IGNORE INTO localities (code, name, qualifier, parent, lat, long) VALUES ('Europe', 'Europe', NULL, NULL, 50.0, 9.0)", [])?;
 
     let stops = rdr.deserialize().map(|r: Result<GTFSStop, _>| r.unwrap()).collect_vec();
@@ -44,12 +45,56 @@ pub fn map_external_gtfs_stops(db: &mut Connection, db_path: &str, source: &Sour
             return;
         }
         
-        if let (StopAddType::MatchStopBeforeAdd, Ok((stop, stance, indicator))) = (&source.add_stops, get_nearest_stop(&mut db, record.stop_lat, record.stop_lon)) {
+        // manually reviewed mappings take priority - an empty ATCO code means the stop should always be created
+        let manual = manual_mappings.get(&record.stop_id);
+        if let Some(Some(code)) = manual {
+            if stance_exists(&mut db, code) {
+                map_mutex.lock().unwrap().insert(record.stop_id.to_string(), code.to_string());
+                return;
+            }
+            println!("Manual mapping of {} to {code} does not match a stance", record.stop_id);
+        }
+
+        let best = if source.add_stops == StopAddType::MatchStopBeforeAdd && !matches!(manual, Some(None)) {
+            best_candidate(&mut db, record).expect("Error finding stop candidates")
+        } else {
+            None
+        };
+        if let Some(candidate) = best.as_ref() {
+            let matched = candidate.score >= ACCEPT_SCORE;
+            // a well named stop can score highly even when it is some way from the stance it is matched to
+            let check = if candidate.score < REVIEW_SCORE {
+                Some("low_confidence_stop_match")
+            } else if candidate.dist > REPORT_MATCH_DISTANCE {
+                Some("distant_stop_match")
+            } else {
+                None
+            };
+            if let Some(check) = check {
+                record_issue(&db, check, source.name.as_str(), None, record.stop_id.as_str(),
+                    format!("{} {} {} {} at {:.0}m (score {:.2})", record.stop_name, if matched { "matched to" } else { "not matched to" },
+                            candidate.name, candidate.code, candidate.dist, candidate.score).as_str())
+                    .expect("Error recording stop match for review");
+                review_mutex.lock().unwrap().push(StopReview {
+                    source: source.name.clone(),
+                    stop_id: record.stop_id.clone(),
+                    atco_code: candidate.code.clone(),
+                    stop_name: record.stop_name.clone(),
+                    candidate_name: format!("{}, {}", candidate.name, candidate.locality_name),
+                    distance: candidate.dist.round(),
+                    score: (candidate.score * 100.0).round() / 100.0,
+                    matched,
+                    check
+                });
+            }
+        }
+
+        if let Some(Candidate { stop, code: stance, indicator, .. }) = best.filter(|c| c.score >= ACCEPT_SCORE) {
             let indicator = indicator.unwrap_or("".to_string());
             if indicator.eq_ignore_ascii_case("at") {
                 // if this stop is called at - use this stop
                 map_mutex.lock().unwrap().insert(record.stop_id.to_string(), stance);
-            } else if let Ok(code) = db.query_row("SELECT code FROM stances WHERE stop=? AND lower(indicator)='at' LIMIT 1", [], |row| row.get::<_, String>(0)) {
+            } else if let Ok(code) = db.query_row("SELECT code FROM stances WHERE stop=? AND lower(indicator)='at' LIMIT 1", [stop], |row| row.get::<_, String>(0)) {
                 // if this stop contains any stop with at - use that
                 map_mutex.lock().unwrap().insert(record.stop_id.to_string(), code);
             } else {
@@ -102,18 +147,95 @@ pub fn map_external_gtfs_stops(db: &mut Connection, db_path: &str, source: &Sour
             stmt.execute(params![&record.stop_id, &record.stop_lat, &record.stop_lon, stop_id]).expect("Stance create fail without existing stop");
         }
     });
+
+    let review = review_mutex.into_inner().unwrap();
+    let review_path = format!("stop_review_{}.csv", source.name.replace(' ', "_"));
+    if review.is_empty() {
+        let _ = fs::remove_file(&review_path);
+    } else {
+        println!("{} low confidence or distant stop matches for {} written to {review_path}", review.len(), source.name);
+        let mut writer = csv::Writer::from_path(&review_path)?;
+        for row in review.iter().sorted_by(|a, b| a.score.total_cmp(&b.score)) {
+            writer.serialize(row)?;
+        }
+        writer.flush()?;
+    }
     Ok(map_mutex.into_inner().unwrap())
 }
 
+/// Manually reviewed mappings, with columns source, stop_id and atco_code (empty to always create a new stop)
+const MANUAL_MAPPINGS_PATH: &str = "stop_mappings.csv";
+/// Only stances within this distance (in metres) are considered
+const MATCH_RADIUS: f64 = 200.0;
+/// Minimum score for an external stop to be merged into an existing stop
+const ACCEPT_SCORE: f64 = 0.6;
+/// Best candidates scoring under this are listed for review
+const REVIEW_SCORE: f64 = 0.75;
+/// Best candidates further than this (in metres) are listed for review however well they score
+const REPORT_MATCH_DISTANCE: f64 = 25.0;
+
 // https://gist.github.com/graydon/11198540
 const UK_LAT_LON: (f64, f64, f64, f64) = (-7.57216793459, 49.959999905, 1.68153079591, 58.6350001085);
 
-fn get_nearest_stop(db: &mut Connection, lat: f64, lon: f64) -> rusqlite::Result<(u64, String, Option<String>)> {
+/// Manual mappings for a source, by external stop ID
+fn load_manual_mappings(source: &Source) -> Result<HashMap<String, Option<String>>, Box<dyn Error>> {
+    if !Path::new(MANUAL_MAPPINGS_PATH).exists() {
+        return Ok(HashMap::new());
+    }
+    let mut rdr = csv::Reader::from_path(MANUAL_MAPPINGS_PATH)?;
+    let mut mappings = HashMap::new();
+    for row in rdr.deserialize() {
+        let row: ManualMapping = row?;
+        if row.source == source.name {
+            mappings.insert(row.stop_id, row.atco_code.filter(|code| !code.is_empty()));
+        }
+    }
+    Ok(mappings)
+}
+
+/// Find the best scoring existing stop for an external stop, using its nearest stance
+fn best_candidate(db: &mut Connection, record: &GTFSStop) -> rusqlite::Result<Option<Candidate>> {
+    let (lat, lon) = (record.stop_lat, record.stop_lon);
     if lat <= UK_LAT_LON.1 || lat >= UK_LAT_LON.3 || lon <= UK_LAT_LON.0 || lon >= UK_LAT_LON.2 {
-        return Err(QueryReturnedNoRows);
+        return Ok(None);
     }
-    db.query_row("SELECT stop,code,indicator,min(geo_distance(lat, long, ?, ?)) as dist FROM stances GROUP BY stop HAVING dist < 50 ORDER BY dist LIMIT 1",
-   [lat, lon], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+    let lat_range = MATCH_RADIUS / 111_000.0;
+    let lon_range = lat_range / lat.to_radians().cos();
+    let candidates = db.prepare_cached(
+        "SELECT stances.stop, stances.code, stances.indicator, stops.name, coalesce(stops.locality_name, ''), geo_distance(stances.lat, stances.long, ?1, ?2) AS dist
+            FROM stances_rtree r
+                INNER JOIN stances ON stances.rowid = r.id
+                INNER JOIN stops ON stops.id = stances.stop
+            WHERE r.max_lat >= ?1 - ?3 AND r.min_lat <= ?1 + ?3 AND r.max_long >= ?2 - ?4 AND r.min_long <= ?2 + ?4 AND dist < ?5
+            ORDER BY dist")?
+        .query_map(params![lat, lon, lat_range, lon_range, MATCH_RADIUS], |row| Ok(Candidate {
+            stop: row.get(0)?,
+            code: row.get(1)?,
+            indicator: row.get(2)?,
+            name: row.get(3)?,
+            locality_name: row.get(4)?,
+            dist: row.get(5)?,
+            score: 0.0
+        }))?
+        .collect::<rusqlite::Result<Vec<Candidate>>>()?;
+    // candidates are ordered by distance, so this keeps the nearest stance of each stop
+    Ok(candidates.into_iter()
+        .unique_by(|candidate| candidate.stop)
+        .map(|candidate| Candidate { score: score_candidate(record, &candidate), ..candidate })
+        .max_by(|a, b| a.score.total_cmp(&b.score)))
+}
+
+/// Score from 0 to 1 combining distance, name similarity and whether the stop name mentions the candidate's town
+fn score_candidate(record: &GTFSStop, candidate: &Candidate) -> f64 {
+    let distance = 1.0 - (candidate.dist / MATCH_RADIUS).min(1.0);
+    let external_name = record.stop_name.to_lowercase();
+    let town = candidate.locality_name.split(" › ").next().unwrap_or_default().to_lowercase();
+    let name = candidate.name.to_lowercase();
+    // external names often include the town, e.g. "Edinburgh (Bus Station)"
+    let name_similarity = sorensen::distance(external_name.as_bytes(), name.as_bytes())
+        .max(sorensen::distance(external_name.as_bytes(), format!("{town} {name}").as_bytes()));
+    let locality = if !town.is_empty() && external_name.contains(town.as_str()) { 1.0 } else { 0.0 };
+    0.5 * distance + 0.35 * name_similarity + 0.15 * locality
 }
 
 fn stance_exists(db: &mut Connection, stop_id: &str) -> bool {
@@ -126,6 +248,37 @@ fn add_distance_function(db: &mut Connection) -> rusqlite::Result<()> {
     })
 }
 
+struct Candidate {
+    stop: u64,
+    code: String,
+    indicator: Option<String>,
+    name: String,
+    locality_name: String,
+    dist: f64,
+    score: f64
+}
+
+#[derive(Deserialize)]
+struct ManualMapping {
+    source: String,
+    stop_id: String,
+    atco_code: Option<String>
+}
+
+/// Row of the review list - the first three columns match stop_mappings.csv
+#[derive(Serialize)]
+struct StopReview {
+    source: String,
+    stop_id: String,
+    atco_code: String,
+    stop_name: String,
+    candidate_name: String,
+    distance: f64,
+    score: f64,
+    matched: bool,
+    check: &'static str
+}
+
 #[derive(Serialize, Deserialize)]
 struct GTFSStop {
     stop_id: String,
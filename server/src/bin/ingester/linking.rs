@@ -1,3 +1,4 @@
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::thread::sleep;
 use std::time::Duration;
@@ -8,10 +9,13 @@ use polars::export::rayon::iter::*;
 use r2d2::{ManageConnection, Pool, PooledConnection};
 use r2d2_sqlite::SqliteConnectionManager;
 use rand::Rng;
-use rusqlite::{params, CachedStatement};
+use rusqlite::{params, CachedStatement, Connection};
 use thread_local::ThreadLocal;
+use BusBoardsServer::observations::{get_observations_path, has_observations};
+use crate::sources::{Source, source_filter};
 
-pub fn link_trips(db_path: &str) -> Result<(), Box<dyn Error>> {
+/// Link trips imported from the given sources
+pub fn link_trips(db_path: &str, sources: &[&Source]) -> Result<(), Box<dyn Error>> {
     println!("Linking trips");
     
     let db_pool = Pool::builder()
@@ -25,18 +29,19 @@ pub fn link_trips(db_path: &str) -> Result<(), Box<dyn Error>> {
     get_pool(&db_pool).execute_batch("ANALYZE trips; ANALYZE stop_times; ANALYZE stances; ANALYZE trips_route_id_index; ANALYZE stop_times_trip_id_stop_sequence_index;").unwrap();
 
     println!("Linking using block ID");
-    get_pool(&db_pool).execute(r#"INSERT INTO links SELECT * FROM (SELECT lag(trips.trip_id) over (PARTITION BY block_id ORDER BY departure_time) AS "from", trips.trip_id AS "to", 0 FROM trips
+    get_pool(&db_pool).execute(format!(r#"INSERT INTO links ("from", "to", show_then, confidence) SELECT * FROM (SELECT lag(trips.trip_id) over (PARTITION BY block_id ORDER BY departure_time) AS "from", trips.trip_id AS "to", 0, {BLOCK_CONFIDENCE} FROM trips
          INNER JOIN stop_times AS departure ON departure.trip_id=trips.trip_id AND departure.stop_sequence=(SELECT max(stop_sequence) FROM stop_times WHERE trip_id=trips.trip_id)
-         WHERE block_id IS NOT NULL)
-         WHERE "from" IS NOT NULL"#, [])?;
+         WHERE block_id IS NOT NULL AND {})
+         WHERE "from" IS NOT NULL"#, source_filter("trips.trip_id", sources)).as_str(), [])?;
     
     println!("Finding routes to link manually");
-    let routes = get_pool(&db_pool).prepare(
+    let routes = get_pool(&db_pool).prepare(format!(
         "SELECT route_id AS rid, service_id
                    FROM trips
                    WHERE route_id NOT IN (SELECT route_id FROM trips WHERE block_id IS NOT NULL)
+                     AND {}
                    GROUP BY rid, service_id
-                   HAVING count(trips.trip_id) > 1")?
+                   HAVING count(trips.trip_id) > 1", source_filter("route_id", sources)).as_str())?
         .query_map([], |row| Ok(RouteInfo {
             route_id: row.get(0)?,
             service_id: row.get(1)?
@@ -70,16 +75,208 @@ pub fn link_trips(db_path: &str) -> Result<(), Box<dyn Error>> {
     let mut db = get_pool(&db_pool);
     let tx = db.transaction()?;
     {
-        let mut stmt = tx.prepare_cached("INSERT INTO links (\"from\", \"to\", show_then) VALUES (?, ?, ?)")?;
+        let mut stmt = tx.prepare_cached("INSERT INTO links (\"from\", \"to\", show_then, confidence) VALUES (?, ?, ?, ?)")?;
         for result in results {
-            stmt.execute(params![result.trip1.as_str(), result.trip2.as_str(), &result.show_then])?;
+            stmt.execute(params![result.trip1.as_str(), result.trip2.as_str(), &result.show_then, ROUTE_CONFIDENCE])?;
         }
     }
     tx.commit()?;
+
+    link_across_routes(&db_pool, sources)?;
     
     Ok(())
 }
 
+/// Confidence of links taken from block IDs
+const BLOCK_CONFIDENCE: f64 = 1.0;
+/// Confidence of links between opposite directions of the same route and service
+const ROUTE_CONFIDENCE: f64 = 0.8;
+/// Longest layover (in seconds) between trips of different routes which can be linked
+const LAYOVER_WINDOW: i64 = 1200;
+/// Cross-route links below this confidence are discarded
+const MIN_CROSS_ROUTE_CONFIDENCE: f64 = 0.5;
+
+/// Link unlinked trips to a trip of the same agency (on any route) departing the stop they terminate at
+fn link_across_routes(db_pool: &Pool<SqliteConnectionManager>, sources: &[&Source]) -> Result<(), Box<dyn Error>> {
+    println!("Linking trips across routes");
+    let db = get_pool(db_pool);
+    let scope = source_filter("t.trip_id", sources);
+    let ends = get_terminals(&db, "max", "arrival_time", scope.as_str(), "SELECT \"from\" FROM links WHERE \"from\" IS NOT NULL")?;
+    let origins = get_terminals(&db, "min", "departure_time", scope.as_str(), "SELECT \"to\" FROM links WHERE \"to\" IS NOT NULL")?;
+
+    // origins by agency and stop, sorted by departure time
+    let mut origins_by_stop: HashMap<(&str, u64), Vec<&Terminal>> = HashMap::new();
+    for origin in &origins {
+        origins_by_stop.entry((origin.agency_id.as_str(), origin.stop)).or_default().push(origin);
+    }
+    for stop_origins in origins_by_stop.values_mut() {
+        stop_origins.sort_by_key(|origin| origin.time);
+    }
+
+    // best candidate for each terminating trip, before checking consistency across days
+    let candidates: Vec<(&Terminal, &Terminal, f64)> = ends.par_iter().filter_map(|end| {
+        let stop_origins = origins_by_stop.get(&(end.agency_id.as_str(), end.stop))?;
+        let first = stop_origins.partition_point(|origin| origin.time < end.time);
+        stop_origins[first..].iter()
+            .take_while(|origin| origin.time - end.time <= LAYOVER_WINDOW)
+            .filter(|origin| origin.trip_id != end.trip_id && end.runs_with(origin))
+            .map(|origin| {
+                let layover = 1.0 - (origin.time - end.time) as f64 / LAYOVER_WINDOW as f64;
+                (*origin, 0.6 * end.day_overlap(origin) + 0.4 * layover)
+            })
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map(|(origin, score)| (end, origin, score))
+    }).collect();
+
+    // a route pair seen across more of the route's services (i.e. on more days) is more likely a real interworking
+    let mut route_services: HashMap<(&str, u64), HashSet<&str>> = HashMap::new();
+    for end in &ends {
+        route_services.entry((end.route_id.as_str(), end.stop)).or_default().insert(end.service_id.as_str());
+    }
+    let mut pair_services: HashMap<(&str, &str, u64), HashSet<&str>> = HashMap::new();
+    for (end, origin, _) in &candidates {
+        pair_services.entry((end.route_id.as_str(), origin.route_id.as_str(), end.stop)).or_default().insert(end.service_id.as_str());
+    }
+    let mut scored = candidates.iter().map(|(end, origin, score)| {
+        let consistency = pair_services[&(end.route_id.as_str(), origin.route_id.as_str(), end.stop)].len() as f64
+            / route_services[&(end.route_id.as_str(), end.stop)].len() as f64;
+        (*end, *origin, 0.7 * score + 0.3 * consistency)
+    }).filter(|(_, _, confidence)| *confidence >= MIN_CROSS_ROUTE_CONFIDENCE).collect_vec();
+    scored.sort_by(|(_, _, a), (_, _, b)| b.total_cmp(a));
+
+    // each trip can only link to one other trip in each direction
+    let mut used_from = HashSet::new();
+    let mut used_to = HashSet::new();
+    let mut db = db;
+    let tx = db.transaction()?;
+    let mut count = 0;
+    {
+        let mut stmt = tx.prepare_cached(r#"INSERT INTO links ("from", "to", show_then, confidence) VALUES (?1, ?2,
+            (SELECT stops.locality NOT IN (SELECT locality FROM stop_times
+                                               INNER JOIN stances ON stances.code=stop_times.stop_id
+                                               INNER JOIN stops ON stops.id=stances.stop
+                                           WHERE stop_times.trip_id=?1)
+                FROM stop_times
+                    INNER JOIN stances ON stances.code=stop_times.stop_id
+                    INNER JOIN stops ON stops.id=stances.stop
+                WHERE stop_times.trip_id=?2 ORDER BY stop_sequence DESC LIMIT 1), ?3)"#)?;
+        for (end, origin, confidence) in scored {
+            if used_from.contains(end.trip_id.as_str()) || used_to.contains(origin.trip_id.as_str()) {
+                continue;
+            }
+            used_from.insert(end.trip_id.as_str());
+            used_to.insert(origin.trip_id.as_str());
+            stmt.execute(params![end.trip_id, origin.trip_id, confidence])?;
+            count += 1;
+        }
+    }
+    tx.commit()?;
+    println!("Linked {count} trips across routes");
+    Ok(())
+}
+
+/// Longest gap (in seconds) between a vehicle's last report on one trip and its first report on the next
+const OBSERVED_GAP: i64 = 7200;
+/// A vehicle must be seen working a pair of trips on at least this many days to link them
+const MIN_OBSERVED_DAYS: i64 = 2;
+/// Observed links below this confidence (the share of the trip's observed days on which it was followed by the same trip) are discarded
+const MIN_OBSERVED_CONFIDENCE: f64 = 0.5;
+
+/// Link trips which realtime vehicles have been seen working one after the other, replacing any static links for those trips.
+/// Replaced static links are kept so that they can be restored if the trips stop being observed together
+pub fn link_observed(db: &mut Connection) -> Result<(), Box<dyn Error>> {
+    if !has_observations() {
+        println!("No vehicle observations - skipping observed linking");
+        return Ok(())
+    }
+    println!("Linking trips from vehicle observations");
+    db.execute("ATTACH ? AS observations", [format!("file:{}?mode=ro", get_observations_path())])?;
+    let pairs: Vec<(String, String, f64)> = db.prepare(format!(r#"
+        WITH sequences AS (
+            SELECT trip_id, date, last_seen,
+                   lead(trip_id) OVER vehicle AS next_trip, lead(first_seen) OVER vehicle AS next_seen
+                FROM observations.vehicle_trips
+                WINDOW vehicle AS (PARTITION BY responder, vehicle_id, date ORDER BY first_seen)
+        ), trip_days AS (
+            SELECT trip_id, count(DISTINCT date) AS days FROM observations.vehicle_trips GROUP BY trip_id
+        )
+        SELECT s.trip_id, s.next_trip, count(DISTINCT s.date) * 1.0 / d.days AS confidence
+            FROM sequences s
+                INNER JOIN trip_days d ON d.trip_id = s.trip_id
+                INNER JOIN main.trips t1 ON t1.trip_id = s.trip_id
+                INNER JOIN main.trips t2 ON t2.trip_id = s.next_trip
+            WHERE s.next_trip IS NOT NULL AND s.next_trip <> s.trip_id
+                AND s.next_seen - s.last_seen BETWEEN -120 AND {OBSERVED_GAP}
+            GROUP BY s.trip_id, s.next_trip
+            HAVING count(DISTINCT s.date) >= {MIN_OBSERVED_DAYS} AND confidence >= {MIN_OBSERVED_CONFIDENCE}
+            ORDER BY confidence DESC, count(DISTINCT s.date) DESC"#).as_str())?
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+        .collect::<rusqlite::Result<_>>()?;
+
+    // each trip can only link to one other trip in each direction
+    let mut used_from = HashSet::new();
+    let mut used_to = HashSet::new();
+    let tx = db.transaction()?;
+    // Put back the static links replaced last time, so that links which are no longer observed fall back to them
+    tx.execute("DELETE FROM links WHERE observed=1", [])?;
+    tx.execute(r#"INSERT INTO links ("from", "to", show_then, confidence, observed)
+        SELECT "from", "to", show_then, confidence, 0 FROM replaced_links
+            WHERE "from" IN (SELECT trip_id FROM trips) AND "to" IN (SELECT trip_id FROM trips)"#, [])?;
+    tx.execute("DELETE FROM replaced_links", [])?;
+    let mut count = 0;
+    {
+        let mut replaced_stmt = tx.prepare_cached(r#"INSERT INTO replaced_links ("from", "to", show_then, confidence)
+            SELECT "from", "to", show_then, confidence FROM links WHERE ("from"=?1 OR "to"=?2) AND observed=0"#)?;
+        let mut stmt = tx.prepare_cached(r#"INSERT INTO links ("from", "to", show_then, confidence, observed) VALUES (?1, ?2,
+            (SELECT stops.locality NOT IN (SELECT locality FROM stop_times
+                                               INNER JOIN stances ON stances.code=stop_times.stop_id
+                                               INNER JOIN stops ON stops.id=stances.stop
+                                           WHERE stop_times.trip_id=?1)
+                FROM stop_times
+                    INNER JOIN stances ON stances.code=stop_times.stop_id
+                    INNER JOIN stops ON stops.id=stances.stop
+                WHERE stop_times.trip_id=?2 ORDER BY stop_sequence DESC LIMIT 1), ?3, 1)"#)?;
+        for (from, to, confidence) in &pairs {
+            if used_from.contains(from.as_str()) || used_to.contains(to.as_str()) {
+                continue;
+            }
+            used_from.insert(from.as_str());
+            used_to.insert(to.as_str());
+            replaced_stmt.execute(params![from, to])?;
+            stmt.execute(params![from, to, confidence])?;
+            count += 1;
+        }
+    }
+    tx.commit()?;
+    db.execute("DETACH observations", [])?;
+    println!("Linked {count} trips from vehicle observations");
+    Ok(())
+}
+
+/// First or last stop of each trip in scope which is not already linked
+fn get_terminals(db: &PooledConnection<SqliteConnectionManager>, aggregate: &str, time_column: &str, scope: &str, linked: &str) -> rusqlite::Result<Vec<Terminal>> {
+    db.prepare(format!(r#"
+        SELECT t.trip_id, t.route_id, r.agency_id, t.service_id, s.stop, st.{time_column}, coalesce(c.validity, 0), coalesce(c.start_date, 0), coalesce(c.end_date, 99999999)
+            FROM (SELECT trip_id, stop_id, {time_column}, {aggregate}(stop_sequence) FROM stop_times GROUP BY trip_id) st
+                INNER JOIN trips t ON t.trip_id = st.trip_id
+                INNER JOIN routes r ON r.route_id = t.route_id
+                INNER JOIN stances s ON s.code = st.stop_id
+                LEFT OUTER JOIN calendar c ON c.service_id = t.service_id
+            WHERE t.trip_id NOT IN ({linked}) AND {scope}"#).as_str())?
+        .query_map([], |row| Ok(Terminal {
+            trip_id: row.get(0)?,
+            route_id: row.get(1)?,
+            agency_id: row.get(2)?,
+            service_id: row.get(3)?,
+            stop: row.get(4)?,
+            time: row.get(5)?,
+            validity: row.get(6)?,
+            start_date: row.get(7)?,
+            end_date: row.get(8)?,
+        }))?
+        .collect()
+}
+
 /// Get connection from database pool
 pub fn get_pool(db: &Pool<SqliteConnectionManager>) -> PooledConnection<SqliteConnectionManager> {
     let mut conn: Result<PooledConnection<SqliteConnectionManager>, r2d2::Error>;
@@ -124,6 +321,35 @@ pub fn get_trips_stmt(db: &PooledConnection<SqliteConnectionManager>) -> CachedS
         "#).unwrap()
 }
 
+/// A trip's first or last stop
+struct Terminal {
+    trip_id: String,
+    route_id: String,
+    agency_id: String,
+    service_id: String,
+    stop: u64,
+    time: i64,
+    validity: u8,
+    start_date: u32,
+    end_date: u32
+}
+
+impl Terminal {
+    fn runs_with(&self, other: &Terminal) -> bool {
+        self.start_date <= other.end_date && other.start_date <= self.end_date
+            && (self.validity == 0 || other.validity == 0 || self.validity & other.validity != 0)
+    }
+
+    /// Proportion of this trip's weekdays the other trip also runs on - unknown for services only using calendar dates
+    fn day_overlap(&self, other: &Terminal) -> f64 {
+        if self.validity == 0 || other.validity == 0 {
+            0.5
+        } else {
+            (self.validity & other.validity).count_ones() as f64 / self.validity.count_ones() as f64
+        }
+    }
+}
+
 struct TripsResult {
     trip1: String,
     trip2: String,
@@ -1,18 +1,24 @@
 use std::collections::HashMap;
 use std::error::Error;
 use std::fs;
+use std::fs::File;
+use std::path::Path;
 use std::str::FromStr;
+use itertools::Itertools;
 use rusqlite::{Connection, params};
 use serde::{Deserialize, Serialize};
 use spex::parsing::XmlReader;
 use spex::xml::Element;
 use BusBoardsServer::download_if_old;
 
+use crate::stop_areas::insert_stop_areas;
+
 pub type Localities = HashMap<LocalityCode, HashMap<StopName, Vec<Stance>>>;
 pub type LocalityCode = String;
 pub type StopName = String;
 
 const NAPTAN_NS: &str = "http://www.naptan.org.uk/";
+const LOCALITIES_PATH: &str = "localities.json";
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Stance {
@@ -29,18 +35,30 @@ pub struct Stance {
     #[serde(rename = "Arrival")]
     pub arrival: bool,
     #[serde(rename = "CrsRef")]
-    pub crs: Option<String>
+    pub crs: Option<String>,
+    /// Most specific NaPTAN stop area containing this stance
+    #[serde(rename = "StopArea", default)]
+    pub stop_area: Option<String>
 }
 
 pub fn load_localities_json() -> Localities {
-    let json_str = fs::read_to_string("localities.json").expect("Cannot find localities.json");
+    let json_str = fs::read_to_string(LOCALITIES_PATH).expect("Cannot find localities.json");
     serde_json::from_str(&json_str).expect("JSON parse fail")
 }
 
+/// Whether the stances stage has written localities.json
+pub fn has_localities_json() -> bool {
+    Path::new(LOCALITIES_PATH).exists()
+}
+
+/// Open the NPTG localities XML, downloading it if old
+pub fn download_nptg() -> Result<File, Box<dyn Error>> {
+    download_if_old("https://naptan.api.dft.gov.uk/v1/nptg", "NPTG.xml")
+}
+
 pub fn insert_localities(db: &mut Connection) -> Result<(), Box<dyn Error>> {
     println!("Insert localities into database");
-    let nptg_file = download_if_old("https://naptan.api.dft.gov.uk/v1/nptg", "NPTG.xml")?;
-    let xml = XmlReader::parse_auto(nptg_file)?;
+    let xml = XmlReader::parse_auto(download_nptg()?)?;
     let mut xml_localities = xml.root()
         .pre_ns(NAPTAN_NS)
         .all("NptgLocalities");
@@ -81,24 +99,30 @@ pub fn insert_stops(db: &mut Connection) -> Result<(), Box<dyn Error>> {
     println!("Importing stops");
     let localities = load_localities_json();
     db.execute_batch(r"
-        DELETE FROM stops;
         DELETE FROM stances;
+        DELETE FROM stops;
+        DELETE FROM dropped_stops;
     ")?;
+    insert_stop_areas(db)?;
 
     let tx = db.transaction()?;
     {
-        let mut insert_stop = tx.prepare("INSERT INTO stops (name, locality) VALUES (?, ?) RETURNING id")?;
+        let mut insert_stop = tx.prepare("INSERT INTO stops (name, locality, stop_area) VALUES (?, ?, ?) RETURNING id")?;
         let mut insert_stance = tx.prepare("INSERT INTO stances (code, street, indicator, lat, long, stop, crs) VALUES (?, ?, ?, ?, ?, ?, ?)")?;
 
         for (locality, stops) in localities {
             for (stop, stances) in stops {
+                // the stop takes the stop area shared by most of its stances
+                let stop_area = stances.iter().filter_map(|stance| stance.stop_area.as_deref()).counts()
+                    .into_iter().max_by(|(a_area, a), (b_area, b)| a.cmp(b).then(b_area.cmp(a_area)))
+                    .map(|(area, _)| area.to_string());
                 // try to insert stop, try inserting into Unknown locality if it fails
-                let insert_stop_result = match insert_stop.query_row([&stop, &locality], |row| row.get::<_, u64>(0)) {
+                let insert_stop_result = match insert_stop.query_row(params![&stop, &locality, &stop_area], |row| row.get::<_, u64>(0)) {
                     Ok(stop_id) => Ok(stop_id),
                     Err(rusqlite::Error::SqliteFailure(err, msg)) => {
                         if err.code == rusqlite::ErrorCode::ConstraintViolation {
                             tx.execute("INSERT OR IGNORE INTO localities (code, name, qualifier, parent, lat, long) VALUES ('Unknown', 'Unknown', NULL, NULL, 53.870659, 1.200235)", [])?;
-                            insert_stop.query_row([&stop, "Unknown"], |row| row.get::<_, u64>(0))
+                            insert_stop.query_row(params![&stop, "Unknown", &stop_area], |row| row.get::<_, u64>(0))
                         } else {
                             Err(rusqlite::Error::SqliteFailure(err, msg))
                         }
@@ -1,94 +1,145 @@
-pub const LOCALITY_CHANGES: [(&str, &str, &str, Option<&str>); 10] = [
-    ("N0077860", "Park Lane (Tyne and Wear Metro Station)", "E0057917", None),
-    ("E0057948", "Bradford Interchange Rail Station", "N0077005", Some("Bradford")),
-    ("E0057974", "Leeds Rail Station", "N0077039", Some("Leeds")),
-    ("ES003919", "Dundee Rail Station", "ES000536", Some("Dundee")),
-    ("E0034956", "London Victoria Coach Station", "E0034917", Some("Victoria")),
-    ("E0057190", "Luton Rail Station", "N0071638", Some("Luton")),
-    ("N0078022", "Bus Station", "ES002978", Some("Glasgow")),
-    ("E0039083", "Rail Station Entrance", "N0071638", Some("Luton")),
-    ("E0033284", "Wellington Bridge St Real Time Tracking", "N0077039", Some("Leeds")),
-    ("E0057149", "Rail Station", "N0077769", Some("Bournemouth"))
-];
+use std::collections::HashSet;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
 
-pub const MANUAL_RENAMES: [(&str, &str, &str); 67] = [
-    ("N0078622", "^Edinburgh Airport \\(Edinburgh Trams\\)$", "Airport"),
-    ("ES001737", "^Haymarket \\(Edinburgh Trams\\)$", "Rail Station"),
-    ("ES001737", "^Haymarket Station$", "Rail Station"),
-    ("E0049583", "^Stand 4$", "Bus Station"),  // Tadcaster
-    ("ES000536", "^Bus Station$", "Seagate Bus Station"),  // Dundee
-    ("N0078275", "^Edinburgh Park Station$", "Rail Station"),
-    ("N0078275", "^Edinburgh Park Station \\(Edinburgh Trams\\)$", "Rail Station"),
-    ("E0057900", "^Newcastle Rail Station$", "Newcastle Central Rail Station"),
-    ("E0057900", "^Central Station \\(Tyne and Wear Metro Station\\)$", "Newcastle Central Rail Station"),
-    ("E0057900", "^Central Station Bewick Street$", "Newcastle Central Rail Station"),
-    ("E0057900", "^Central Stn Clayton St$", "Newcastle Central Rail Station"),
-    ("E0057900", "^Central Stn Neville St$", "Newcastle Central Rail Station"),
-    ("E0057900", "^Central Stn Westgate Rd$", "Newcastle Central Rail Station"),
-    ("E0057900", "^Central Station Westgate Road$", "Newcastle Central Rail Station"),
-    ("E0057900", "^Central Rail Station$", "Newcastle Central Rail Station"),
-    ("E0057900", "^Central Stn$", "Newcastle Central Rail Station"),
-    ("N0078208", "^ Ponteland Road - Newcastle Airport$", "Newcastle Airport Ponteland Road"),
-    ("N0078208", "^Newcastle Airport Metro Station$", "Newcastle Airport (Tyne and Wear Metro Station)"),
-    ("E0055009", "^Hull Rail Station$", "Paragon Interchange (Rail Station)"),
-    ("E0055009", "^Hull Interchange$", "Paragon Interchange (Rail Station)"),
-    ("N0077005", "^Bradford Interchange Rail Station$", "Interchange"),
-    ("E0057917", "^Sunderland Interchange$", "Park Lane Interchange"),
-    ("E0057917", "^Park Lane \\(Tyne and Wear Metro Station\\)$", "Park Lane Interchange"),
-    ("E0057917", "^Sunderland \\(Tyne and Wear Metro Station\\)$", "Rail Station"),
-    ("ES000536", "^Station$", "Rail Station"),
-    ("E0050224", "^East Midlands Parkway Station$", "East Midlands Parkway Rail Station"),
-    ("E0030375", "^Meadowhall Rail Station$", "Meadowhall Interchange"),
-    ("E0030375", "^Meadowhall Interchange \\(S Yorks Supertram\\)$", "Meadowhall Interchange"),
-    ("N0077854", "^Metrocentre Rail Station$", "Metrocentre Interchange"),
-    ("N0077039", "^Leeds BS Ent Real Time Tracking$", "Bus Station"),
-    ("N0077039", "^Leeds Bus Station Entrance$", "Bus Station"),
-    ("N0077039", "^Station A$", "Rail Station A"),
-    ("N0077039", "^Station B$", "Rail Station B"),
-    ("N0077039", "^Station C$", "Rail Station C"),
-    ("N0077039", "^Station D$", "Rail Station D"),
-    ("N0077039", "^Station E$", "Rail Station E"),
-    ("N0077039", "^Station F$", "Rail Station F"),
-    ("N0077039", "^Leeds Station Interchange$", "Rail Station"),
-    ("E0039258", "^Bus Station Express Lounge$", "Bus Station"),
-    ("E0033527", "^Bus Station stand D$", "Bus Station"),
-    ("N0075057", "^Rail Station$", "Manchester Airport Rail Station"),
-    ("N0075057", "^Manchester Airport The Station$", "Manchester Airport Rail Station"),
-    ("N0075057", "Manchester Airport The Station", "Rail Station"),
-    ("N0075057", "^Manchester Airport \\(Manchester Metrolink\\)$", "Manchester Airport Rail Station"),
-    ("E0034917", "^London Victoria Coach Station$", "Victoria Coach Station"),
-    ("N0073334", "Park and Ride Stance C", "Park and Ride"),
-    ("N0073334", "Broxden Park\\+Ride", "Park and Ride"),
-    ("N0071638", "^Luton Rail Station$", "Rail Station Interchange"),
-    ("N0071638", "^Luton Station Interchange$", "Rail Station Interchange"),
-    ("N0071638", "^Rail Station Entrance$", "Rail Station Interchange"),
-    ("E0056332", "^Bus Station Arrive$", "Bus Station"),
-    ("E0015874", "^Arrival Stand$", "Bus Station"),
-    ("ES002978", "^Bus Station$", "Partick Station Interchange"),
-    ("ES002978", "^Partick Rail Station$", "Partick Station Interchange"),
-    ("ES002978", "^Partick SPT Subway Station$", "Partick Station Interchange"),
-    ("ES002978", "^Partick Interchange$", "Partick Station Interchange"),
-    ("ES003486", "Stance", "Rail Station"), // could be Goosecroft Road - bit of an editorial decision
-    ("ES001670", "Stance", "Bus Station"),
-    ("ES000097", "Stance", "Bus Station"),
-    ("ES001470", "^Stance 8$", "Transport Interchange"),
-    ("ES000923", "^Town Centre Stances$", "Town Centre"),
-    ("ES000923", "^Town Centre stances$", "Town Centre"),
-    ("N0077005", "^Nelson Street Real Time Tracking$", "Nelson Street"),
-    ("N0078288", "^St Partick Square$", "St Patrick Square"),
-    ("E0049949", "^Rail station$", "Rail Station"),
-    ("ES003921", "^Games Shuttle$", "Buchanan Bus Station"),
-    ("N0080902", "^Coach Stop$", "Bond Street")
-];
+use config::{Config, File, FileFormat};
+use polars::frame::DataFrame;
+use regex::Regex;
+use serde::Deserialize;
 
-pub const MANUAL_ARRIVALS: [&str; 4] = [
-    "6400L00040",
-    "6090117",
-    "64803493",
-    "6490IM002"
-];
+const OVERRIDES_PATH: &str = "stop_overrides.yaml";
+/// Directory of operator-contributed override files, layered on top of the main file in name order
+const OPERATOR_OVERRIDES_DIR: &str = "stop_overrides";
+const SUPPORTED_VERSION: u32 = 1;
 
-pub const NAPTAN_OVERRIDES: [(&str, &str); 2] = [
-    ("E0055323", "E0043654"),
-    ("N0072212", "E0041739")
-];
\ No newline at end of file
+/// Manual fixes to NaPTAN stop grouping, loaded from stop_overrides.yaml
+#[derive(Deserialize, Default)]
+pub struct StopOverrides {
+    pub version: u32,
+    #[serde(default)]
+    pub locality_changes: Vec<LocalityChange>,
+    #[serde(default)]
+    pub renames: Vec<Rename>,
+    #[serde(default)]
+    pub arrivals: Vec<String>,
+    #[serde(default)]
+    pub naptan_overrides: Vec<NaptanOverride>
+}
+
+/// Move a single stop into another locality
+#[derive(Deserialize)]
+pub struct LocalityChange {
+    pub locality: String,
+    pub name: String,
+    pub new_locality: String,
+    pub parent_name: Option<String>
+}
+
+/// Rename stops in a locality whose name matches `pattern`
+#[derive(Deserialize)]
+pub struct Rename {
+    pub locality: String,
+    pub pattern: String,
+    pub name: String
+}
+
+/// Move every stop in a locality into another locality
+#[derive(Deserialize)]
+pub struct NaptanOverride {
+    pub locality: String,
+    pub new_locality: String
+}
+
+impl StopOverrides {
+    /// Layer another set of overrides on top of these, with the new overrides taking precedence
+    fn merge(&mut self, other: StopOverrides) {
+        self.locality_changes.retain(|change| !other.locality_changes.iter().any(|c| c.locality == change.locality && c.name == change.name));
+        self.locality_changes.extend(other.locality_changes);
+        // the first matching rename is used, so layered renames go first
+        self.renames.retain(|rename| !other.renames.iter().any(|r| r.locality == rename.locality && r.pattern == rename.pattern));
+        self.renames.splice(0..0, other.renames);
+        for arrival in other.arrivals {
+            if !self.arrivals.contains(&arrival) {
+                self.arrivals.push(arrival);
+            }
+        }
+        self.naptan_overrides.retain(|over| !other.naptan_overrides.iter().any(|o| o.locality == over.locality));
+        self.naptan_overrides.extend(other.naptan_overrides);
+    }
+}
+
+pub fn load_overrides() -> Result<StopOverrides, Box<dyn Error>> {
+    let mut overrides = load_overrides_file(OVERRIDES_PATH)?;
+    for path in operator_override_paths()? {
+        println!("Layering stop overrides from {}", path.display());
+        overrides.merge(load_overrides_file(path.to_str().ok_or("Invalid override path")?)?);
+    }
+    Ok(overrides)
+}
+
+/// Every stop override file, in the order they are layered
+pub fn override_paths() -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    Ok([PathBuf::from(OVERRIDES_PATH)].into_iter().chain(operator_override_paths()?).collect())
+}
+
+fn operator_override_paths() -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    if !Path::new(OPERATOR_OVERRIDES_DIR).is_dir() {
+        return Ok(vec![]);
+    }
+    let mut paths = fs::read_dir(OPERATOR_OVERRIDES_DIR)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    paths.retain(|path| path.extension().is_some_and(|ext| ext == "yaml" || ext == "yml"));
+    paths.sort();
+    Ok(paths)
+}
+
+fn load_overrides_file(path: &str) -> Result<StopOverrides, Box<dyn Error>> {
+    let overrides: StopOverrides = Config::builder()
+        .add_source(File::new(path, FileFormat::Yaml))
+        .build()?
+        .try_deserialize()
+        .map_err(|e| format!("{path}: {e}"))?;
+    if overrides.version > SUPPORTED_VERSION {
+        return Err(format!("{path} is version {} but only version {SUPPORTED_VERSION} is supported", overrides.version).into());
+    }
+    for rename in &overrides.renames {
+        Regex::new(rename.pattern.as_str()).map_err(|e| format!("{path}: invalid pattern for {}: {e}", rename.locality))?;
+    }
+    Ok(overrides)
+}
+
+/// Warn about overrides which no longer match anything in NaPTAN
+pub fn validate_overrides(overrides: &StopOverrides, df: &DataFrame) -> Result<(), Box<dyn Error>> {
+    let localities = df.column("NptgLocalityCode")?.str()?;
+    let names = df.column("CommonName")?.str()?;
+    let stops: HashSet<(&str, &str)> = localities.into_iter().zip(names.into_iter())
+        .filter_map(|(locality, name)| Some((locality?, name?)))
+        .collect();
+    let locality_codes: HashSet<&str> = stops.iter().map(|(locality, _)| *locality).collect();
+    let atco_codes: HashSet<&str> = df.column("ATCOCode")?.str()?.into_iter().flatten().collect();
+
+    for change in &overrides.locality_changes {
+        if !stops.contains(&(change.locality.as_str(), change.name.as_str())) {
+            println!("Warning: locality change for {} in {} matches no stops", change.name, change.locality);
+        }
+    }
+    for rename in &overrides.renames {
+        let pattern = Regex::new(rename.pattern.as_str())?;
+        if !stops.iter().any(|(locality, name)| *locality == rename.locality && pattern.is_match(name)) {
+            println!("Warning: rename {} in {} matches no stops", rename.pattern, rename.locality);
+        }
+    }
+    for arrival in &overrides.arrivals {
+        if !atco_codes.contains(arrival.as_str()) {
+            println!("Warning: arrival stance {arrival} is not in NaPTAN");
+        }
+    }
+    for over in &overrides.naptan_overrides {
+        if !locality_codes.contains(over.locality.as_str()) {
+            println!("Warning: locality override for {} matches no stops", over.locality);
+        }
+    }
+    Ok(())
+}
@@ -1,68 +1,281 @@
+mod cli;
 mod sources;
 mod gtfs;
 mod cleanup;
 mod traveline;
+mod trip_patterns;
 mod localities;
 mod grouping;
 mod locality_changes;
 mod gtfs_stops;
 mod linking;
+mod metadata;
+mod segments;
+mod stop_areas;
+mod txc;
+mod validation;
 
 
 use std::collections::HashMap;
 use std::error::Error;
 use std::string::ToString;
-use std::fs::remove_file;
+use std::fs::{remove_file, rename};
+use std::path::Path;
+use std::process::exit;
+use std::str::FromStr;
 
-use rusqlite::Connection;
+use rusqlite::{Connection, OpenFlags};
+
+use BusBoardsServer::observations::has_observations;
 
 use crate::cleanup::cleanup;
+use crate::cli::{parse_args, select_sources, Stage, NAPTAN_STAGES, STAGES, USAGE};
 use crate::gtfs_stops::map_external_gtfs_stops;
-use crate::grouping::group_stances;
-use crate::gtfs::process_source;
-use crate::linking::link_trips;
-use crate::localities::{insert_localities, insert_stops};
-use crate::sources::{StopAddType, SOURCES};
+use crate::grouping::{group_stances, hash_naptan, NAPTAN_HASH_KEY};
+use crate::gtfs::{delete_source, get_stored_hash, hash_source, process_source, store_hash, Overrides};
+use crate::linking::{link_observed, link_trips};
+use crate::localities::{has_localities_json, insert_localities, insert_stops};
+use crate::metadata::{is_current_schema, write_metadata};
+use crate::segments::learn_segment_times;
+use crate::sources::{Source, StopAddType, SOURCES};
 use crate::traveline::download_noc;
+use crate::trip_patterns::build_trip_patterns;
+use crate::validation::validate;
 
 const DEFAULT_DB_PATH: &str = "stops.sqlite";
 const SQL_INDEXES: &str = include_str!("sql/indexes.sql");
 const SQL_MODEL: &str = include_str!("sql/model.sql");
 
 fn main() {
-    let db_path = std::env::var("BUSES_DB_PATH").unwrap_or(DEFAULT_DB_PATH.to_string());
-    let _ = remove_file(db_path.as_str());
-    let _ = remove_file(format!("{db_path}-shm"));
-    let _ = remove_file(format!("{db_path}-wal"));
-    
-    group_stances().expect("Stance grouping error");
-
-    println!("Opening database");
-    let mut connection = open_db(db_path.as_str()).expect("DB init error");
-    
-    create_tables(&connection).expect("Table create error");
-    insert_localities(&mut connection).expect("Locality insert error");
-    insert_stops(&mut connection).expect("Stop insert error");
-    
-    let no_overrides = HashMap::new();
-    for source in SOURCES {
-        if source.add_stops == StopAddType::None {
-            process_source(&mut connection, &source, &no_overrides).expect("Download error");
+    let args = match parse_args(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("{err}\n\n{USAGE}");
+            exit(1);
+        }
+    };
+    if args.help {
+        println!("{USAGE}");
+        return;
+    }
+
+    let enabled: Vec<&Source> = SOURCES.iter().filter(|source| source.enabled).collect();
+    let selected: Vec<&Source> = match select_sources(args.sources.as_ref(), &enabled) {
+        Ok(selected) => selected,
+        Err(err) => {
+            eprintln!("{err}");
+            exit(1);
+        }
+    };
+
+    let live_path = std::env::var("BUSES_DB_PATH").unwrap_or(DEFAULT_DB_PATH.to_string());
+    // Build into a staging copy of the database so the live database stays usable until it is swapped
+    let db_path = if args.in_place { live_path.clone() } else { format!("{live_path}.staging") };
+    let mut hashes: HashMap<String, String> = HashMap::new();
+    // NaPTAN is only downloaded when its stages might run
+    let naptan_hash = args.stages.as_ref().map_or(true, |stages| stages.iter().any(|stage| NAPTAN_STAGES.contains(stage)))
+        .then(|| hash_naptan().expect("NaPTAN download error"));
+    // stances are only regrouped when NaPTAN or the stop overrides change, or there are no stances from a previous run
+    let is_naptan_changed = |db: &Connection| naptan_hash.as_ref().is_some_and(|hash| {
+        args.force || !has_localities_json() || get_stored_hash(db, NAPTAN_HASH_KEY).as_ref() != Some(hash)
+    });
+
+    let resuming = args.resume && Path::new(db_path.as_str()).exists();
+    let (mut connection, changed, completed, learn_only) = if resuming {
+        println!("Resuming from {db_path}");
+        let connection = open_db(db_path.as_str()).expect("DB init error");
+        create_tables(&connection).expect("Table create error");
+        let (changed, completed) = load_progress(&connection).expect("Progress read error");
+        (connection, changed, completed, false)
+    } else {
+        let live_db = if Path::new(live_path.as_str()).exists() {
+            Some(Connection::open_with_flags(live_path.as_str(), OpenFlags::SQLITE_OPEN_READ_ONLY).expect("DB init error"))
+        } else if args.stages.is_some() {
+            eprintln!("No existing database at {live_path} to run stages against");
+            exit(1);
         } else {
-            let mut stop_overrides = HashMap::new();
-            stop_overrides.insert("stop_id".to_string(), map_external_gtfs_stops(&mut connection, db_path.as_str(), source).expect("Stop mapping error"));
-            process_source(&mut connection, &source, &stop_overrides).expect("Download error");
+            None
+        };
+        // rows imported under an older schema may not carry their source's prefix, so can't be replaced incrementally
+        let live_db = match live_db {
+            Some(db) if args.stages.is_none() && !is_current_schema(&db) => {
+                println!("{live_path} was built with an older schema - rebuilding from scratch");
+                None
+            }
+            live_db => live_db
+        };
+
+        let changed: Vec<&Source> = if args.force || args.stages.is_some() || args.sources.is_some() {
+            selected.clone()
+        } else {
+            // Only re-import sources whose zip has changed since the last import
+            selected.iter().copied().filter(|source| {
+                let hash = hash_source(source).expect("Download error");
+                let is_changed = live_db.as_ref().and_then(|db| get_stored_hash(db, &source.path)).as_ref() != Some(&hash);
+                hashes.insert(source.prefix.clone(), hash);
+                is_changed
+            }).collect()
+        };
+        let disabled = live_db.as_ref().map_or(vec![], disabled_sources);
+        let naptan_changed = live_db.as_ref().map_or(true, |db| is_naptan_changed(db));
+        // with nothing to rebuild, the live database only needs to learn from new vehicle observations
+        let learn_only = changed.is_empty() && disabled.is_empty() && !naptan_changed && live_db.as_ref().is_some_and(is_current_schema);
+        if learn_only && !has_observations() {
+            println!("No sources have changed - nothing to do!");
+            return;
+        }
+        for source in &changed {
+            println!("- {} will be imported", source.name);
         }
+        for source in &disabled {
+            println!("- {} is disabled and will be removed", source.name);
+        }
+        if naptan_changed {
+            println!("- NaPTAN stops will be regrouped");
+        }
+
+        if learn_only {
+            println!("No sources have changed - learning from vehicle observations in place");
+        }
+
+        match live_db {
+            Some(db) => {
+                if !args.in_place && !learn_only {
+                    remove_db_files(db_path.as_str());
+                    println!("Copying database for staging");
+                    db.execute("VACUUM INTO ?", [db_path.as_str()]).expect("DB copy error");
+                }
+                db.close().expect("Could not close connection");
+            }
+            None => remove_db_files(db_path.as_str())
+        }
+
+        println!("Opening database");
+        let connection = open_db(if learn_only { live_path.as_str() } else { db_path.as_str() }).expect("DB init error");
+        create_tables(&connection).expect("Table create error");
+        start_progress(&connection, &changed).expect("Progress write error");
+        (connection, changed, Vec::new(), learn_only)
+    };
+    let in_place = args.in_place || learn_only;
+
+    let mut stop_overrides: HashMap<String, Overrides> = HashMap::new();
+    let disabled = disabled_sources(&connection);
+    let naptan_changed = is_naptan_changed(&connection);
+    // --stage runs and learning from observations don't rebuild everything, so leave the existing schema version
+    let full_build = args.stages.is_none() && !learn_only;
+    let stages = args.stages.unwrap_or_else(|| if learn_only {
+        vec![Stage::Observed, Stage::Segments]
+    } else if naptan_changed {
+        STAGES.to_vec()
+    } else {
+        println!("NaPTAN has not changed - keeping existing stances and stops");
+        STAGES.iter().copied().filter(|stage| !NAPTAN_STAGES.contains(stage)).collect()
+    });
+    for stage in stages {
+        if completed.contains(&stage) {
+            println!("Skipping {} stage - already complete", stage.name());
+            continue;
+        }
+        println!("Running {} stage", stage.name());
+        match stage {
+            Stage::Stances => { group_stances().expect("Stance grouping error"); }
+            Stage::Localities => insert_localities(&mut connection).expect("Locality insert error"),
+            Stage::Stops => {
+                insert_stops(&mut connection).expect("Stop insert error");
+                // stops for external sources must be re-added as insert_stops replaces all stances
+                for source in enabled.iter().filter(|source| source.add_stops != StopAddType::None) {
+                    stop_overrides.insert(source.prefix.clone(), map_stops(&mut connection, db_path.as_str(), source));
+                }
+                if let Some(hash) = &naptan_hash {
+                    store_hash(&connection, NAPTAN_HASH_KEY, hash.as_str()).expect("Hash write error");
+                }
+            }
+            Stage::Sources => {
+                for source in &disabled {
+                    delete_source(&mut connection, source).expect("Source removal error");
+                }
+                let no_overrides = HashMap::new();
+                for source in &changed {
+                    let overrides = stop_overrides.remove(&source.prefix).or_else(|| {
+                        (source.add_stops != StopAddType::None).then(|| map_stops(&mut connection, db_path.as_str(), source))
+                    });
+                    let hash = hashes.remove(&source.prefix).unwrap_or_else(|| hash_source(source).expect("Download error"));
+                    process_source(&mut connection, source, overrides.as_ref().unwrap_or(&no_overrides), hash.as_str()).expect("Import error");
+                }
+            }
+            // indexes are only built on a fresh database - otherwise they are kept up to date as rows are replaced
+            Stage::Indexes => create_indexes(&mut connection).expect("Index creation error"),
+            Stage::Cleanup => cleanup(&mut connection, &changed).expect("Cleanup error"),
+            Stage::Patterns => build_trip_patterns(&mut connection).expect("Trip pattern error"),
+            Stage::Linking => link_trips(db_path.as_str(), &changed).expect("Trip linking error"),
+            Stage::Observed => link_observed(&mut connection).expect("Observed linking error"),
+            Stage::Segments => learn_segment_times(&mut connection).expect("Segment time error"),
+            Stage::Noc => download_noc(&mut connection).expect("Traveline error"),
+            Stage::Validate => validate(&mut connection).expect("Validation error"),
+        }
+        complete_stage(&connection, stage).expect("Progress write error");
+    }
+    write_metadata(&mut connection, full_build).expect("Metadata write error");
+
+    if !args.in_place {
+        // leave WAL mode so that no -wal or -shm files are needed alongside the live database
+        connection.pragma_update(None, "journal_mode", "DELETE").expect("Journal mode error");
     }
-    
-    create_indexes(&mut connection).expect("Index creation error");
-    cleanup(&mut connection).expect("Cleanup error");
-    link_trips(db_path.as_str()).expect("Trip linking error");
-    download_noc(&mut connection).expect("Traveline error");
     connection.close().expect("Could not close connection");
+    if !in_place {
+        println!("Swapping in new database");
+        rename(db_path.as_str(), live_path.as_str()).expect("Database swap error");
+    }
     println!("Done!")
 }
 
+/// Add stops for an external source, returning overrides mapping its stop IDs to existing stances
+fn map_stops(connection: &mut Connection, db_path: &str, source: &Source) -> Overrides {
+    let stop_map = map_external_gtfs_stops(connection, db_path, source).expect("Stop mapping error");
+    ["stop_id", "parent_station", "from_stop_id", "to_stop_id"].iter()
+        .map(|field| (field.to_string(), stop_map.clone()))
+        .collect()
+}
+
+/// Disabled sources which still have imported data
+fn disabled_sources(conn: &Connection) -> Vec<&'static Source> {
+    SOURCES.iter().filter(|source| !source.enabled && get_stored_hash(conn, &source.path).is_some()).collect()
+}
+
+/// Record the sources being imported by this run, so that a failed run can be resumed
+fn start_progress(conn: &Connection, changed: &[&Source]) -> rusqlite::Result<()> {
+    conn.execute("DELETE FROM ingest_progress", [])?;
+    conn.execute("DELETE FROM ingest_sources", [])?;
+    for source in changed {
+        conn.execute("INSERT INTO ingest_sources (prefix) VALUES (?)", [&source.prefix])?;
+    }
+    Ok(())
+}
+
+fn complete_stage(conn: &Connection, stage: Stage) -> rusqlite::Result<usize> {
+    conn.execute("INSERT OR IGNORE INTO ingest_progress (stage) VALUES (?)", [stage.name()])
+}
+
+/// Read the sources and completed stages of a previous run
+fn load_progress(conn: &Connection) -> Result<(Vec<&'static Source>, Vec<Stage>), Box<dyn Error>> {
+    let prefixes: Vec<String> = conn.prepare("SELECT prefix FROM ingest_sources")?
+        .query_map([], |row| row.get(0))?
+        .collect::<rusqlite::Result<_>>()?;
+    let changed = SOURCES.iter().filter(|source| prefixes.contains(&source.prefix)).collect();
+    let completed = conn.prepare("SELECT stage FROM ingest_progress")?
+        .query_map([], |row| row.get::<_, String>(0))?
+        .map(|stage| Ok(Stage::from_str(stage?.as_str())?))
+        .collect::<Result<_, Box<dyn Error>>>()?;
+    Ok((changed, completed))
+}
+
+/// Remove a database along with its WAL files
+fn remove_db_files(db_path: &str) {
+    let _ = remove_file(db_path);
+    let _ = remove_file(format!("{db_path}-shm"));
+    let _ = remove_file(format!("{db_path}-wal"));
+}
+
 fn open_db(db_path: &str) -> Result<Connection, rusqlite::Error> {
     let conn = Connection::open(db_path)?;
     conn.pragma_update(None, "journal_mode", "WAL")?;
@@ -73,6 +286,22 @@ fn open_db(db_path: &str) -> Result<Connection, rusqlite::Error> {
 fn create_tables(conn: &Connection) -> Result<(), Box<dyn Error>> {
     println!("Initialising tables");
     conn.execute_batch(SQL_MODEL)?;
+    // columns added since the database was first created
+    add_column_if_missing(conn, "stops", "stop_area", "TEXT")?;
+    add_column_if_missing(conn, "links", "confidence", "REAL DEFAULT 1")?;
+    add_column_if_missing(conn, "links", "observed", "BOOLEAN DEFAULT 0")?;
+    // index stances added before the spatial index existed
+    conn.execute(r#"INSERT INTO stances_rtree (id, min_lat, max_lat, min_long, max_long)
+        SELECT rowid, lat, lat, long, long FROM stances
+            WHERE lat IS NOT NULL AND long IS NOT NULL AND rowid NOT IN (SELECT id FROM stances_rtree)"#, [])?;
+    Ok(())
+}
+
+fn add_column_if_missing(conn: &Connection, table: &str, column: &str, definition: &str) -> rusqlite::Result<()> {
+    let exists: bool = conn.query_row("SELECT count(*) > 0 FROM pragma_table_info(?1) WHERE name=?2", [table, column], |row| row.get(0))?;
+    if !exists {
+        conn.execute(format!("ALTER TABLE {table} ADD COLUMN {column} {definition}").as_str(), [])?;
+    }
     Ok(())
 }
 
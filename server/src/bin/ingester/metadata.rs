@@ -0,0 +1,49 @@
+use std::error::Error;
+use std::fs;
+
+use chrono::{DateTime, Local, Utc};
+use rusqlite::{params, Connection};
+
+use BusBoardsServer::SCHEMA_VERSION;
+
+use crate::gtfs::get_stored_hash;
+use crate::sources::{source_filter, Source, SOURCES};
+
+/// Record the version of a source which has just been imported
+pub fn record_source(db: &Connection, source: &Source, hash: &str) -> Result<(), Box<dyn Error>> {
+    let downloaded: DateTime<Utc> = fs::metadata(&source.path)?.modified()?.into();
+    db.execute("REPLACE INTO source_metadata (prefix, name, downloaded, imported, hash) VALUES (?, ?, ?, ?, ?)",
+               params![source.prefix, source.name, downloaded.to_rfc3339(), Local::now().to_rfc3339(), hash])?;
+    Ok(())
+}
+
+/// Whether a database was built with the current schema version
+pub fn is_current_schema(db: &Connection) -> bool {
+    db.query_row("SELECT value FROM metadata WHERE key='schema_version'", [], |row| row.get::<_, String>(0))
+        .is_ok_and(|version| version == SCHEMA_VERSION.to_string())
+}
+
+/// Count the rows imported from each source, and write the build time and schema version after a full build.
+/// Runs of only some stages keep the existing schema version, as the rest of the data may predate it
+pub fn write_metadata(db: &mut Connection, full_build: bool) -> Result<(), Box<dyn Error>> {
+    println!("Writing metadata");
+    let tx = db.transaction()?;
+    for source in SOURCES.iter() {
+        let Some(hash) = get_stored_hash(&tx, &source.path) else { continue };
+        // sources imported before metadata was recorded have no download or import time
+        tx.execute("INSERT OR IGNORE INTO source_metadata (prefix, name, hash) VALUES (?, ?, ?)",
+                   params![source.prefix, source.name, hash])?;
+        let count = |table: &str, column: &str| -> rusqlite::Result<u64> {
+            tx.query_row(format!("SELECT count(DISTINCT {column}) FROM {table} WHERE {}", source_filter(column, &[source])).as_str(), [], |row| row.get(0))
+        };
+        let (routes, trips, services) = (count("routes", "route_id")?, count("trips", "trip_id")?, count("trips", "service_id")?);
+        tx.execute("UPDATE source_metadata SET routes=?, trips=?, services=? WHERE prefix=?",
+                   params![routes, trips, services, source.prefix])?;
+    }
+    if full_build {
+        tx.execute("REPLACE INTO metadata (key, value) VALUES ('schema_version', ?)", [SCHEMA_VERSION.to_string()])?;
+        tx.execute("REPLACE INTO metadata (key, value) VALUES ('built', ?)", [Local::now().to_rfc3339()])?;
+    }
+    tx.commit()?;
+    Ok(())
+}
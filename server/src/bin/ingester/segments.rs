@@ -0,0 +1,223 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::hash::Hash;
+
+use chrono::NaiveDate;
+use rusqlite::{params, Connection};
+
+use BusBoardsServer::observations::{get_observations_path, has_observations};
+use BusBoardsServer::segments::{day_type, ALL_HOURS};
+
+/// Learned times need at least this many observations to be used
+const MIN_SAMPLES: usize = 3;
+/// Observed segments longer than this (in seconds) are assumed to be gaps in the vehicle's reports
+const MAX_SEGMENT_TIME: i64 = 3600;
+
+/// Samples by (from stop, to stop, day type, hour)
+type SegmentSamples = HashMap<((String, String), u32, u32), Vec<i64>>;
+/// Samples by (stop, day type, hour)
+type DwellSamples = HashMap<(String, u32, u32), Vec<i64>>;
+
+/// One stop of an observed trip, with the scheduled stop before it
+struct Progress {
+    date: String,
+    trip_id: String,
+    stop_sequence: u32,
+    prev_sequence: Option<u32>,
+    stop_id: String,
+    prev_stop: Option<String>,
+    departure: Option<u32>,
+    prev_departure: Option<u32>,
+    /// When the vehicle was first seen heading for this stop - roughly when it left the previous stop
+    first_seen: i64,
+    stopped_first: Option<i64>,
+    stopped_last: Option<i64>
+}
+
+/// Learn the median running time between each pair of stops, and the median dwell time at each stop,
+/// by day type and hour from the stop progress of realtime vehicles
+pub fn learn_segment_times(db: &mut Connection) -> Result<(), Box<dyn Error>> {
+    if !has_observations() {
+        println!("No vehicle observations - skipping segment times");
+        return Ok(())
+    }
+    println!("Learning segment times from vehicle observations");
+    db.execute("ATTACH ? AS observations", [format!("file:{}?mode=ro", get_observations_path())])?;
+    let mut segments: SegmentSamples = HashMap::new();
+    let mut dwells: DwellSamples = HashMap::new();
+    {
+        let mut stmt = db.prepare(r#"
+            WITH trip_stops AS (
+                SELECT trip_id, stop_sequence, stop_id, departure_time,
+                       lag(stop_sequence) OVER trip AS prev_sequence, lag(stop_id) OVER trip AS prev_stop,
+                       lag(departure_time) OVER trip AS prev_departure
+                    FROM main.stop_times
+                    WHERE trip_id IN (SELECT DISTINCT trip_id FROM observations.stop_progress)
+                    WINDOW trip AS (PARTITION BY trip_id ORDER BY stop_sequence)
+            )
+            SELECT p.date, p.trip_id, p.stop_sequence, ts.prev_sequence, ts.stop_id, ts.prev_stop,
+                   ts.departure_time, ts.prev_departure, p.first_seen, p.stopped_first, p.stopped_last
+                FROM observations.stop_progress p
+                    INNER JOIN trip_stops ts ON ts.trip_id = p.trip_id AND ts.stop_sequence = p.stop_sequence
+                ORDER BY p.date, p.trip_id, p.stop_sequence"#)?;
+        let mut rows = stmt.query([])?;
+        let mut previous: Option<Progress> = None;
+        while let Some(row) = rows.next()? {
+            let progress = Progress {
+                date: row.get(0)?,
+                trip_id: row.get(1)?,
+                stop_sequence: row.get(2)?,
+                prev_sequence: row.get(3)?,
+                stop_id: row.get(4)?,
+                prev_stop: row.get(5)?,
+                departure: row.get(6)?,
+                prev_departure: row.get(7)?,
+                first_seen: row.get(8)?,
+                stopped_first: row.get(9)?,
+                stopped_last: row.get(10)?
+            };
+            add_progress(&mut segments, &mut dwells, &progress, previous.as_ref());
+            previous = Some(progress);
+        }
+    }
+    db.execute("DETACH observations", [])?;
+
+    let tx = db.transaction()?;
+    tx.execute_batch("DELETE FROM segment_times; DELETE FROM dwell_times;")?;
+    let (mut segment_count, mut dwell_count) = (0, 0);
+    {
+        let mut stmt = tx.prepare("INSERT INTO segment_times (from_stop, to_stop, day_type, hour, run_time, samples) VALUES (?, ?, ?, ?, ?, ?)")?;
+        for (((from, to), day, hour), mut samples) in segments {
+            if let Some(median) = median(&mut samples) {
+                stmt.execute(params![from, to, day, hour, median, samples.len()])?;
+                segment_count += 1;
+            }
+        }
+        let mut stmt = tx.prepare("INSERT INTO dwell_times (stop_id, day_type, hour, dwell, samples) VALUES (?, ?, ?, ?, ?)")?;
+        for ((stop_id, day, hour), mut samples) in dwells {
+            if let Some(median) = median(&mut samples) {
+                stmt.execute(params![stop_id, day, hour, median, samples.len()])?;
+                dwell_count += 1;
+            }
+        }
+    }
+    tx.commit()?;
+    println!("Learned {segment_count} segment times and {dwell_count} dwell times");
+    Ok(())
+}
+
+/// Add the dwell at this stop, and the running time of the segment ending here if the vehicle was also seen at the previous stop
+fn add_progress(segments: &mut SegmentSamples, dwells: &mut DwellSamples, progress: &Progress, previous: Option<&Progress>) {
+    let Ok(date) = NaiveDate::parse_from_str(progress.date.as_str(), "%Y%m%d") else { return };
+    let day = day_type(date);
+
+    if let (Some(first), Some(last)) = (progress.stopped_first, progress.stopped_last) {
+        add_sample(dwells, (progress.stop_id.clone(), day), progress.departure, last - first);
+    }
+    // the previous stop's segment ends when the vehicle starts heading for this stop
+    let follows = previous.filter(|prev| prev.date == progress.date && prev.trip_id == progress.trip_id
+        && progress.prev_sequence == Some(prev.stop_sequence));
+    if let Some((prev, prev_stop)) = follows.and_then(|prev| Some((prev, prev.prev_stop.as_ref()?))) {
+        let run_time = progress.first_seen - prev.first_seen;
+        if (0..=MAX_SEGMENT_TIME).contains(&run_time) {
+            add_sample(segments, ((prev_stop.clone(), prev.stop_id.clone()), day), prev.prev_departure, run_time);
+        }
+    }
+}
+
+/// Add a sample to the bucket for its scheduled hour, and to the all-hours bucket
+fn add_sample<K: Clone + Eq + Hash>(samples: &mut HashMap<(K, u32, u32), Vec<i64>>, (key, day): (K, u32), scheduled: Option<u32>, sample: i64) {
+    if let Some(time) = scheduled {
+        samples.entry((key.clone(), day, time / 3600 % 24)).or_default().push(sample);
+    }
+    samples.entry((key, day, ALL_HOURS)).or_default().push(sample);
+}
+
+/// Median of the samples, if there are enough of them
+fn median(samples: &mut [i64]) -> Option<i64> {
+    if samples.len() < MIN_SAMPLES {
+        return None
+    }
+    samples.sort_unstable();
+    Some(samples[samples.len() / 2])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Progress at the nth stop of a Monday trip, scheduled to depart every five minutes from 10:00
+    fn progress(n: u32, first_seen: i64, stopped: Option<(i64, i64)>) -> Progress {
+        Progress {
+            date: "20240101".to_string(),
+            trip_id: "T1".to_string(),
+            stop_sequence: n,
+            prev_sequence: n.checked_sub(1),
+            stop_id: format!("S{n}"),
+            prev_stop: n.checked_sub(1).map(|prev| format!("S{prev}")),
+            departure: Some(36000 + n * 300),
+            prev_departure: n.checked_sub(1).map(|prev| 36000 + prev * 300),
+            first_seen,
+            stopped_first: stopped.map(|(first, _)| first),
+            stopped_last: stopped.map(|(_, last)| last)
+        }
+    }
+
+    #[test]
+    fn median_needs_enough_samples() {
+        assert_eq!(median(&mut []), None);
+        assert_eq!(median(&mut [60, 120]), None);
+        assert_eq!(median(&mut [300, 60, 120]), Some(120));
+        assert_eq!(median(&mut [300, 60, 120, 90]), Some(120));
+    }
+
+    #[test]
+    fn samples_are_added_by_hour_and_for_all_hours() {
+        let mut samples = HashMap::new();
+        add_sample(&mut samples, ("S1", 0), Some(10 * 3600 + 59 * 60), 60);
+        add_sample(&mut samples, ("S1", 0), Some(25 * 3600), 90);
+        add_sample(&mut samples, ("S1", 0), None, 120);
+
+        assert_eq!(samples.get(&("S1", 0, 10)), Some(&vec![60]));
+        assert_eq!(samples.get(&("S1", 0, 1)), Some(&vec![90]));
+        assert_eq!(samples.get(&("S1", 0, ALL_HOURS)), Some(&vec![60, 90, 120]));
+        assert_eq!(samples.len(), 3);
+    }
+
+    #[test]
+    fn segments_and_dwells_are_sampled() {
+        let (mut segments, mut dwells) = (HashMap::new(), HashMap::new());
+        let first = progress(1, 1000, Some((1100, 1130)));
+        let second = progress(2, 1150, None);
+        add_progress(&mut segments, &mut dwells, &first, None);
+        add_progress(&mut segments, &mut dwells, &second, Some(&first));
+
+        let segment = (("S0".to_string(), "S1".to_string()), 0, 10);
+        assert_eq!(segments.get(&segment), Some(&vec![150]));
+        assert_eq!(dwells.get(&("S1".to_string(), 0, 10)), Some(&vec![30]));
+        assert!(!dwells.contains_key(&("S2".to_string(), 0, ALL_HOURS)));
+    }
+
+    #[test]
+    fn long_segments_are_ignored() {
+        let (mut segments, mut dwells) = (HashMap::new(), HashMap::new());
+        let first = progress(1, 1000, None);
+        add_progress(&mut segments, &mut dwells, &progress(2, 1000 + MAX_SEGMENT_TIME, None), Some(&first));
+        add_progress(&mut segments, &mut dwells, &progress(2, 1001 + MAX_SEGMENT_TIME, None), Some(&first));
+        add_progress(&mut segments, &mut dwells, &progress(2, 999, None), Some(&first));
+
+        assert_eq!(segments.get(&(("S0".to_string(), "S1".to_string()), 0, ALL_HOURS)), Some(&vec![MAX_SEGMENT_TIME]));
+    }
+
+    #[test]
+    fn segments_need_consecutive_stops_of_the_same_trip() {
+        let (mut segments, mut dwells) = (HashMap::new(), HashMap::new());
+        let first = progress(1, 1000, None);
+        let mut other_trip = progress(2, 1100, None);
+        other_trip.trip_id = "T2".to_string();
+        add_progress(&mut segments, &mut dwells, &other_trip, Some(&first));
+        add_progress(&mut segments, &mut dwells, &progress(3, 1200, None), Some(&first));
+
+        assert!(segments.is_empty());
+    }
+}
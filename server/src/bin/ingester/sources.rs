@@ -1,38 +1,105 @@
-pub struct Source<'a> {
-    pub name: &'a str,
-    pub prefix: &'a str,
-    pub url: &'a str,
-    pub path: &'a str,
-    pub add_stops: StopAddType
+use std::collections::HashSet;
+use std::error::Error;
+use std::fs::File;
+use std::sync::LazyLock;
+
+use BusBoardsServer::config::load_config;
+pub use BusBoardsServer::config::{GTFSSource as Source, SourceFormat, StopAddType};
+use BusBoardsServer::{download_if_old, GTFSResponder};
+
+/// All GTFS sources configured in sources.yaml, including disabled sources
+pub static SOURCES: LazyLock<Vec<Source>> = LazyLock::new(|| load_sources().expect("GTFS source config error"));
+
+fn load_sources() -> Result<Vec<Source>, Box<dyn Error>> {
+    let sources = load_config().gtfs;
+    check_sources(&sources)?;
+    Ok(sources)
+}
+
+/// Every imported ID must be owned by exactly one source, so each source needs a prefix which no other prefix starts with
+fn check_sources(sources: &[Source]) -> Result<(), String> {
+    if sources.is_empty() {
+        return Err("No GTFS sources configured".to_string());
+    }
+    let mut prefixes = HashSet::new();
+    for source in sources {
+        if source.prefix.is_empty() {
+            return Err(format!("{} has no prefix", source.name));
+        }
+        if !prefixes.insert(source.prefix.as_str()) {
+            return Err(format!("Duplicate prefix '{}' used by {}", source.prefix, source.name));
+        }
+        if let Some(other) = sources.iter().find(|other| other.prefix != source.prefix && other.prefix.starts_with(&source.prefix)) {
+            return Err(format!("Prefix '{}' of {} starts the prefix '{}' of {}", source.prefix, source.name, other.prefix, other.name));
+        }
+        if source.format == SourceFormat::TransXChange && source.add_stops != StopAddType::None {
+            return Err(format!("{} is TransXChange so must use NaPTAN stops", source.name));
+        }
+    }
+    // TransXChange timetables would duplicate every service in the BODS GTFS timetables
+    let bods = sources.iter().find(|source| source.enabled && source.format == SourceFormat::GTFS && source.realtime == Some(GTFSResponder::BODS));
+    let txc = sources.iter().find(|source| source.enabled && source.format == SourceFormat::TransXChange);
+    if let (Some(bods), Some(txc)) = (bods, txc) {
+        return Err(format!("{} and {} would duplicate each other's services, so only one can be enabled", bods.name, txc.name));
+    }
+    Ok(())
+}
+
+/// Open a source's zip, downloading it first if it has a URL
+pub fn open_source(source: &Source) -> Result<File, Box<dyn Error>> {
+    match &source.url {
+        Some(url) => download_if_old(url, &source.path),
+        None => Ok(File::open(&source.path)?)
+    }
+}
+
+/// The source owning an imported ID - the source whose prefix it starts with
+pub fn source_for_id(id: &str) -> Option<&'static Source> {
+    SOURCES.iter().find(|source| id.starts_with(source.prefix.as_str()))
 }
 
-#[derive(Eq, PartialEq)]
-pub enum StopAddType {
-    None,
-    MatchStopBeforeAdd,
-    AddIfMissing
+/// SQL condition matching rows in `column` that were imported from any of `sources`, by their prefix
+pub fn source_filter(column: &str, sources: &[&Source]) -> String {
+    if SOURCES.iter().all(|s| sources.iter().any(|c| c.prefix == s.prefix)) {
+        return "1".to_string();
+    }
+    let conditions = sources.iter()
+        .map(|source| format!("substr({column}, 1, {}) = '{}'", source.prefix.len(), source.prefix.replace('\'', "''")))
+        .collect::<Vec<String>>();
+    if conditions.is_empty() {
+        "0".to_string()
+    } else {
+        format!("({})", conditions.join(" OR "))
+    }
 }
 
-pub const SOURCES: &[Source] = &[
-    Source {
-        name: "BODS (approx ~550MB)",
-        prefix: "",
-        url: "https://data.bus-data.dft.gov.uk/timetable/download/gtfs-file/all/",
-        path: "gtfs/itm_all_gtfs.zip",
-        add_stops: StopAddType::None
-    },
-    Source {
-        name: "Ember",
-        prefix: "E",
-        url: "https://api.ember.to/v1/gtfs/static/",
-        path: "gtfs/ember.zip",
-        add_stops: StopAddType::AddIfMissing
-    },
-    Source {
-        name: "Flix",
-        prefix: "F",
-        url: "https://gtfs.gis.flix.tech/gtfs_generic_eu.zip",
-        path: "gtfs/flix.zip",
-        add_stops: StopAddType::MatchStopBeforeAdd
-    }
-];
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source(name: &str, prefix: &str) -> Source {
+        Source { name: name.to_string(), prefix: prefix.to_string(), ..Default::default() }
+    }
+
+    #[test]
+    fn distinct_prefixes_are_accepted() {
+        assert!(check_sources(&[source("BODS", "B"), source("Ember", "E"), source("Flix", "F")]).is_ok());
+    }
+
+    #[test]
+    fn ambiguous_prefixes_are_rejected() {
+        assert!(check_sources(&[source("BODS", ""), source("Ember", "E")]).is_err());
+        assert!(check_sources(&[source("Ember", "E"), source("Ember Express", "EX")]).is_err());
+        assert!(check_sources(&[source("Ember", "E"), source("Ember", "E")]).is_err());
+    }
+
+    #[test]
+    fn transxchange_replaces_bods_gtfs() {
+        let bods = || Source { realtime: Some(GTFSResponder::BODS), ..source("BODS", "B") };
+        let txc = |enabled| Source { format: SourceFormat::TransXChange, enabled, ..source("BODS TransXChange", "T") };
+        assert!(check_sources(&[bods(), txc(true)]).is_err());
+        assert!(check_sources(&[bods(), txc(false)]).is_ok());
+        assert!(check_sources(&[Source { enabled: false, ..bods() }, txc(true)]).is_ok());
+        assert!(check_sources(&[source("Ember", "E"), txc(true)]).is_ok());
+    }
+}
@@ -20,4 +20,7 @@ create unique index if not exists traveline_agency_id_uindex
     on traveline (agency_id);
 
 create index if not exists trips_route_id_index
-    on trips (route_id);
\ No newline at end of file
+    on trips (route_id);
+
+create index if not exists transfers_from_stop_id_index
+    on transfers (from_stop_id);
\ No newline at end of file
@@ -21,7 +21,30 @@ create table if not exists stops
     locality      text
         constraint stops_localities_code_fk
             references localities,
-    locality_name TEXT
+    locality_name TEXT,
+    stop_area     TEXT
+);
+
+-- stops without departures, kept by cleanup so that a later import serving them can restore them
+create table if not exists dropped_stops
+(
+    id            integer
+        constraint dropped_stops_pk
+            primary key,
+    name          text,
+    locality      text,
+    locality_name TEXT,
+    stop_area     TEXT
+);
+
+create table if not exists stop_areas
+(
+    code   TEXT
+        constraint stop_areas_pk
+            primary key,
+    name   TEXT,
+    type   TEXT,
+    parent TEXT
 );
 
 create table if not exists stances
@@ -39,6 +62,36 @@ create table if not exists stances
     crs       TEXT
 );
 
+-- spatial index of stances by rowid, kept up to date by the triggers below
+create virtual table if not exists stances_rtree using rtree
+(
+    id,
+    min_lat,
+    max_lat,
+    min_long,
+    max_long
+);
+
+create trigger if not exists stances_rtree_insert
+    after insert on stances when new.lat is not null and new.long is not null
+begin
+    insert into stances_rtree (id, min_lat, max_lat, min_long, max_long) values (new.rowid, new.lat, new.lat, new.long, new.long);
+end;
+
+create trigger if not exists stances_rtree_update
+    after update of lat, long on stances
+begin
+    delete from stances_rtree where id = old.rowid;
+    insert into stances_rtree (id, min_lat, max_lat, min_long, max_long)
+        select new.rowid, new.lat, new.lat, new.long, new.long where new.lat is not null and new.long is not null;
+end;
+
+create trigger if not exists stances_rtree_delete
+    after delete on stances
+begin
+    delete from stances_rtree where id = old.rowid;
+end;
+
 create table if not exists shapes
 (
     shape_id          TEXT PRIMARY KEY,
@@ -123,12 +176,138 @@ create table if not exists stop_times
         on delete cascade on update cascade
 );
 
+create table if not exists frequencies
+(
+    trip_id      TEXT,
+    start_time   integer,
+    end_time     integer,
+    headway_secs integer,
+    exact_times  integer,
+    constraint frequencies_pk
+        primary key (trip_id, start_time)
+);
+
+create table if not exists headways
+(
+    trip_id      TEXT
+        constraint headways_pk
+            primary key
+        constraint headways_trips_trip_id_fk
+            references trips
+            on delete cascade,
+    headway_secs integer,
+    exact_times  integer
+);
+
+create table if not exists transfers
+(
+    from_stop_id      TEXT,
+    to_stop_id        TEXT,
+    from_trip_id      TEXT,
+    to_trip_id        TEXT,
+    transfer_type     integer,
+    min_transfer_time integer,
+    source            TEXT
+);
+
+create table if not exists stance_attributes
+(
+    code                TEXT
+        constraint stance_attributes_pk
+            primary key,
+    parent_station      TEXT,
+    wheelchair_boarding integer,
+    source              TEXT
+);
+
+create table if not exists validation_issues
+(
+    check_name TEXT,
+    source     TEXT,
+    agency_id  TEXT,
+    item       TEXT,
+    detail     TEXT
+);
+
+create table if not exists validation_summary
+(
+    source     TEXT,
+    agency_id  TEXT,
+    check_name TEXT,
+    count      integer
+);
+
+create table if not exists ingest_progress
+(
+    stage TEXT PRIMARY KEY
+);
+
+create table if not exists ingest_sources
+(
+    prefix TEXT PRIMARY KEY
+);
+
 create table if not exists file_hashes
 (
     source TEXT PRIMARY KEY,
     hash TEXT
 );
 
+create table if not exists patterns
+(
+    pattern_id integer PRIMARY KEY,
+    stops      TEXT,
+    seqs       BLOB
+);
+
+create table if not exists trip_patterns
+(
+    trip_id    TEXT PRIMARY KEY,
+    pattern_id integer,
+    start_time integer,
+    end_time   integer,
+    offsets    BLOB
+);
+
+create table if not exists metadata
+(
+    key   TEXT PRIMARY KEY,
+    value TEXT
+);
+
+create table if not exists source_metadata
+(
+    prefix     TEXT PRIMARY KEY,
+    name       TEXT,
+    downloaded TEXT,
+    imported   TEXT,
+    hash       TEXT,
+    routes     integer,
+    trips      integer,
+    services   integer
+);
+
+create table if not exists segment_times
+(
+    from_stop TEXT,
+    to_stop   TEXT,
+    day_type  integer,
+    hour      integer,
+    run_time  integer,
+    samples   integer,
+    PRIMARY KEY (from_stop, to_stop, day_type, hour)
+);
+
+create table if not exists dwell_times
+(
+    stop_id  TEXT,
+    day_type integer,
+    hour     integer,
+    dwell    integer,
+    samples  integer,
+    PRIMARY KEY (stop_id, day_type, hour)
+);
+
 CREATE TABLE IF NOT EXISTS polar
 (
     gtfs      TEXT
@@ -173,6 +352,17 @@ CREATE TABLE IF NOT EXISTS links
                 on conflict replace
         constraint links_trips_trip_id_fk_2
             references trips,
-    show_then BOOLEAN
+    show_then BOOLEAN,
+    confidence REAL DEFAULT 1,
+    observed BOOLEAN DEFAULT 0
+);
+
+-- static links replaced by observed links, restored before observed links are relearned
+CREATE TABLE IF NOT EXISTS replaced_links
+(
+    "from"     TEXT,
+    "to"       TEXT,
+    show_then  BOOLEAN,
+    confidence REAL
 );
 
@@ -0,0 +1,151 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+
+use BusBoardsServer::download_naptan_xml;
+
+const STOP_AREAS_PATH: &str = "stop_areas.json";
+
+/// NaPTAN stop area definition
+#[derive(Serialize, Deserialize, Default, Clone)]
+pub struct StopArea {
+    pub name: String,
+    /// e.g. GBCS (bus/coach station), GCLS (on-street cluster)
+    pub area_type: String,
+    pub parent: Option<String>
+}
+
+/// NaPTAN stop areas, and the areas each stance belongs to
+#[derive(Default)]
+pub struct StopAreas {
+    /// Stop area definitions by stop area code
+    pub areas: HashMap<String, StopArea>,
+    /// Stop areas each stance is a member of by ATCO code, in the order NaPTAN lists them
+    pub memberships: HashMap<String, Vec<String>>
+}
+
+impl StopAreas {
+    /// The stop area a stance is grouped by - the most specific of its own areas, so that a stance in both
+    /// a stop area and its parent is grouped by the child. Ties go to the area NaPTAN lists first
+    pub fn stance_area(&self, atco_code: &str) -> Option<String> {
+        let areas = self.memberships.get(atco_code)?;
+        areas.iter()
+            .find(|area| !areas.iter().any(|other| other != *area && self.is_ancestor(area, other)))
+            .cloned()
+    }
+
+    /// Whether `ancestor` is a parent (or grandparent etc.) of `area`
+    fn is_ancestor(&self, ancestor: &str, area: &str) -> bool {
+        let mut parent = self.areas.get(area).and_then(|area| area.parent.as_deref());
+        // parent chains are short - the limit guards against cycles in the data
+        for _ in 0..10 {
+            match parent {
+                None => return false,
+                Some(code) if code == ancestor => return true,
+                Some(code) => parent = self.areas.get(code).and_then(|area| area.parent.as_deref())
+            }
+        }
+        false
+    }
+}
+
+/// Load stop area definitions and memberships from the NaPTAN XML
+pub fn load_stop_areas() -> Result<StopAreas, Box<dyn Error>> {
+    println!("Loading stop areas");
+    let mut reader = Reader::from_reader(BufReader::new(download_naptan_xml()?));
+    reader.config_mut().trim_text(true);
+
+    let mut stop_areas = StopAreas::default();
+    let mut path: Vec<Vec<u8>> = Vec::new();
+    let mut atco_code: Option<String> = None;
+    let mut area_code: Option<String> = None;
+    let mut area = StopArea::default();
+    let mut buf = Vec::new();
+    loop {
+        match reader.read_event_into(&mut buf)? {
+            Event::Start(e) => path.push(e.local_name().as_ref().to_vec()),
+            Event::End(_) => match path.pop().as_deref() {
+                Some(b"StopPoint") => atco_code = None,
+                Some(b"StopArea") => if let Some(code) = area_code.take() {
+                    stop_areas.areas.insert(code, std::mem::take(&mut area));
+                },
+                _ => {}
+            },
+            Event::Text(text) => {
+                let parent = path.len().checked_sub(2).map(|i| path[i].as_slice());
+                let in_stop_point = path.iter().any(|name| name == b"StopPoint");
+                match (parent, path.last().map(|name| name.as_slice())) {
+                    (_, Some(b"AtcoCode")) if in_stop_point => atco_code = Some(text.unescape()?.to_string()),
+                    (_, Some(b"StopAreaRef")) if in_stop_point => if let Some(code) = &atco_code {
+                        let areas = stop_areas.memberships.entry(code.clone()).or_default();
+                        let area = text.unescape()?.to_string();
+                        if !areas.contains(&area) {
+                            areas.push(area);
+                        }
+                    },
+                    (Some(b"StopArea"), Some(b"StopAreaCode")) => area_code = Some(text.unescape()?.to_string()),
+                    (Some(b"StopArea"), Some(b"Name")) => area.name = text.unescape()?.to_string(),
+                    (Some(b"StopArea"), Some(b"StopAreaType")) => area.area_type = text.unescape()?.to_string(),
+                    (Some(b"StopArea"), Some(b"ParentActiveStopAreaRef")) => area.parent = Some(text.unescape()?.to_string()),
+                    _ => {}
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+        buf.clear();
+    }
+    Ok(stop_areas)
+}
+
+/// Save stop area definitions for the Stops stage, as stances are saved to localities.json
+pub fn write_stop_areas(stop_areas: &StopAreas) -> Result<(), Box<dyn Error>> {
+    serde_json::to_writer(BufWriter::new(File::create(STOP_AREAS_PATH)?), &stop_areas.areas)?;
+    Ok(())
+}
+
+/// Replace the stop_areas table with the definitions saved by the Stances stage
+pub fn insert_stop_areas(db: &mut Connection) -> Result<(), Box<dyn Error>> {
+    let areas: HashMap<String, StopArea> = serde_json::from_reader(BufReader::new(File::open(STOP_AREAS_PATH)?))?;
+    let tx = db.transaction()?;
+    tx.execute("DELETE FROM stop_areas", [])?;
+    {
+        let mut stmt = tx.prepare("INSERT INTO stop_areas (code, name, type, parent) VALUES (?, ?, ?, ?)")?;
+        for (code, area) in areas {
+            stmt.execute(params![code, area.name, area.area_type, area.parent])?;
+        }
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stop_areas(memberships: &[(&str, &[&str])], parents: &[(&str, &str)]) -> StopAreas {
+        StopAreas {
+            areas: parents.iter().map(|(code, parent)| (code.to_string(), StopArea { parent: Some(parent.to_string()), ..Default::default() })).collect(),
+            memberships: memberships.iter().map(|(atco, areas)| (atco.to_string(), areas.iter().map(|area| area.to_string()).collect())).collect()
+        }
+    }
+
+    #[test]
+    fn child_area_is_preferred_to_parent() {
+        let areas = stop_areas(&[("S1", &["PARENT", "CHILD"])], &[("CHILD", "PARENT")]);
+        assert_eq!(areas.stance_area("S1").as_deref(), Some("CHILD"));
+    }
+
+    #[test]
+    fn unrelated_areas_use_naptan_order() {
+        let areas = stop_areas(&[("S1", &["B", "A"])], &[]);
+        assert_eq!(areas.stance_area("S1").as_deref(), Some("B"));
+        assert_eq!(areas.stance_area("S2"), None);
+    }
+}
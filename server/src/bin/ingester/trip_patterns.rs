@@ -0,0 +1,82 @@
+use std::collections::HashMap;
+use std::error::Error;
+
+use rusqlite::{params, Connection};
+
+use BusBoardsServer::patterns::{pack, unpack};
+
+/// Stop IDs and stop sequences shared by every trip with the same pattern
+type PatternKey = (Vec<String>, Vec<u32>);
+
+/// Materialise the stop pattern and departure offsets of each trip without one, for realtime trip matching.
+/// Patterns of trips which have been removed or re-imported are dropped first.
+pub fn build_trip_patterns(db: &mut Connection) -> Result<(), Box<dyn Error>> {
+    println!("Building trip patterns");
+    let tx = db.transaction()?;
+    tx.execute_batch(r#"
+        DELETE FROM trip_patterns WHERE trip_id NOT IN (SELECT trip_id FROM trips);
+        DELETE FROM patterns WHERE pattern_id NOT IN (SELECT pattern_id FROM trip_patterns);
+    "#)?;
+
+    let mut patterns: HashMap<PatternKey, i64> = tx.prepare("SELECT pattern_id, stops, seqs FROM patterns")?
+        .query_map([], |row| {
+            let stops: String = row.get(1)?;
+            let seqs: Vec<u8> = row.get(2)?;
+            Ok(((stops.split(',').map(str::to_string).collect(), unpack(&seqs)), row.get(0)?))
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+    let mut next_id = patterns.values().max().map_or(1, |id| id + 1);
+
+    let mut trips: Vec<(String, i64, i64, Vec<u32>)> = Vec::new();
+    {
+        let mut stmt = tx.prepare(r#"
+            SELECT trip_id, stop_id, stop_sequence, departure_time FROM stop_times
+                WHERE trip_id NOT IN (SELECT trip_id FROM trip_patterns)
+                ORDER BY trip_id, stop_sequence"#)?;
+        let mut rows = stmt.query([])?;
+        let mut insert_pattern = tx.prepare("INSERT INTO patterns (pattern_id, stops, seqs) VALUES (?, ?, ?)")?;
+        let mut current: Option<String> = None;
+        let (mut stops, mut seqs, mut times): (Vec<String>, Vec<u32>, Vec<Option<i64>>) = (Vec::new(), Vec::new(), Vec::new());
+        loop {
+            let row = rows.next()?;
+            let trip_id: Option<String> = row.map(|row| row.get(0)).transpose()?;
+            if current.is_some() && current != trip_id {
+                let key = (std::mem::take(&mut stops), std::mem::take(&mut seqs));
+                let pattern_id = match patterns.get(&key) {
+                    Some(id) => *id,
+                    None => {
+                        let id = next_id;
+                        next_id += 1;
+                        insert_pattern.execute(params![id, key.0.join(","), pack(&key.1)])?;
+                        patterns.insert(key, id);
+                        id
+                    }
+                };
+                // untimed stops take the time of the previous timed stop
+                let start = times.iter().flatten().next().copied().unwrap_or_default();
+                let mut last = start;
+                let offsets = times.drain(..).map(|time| {
+                    last = time.unwrap_or(last);
+                    (last - start).max(0) as u32
+                }).collect();
+                trips.push((current.take().unwrap(), pattern_id, start, offsets));
+            }
+            let Some(row) = row else { break };
+            current = trip_id;
+            stops.push(row.get(1)?);
+            seqs.push(row.get(2)?);
+            times.push(row.get(3)?);
+        }
+    }
+
+    {
+        let mut stmt = tx.prepare("INSERT INTO trip_patterns (trip_id, pattern_id, start_time, end_time, offsets) VALUES (?, ?, ?, ?, ?)")?;
+        for (trip_id, pattern_id, start, offsets) in &trips {
+            let end = start + *offsets.last().unwrap_or(&0) as i64;
+            stmt.execute(params![trip_id, pattern_id, start, end, pack(offsets)])?;
+        }
+    }
+    tx.commit()?;
+    println!("Built patterns for {} trips ({} patterns in total)", trips.len(), patterns.len());
+    Ok(())
+}
@@ -0,0 +1,909 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufReader, Cursor, Read, Seek};
+use std::time::Instant;
+
+use chrono::{Datelike, Duration, NaiveDate, NaiveTime, Timelike};
+use rusqlite::{Connection, params, Transaction};
+use serde::Deserialize;
+use zip::ZipArchive;
+
+use BusBoardsServer::download_if_old;
+
+use crate::sources::Source;
+
+/// Import a zip of TransXChange files (which may contain further zips) into the GTFS tables
+pub fn import_txc(db: &mut Connection, source: &Source) -> Result<(), Box<dyn Error>> {
+    let timer = Instant::now();
+    let mut importer = TxCImporter {
+        prefix: source.prefix.as_str(),
+        bank_holidays: load_bank_holidays()?,
+        calendars: HashMap::new(),
+        trip_ids: HashMap::new()
+    };
+    let zip_file = File::open(&source.path)?;
+    importer.import_archive(db, ZipArchive::new(BufReader::new(zip_file))?)?;
+    println!("{}s to import {}", timer.elapsed().as_secs(), source.path);
+    Ok(())
+}
+
+struct TxCImporter<'a> {
+    prefix: &'a str,
+    bank_holidays: BankHolidayDates,
+    /// Service IDs already created, keyed by their calendar
+    calendars: HashMap<CalendarKey, String>,
+    /// Trip IDs already imported, with the file they came from
+    trip_ids: HashMap<String, String>
+}
+
+impl TxCImporter<'_> {
+    fn import_archive<R: Read + Seek>(&mut self, db: &mut Connection, mut archive: ZipArchive<R>) -> Result<(), Box<dyn Error>> {
+        for i in 0..archive.len() {
+            let mut file = archive.by_index(i)?;
+            let name = file.name().to_string();
+            let mut contents = Vec::with_capacity(file.size() as usize);
+            file.read_to_end(&mut contents)?;
+            drop(file);
+            if name.ends_with(".zip") {
+                self.import_archive(db, ZipArchive::new(Cursor::new(contents))?)?;
+            } else if name.ends_with(".xml") {
+                match quick_xml::de::from_reader::<_, TransXChange>(contents.as_slice()) {
+                    Ok(txc) => {
+                        let tx = db.transaction()?;
+                        self.import_file(&tx, &txc, name.as_str())?;
+                        tx.commit()?;
+                    },
+                    Err(err) => println!("Could not parse {}: {}", name, err)
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn import_file(&mut self, tx: &Transaction, txc: &TransXChange, file_name: &str) -> Result<(), Box<dyn Error>> {
+        let prefix = self.prefix;
+        let sections: HashMap<&str, &JourneyPatternSection> = txc.journey_pattern_sections.journey_pattern_section.iter()
+            .map(|s| (s.id.as_str(), s)).collect();
+        let operators: HashMap<&str, &Operator> = txc.operators.operator.iter()
+            .map(|o| (o.id.as_str(), o)).collect();
+
+        let mut insert_agency = tx.prepare_cached("REPLACE INTO agency (agency_id, agency_name, agency_url, agency_timezone, agency_lang) VALUES (?, ?, NULL, 'Europe/London', 'EN')")?;
+        for operator in &txc.operators.operator {
+            insert_agency.execute(params![format!("{prefix}{}", operator.code()), operator.name()])?;
+        }
+
+        let mut insert_route = tx.prepare_cached("REPLACE INTO routes (route_id, agency_id, route_short_name, route_long_name, route_type) VALUES (?, ?, ?, ?, '3')")?;
+        let mut insert_trip = tx.prepare_cached("REPLACE INTO trips (trip_id, route_id, service_id, trip_headsign, shape_id, direction_id, block_id) VALUES (?, ?, ?, ?, NULL, ?, ?)")?;
+        let mut insert_stop_time = tx.prepare_cached("INSERT INTO stop_times (trip_id, arrival_time, departure_time, stop_id, stop_headsign, stop_sequence, timepoint, drop_off_type, pickup_type) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)")?;
+
+        for service in &txc.services.service {
+            let operator = service.registered_operator_ref.as_deref().and_then(|r| operators.get(r))
+                .or_else(|| txc.operators.operator.first().as_ref());
+            let Some(operator) = operator else {
+                println!("No operator for service {}", service.service_code);
+                continue;
+            };
+            let agency_id = format!("{prefix}{}", operator.code());
+            let route_long_name = match (&service.standard_service.origin, &service.standard_service.destination) {
+                (Some(origin), Some(destination)) => Some(format!("{origin} - {destination}")),
+                _ => None
+            };
+            for line in &service.lines.line {
+                insert_route.execute(params![format!("{prefix}{}:{}", service.service_code, line.id), agency_id, line.line_name, route_long_name])?;
+            }
+
+            let patterns: HashMap<&str, &JourneyPattern> = service.standard_service.journey_pattern.iter()
+                .map(|jp| (jp.id.as_str(), jp)).collect();
+            for journey in txc.vehicle_journeys.vehicle_journey.iter().filter(|vj| vj.service_ref == service.service_code) {
+                let Some(pattern) = patterns.get(journey.journey_pattern_ref.as_str()) else {
+                    continue;
+                };
+                let stops = get_journey_stops(journey, pattern, &sections);
+                let Some(first_stop) = stops.first() else {
+                    continue;
+                };
+                // journey codes should be unique within a service, but the same service can appear in several files
+                let trip_id = format!("{prefix}{}:{}", service.service_code, journey.vehicle_journey_code);
+                if let Some(existing) = self.trip_ids.get(&trip_id) {
+                    println!("Duplicate trip {trip_id} in {file_name} (already imported from {existing}) - skipping");
+                    continue;
+                }
+                self.trip_ids.insert(trip_id.clone(), file_name.to_string());
+                let profile = journey.operating_profile.as_ref().or(service.operating_profile.as_ref());
+                let service_id = self.get_service_id(tx, &service.operating_period, profile, first_stop.stop_id.starts_with('6'))?;
+                let headsign = journey.destination_display.as_ref()
+                    .or(pattern.destination_display.as_ref())
+                    .or(service.standard_service.destination.as_ref());
+                let direction = match pattern.direction.as_deref() {
+                    Some("inbound") | Some("anticlockwise") => 1,
+                    _ => 0
+                };
+                let block_id = journey.operational.as_ref().and_then(|o| o.block.as_ref())
+                    .map(|block| format!("{agency_id}:{}:{service_id}", block.block_number));
+
+                insert_trip.execute(params![trip_id, format!("{prefix}{}:{}", service.service_code, journey.line_ref), service_id, headsign, direction, block_id])?;
+                for (i, stop) in stops.iter().enumerate() {
+                    insert_stop_time.execute(params![
+                        trip_id, stop.arrival, stop.departure, stop.stop_id, stop.headsign, i + 1,
+                        stop.timepoint, stop.drop_off_type, stop.pickup_type
+                    ])?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Get the service ID for an operating profile, creating its calendar if needed
+    fn get_service_id(&mut self, tx: &Transaction, period: &OperatingPeriod, profile: Option<&OperatingProfile>, scotland: bool) -> Result<String, Box<dyn Error>> {
+        let key = self.get_calendar(period, profile, scotland);
+        if let Some(service_id) = self.calendars.get(&key) {
+            return Ok(service_id.clone());
+        }
+        let service_id = format!("{}{}", self.prefix, self.calendars.len());
+        tx.prepare_cached("REPLACE INTO calendar (service_id, start_date, end_date, validity) VALUES (?, ?, ?, ?)")?
+            .execute(params![service_id, gtfs_date(&key.start), gtfs_date(&key.end), key.validity])?;
+        let mut insert_date = tx.prepare_cached("REPLACE INTO calendar_dates (service_id, date, exception_type) VALUES (?, ?, ?)")?;
+        for (date, exception_type) in &key.exceptions {
+            insert_date.execute(params![service_id, gtfs_date(date), exception_type])?;
+        }
+        self.calendars.insert(key, service_id.clone());
+        Ok(service_id)
+    }
+
+    /// Work out the days an operating profile runs on, with bank holidays and special days as exceptions
+    fn get_calendar(&self, period: &OperatingPeriod, profile: Option<&OperatingProfile>, scotland: bool) -> CalendarKey {
+        let start = period.start_date;
+        let end = period.end_date.unwrap_or(start + Duration::days(365));
+        let days = profile.and_then(|p| p.regular_day_type.as_ref());
+        let validity = match days {
+            None => get_validity(&[DayOfWeek::MondayToFriday]),
+            Some(RegularDayType { holidays_only: Some(_), .. }) => 0,
+            Some(RegularDayType { days_of_week, .. }) => days_of_week.as_ref().map_or(0, |d| get_validity(&d.days))
+        };
+
+        // exceptions as date -> GTFS exception type (1 = added, 2 = removed)
+        let mut exceptions: HashMap<NaiveDate, u8> = HashMap::new();
+        let holidays = if scotland { &self.bank_holidays.scotland } else { &self.bank_holidays.england_and_wales };
+        if let Some(bank_holidays) = profile.and_then(|p| p.bank_holiday_operation.as_ref()) {
+            for (days, exception_type) in [(&bank_holidays.days_of_non_operation, 2), (&bank_holidays.days_of_operation, 1)] {
+                for holiday in days.iter().flat_map(|d| &d.days) {
+                    for date in holiday.get_dates(holidays, start.year()..=end.year()) {
+                        exceptions.insert(date, exception_type);
+                    }
+                }
+            }
+        }
+        if let Some(special_days) = profile.and_then(|p| p.special_days_operation.as_ref()) {
+            for (ranges, exception_type) in [(&special_days.days_of_non_operation, 2), (&special_days.days_of_operation, 1)] {
+                for range in ranges.iter().flat_map(|r| &r.date_range) {
+                    for date in range.start_date.iter_days().take_while(|d| *d <= range.end_date) {
+                        exceptions.insert(date, exception_type);
+                    }
+                }
+            }
+        }
+        // exceptions only need storing if they change whether the service runs
+        let mut exceptions: Vec<(NaiveDate, u8)> = exceptions.into_iter()
+            .filter(|(date, exception_type)| *date >= start && *date <= end
+                && (validity >> date.weekday().num_days_from_monday() & 1 == 1) != (*exception_type == 1))
+            .collect();
+        exceptions.sort();
+        CalendarKey { start, end, validity, exceptions }
+    }
+}
+
+/// Work out the stops, times and stop attributes for a vehicle journey.
+/// The journey's own timing links override the run and wait times of the pattern's timing links
+fn get_journey_stops(journey: &VehicleJourney, pattern: &JourneyPattern, sections: &HashMap<&str, &JourneyPatternSection>) -> Vec<JourneyStop> {
+    let overrides: HashMap<&str, &VehicleJourneyTimingLink> = journey.vehicle_journey_timing_link.iter()
+        .map(|link| (link.journey_pattern_timing_link_ref.as_str(), link)).collect();
+    let links: Vec<(&TimingLink, Option<&&VehicleJourneyTimingLink>)> = pattern.journey_pattern_section_refs.iter()
+        .filter_map(|r| sections.get(r.as_str()))
+        .flat_map(|s| &s.journey_pattern_timing_link)
+        .map(|link| (link, link.id.as_deref().and_then(|id| overrides.get(id))))
+        .collect();
+    let from_wait = |(link, journey_link): &(&TimingLink, Option<&&VehicleJourneyTimingLink>)| parse_duration(
+        journey_link.and_then(|l| l.from.as_ref()).and_then(|f| f.wait_time.as_ref()).or(link.from.wait_time.as_ref()));
+    let to_wait = |(link, journey_link): &(&TimingLink, Option<&&VehicleJourneyTimingLink>)| parse_duration(
+        journey_link.and_then(|l| l.to.as_ref()).and_then(|t| t.wait_time.as_ref()).or(link.to.wait_time.as_ref()));
+
+    let mut time = journey.departure_time.num_seconds_from_midnight() as i64;
+    let mut stops = Vec::with_capacity(links.len() + 1);
+    for (i, timing_link) in links.iter().enumerate() {
+        let (link, journey_link) = timing_link;
+        if i == 0 {
+            let arrival = time;
+            time += from_wait(timing_link);
+            stops.push(JourneyStop::new(&link.from, None, arrival, time));
+        }
+        time += parse_duration(journey_link.and_then(|l| l.run_time.as_ref()).or(link.run_time.as_ref()));
+        let arrival = time;
+        let next = links.get(i + 1);
+        time += to_wait(timing_link) + next.map_or(0, from_wait);
+        stops.push(JourneyStop::new(&link.to, next.map(|(l, _)| &l.from), arrival, time));
+    }
+    stops
+}
+
+struct JourneyStop {
+    stop_id: String,
+    arrival: i64,
+    departure: i64,
+    headsign: Option<String>,
+    timepoint: u8,
+    pickup_type: u8,
+    drop_off_type: u8
+}
+
+impl JourneyStop {
+    /// Create a stop from the end of one timing link and (optionally) the start of the next
+    fn new(stop: &TimingLinkStop, next: Option<&TimingLinkStop>, arrival: i64, departure: i64) -> JourneyStop {
+        let headsign = next.and_then(|n| n.dynamic_destination_display.clone())
+            .or_else(|| stop.dynamic_destination_display.clone());
+        let timing_status = next.and_then(|n| n.timing_status.as_deref()).or(stop.timing_status.as_deref());
+        let activity = next.and_then(|n| n.activity.as_deref()).or(stop.activity.as_deref());
+        JourneyStop {
+            stop_id: stop.stop_point_ref.clone(),
+            arrival,
+            departure,
+            headsign,
+            // principal timing points and time info points both have times passengers can rely on
+            timepoint: matches!(timing_status, Some("PTP") | Some("principalTimingPoint") | Some("TIP") | Some("timeInfoPoint")) as u8,
+            pickup_type: matches!(activity, Some("setDown") | Some("pass")) as u8,
+            drop_off_type: matches!(activity, Some("pickUp") | Some("pass")) as u8,
+        }
+    }
+}
+
+/// Parse an ISO 8601 duration such as PT1H2M30S into seconds
+fn parse_duration(duration: Option<&String>) -> i64 {
+    let Some(duration) = duration else {
+        return 0;
+    };
+    let mut total = 0;
+    let mut number = String::new();
+    for c in duration.chars().skip_while(|c| *c != 'T').skip(1) {
+        match c {
+            'H' => total += number.parse::<f64>().unwrap_or(0.0) * 3600.0,
+            'M' => total += number.parse::<f64>().unwrap_or(0.0) * 60.0,
+            'S' => total += number.parse::<f64>().unwrap_or(0.0),
+            _ => {
+                number.push(c);
+                continue;
+            }
+        }
+        number.clear();
+    }
+    total as i64
+}
+
+fn gtfs_date(date: &NaiveDate) -> u32 {
+    date.year() as u32 * 10000 + date.month() * 100 + date.day()
+}
+
+fn get_validity(days: &[DayOfWeek]) -> u8 {
+    days.iter().fold(0, |validity, day| validity | match day {
+        DayOfWeek::Monday => 1,
+        DayOfWeek::Tuesday => 1 << 1,
+        DayOfWeek::Wednesday => 1 << 2,
+        DayOfWeek::Thursday => 1 << 3,
+        DayOfWeek::Friday => 1 << 4,
+        DayOfWeek::Saturday => 1 << 5,
+        DayOfWeek::Sunday => 1 << 6,
+        DayOfWeek::MondayToFriday => 0b0011111,
+        DayOfWeek::MondayToSaturday => 0b0111111,
+        DayOfWeek::MondayToSunday => 0b1111111,
+        DayOfWeek::Weekend => 0b1100000,
+        DayOfWeek::NotMonday => 0b1111110,
+        DayOfWeek::NotTuesday => 0b1111101,
+        DayOfWeek::NotWednesday => 0b1111011,
+        DayOfWeek::NotThursday => 0b1110111,
+        DayOfWeek::NotFriday => 0b1101111,
+        DayOfWeek::NotSaturday => 0b1011111,
+        DayOfWeek::NotSunday => 0b0111111,
+        DayOfWeek::Other => 0
+    })
+}
+
+#[derive(Eq, PartialEq, Hash)]
+struct CalendarKey {
+    start: NaiveDate,
+    end: NaiveDate,
+    validity: u8,
+    exceptions: Vec<(NaiveDate, u8)>
+}
+
+/// Bank holiday dates by TransXChange bank holiday name
+#[derive(Default)]
+struct BankHolidayDates {
+    england_and_wales: HashMap<&'static str, HashSet<NaiveDate>>,
+    scotland: HashMap<&'static str, HashSet<NaiveDate>>
+}
+
+#[derive(Deserialize)]
+struct GovBankHolidays {
+    #[serde(rename = "england-and-wales")]
+    england_and_wales: GovBankHolidayDivision,
+    scotland: GovBankHolidayDivision
+}
+
+#[derive(Deserialize)]
+struct GovBankHolidayDivision {
+    events: Vec<GovBankHoliday>
+}
+
+#[derive(Deserialize)]
+struct GovBankHoliday {
+    title: String,
+    date: NaiveDate,
+    notes: String
+}
+
+/// Download UK bank holiday dates and map them to TransXChange names
+fn load_bank_holidays() -> Result<BankHolidayDates, Box<dyn Error>> {
+    let file = download_if_old("https://www.gov.uk/bank-holidays.json", "bank-holidays.json")?;
+    let holidays: GovBankHolidays = serde_json::from_reader(BufReader::new(file))?;
+    Ok(BankHolidayDates {
+        england_and_wales: map_bank_holidays(&holidays.england_and_wales, false),
+        scotland: map_bank_holidays(&holidays.scotland, true)
+    })
+}
+
+/// Map the bank holidays of one part of the UK to their TransXChange names
+fn map_bank_holidays(division: &GovBankHolidayDivision, scotland: bool) -> HashMap<&'static str, HashSet<NaiveDate>> {
+    let mut dates: HashMap<&'static str, HashSet<NaiveDate>> = HashMap::new();
+    for event in &division.events {
+        let substitute = event.notes.to_lowercase().contains("substitute");
+        let name = match (event.title.replace('’', "'").as_str(), substitute) {
+            ("New Year's Day", false) => "NewYearsDay",
+            ("New Year's Day", true) => "NewYearsDayHoliday",
+            ("2nd January", false) => "Jan2ndScotland",
+            ("2nd January", true) => "Jan2ndScotlandHoliday",
+            ("Good Friday", _) => "GoodFriday",
+            ("Easter Monday", _) => "EasterMonday",
+            ("Early May bank holiday", _) => "MayDay",
+            ("Spring bank holiday", _) => "SpringBank",
+            ("Summer bank holiday", _) if scotland => "AugustBankHolidayScotland",
+            ("Summer bank holiday", _) => "LateSummerBankHolidayNotScotland",
+            ("St Andrew's Day", false) => "StAndrewsDay",
+            ("St Andrew's Day", true) => "StAndrewsDayHoliday",
+            ("Christmas Day", false) => "ChristmasDay",
+            ("Christmas Day", true) => "ChristmasDayHoliday",
+            ("Boxing Day", false) => "BoxingDay",
+            ("Boxing Day", true) => "BoxingDayHoliday",
+            _ => "OtherPublicHoliday"
+        };
+        dates.entry(name).or_default().insert(event.date);
+    }
+    dates
+}
+
+#[derive(Deserialize)]
+enum BankHoliday {
+    ChristmasDay, BoxingDay, GoodFriday, NewYearsDay, Jan2ndScotland, EasterMonday, MayDay, SpringBank,
+    LateSummerBankHolidayNotScotland, AugustBankHolidayScotland, StAndrewsDay,
+    ChristmasDayHoliday, BoxingDayHoliday, NewYearsDayHoliday, Jan2ndScotlandHoliday, StAndrewsDayHoliday,
+    ChristmasEve, NewYearsEve,
+    AllBankHolidays, HolidayMondays, Christmas, DisplacementHolidays, EarlyRunOff,
+    #[serde(other)]
+    Other
+}
+
+impl BankHoliday {
+    /// Get the dates of this holiday (or group of holidays)
+    fn get_dates(&self, holidays: &HashMap<&'static str, HashSet<NaiveDate>>, years: std::ops::RangeInclusive<i32>) -> Vec<NaiveDate> {
+        let named = |names: &[&str]| names.iter()
+            .flat_map(|name| holidays.get(*name).into_iter().flatten().copied())
+            .collect::<Vec<NaiveDate>>();
+        match self {
+            BankHoliday::ChristmasEve => years.filter_map(|y| NaiveDate::from_ymd_opt(y, 12, 24)).collect(),
+            BankHoliday::NewYearsEve => years.filter_map(|y| NaiveDate::from_ymd_opt(y, 12, 31)).collect(),
+            BankHoliday::EarlyRunOff => years.flat_map(|y| [NaiveDate::from_ymd_opt(y, 12, 24), NaiveDate::from_ymd_opt(y, 12, 31)]).flatten().collect(),
+            BankHoliday::AllBankHolidays => holidays.values().flatten().copied().collect(),
+            BankHoliday::HolidayMondays => named(&["EasterMonday", "MayDay", "SpringBank", "LateSummerBankHolidayNotScotland", "AugustBankHolidayScotland"]),
+            BankHoliday::Christmas => named(&["ChristmasDay", "BoxingDay"]),
+            BankHoliday::DisplacementHolidays => named(&["ChristmasDayHoliday", "BoxingDayHoliday", "NewYearsDayHoliday", "Jan2ndScotlandHoliday", "StAndrewsDayHoliday"]),
+            BankHoliday::ChristmasDay => named(&["ChristmasDay"]),
+            BankHoliday::BoxingDay => named(&["BoxingDay"]),
+            BankHoliday::GoodFriday => named(&["GoodFriday"]),
+            BankHoliday::NewYearsDay => named(&["NewYearsDay"]),
+            BankHoliday::Jan2ndScotland => named(&["Jan2ndScotland"]),
+            BankHoliday::EasterMonday => named(&["EasterMonday"]),
+            BankHoliday::MayDay => named(&["MayDay"]),
+            BankHoliday::SpringBank => named(&["SpringBank"]),
+            BankHoliday::LateSummerBankHolidayNotScotland => named(&["LateSummerBankHolidayNotScotland"]),
+            BankHoliday::AugustBankHolidayScotland => named(&["AugustBankHolidayScotland"]),
+            BankHoliday::StAndrewsDay => named(&["StAndrewsDay"]),
+            BankHoliday::ChristmasDayHoliday => named(&["ChristmasDayHoliday"]),
+            BankHoliday::BoxingDayHoliday => named(&["BoxingDayHoliday"]),
+            BankHoliday::NewYearsDayHoliday => named(&["NewYearsDayHoliday"]),
+            BankHoliday::Jan2ndScotlandHoliday => named(&["Jan2ndScotlandHoliday"]),
+            BankHoliday::StAndrewsDayHoliday => named(&["StAndrewsDayHoliday"]),
+            BankHoliday::Other => vec![]
+        }
+    }
+}
+
+#[derive(Deserialize)]
+enum DayOfWeek {
+    Monday, Tuesday, Wednesday, Thursday, Friday, Saturday, Sunday,
+    MondayToFriday, MondayToSaturday, MondayToSunday, Weekend,
+    NotMonday, NotTuesday, NotWednesday, NotThursday, NotFriday, NotSaturday, NotSunday,
+    #[serde(other)]
+    Other
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct TransXChange {
+    #[serde(default)]
+    operators: Operators,
+    #[serde(default)]
+    services: Services,
+    #[serde(default)]
+    journey_pattern_sections: JourneyPatternSections,
+    #[serde(default)]
+    vehicle_journeys: VehicleJourneys,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "PascalCase")]
+struct Operators {
+    #[serde(default, alias = "LicensedOperator")]
+    operator: Vec<Operator>
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Operator {
+    #[serde(rename = "@id")]
+    id: String,
+    national_operator_code: Option<String>,
+    operator_code: Option<String>,
+    operator_short_name: Option<String>,
+    trading_name: Option<String>
+}
+
+impl Operator {
+    fn code(&self) -> &str {
+        self.national_operator_code.as_deref().or(self.operator_code.as_deref()).unwrap_or(self.id.as_str())
+    }
+
+    fn name(&self) -> &str {
+        self.trading_name.as_deref().or(self.operator_short_name.as_deref()).unwrap_or(self.code())
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "PascalCase")]
+struct Services {
+    #[serde(default)]
+    service: Vec<Service>
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Service {
+    service_code: String,
+    lines: Lines,
+    operating_period: OperatingPeriod,
+    operating_profile: Option<OperatingProfile>,
+    registered_operator_ref: Option<String>,
+    standard_service: StandardService
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Lines {
+    #[serde(default)]
+    line: Vec<Line>
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Line {
+    #[serde(rename = "@id")]
+    id: String,
+    line_name: String
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct OperatingPeriod {
+    start_date: NaiveDate,
+    end_date: Option<NaiveDate>
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct StandardService {
+    origin: Option<String>,
+    destination: Option<String>,
+    #[serde(default)]
+    journey_pattern: Vec<JourneyPattern>
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct JourneyPattern {
+    #[serde(rename = "@id")]
+    id: String,
+    destination_display: Option<String>,
+    direction: Option<String>,
+    #[serde(default)]
+    journey_pattern_section_refs: Vec<String>
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "PascalCase")]
+struct JourneyPatternSections {
+    #[serde(default)]
+    journey_pattern_section: Vec<JourneyPatternSection>
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct JourneyPatternSection {
+    #[serde(rename = "@id")]
+    id: String,
+    #[serde(default)]
+    journey_pattern_timing_link: Vec<TimingLink>
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct TimingLink {
+    #[serde(rename = "@id")]
+    id: Option<String>,
+    from: TimingLinkStop,
+    to: TimingLinkStop,
+    run_time: Option<String>
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct TimingLinkStop {
+    stop_point_ref: String,
+    timing_status: Option<String>,
+    wait_time: Option<String>,
+    dynamic_destination_display: Option<String>,
+    activity: Option<String>
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "PascalCase")]
+struct VehicleJourneys {
+    #[serde(default)]
+    vehicle_journey: Vec<VehicleJourney>
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct VehicleJourney {
+    operational: Option<Operational>,
+    operating_profile: Option<OperatingProfile>,
+    vehicle_journey_code: String,
+    service_ref: String,
+    line_ref: String,
+    journey_pattern_ref: String,
+    departure_time: NaiveTime,
+    destination_display: Option<String>,
+    #[serde(default)]
+    vehicle_journey_timing_link: Vec<VehicleJourneyTimingLink>
+}
+
+/// Journey-specific run and wait times for one of its pattern's timing links
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct VehicleJourneyTimingLink {
+    journey_pattern_timing_link_ref: String,
+    run_time: Option<String>,
+    from: Option<VehicleJourneyTimingLinkStop>,
+    to: Option<VehicleJourneyTimingLinkStop>
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct VehicleJourneyTimingLinkStop {
+    wait_time: Option<String>
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Operational {
+    block: Option<Block>
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Block {
+    block_number: String
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct OperatingProfile {
+    regular_day_type: Option<RegularDayType>,
+    special_days_operation: Option<SpecialDaysOperation>,
+    bank_holiday_operation: Option<BankHolidayOperation>
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct RegularDayType {
+    days_of_week: Option<DaysOfWeek>,
+    holidays_only: Option<()>
+}
+
+#[derive(Deserialize)]
+struct DaysOfWeek {
+    #[serde(rename = "$value", default)]
+    days: Vec<DayOfWeek>
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct SpecialDaysOperation {
+    days_of_operation: Option<DateRanges>,
+    days_of_non_operation: Option<DateRanges>
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct DateRanges {
+    #[serde(default)]
+    date_range: Vec<DateRange>
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct DateRange {
+    start_date: NaiveDate,
+    end_date: NaiveDate
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct BankHolidayOperation {
+    days_of_operation: Option<BankHolidays>,
+    days_of_non_operation: Option<BankHolidays>
+}
+
+#[derive(Deserialize)]
+struct BankHolidays {
+    #[serde(rename = "$value", default)]
+    days: Vec<BankHoliday>
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TXC: &str = r#"
+        <TransXChange>
+            <Operators>
+                <Operator id="O1"><NationalOperatorCode>ABCD</NationalOperatorCode><TradingName>Test Buses</TradingName></Operator>
+            </Operators>
+            <Services>
+                <Service>
+                    <ServiceCode>S1</ServiceCode>
+                    <Lines><Line id="L1"><LineName>1</LineName></Line></Lines>
+                    <OperatingPeriod><StartDate>2024-01-01</StartDate><EndDate>2024-12-31</EndDate></OperatingPeriod>
+                    <RegisteredOperatorRef>O1</RegisteredOperatorRef>
+                    <StandardService>
+                        <Origin>Town</Origin>
+                        <Destination>City</Destination>
+                        <JourneyPattern id="JP1">
+                            <DestinationDisplay>City</DestinationDisplay>
+                            <Direction>outbound</Direction>
+                            <JourneyPatternSectionRefs>JPS1</JourneyPatternSectionRefs>
+                        </JourneyPattern>
+                    </StandardService>
+                </Service>
+            </Services>
+            <JourneyPatternSections>
+                <JourneyPatternSection id="JPS1">
+                    <JourneyPatternTimingLink id="JPTL1">
+                        <From><StopPointRef>6200A</StopPointRef><TimingStatus>principalTimingPoint</TimingStatus><Activity>pickUp</Activity></From>
+                        <To><StopPointRef>6200B</StopPointRef><TimingStatus>otherPoint</TimingStatus><WaitTime>PT1M</WaitTime></To>
+                        <RunTime>PT5M</RunTime>
+                    </JourneyPatternTimingLink>
+                    <JourneyPatternTimingLink id="JPTL2">
+                        <From><StopPointRef>6200B</StopPointRef><TimingStatus>otherPoint</TimingStatus><DynamicDestinationDisplay>City Centre</DynamicDestinationDisplay></From>
+                        <To><StopPointRef>6200C</StopPointRef><TimingStatus>PTP</TimingStatus><Activity>setDown</Activity></To>
+                        <RunTime>PT10M30S</RunTime>
+                    </JourneyPatternTimingLink>
+                </JourneyPatternSection>
+            </JourneyPatternSections>
+            <VehicleJourneys>
+                <VehicleJourney>
+                    <VehicleJourneyCode>VJ1</VehicleJourneyCode>
+                    <ServiceRef>S1</ServiceRef>
+                    <LineRef>L1</LineRef>
+                    <JourneyPatternRef>JP1</JourneyPatternRef>
+                    <DepartureTime>08:00:00</DepartureTime>
+                </VehicleJourney>
+                <VehicleJourney>
+                    <VehicleJourneyCode>VJ2</VehicleJourneyCode>
+                    <ServiceRef>S1</ServiceRef>
+                    <LineRef>L1</LineRef>
+                    <JourneyPatternRef>JP1</JourneyPatternRef>
+                    <DepartureTime>23:50:00</DepartureTime>
+                    <VehicleJourneyTimingLink>
+                        <JourneyPatternTimingLinkRef>JPTL1</JourneyPatternTimingLinkRef>
+                        <RunTime>PT8M</RunTime>
+                        <To><WaitTime>PT2M</WaitTime></To>
+                    </VehicleJourneyTimingLink>
+                </VehicleJourney>
+            </VehicleJourneys>
+        </TransXChange>"#;
+
+    fn date(y: i32, m: u32, d: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(y, m, d).unwrap()
+    }
+
+    fn journey_stops(txc: &TransXChange, journey: usize) -> Vec<JourneyStop> {
+        let sections: HashMap<&str, &JourneyPatternSection> = txc.journey_pattern_sections.journey_pattern_section.iter().map(|s| (s.id.as_str(), s)).collect();
+        get_journey_stops(&txc.vehicle_journeys.vehicle_journey[journey], &txc.services.service[0].standard_service.journey_pattern[0], &sections)
+    }
+
+    fn importer(england_and_wales: &[(&'static str, NaiveDate)], scotland: &[(&'static str, NaiveDate)]) -> TxCImporter<'static> {
+        let dates = |holidays: &[(&'static str, NaiveDate)]| {
+            let mut dates: HashMap<&'static str, HashSet<NaiveDate>> = HashMap::new();
+            for (name, date) in holidays {
+                dates.entry(*name).or_default().insert(*date);
+            }
+            dates
+        };
+        TxCImporter {
+            prefix: "T",
+            bank_holidays: BankHolidayDates { england_and_wales: dates(england_and_wales), scotland: dates(scotland) },
+            calendars: HashMap::new(),
+            trip_ids: HashMap::new()
+        }
+    }
+
+    fn calendar(importer: &TxCImporter, profile: &str, scotland: bool) -> CalendarKey {
+        let period = OperatingPeriod { start_date: date(2024, 1, 1), end_date: Some(date(2024, 12, 31)) };
+        let profile: OperatingProfile = quick_xml::de::from_str(profile).unwrap();
+        importer.get_calendar(&period, Some(&profile), scotland)
+    }
+
+    #[test]
+    fn files_are_parsed() {
+        let txc: TransXChange = quick_xml::de::from_str(TXC).unwrap();
+        assert_eq!(txc.operators.operator[0].code(), "ABCD");
+        assert_eq!(txc.operators.operator[0].name(), "Test Buses");
+        let service = &txc.services.service[0];
+        assert_eq!(service.lines.line[0].line_name, "1");
+        assert_eq!(service.standard_service.journey_pattern[0].journey_pattern_section_refs, vec!["JPS1"]);
+        assert_eq!(txc.journey_pattern_sections.journey_pattern_section[0].journey_pattern_timing_link.len(), 2);
+        assert_eq!(txc.vehicle_journeys.vehicle_journey.len(), 2);
+    }
+
+    #[test]
+    fn journeys_follow_their_pattern_timing_links() {
+        let txc: TransXChange = quick_xml::de::from_str(TXC).unwrap();
+        let stops = journey_stops(&txc, 0);
+        let stop_ids: Vec<&str> = stops.iter().map(|stop| stop.stop_id.as_str()).collect();
+        assert_eq!(stop_ids, vec!["6200A", "6200B", "6200C"]);
+        let times: Vec<(i64, i64)> = stops.iter().map(|stop| (stop.arrival, stop.departure)).collect();
+        assert_eq!(times, vec![(28800, 28800), (29100, 29160), (29790, 29790)]);
+
+        assert_eq!(stops.iter().map(|stop| stop.timepoint).collect::<Vec<_>>(), vec![1, 0, 1]);
+        assert_eq!((stops[0].pickup_type, stops[0].drop_off_type), (0, 1));
+        assert_eq!((stops[1].pickup_type, stops[1].drop_off_type), (0, 0));
+        assert_eq!((stops[2].pickup_type, stops[2].drop_off_type), (1, 0));
+        assert_eq!(stops[1].headsign.as_deref(), Some("City Centre"));
+        assert_eq!(stops[2].headsign, None);
+    }
+
+    #[test]
+    fn journey_timing_links_override_the_pattern() {
+        let txc: TransXChange = quick_xml::de::from_str(TXC).unwrap();
+        let times: Vec<(i64, i64)> = journey_stops(&txc, 1).iter().map(|stop| (stop.arrival, stop.departure)).collect();
+        // times carry on past midnight
+        assert_eq!(times, vec![(85800, 85800), (86280, 86400), (87030, 87030)]);
+    }
+
+    #[test]
+    fn durations_are_parsed() {
+        assert_eq!(parse_duration(Some(&"PT1H2M30S".to_string())), 3750);
+        assert_eq!(parse_duration(Some(&"PT45S".to_string())), 45);
+        assert_eq!(parse_duration(Some(&"PT0S".to_string())), 0);
+        assert_eq!(parse_duration(None), 0);
+    }
+
+    #[test]
+    fn operating_profiles_become_calendars() {
+        let importer = importer(&[("ChristmasDay", date(2024, 12, 25)), ("BoxingDay", date(2024, 12, 26)), ("GoodFriday", date(2024, 3, 29))], &[]);
+        let key = calendar(&importer, r#"
+            <OperatingProfile>
+                <RegularDayType><DaysOfWeek><MondayToFriday/></DaysOfWeek></RegularDayType>
+                <SpecialDaysOperation>
+                    <DaysOfOperation><DateRange><StartDate>2024-07-06</StartDate><EndDate>2024-07-07</EndDate></DateRange></DaysOfOperation>
+                    <DaysOfNonOperation><DateRange><StartDate>2024-07-01</StartDate><EndDate>2024-07-02</EndDate></DateRange></DaysOfNonOperation>
+                </SpecialDaysOperation>
+                <BankHolidayOperation>
+                    <DaysOfOperation><GoodFriday/></DaysOfOperation>
+                    <DaysOfNonOperation><Christmas/></DaysOfNonOperation>
+                </BankHolidayOperation>
+            </OperatingProfile>"#, false);
+        assert_eq!(key.validity, 0b0011111);
+        // Good Friday is already a day the service runs
+        assert_eq!(key.exceptions, vec![
+            (date(2024, 7, 1), 2), (date(2024, 7, 2), 2), (date(2024, 7, 6), 1), (date(2024, 7, 7), 1),
+            (date(2024, 12, 25), 2), (date(2024, 12, 26), 2)
+        ]);
+    }
+
+    #[test]
+    fn holiday_only_services_run_on_their_holidays() {
+        let importer = importer(&[("ChristmasDay", date(2024, 12, 25)), ("ChristmasDay", date(2025, 12, 25))], &[]);
+        let key = calendar(&importer, r#"
+            <OperatingProfile>
+                <RegularDayType><HolidaysOnly/></RegularDayType>
+                <BankHolidayOperation><DaysOfOperation><ChristmasDay/></DaysOfOperation></BankHolidayOperation>
+            </OperatingProfile>"#, false);
+        assert_eq!(key.validity, 0);
+        assert_eq!(key.exceptions, vec![(date(2024, 12, 25), 1)]);
+    }
+
+    #[test]
+    fn services_run_on_weekdays_without_a_profile() {
+        let period = OperatingPeriod { start_date: date(2024, 1, 1), end_date: None };
+        let key = importer(&[], &[]).get_calendar(&period, None, false);
+        assert_eq!(key.validity, 0b0011111);
+        assert_eq!(key.end, date(2024, 12, 31));
+        assert!(key.exceptions.is_empty());
+    }
+
+    #[test]
+    fn scottish_stops_use_scottish_bank_holidays() {
+        let importer = importer(&[("LateSummerBankHolidayNotScotland", date(2024, 8, 26))], &[("AugustBankHolidayScotland", date(2024, 8, 5))]);
+        let profile = r#"
+            <OperatingProfile>
+                <RegularDayType><DaysOfWeek><MondayToSunday/></DaysOfWeek></RegularDayType>
+                <BankHolidayOperation><DaysOfNonOperation><HolidayMondays/></DaysOfNonOperation></BankHolidayOperation>
+            </OperatingProfile>"#;
+        assert_eq!(calendar(&importer, profile, false).exceptions, vec![(date(2024, 8, 26), 2)]);
+        assert_eq!(calendar(&importer, profile, true).exceptions, vec![(date(2024, 8, 5), 2)]);
+    }
+
+    #[test]
+    fn bank_holidays_are_mapped_to_transxchange_names() {
+        let division: GovBankHolidayDivision = serde_json::from_str(r#"{"division": "scotland", "events": [
+            {"title": "New Year’s Day", "date": "2022-01-03", "notes": "Substitute day", "bunting": true},
+            {"title": "2nd January", "date": "2022-01-04", "notes": "Substitute day", "bunting": true},
+            {"title": "Summer bank holiday", "date": "2022-08-01", "notes": "", "bunting": true},
+            {"title": "St Andrew’s Day", "date": "2022-11-30", "notes": "", "bunting": true},
+            {"title": "Bank holiday for the coronation of King Charles III", "date": "2023-05-08", "notes": "", "bunting": true}
+        ]}"#).unwrap();
+        let scotland = map_bank_holidays(&division, true);
+        assert_eq!(scotland.get("NewYearsDayHoliday"), Some(&HashSet::from([date(2022, 1, 3)])));
+        assert_eq!(scotland.get("Jan2ndScotlandHoliday"), Some(&HashSet::from([date(2022, 1, 4)])));
+        assert_eq!(scotland.get("AugustBankHolidayScotland"), Some(&HashSet::from([date(2022, 8, 1)])));
+        assert_eq!(scotland.get("StAndrewsDay"), Some(&HashSet::from([date(2022, 11, 30)])));
+        assert_eq!(scotland.get("OtherPublicHoliday"), Some(&HashSet::from([date(2023, 5, 8)])));
+        assert!(!scotland.contains_key("NewYearsDay"));
+
+        let england_and_wales = map_bank_holidays(&division, false);
+        assert_eq!(england_and_wales.get("LateSummerBankHolidayNotScotland"), Some(&HashSet::from([date(2022, 8, 1)])));
+    }
+
+    #[test]
+    fn bank_holiday_groups_cover_their_holidays() {
+        let holidays = HashMap::from([
+            ("ChristmasDay", HashSet::from([date(2024, 12, 25)])),
+            ("BoxingDayHoliday", HashSet::from([date(2021, 12, 28)])),
+            ("OtherPublicHoliday", HashSet::from([date(2023, 5, 8)]))
+        ]);
+        let sorted = |mut dates: Vec<NaiveDate>| { dates.sort(); dates };
+        assert_eq!(sorted(BankHoliday::AllBankHolidays.get_dates(&holidays, 2024..=2024)), vec![date(2021, 12, 28), date(2023, 5, 8), date(2024, 12, 25)]);
+        assert_eq!(BankHoliday::Christmas.get_dates(&holidays, 2024..=2024), vec![date(2024, 12, 25)]);
+        assert_eq!(BankHoliday::DisplacementHolidays.get_dates(&holidays, 2024..=2024), vec![date(2021, 12, 28)]);
+        assert_eq!(BankHoliday::EarlyRunOff.get_dates(&holidays, 2024..=2025), vec![date(2024, 12, 24), date(2024, 12, 31), date(2025, 12, 24), date(2025, 12, 31)]);
+        assert!(BankHoliday::Other.get_dates(&holidays, 2024..=2024).is_empty());
+    }
+}
@@ -0,0 +1,139 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs::File;
+
+use chrono::Local;
+use rusqlite::{Connection, params, ToSql};
+use serde::Serialize;
+
+use crate::sources::source_for_id;
+
+const REPORT_PATH: &str = "validation_report.json";
+const MAX_EXAMPLES: usize = 20;
+
+/// Checks run against the finished database.
+/// Each query returns (agency_id, id used to find the owning source, item, detail), with any `?1` bound to today's date
+const CHECKS: [(&str, &str); 5] = [
+    ("orphaned_stop_id", r#"
+        SELECT r.agency_id, min(st.trip_id), st.stop_id, count(*) || ' stop times, e.g. trip ' || min(st.trip_id)
+            FROM stop_times st
+                LEFT OUTER JOIN stances s ON s.code = st.stop_id
+                INNER JOIN trips t ON t.trip_id = st.trip_id
+                INNER JOIN routes r ON r.route_id = t.route_id
+            WHERE s.code IS NULL
+            GROUP BY st.stop_id, r.agency_id"#),
+    ("no_active_dates", r#"
+        SELECT r.agency_id, t.trip_id, t.trip_id, 'service ' || coalesce(t.service_id, 'missing')
+            FROM trips t
+                INNER JOIN routes r ON r.route_id = t.route_id
+                LEFT OUTER JOIN calendar c ON c.service_id = t.service_id
+            WHERE coalesce(c.validity <> 0 AND c.end_date >= ?1, 0) = 0
+                AND NOT EXISTS (SELECT 1 FROM calendar_dates d WHERE d.service_id = t.service_id AND d.exception_type = 1 AND d.date >= ?1)"#),
+    ("non_monotonic_stop_times", r#"
+        SELECT r.agency_id, st.trip_id, st.trip_id, 'first at stop_sequence ' || min(st.stop_sequence)
+            FROM (SELECT trip_id, stop_sequence, arrival_time, departure_time,
+                        lag(departure_time) OVER (PARTITION BY trip_id ORDER BY stop_sequence) AS previous
+                    FROM stop_times) st
+                INNER JOIN trips t ON t.trip_id = st.trip_id
+                INNER JOIN routes r ON r.route_id = t.route_id
+            WHERE (st.arrival_time < st.previous OR st.departure_time < st.arrival_time)
+            GROUP BY st.trip_id"#),
+    ("duplicated_trip", r#"
+        SELECT r.agency_id, min(t.trip_id), min(t.trip_id), 'duplicated by ' || group_concat(t.trip_id, ', ')
+            FROM trips t
+                INNER JOIN (SELECT trip_id, stop_id, departure_time, min(stop_sequence) FROM stop_times GROUP BY trip_id) origin
+                    ON origin.trip_id = t.trip_id
+                INNER JOIN routes r ON r.route_id = t.route_id
+            GROUP BY t.route_id, t.service_id, origin.stop_id, origin.departure_time, t.trip_headsign
+            HAVING count(*) > 1"#),
+    ("route_without_trips", r#"
+        SELECT agency_id, route_id, route_id, coalesce(route_short_name, '')
+            FROM routes
+            WHERE NOT EXISTS (SELECT 1 FROM trips WHERE trips.route_id = routes.route_id)"#),
+];
+
+/// Record an issue found while importing, for the validation report
+pub fn record_issue(db: &Connection, check_name: &str, source: &str, agency_id: Option<&str>, item: &str, detail: &str) -> rusqlite::Result<usize> {
+    db.prepare_cached("INSERT INTO validation_issues (check_name, source, agency_id, item, detail) VALUES (?, ?, ?, ?, ?)")?
+        .execute(params![check_name, source, agency_id, item, detail])
+}
+
+/// Run all data-quality checks, then write the summary table and JSON report
+pub fn validate(db: &mut Connection) -> Result<(), Box<dyn Error>> {
+    println!("Validating data");
+    let today: u32 = Local::now().format("%Y%m%d").to_string().parse()?;
+    let tx = db.transaction()?;
+    for (check_name, query) in CHECKS {
+        println!("Checking {check_name}");
+        tx.execute("DELETE FROM validation_issues WHERE check_name=?", [check_name])?;
+        let mut stmt = tx.prepare(query)?;
+        let params: &[&dyn ToSql] = if stmt.parameter_count() > 0 { &[&today] } else { &[] };
+        let issues: Vec<(Option<String>, String, String, String)> = stmt
+            .query_map(params, |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)))?
+            .collect::<rusqlite::Result<_>>()?;
+        drop(stmt);
+        for (agency_id, owner, item, detail) in issues {
+            let source = source_for_id(owner.as_str()).map(|s| s.name.as_str()).unwrap_or("Unknown");
+            record_issue(&tx, check_name, source, agency_id.as_deref(), item.as_str(), detail.as_str())?;
+        }
+    }
+    tx.execute_batch(r#"
+        DELETE FROM validation_summary;
+        INSERT INTO validation_summary (source, agency_id, check_name, count)
+            SELECT source, agency_id, check_name, count(*) FROM validation_issues GROUP BY source, agency_id, check_name;
+    "#)?;
+    tx.commit()?;
+    write_report(db)
+}
+
+fn write_report(db: &Connection) -> Result<(), Box<dyn Error>> {
+    let mut report = Report { generated: Local::now().to_rfc3339(), sources: BTreeMap::new() };
+    let mut stmt = db.prepare(r#"
+        SELECT i.source, coalesce(i.agency_id, ''), i.check_name, s.count, i.item, i.detail FROM validation_issues i
+            INNER JOIN validation_summary s ON s.source = i.source AND s.agency_id IS i.agency_id AND s.check_name = i.check_name
+            ORDER BY i.source, i.agency_id, i.check_name, i.item"#)?;
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        let check = report.sources.entry(row.get(0)?).or_default()
+            .entry(row.get(1)?).or_default()
+            .entry(row.get(2)?).or_insert_with(|| CheckReport { count: 0, examples: Vec::new() });
+        check.count = row.get(3)?;
+        if check.examples.len() < MAX_EXAMPLES {
+            check.examples.push(Issue { item: row.get(4)?, detail: row.get(5)? });
+        }
+    }
+
+    for (source, agencies) in &report.sources {
+        let mut totals: BTreeMap<&str, u64> = BTreeMap::new();
+        for checks in agencies.values() {
+            for (check_name, check) in checks {
+                *totals.entry(check_name.as_str()).or_default() += check.count;
+            }
+        }
+        for (check_name, count) in totals {
+            println!("- {source}: {count} x {check_name}");
+        }
+    }
+    serde_json::to_writer_pretty(File::create(REPORT_PATH)?, &report)?;
+    println!("Validation report written to {REPORT_PATH}");
+    Ok(())
+}
+
+/// Issues by source name, then agency ID (empty if not agency-specific), then check name
+#[derive(Serialize)]
+struct Report {
+    generated: String,
+    sources: BTreeMap<String, BTreeMap<String, BTreeMap<String, CheckReport>>>
+}
+
+#[derive(Serialize)]
+struct CheckReport {
+    count: u64,
+    examples: Vec<Issue>
+}
+
+#[derive(Serialize)]
+struct Issue {
+    item: String,
+    detail: Option<String>
+}
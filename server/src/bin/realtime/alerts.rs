@@ -0,0 +1,147 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use itertools::Itertools;
+use sha2::{Digest, Sha256};
+
+use crate::api::service::find_best_match;
+use crate::GTFSAlerts;
+use crate::transit_realtime::{Alert, FeedEntity};
+use crate::transit_realtime::alert::Effect;
+
+/// The sources (e.g. bods, lothian, passenger) which reported each alert, by alert id
+pub type AlertSources = HashMap<String, Vec<String>>;
+
+/// Give the alerts from each source a stable id, merging alerts which say the same thing about the same stops, routes and times.
+/// Each alert may come with the source's own reference for it (e.g. a SIRI SituationNumber), which is used as its id.
+/// Sources are in order of priority - merged alerts keep the text of the first source to report them
+pub fn identify_alerts(sources: Vec<(&str, Vec<(Option<String>, Alert)>)>) -> (Vec<FeedEntity>, AlertSources) {
+    let mut entities: Vec<FeedEntity> = vec![];
+    let mut by_content: HashMap<String, usize> = HashMap::new();
+    let mut alert_sources: Vec<Vec<String>> = vec![];
+    for (source, alerts) in sources {
+        for (reference, alert) in alerts {
+            let key = content_key(&alert);
+            let i = match by_content.get(&key) {
+                Some(&i) => {
+                    let existing = &mut entities[i];
+                    // Prefer the source's own reference, so the id does not depend on which sources are reporting the alert
+                    if let Some(reference) = reference && existing.id == key {
+                        existing.id = reference;
+                    }
+                    if let Some(existing) = existing.alert.as_mut() && existing.url.is_none() {
+                        existing.url = alert.url;
+                    }
+                    i
+                }
+                None => {
+                    by_content.insert(key.clone(), entities.len());
+                    alert_sources.push(vec![]);
+                    entities.push(FeedEntity {
+                        id: reference.unwrap_or(key),
+                        is_deleted: None,
+                        trip_update: None,
+                        vehicle: None,
+                        alert: Some(alert),
+                        shape: None,
+                    });
+                    entities.len() - 1
+                }
+            };
+            if !alert_sources[i].iter().any(|existing| existing == source) {
+                alert_sources[i].push(source.to_string());
+            }
+        }
+    }
+    let alert_sources = entities.iter().map(|entity| entity.id.clone()).zip(alert_sources).collect();
+    (entities, alert_sources)
+}
+
+/// Whether an alert is in effect at the given time - alerts without an active period always are
+pub fn is_active(alert: &Alert, time: &DateTime<Utc>) -> bool {
+    let time = time.timestamp().max(0) as u64;
+    alert.active_period.is_empty() || alert.active_period.iter().any(|period| {
+        period.start.map_or(true, |start| start <= time) && period.end.map_or(true, |end| time <= end)
+    })
+}
+
+/// Whether a trip running on the given date (YYYYMMDD) is cancelled or diverted by an alert.
+/// Alerts naming the trip without a date must be in effect at the given time
+pub fn get_trip_effect(alerts: &GTFSAlerts, trip_id: &str, date: &str, time: &DateTime<Utc>) -> Option<Effect> {
+    alerts.pin().iter().flat_map(|(_, entities)| {
+        entities.iter().filter_map(|entity| entity.alert.as_ref())
+            .filter(|alert| alert.informed_entity.iter().any(|entity| {
+                entity.trip.as_ref().is_some_and(|trip| trip.trip_id.as_deref() == Some(trip_id)
+                    && trip.start_date.as_deref().map_or_else(|| is_active(alert, time), |start_date| start_date == date))
+            }))
+            .filter_map(|alert| alert.effect.and_then(|effect| Effect::try_from(effect).ok()))
+            .filter(|effect| matches!(effect, Effect::NoService | Effect::Detour))
+            .collect_vec()
+    }).min_by_key(|effect| *effect as i32)
+}
+
+/// Hash of an alert's text (ignoring case, spacing and punctuation), informed entities and active periods,
+/// so the same alert from different sources has the same key but separate alerts with the same wording do not
+fn content_key(alert: &Alert) -> String {
+    let mut hasher = Sha256::new();
+    for text in [&alert.header_text, &alert.description_text] {
+        let text: String = find_best_match(text).unwrap_or_default().chars()
+            .filter(|c| c.is_alphanumeric())
+            .flat_map(char::to_lowercase)
+            .collect();
+        hasher.update(text.as_bytes());
+        hasher.update([0]);
+    }
+    for entity in alert.informed_entity.iter().map(|entity| format!("{entity:?}")).sorted().dedup() {
+        hasher.update(entity.as_bytes());
+        hasher.update([0]);
+    }
+    for (start, end) in alert.active_period.iter().map(|period| (period.start, period.end)).sorted().dedup() {
+        hasher.update(format!("{start:?}-{end:?}").as_bytes());
+        hasher.update([0]);
+    }
+    format!("{:x}", hasher.finalize())[..16].to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::siri::create_translated_string;
+    use crate::transit_realtime::{EntitySelector, TimeRange};
+
+    fn alert(header: &str, stop_id: &str, start: u64) -> Alert {
+        Alert {
+            header_text: Some(create_translated_string(header.to_string())),
+            informed_entity: vec![EntitySelector { stop_id: Some(stop_id.to_string()), ..Default::default() }],
+            active_period: vec![TimeRange { start: Some(start), end: None }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn equivalent_alerts_are_merged() {
+        let (entities, sources) = identify_alerts(vec![
+            ("lothian", vec![(None, alert("Roadworks on Princes St", "S1", 100))]),
+            ("bods", vec![(Some("SX1-0".to_string()), alert("Roadworks on Princes St.", "S1", 100))])
+        ]);
+        assert_eq!(entities.len(), 1);
+        assert_eq!(entities[0].id, "SX1-0");
+        assert_eq!(sources["SX1-0"], vec!["lothian", "bods"]);
+    }
+
+    #[test]
+    fn separate_situations_with_the_same_text_are_kept() {
+        let (entities, _) = identify_alerts(vec![
+            ("bods", vec![(None, alert("Diversion", "S1", 100)), (None, alert("Diversion", "S2", 100)), (None, alert("Diversion", "S1", 200))])
+        ]);
+        assert_eq!(entities.len(), 3);
+        assert_eq!(entities.iter().map(|entity| &entity.id).unique().count(), 3);
+    }
+
+    #[test]
+    fn ids_are_stable() {
+        let id = |alerts: Vec<(Option<String>, Alert)>| identify_alerts(vec![("passenger", alerts)]).0[0].id.clone();
+        assert_eq!(id(vec![(None, alert("Diversion", "S1", 100))]), id(vec![(None, alert("Diversion", "S1", 100))]));
+        assert_eq!(id(vec![(None, alert("Diversion", "S1", 100))]).len(), 16);
+    }
+}
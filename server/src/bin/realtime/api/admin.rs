@@ -0,0 +1,113 @@
+use std::sync::Arc;
+
+use axum::extract::{Path, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::Json;
+use axum::response::{ErrorResponse, IntoResponse};
+use chrono::Utc;
+
+use BusBoardsServer::GTFSResponder::NOTICES;
+
+use crate::api::util::{JsonError, ServiceError, INTERNAL_ERROR};
+use crate::GTFSState;
+use crate::notices::{Notice, StoredNotice};
+
+const UNAUTHORISED: (StatusCode, &str) = (StatusCode::UNAUTHORIZED, "Unauthorised");
+const NOT_FOUND: (StatusCode, &str) = (StatusCode::NOT_FOUND, "Notice not found");
+
+/// Every operator notice, including expired ones
+pub async fn list_notices(headers: HeaderMap, State(state): State<Arc<GTFSState>>) -> Result<Json<Vec<StoredNotice>>, ErrorResponse> {
+    authorise(&headers)?;
+    Ok(Json(state.notices.list().or_error(INTERNAL_ERROR)?))
+}
+
+/// Post a new operator notice
+pub async fn create_notice(headers: HeaderMap, State(state): State<Arc<GTFSState>>, Json(notice): Json<Notice>) -> Result<Json<StoredNotice>, ErrorResponse> {
+    authorise(&headers)?;
+    notice.validate().map_err(bad_request)?;
+    let stored = state.notices.create(notice).or_error(INTERNAL_ERROR)?;
+    publish_notices(&state);
+    Ok(Json(stored))
+}
+
+/// Replace an operator notice
+pub async fn update_notice(headers: HeaderMap, Path(id): Path<String>, State(state): State<Arc<GTFSState>>, Json(notice): Json<Notice>) -> Result<Json<StoredNotice>, ErrorResponse> {
+    authorise(&headers)?;
+    notice.validate().map_err(bad_request)?;
+    let stored = state.notices.update(id.as_str(), notice).or_error(INTERNAL_ERROR)?.or_error(NOT_FOUND)?;
+    publish_notices(&state);
+    Ok(Json(stored))
+}
+
+/// End an operator notice now
+pub async fn expire_notice(headers: HeaderMap, Path(id): Path<String>, State(state): State<Arc<GTFSState>>) -> Result<Json<StoredNotice>, ErrorResponse> {
+    authorise(&headers)?;
+    let existing = state.notices.get(id.as_str()).or_error(INTERNAL_ERROR)?.or_error(NOT_FOUND)?;
+    let stored = state.notices.update(id.as_str(), existing.notice.expire(Utc::now())).or_error(INTERNAL_ERROR)?.or_error(NOT_FOUND)?;
+    publish_notices(&state);
+    Ok(Json(stored))
+}
+
+fn bad_request(msg: &str) -> ErrorResponse {
+    (StatusCode::BAD_REQUEST, Json(JsonError::from(msg))).into_response().into()
+}
+
+/// Show changes straight away rather than waiting for the notices listener
+fn publish_notices(state: &Arc<GTFSState>) {
+    let (alerts, sources) = state.notices.current_alerts();
+    state.alerts.pin().insert(NOTICES, alerts);
+    state.alert_sources.pin().insert(NOTICES, sources);
+    // cached service data of every source includes the alerts shown with it
+    state.realtime_cache.pin().clear();
+}
+
+/// Check the request's bearer token against the BUSES_ADMIN_KEY environment variable.
+/// The admin API is disabled if the key is not set
+fn authorise(headers: &HeaderMap) -> Result<(), ErrorResponse> {
+    check_key(headers, std::env::var("BUSES_ADMIN_KEY").ok())
+}
+
+fn check_key(headers: &HeaderMap, key: Option<String>) -> Result<(), ErrorResponse> {
+    let key = key.filter(|key| !key.is_empty()).or_error(UNAUTHORISED)?;
+    let token = headers.get(header::AUTHORIZATION).and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer ")).or_error(UNAUTHORISED)?;
+    // Compare every byte so the time taken does not reveal how much of the key matched
+    let matches = token.len() == key.len() && token.bytes().zip(key.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0;
+    Some(()).filter(|_| matches).or_error(UNAUTHORISED)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    fn bearer(token: &str) -> HeaderMap {
+        HeaderMap::from_iter([(header::AUTHORIZATION, HeaderValue::from_str(format!("Bearer {token}").as_str()).unwrap())])
+    }
+
+    fn is_unauthorised(result: Result<(), ErrorResponse>) -> bool {
+        result.is_err_and(|err| err.into_response().status() == StatusCode::UNAUTHORIZED)
+    }
+
+    #[test]
+    fn matching_token_is_authorised() {
+        assert!(check_key(&bearer("secret"), Some("secret".to_string())).is_ok());
+    }
+
+    #[test]
+    fn admin_api_is_disabled_without_a_key() {
+        assert!(is_unauthorised(check_key(&bearer("secret"), None)));
+        assert!(is_unauthorised(check_key(&bearer(""), Some(String::new()))));
+    }
+
+    #[test]
+    fn wrong_tokens_are_unauthorised() {
+        let key = Some("secret".to_string());
+        assert!(is_unauthorised(check_key(&HeaderMap::new(), key.clone())));
+        assert!(is_unauthorised(check_key(&bearer("secreT"), key.clone())));
+        assert!(is_unauthorised(check_key(&bearer("secret2"), key.clone())));
+        assert!(is_unauthorised(check_key(&bearer("secre"), key.clone())));
+        assert!(is_unauthorised(check_key(&HeaderMap::from_iter([(header::AUTHORIZATION, HeaderValue::from_static("secret"))]), key)));
+    }
+}
@@ -0,0 +1,75 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use axum::extract::{Query, State};
+use axum::http::StatusCode;
+use axum::Json;
+use axum::response::ErrorResponse;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use itertools::Itertools;
+
+use crate::alerts::is_active;
+use crate::api::service::find_best_match;
+use crate::api::util::ServiceError;
+use crate::GTFSState;
+use crate::transit_realtime::EntitySelector;
+use crate::util::get_bst_offset;
+
+/// Alerts in effect at a time (now by default), optionally only those for a stop, route, agency or source
+pub async fn get_alert_list(Query(params): Query<HashMap<String, String>>, State(state): State<Arc<GTFSState>>) -> Result<Json<Vec<AlertResponse>>, ErrorResponse> {
+    let time = match params.get("time") {
+        None => Utc::now(),
+        Some(time_str) => NaiveDateTime::parse_from_str(time_str, "%Y-%m-%dT%H:%M").map(|t| t.and_utc() - get_bst_offset())
+            .or_error((StatusCode::BAD_REQUEST, "Invalid time"))?,
+    };
+    let matches = |value: &Option<String>, param: &str| params.get(param).map_or(true, |wanted| value.as_ref() == Some(wanted));
+    let selects = |entities: &[EntitySelector]| entities.iter().any(|entity|
+        matches(&entity.stop_id, "stop") && matches(&entity.route_id, "route") && matches(&entity.agency_id, "agency"));
+
+    let alert_sources = state.alert_sources.pin();
+    let alerts = state.alerts.pin().iter().flat_map(|(responder, entities)| {
+        let responder_sources = alert_sources.get(responder);
+        entities.iter()
+            .filter_map(|entity| {
+                let sources = responder_sources.and_then(|sources| sources.get(&entity.id)).cloned().unwrap_or_default();
+                if params.get("source").is_some_and(|source| !sources.contains(source)) {
+                    return None
+                }
+                let alert = entity.alert.as_ref().filter(|alert| is_active(alert, &time) && selects(&alert.informed_entity))?;
+                Some(AlertResponse {
+                    id: entity.id.clone(),
+                    sources,
+                    header: find_best_match(&alert.header_text),
+                    description: find_best_match(&alert.description_text),
+                    url: find_best_match(&alert.url),
+                    active_period: alert.active_period.iter().map(|period| AlertPeriod {
+                        start: period.start.and_then(|start| DateTime::from_timestamp(start as i64, 0)),
+                        end: period.end.and_then(|end| DateTime::from_timestamp(end as i64, 0))
+                    }).collect(),
+                    stops: alert.informed_entity.iter().filter_map(|entity| entity.stop_id.clone()).unique().collect(),
+                    routes: alert.informed_entity.iter().filter_map(|entity| entity.route_id.clone()).unique().collect(),
+                    agencies: alert.informed_entity.iter().filter_map(|entity| entity.agency_id.clone()).unique().collect()
+                })
+            }).collect_vec()
+    }).sorted_by(|a, b| a.id.cmp(&b.id)).collect();
+    Ok(Json(alerts))
+}
+
+#[derive(Serialize)]
+pub struct AlertResponse {
+    id: String,
+    sources: Vec<String>,
+    header: Option<String>,
+    description: Option<String>,
+    url: Option<String>,
+    active_period: Vec<AlertPeriod>,
+    stops: Vec<String>,
+    routes: Vec<String>,
+    agencies: Vec<String>
+}
+
+#[derive(Serialize)]
+pub struct AlertPeriod {
+    start: Option<DateTime<Utc>>,
+    end: Option<DateTime<Utc>>
+}
@@ -0,0 +1,14 @@
+use std::sync::Arc;
+
+use axum::extract::State;
+use axum::Json;
+use axum::response::ErrorResponse;
+
+use crate::api::util::{ServiceError, INTERNAL_ERROR};
+use crate::db::{get_metadata, DatasetMetadata};
+use crate::GTFSState;
+
+/// When the timetable data was built, and the version of each source it contains
+pub async fn get_meta(State(state): State<Arc<GTFSState>>) -> Result<Json<DatasetMetadata>, ErrorResponse> {
+    Ok(Json(get_metadata(&state.db).or_error(INTERNAL_ERROR)?))
+}
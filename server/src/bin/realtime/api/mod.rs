@@ -3,4 +3,8 @@ pub mod util;
 pub mod stop;
 pub mod train;
 pub mod search;
-pub mod darwin;
\ No newline at end of file
+pub mod darwin;
+pub mod meta;
+pub mod vehicle;
+pub mod alerts;
+pub mod admin;
\ No newline at end of file
@@ -5,14 +5,19 @@ use std::ops::Add;
 use std::str;
 use std::sync::{Arc, RwLock};
 
+use crate::alerts::{get_trip_effect, is_active};
 use crate::api::util;
 use crate::api::util::{cache_service_data, get_or_cache_all_service_data, ServiceError, INTERNAL_ERROR};
-use crate::db::{find_links, get_service_shape, get_stop_positions, query_service, query_service_operator, query_stops, Connections, OperatorsQuery, StopsQuery};
+use crate::coverage::Tracking;
+use crate::db::{find_links, get_learned_times, get_service_shape, get_stop_positions, query_service, query_service_operator, query_stops, Connections, LearnedTime, OperatorsQuery, StopsQuery};
 use crate::transit_realtime::trip_descriptor::ScheduleRelationship::Canceled;
 use crate::transit_realtime::trip_update::stop_time_update::ScheduleRelationship::Skipped;
-use crate::transit_realtime::{FeedEntity, Position, TranslatedString, TripUpdate};
-use crate::util::{adjust_timestamp, haversine_closest_point};
+use crate::transit_realtime::alert::Effect;
+use crate::transit_realtime::vehicle_position::OccupancyStatus;
+use crate::transit_realtime::{Alert, FeedEntity, Position, TranslatedString, TripUpdate, VehiclePosition};
+use crate::util::{adjust_timestamp, gtfs_date, haversine_closest_point, occupancy_status};
 use crate::{uw, GTFSAlerts, GTFSState};
+use BusBoardsServer::segments::day_type;
 use axum::extract::{Query, State};
 use axum::http::StatusCode;
 use axum::response::ErrorResponse;
@@ -26,6 +31,9 @@ use regex::Regex;
 use serde_nested_with::serde_nested;
 use util::find_realtime_trip_with_gtfs;
 
+/// Only carry delays over from the previous trip when it is this likely to be the same vehicle
+const MIN_REALTIME_LINK_CONFIDENCE: f64 = 0.75;
+
 pub async fn get_service(Query(params): Query<HashMap<String, String>>, State(state): State<Arc<GTFSState>>) -> Result<Json<ServiceData>, ErrorResponse> {
     let id = params.get("id").or_error((StatusCode::BAD_REQUEST, "ID not provided"))?;
     let data = get_service_data(&state, id)?;
@@ -51,10 +59,17 @@ pub fn get_service_data(state: &Arc<GTFSState>, id: &String) -> Result<ServiceDa
     let route = shape.unwrap_or_else(|_| polyline::encode_coordinates(
         stops.iter().filter_map(|s| Some(coord! {x: s.long?, y: s.lat?})), 5).unwrap());
 
-    let mut cancelled = false;
+    // Journeys cancelled or diverted by disruptions
+    let now = Utc::now();
+    let effect = get_trip_effect(&state.alerts, id, gtfs_date(&adjust_timestamp(&now)).as_str(), &now);
+    let mut cancelled = effect == Some(Effect::NoService);
+    if cancelled {
+        stops.iter_mut().for_each(|stop| stop.status = Some("Cancelled".to_string()))
+    }
+
     let find_realtime_trip = find_realtime_trip_with_gtfs(id, &state.vehicles);
     let realtime = if let Some((_, ref trip)) = find_realtime_trip {
-        cancelled = uw! {trip.vehicle.as_ref()?.trip.as_ref()?.schedule_relationship} == Some(Canceled.into())
+        cancelled = cancelled || uw! {trip.vehicle.as_ref()?.trip.as_ref()?.schedule_relationship} == Some(Canceled.into())
             || uw! {trip.trip_update.as_ref()?.trip.schedule_relationship} == Some(Canceled.into());
         if cancelled {
             stops.iter_mut().for_each(|stop| stop.status = Some("Cancelled".to_string()))
@@ -75,13 +90,26 @@ pub fn get_service_data(state: &Arc<GTFSState>, id: &String) -> Result<ServiceDa
         None
     }.or_else(|| realtime_from_links(state, &mut stops, &links));
 
-    let alerts = get_alerts(&state.alerts, Some(id), Some(&service.route_id), Some(&operator.id));
+    let diverted = !cancelled && effect == Some(Effect::Detour);
+    if diverted {
+        stops.iter_mut().filter(|stop| stop.status.is_none()).for_each(|stop| stop.status = Some("Diverted".to_string()))
+    }
+
+    // Trips of fully tracked operators which should have a vehicle by now - a diverted trip is already explained
+    let tracking = if find_realtime_trip.is_none() && realtime.is_none() && !cancelled && !diverted { state.coverage.tracking(id) } else { None };
+    if let Some(tracking) = tracking {
+        stops.iter_mut().filter(|stop| stop.status.is_none()).for_each(|stop| stop.status = Some(tracking.status().to_string()))
+    }
+
+    let alerts = get_alerts(&state.alerts, Some(id), Some(&service.route_id), Some(&operator.id), &Utc::now());
 
     let data = ServiceData {
         service: ServiceInfo {
             code: service.code,
             dest: service.dest.to_string(),
-            cancelled
+            cancelled,
+            diverted,
+            tracking
         },
         operator,
         branches: vec![
@@ -108,7 +136,8 @@ pub fn get_service_data(state: &Arc<GTFSState>, id: &String) -> Result<ServiceDa
 }
 
 fn realtime_from_links(state: &Arc<GTFSState>, mut stops: &mut Vec<StopsQuery>, links: &Result<Connections, Box<dyn Error>>) -> Option<RealtimeInfo> {
-    if let Some(previous_service) = uw!(links.as_ref().ok()?.from.as_ref()) {
+    if let Some(previous_service) = uw!(links.as_ref().ok()?.from.as_ref())
+        && (previous_service.observed || previous_service.confidence >= MIN_REALTIME_LINK_CONFIDENCE) {
         if let Some(previous_service_data) = get_or_cache_all_service_data(state, previous_service.trip_id.as_str()) {
             let branch = previous_service_data.branches.first()?;
             if let Some(realtime) = branch.realtime.as_ref() {
@@ -171,6 +200,39 @@ fn realtime_from_links(state: &Arc<GTFSState>, mut stops: &mut Vec<StopsQuery>,
     None
 }
 
+/// Predict the departure from each stop from the current stop onwards, starting from the predicted arrival at the current stop.
+/// Learned running and dwell times are used where known; buses wait for their scheduled departure at timing points,
+/// and before any segment without a learned time, where the schedule is all there is to go on.
+fn predict_departures(scheduled_times: &[ScheduledTime], timepoints: &[bool], learned: &[LearnedTime], current: usize, arrival: DateTime<Utc>) -> Vec<DateTime<Utc>> {
+    let mut departures: Vec<DateTime<Utc>> = Vec::with_capacity(scheduled_times.len() - current);
+    let mut arrival = arrival;
+    for i in current..scheduled_times.len() {
+        if let Some(prev_departure) = departures.last() {
+            arrival = *prev_departure + match learned[i].run_time {
+                Some(run_time) => TimeDelta::seconds((run_time - learned[i].dwell.unwrap_or(0)).max(0)),
+                None => scheduled_times[i].arr - scheduled_times[i - 1].dep
+            };
+        }
+        let mut departure = arrival + TimeDelta::seconds(learned[i].dwell.unwrap_or(0));
+        let next_learned = learned.get(i + 1).is_some_and(|next| next.run_time.is_some());
+        if timepoints[i] || !next_learned {
+            departure = max(departure, scheduled_times[i].dep);
+        }
+        departures.push(departure);
+    }
+    departures
+}
+
+/// Status for a stop from its predicted departure - only shown as expected if it changes the displayed time
+fn predicted_status(scheduled_time: &ScheduledTime, predicted: DateTime<Utc>) -> String {
+    let delay = predicted - scheduled_time.dep;
+    if (delay >= TimeDelta::milliseconds(1000 * 120) || delay <= TimeDelta::milliseconds(-1000 * 60)) && scheduled_time.dep.minute() != predicted.minute() {
+        format!("Exp. {}", predicted.format("%H:%M"))
+    } else {
+        "On time".to_string()
+    }
+}
+
 fn calculate_delay_status(delay: &mut TimeDelta, scheduled_time: &ScheduledTime, delayed_time: DateTime<Utc>) -> String {
     let mut status = "".to_string();
     if *delay >= TimeDelta::milliseconds(1000 * 120) || *delay <= TimeDelta::milliseconds(-1000 * 60) {
@@ -196,21 +258,15 @@ fn calculate_delay_status(delay: &mut TimeDelta, scheduled_time: &ScheduledTime,
     status
 }
 
-pub fn get_alerts(alerts: &GTFSAlerts, trip_id: Option<&String>, route_id: Option<&String>, agency_id: Option<&String>) -> Vec<StopAlert> {
+pub fn get_alerts(alerts: &GTFSAlerts, trip_id: Option<&String>, route_id: Option<&String>, agency_id: Option<&String>, time: &DateTime<Utc>) -> Vec<StopAlert> {
     alerts.pin().iter().flat_map(|(_, a)| {
-        a.iter().filter(|&alert| {
-            alert.informed_entity.iter().any(|entity|
+        a.iter().filter_map(|entity| Some((entity, entity.alert.as_ref()?))).filter(|(_, alert)| {
+            is_active(alert, time) && alert.informed_entity.iter().any(|entity|
                 (entity.agency_id.as_ref() == agency_id && agency_id.is_some())
                     || (entity.route_id.as_ref() == route_id && route_id.is_some())
                     || (uw!(entity.trip.as_ref()?.trip_id.as_ref()) == trip_id && trip_id.is_some())
             )
-        }).map(|alert| {
-            StopAlert {
-                header: find_best_match(&alert.header_text),
-                description: find_best_match(&alert.description_text),
-                url: find_best_match(&alert.url)
-            }
-        }).collect_vec()
+        }).map(|(entity, alert)| StopAlert::from_alert(entity.id.as_str(), alert)).collect_vec()
     }).collect_vec()
 }
 
@@ -244,37 +300,49 @@ fn realtime_from_position(state: &Arc<GTFSState>, id: &String, stops: &mut Vec<S
                 }
             }).collect_vec();
 
-            let prev_stop = &scheduled_times[(current_stop_index - 1).max(0)];
+            let timepoints = stops.iter().map(|stop| stop.major).collect_vec();
+            let learned = get_learned_times(&state.db, id, day_type(date.date_naive()));
+            let learned = stops.iter().map(|stop| learned.get(&stop.seq).copied().unwrap_or_default()).collect_vec();
+
+            let prev_stop = &scheduled_times[current_stop_index.saturating_sub(1)];
             let curr_stop = &scheduled_times[current_stop_index];
 
             // Get the time that the bus should have been at this position at
             let expected_time = prev_stop.dep + TimeDelta::milliseconds(((curr_stop.arr - prev_stop.dep).num_milliseconds() as f64 * pct) as i64);
             let vehicle_time = adjust_timestamp(&uw!(trip.vehicle.as_ref()?.timestamp).and_then(|t| DateTime::from_timestamp(t as i64, 0)).unwrap_or(*time_now));
-            let mut delay = vehicle_time - expected_time;
 
-            // Apply delay to all stops past the current stop
+            // Time left to reach the current stop, from its learned running time if there is one
+            let arrival = match learned[current_stop_index] {
+                LearnedTime { run_time: Some(run_time), dwell } if current_stop_index > 0 =>
+                    vehicle_time + TimeDelta::milliseconds(((run_time - dwell.unwrap_or(0)).max(0) as f64 * 1000.0 * (1.0 - pct)) as i64),
+                _ => curr_stop.arr + (vehicle_time - expected_time)
+            };
+            let predicted = predict_departures(&scheduled_times, &timepoints, &learned, current_stop_index, arrival);
+
+            // Predict departures from the current stop onwards
             // (don't show 'Departed' if too close to the last stop - may be a GPS error)
-            let evaluate_index = max(current_stop_index - 1, 0);
+            let evaluate_index = current_stop_index.saturating_sub(1);
             let evaluate_pos = stops[evaluate_index].position();
             let include_last_stop = if evaluate_pos.is_some() && Point::from(evaluate_pos.unwrap()).geodesic_distance(&current_pos_point) <= 50.0 { 1 } else { 0 };
 
             // Only show Departed 5 mins before departure time
             for i in 0..stops.len() {
                 let scheduled_time = &scheduled_times[i];
-                let delayed_time = scheduled_time.arr + delay;
-                
-                if i < current_stop_index - include_last_stop && (scheduled_time.dep - adjust_timestamp(time_now)).num_seconds() < 120 {
+
+                if i < current_stop_index.saturating_sub(include_last_stop) && (scheduled_time.dep - adjust_timestamp(time_now)).num_seconds() < 120 {
                     stops[i].status = Some("Departed".to_string());
                     continue;
                 }
 
-                stops[i].status = Some(calculate_delay_status(&mut delay, scheduled_time, delayed_time));
+                let predicted_time = if i >= current_stop_index { predicted[i - current_stop_index] } else { max(vehicle_time, scheduled_time.dep) };
+                stops[i].status = Some(predicted_status(scheduled_time, predicted_time));
 
                 // Show current delayed stop in major stops list for context (since previous stops don't show delay, can look on time when delayed)
                 if stops[current_stop_index].status != Some("On time".to_string()) {
                     stops[current_stop_index].major = true
                 }
             };
+            let delay = *predicted.last().unwrap() - scheduled_times.last().unwrap().dep;
 
             Some(RealtimeInfo {
                 stop: current_stop_index as i64,
@@ -358,12 +426,29 @@ fn realtime_from_trip_update(stops: &mut Vec<StopsQuery>, trip: &FeedEntity, cur
 
 fn get_vehicle_info(trip: &FeedEntity) -> VehicleInfo {
     VehicleInfo {
+        id: uw!(trip.vehicle.as_ref()?.vehicle.as_ref()).and_then(|vd| vd.id.clone()),
         name: uw!(trip.vehicle.as_ref()?.vehicle.as_ref()).and_then(|vd| vd.label.clone()),
         license: uw!(trip.vehicle.as_ref()?.vehicle.as_ref()).and_then(|vd| vd.license_plate.clone()),
-        occupancy_pct: uw!(trip.vehicle.as_ref()?.occupancy_percentage).clone()
+        occupancy_pct: uw!(trip.vehicle.as_ref()?.occupancy_percentage).clone(),
+        occupancy: trip.vehicle.as_ref().and_then(describe_occupancy)
     }
 }
 
+/// How full the vehicle is, for passengers - from its occupancy status, or its occupancy percentage if there is no status
+fn describe_occupancy(vehicle: &VehiclePosition) -> Option<String> {
+    let status = vehicle.occupancy_status.and_then(|status| OccupancyStatus::try_from(status).ok())
+        .or(vehicle.occupancy_percentage.map(occupancy_status))?;
+    match status {
+        OccupancyStatus::Empty | OccupancyStatus::ManySeatsAvailable => Some("Seats available"),
+        OccupancyStatus::FewSeatsAvailable => Some("Few seats available"),
+        OccupancyStatus::StandingRoomOnly => Some("Standing room only"),
+        OccupancyStatus::CrushedStandingRoomOnly => Some("Very busy"),
+        OccupancyStatus::Full => Some("Full"),
+        OccupancyStatus::NotAcceptingPassengers => Some("Not accepting passengers"),
+        OccupancyStatus::NoDataAvailable | OccupancyStatus::NotBoardable => None
+    }.map(str::to_string)
+}
+
 fn get_start_date(trip: &FeedEntity, time_now: &DateTime<Utc>) -> DateTime<Utc> {
     uw!(trip.vehicle.as_ref()?.trip.as_ref()?.start_date.as_ref()).map(|d| NaiveDate::parse_from_str(d.as_str(), "%Y%m%d").map(|dt| dt.and_time(NaiveTime::default()).and_utc()).ok())
         .flatten().unwrap_or(*time_now)
@@ -423,15 +508,17 @@ pub struct RealtimeInfo {
     pos: Option<Position>,
     delay: Option<i64>,
     date: NaiveDate,
-    on_previous: bool,
-    vehicle: VehicleInfo
+    pub(crate) on_previous: bool,
+    pub(crate) vehicle: VehicleInfo
 }
 
 #[derive(Serialize, Clone)]
 pub struct VehicleInfo {
+    id: Option<String>,
     license: Option<String>,
     name: Option<String>,
-    occupancy_pct: Option<u32>
+    occupancy_pct: Option<u32>,
+    pub(crate) occupancy: Option<String>
 }
 
 pub struct ScheduledTime {
@@ -441,16 +528,31 @@ pub struct ScheduledTime {
 
 #[derive(Serialize, Clone, Eq, PartialEq, Hash)]
 pub struct StopAlert {
+    pub(crate) id: String,
     pub(crate) header: Option<String>,
     pub(crate) description: Option<String>,
     pub(crate) url: Option<String>
 }
 
+impl StopAlert {
+    pub fn from_alert(id: &str, alert: &Alert) -> StopAlert {
+        StopAlert {
+            id: id.to_string(),
+            header: find_best_match(&alert.header_text),
+            description: find_best_match(&alert.description_text),
+            url: find_best_match(&alert.url)
+        }
+    }
+}
+
 #[derive(Serialize, Clone)]
 pub struct ServiceInfo {
     pub code: String,
     pub dest: String,
-    pub cancelled: bool
+    pub cancelled: bool,
+    pub diverted: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tracking: Option<Tracking>
 }
 
 #[derive(Serialize, Clone)]
@@ -468,4 +570,41 @@ pub struct ServiceData {
     pub operator: OperatorsQuery,
     pub branches: Vec<ServiceBranch>,
     pub alerts: Vec<StopAlert>
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Departure at the given minutes after 10:00
+    fn at(minutes: i64) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339("2024-01-01T10:00:00Z").unwrap().to_utc() + TimeDelta::minutes(minutes)
+    }
+
+    /// Stops departing at the given minutes after 10:00, with no scheduled dwell
+    fn scheduled(minutes: &[i64]) -> Vec<ScheduledTime> {
+        minutes.iter().map(|minutes| ScheduledTime { arr: at(*minutes), dep: at(*minutes) }).collect()
+    }
+
+    fn learned(run_time: Option<i64>, dwell: Option<i64>, stops: usize) -> Vec<LearnedTime> {
+        vec![LearnedTime { run_time, dwell }; stops]
+    }
+
+    #[test]
+    fn buses_hold_at_timepoints() {
+        let departures = predict_departures(&scheduled(&[0, 5, 10, 15]), &[false, true, false, false], &learned(Some(120), None, 4), 0, at(0));
+        // early at the timepoint, then running on learned times until the last stop, which has no learned segment after it
+        assert_eq!(departures, vec![at(0), at(5), at(7), at(15)]);
+    }
+
+    #[test]
+    fn learned_dwells_are_added() {
+        let departures = predict_departures(&scheduled(&[0, 1, 2]), &[false; 3], &learned(Some(180), Some(60), 3), 1, at(10));
+        assert_eq!(departures, vec![at(11), at(14)]);
+    }
+
+    #[test]
+    fn scheduled_run_times_are_used_without_learned_times() {
+        let departures = predict_departures(&scheduled(&[0, 5, 10]), &[false; 3], &learned(None, None, 3), 0, at(3));
+        assert_eq!(departures, vec![at(3), at(8), at(13)]);
+    }
+}
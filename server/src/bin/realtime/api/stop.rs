@@ -1,5 +1,4 @@
 use std::collections::{HashMap, HashSet};
-use std::hash::{DefaultHasher, Hash};
 use std::sync::{Arc, RwLock};
 
 use axum::extract::{Query, State};
@@ -20,10 +19,11 @@ use BusBoardsServer::GTFSResponder;
 
 use crate::{GTFSAlerts, GTFSState, uw};
 use crate::api::darwin::{GetDepartureBoardRequest, GetDepartureBoardResponse, LDBService, SoapFault, StationBoard};
-use crate::api::service::{find_best_match, StopAlert};
+use crate::api::service::StopAlert;
 use crate::api::util::{find_realtime_trip_with_gtfs, get_or_cache_service_data, INTERNAL_ERROR, ServiceError, get_or_cache_all_service_data};
-use crate::db::{get_services_between, get_stance_info, get_stop_info, StanceInfo, StopInfoQuery, StopService};
-use crate::util::adjust_timestamp;
+use crate::db::{get_nearby_stops, get_services_between, get_stance_info, get_stop_info, NearbyStop, StanceInfo, StopInfoQuery, StopService};
+use crate::alerts::is_active;
+use crate::util::{adjust_timestamp, get_bst_offset};
 
 pub async fn get_stop(Query(params): Query<HashMap<String, String>>, State(state): State<Arc<GTFSState>>) -> Result<Json<StopResponse>, ErrorResponse> {
     let locality = params.get("locality").or_error(INVALID_QUERY)?;
@@ -63,6 +63,21 @@ pub async fn get_basic_stop_info(Query(params): Query<HashMap<String, String>>,
     }))
 }
 
+const DEFAULT_NEARBY_RADIUS: f64 = 500.0;
+const MAX_NEARBY_RADIUS: f64 = 5000.0;
+const MAX_NEARBY_STOPS: usize = 20;
+
+/// Stops near a location, nearest first
+pub async fn get_nearby(Query(params): Query<HashMap<String, String>>, State(state): State<Arc<GTFSState>>) -> Result<Json<Vec<NearbyStop>>, ErrorResponse> {
+    let lat: f64 = params.get("lat").and_then(|lat| lat.parse().ok()).or_error(INVALID_QUERY)?;
+    let lon: f64 = params.get("lon").and_then(|lon| lon.parse().ok()).or_error(INVALID_QUERY)?;
+    if !(-90.0..=90.0).contains(&lat) || !(-180.0..=180.0).contains(&lon) { return Err(ErrorResponse::from(INVALID_QUERY.into_response())) }
+    let radius = params.get("radius").and_then(|radius| radius.parse().ok()).unwrap_or(DEFAULT_NEARBY_RADIUS).clamp(0.0, MAX_NEARBY_RADIUS);
+    let limit = params.get("limit").and_then(|limit| limit.parse().ok()).unwrap_or(MAX_NEARBY_STOPS).min(MAX_NEARBY_STOPS);
+
+    Ok(Json(get_nearby_stops(&state.db, lat, lon, radius, limit).or_error(INTERNAL_ERROR)?))
+}
+
 pub async fn get_stop_data(state: &Arc<GTFSState>, locality: &String, name: &String, date: &DateTime<Utc>, filter_loc: Option<&String>, filter_name: Option<&String>) -> Result<StopResponse, ErrorResponse> {
     let filter = filter_loc.is_some() && filter_name.is_some();
 
@@ -74,10 +89,10 @@ pub async fn get_stop_data(state: &Arc<GTFSState>, locality: &String, name: &Str
     let mut stance_info = get_stance_info(&state.db, stop_info.id).or_error(INTERNAL_ERROR)?;
 
     // Get stop alerts
-    let mut hasher = DefaultHasher::new();
+    let alerts_time = *date - get_bst_offset();
     let alerts = stance_info.iter()
-        .flat_map(|stance| get_stop_alerts(&state.alerts, stance.code.as_str()))
-        .unique_by(|alert| alert.hash(&mut hasher))
+        .flat_map(|stance| get_stop_alerts(&state.alerts, stance.code.as_str(), &alerts_time))
+        .unique_by(|alert| alert.id.clone())
         .collect_vec();
     
     // Sort by indicator
@@ -157,6 +172,11 @@ pub async fn get_stop_data(state: &Arc<GTFSState>, locality: &String, name: &Str
                     .take_if(|etd| etd.chars().next().unwrap().is_numeric())
                     .map(|etd| format!("Exp. {etd}")).or(service.etd.as_ref().map(|etd| etd.to_string())),
                 then_headsign: None,
+                headway: None,
+                wheelchair: None,
+                parent: None,
+                transfers: vec![],
+                occupancy: None,
             }
         }).collect_vec();
 
@@ -222,7 +242,8 @@ fn set_status_by_realtime(state: &Arc<GTFSState>, (stop, resp): &mut (&mut StopS
         if service.branches.len() != 1 {
             return;
         }
-        stop.status = service.branches[0].stops.iter().find(|ss| ss.seq == stop.stop_sequence).and_then(|s| s.status.clone())
+        stop.status = service.branches[0].stops.iter().find(|ss| ss.seq == stop.stop_sequence).and_then(|s| s.status.clone());
+        stop.occupancy = service.branches[0].realtime.as_ref().filter(|rt| !rt.on_previous).and_then(|rt| rt.vehicle.occupancy.clone())
     }
 }
 
@@ -241,22 +262,19 @@ async fn get_station_departures(offset: f32, crs: Vec<String>) -> Vec<StationBoa
     }
 }
 
-fn get_stop_alerts(cache: &GTFSAlerts, code: &str) -> Vec<StopAlert> {
+/// Alerts for a stance which are in effect at the given time
+fn get_stop_alerts(cache: &GTFSAlerts, code: &str, time: &DateTime<Utc>) -> Vec<StopAlert> {
     cache.pin().iter().flat_map(|(_, c)| {
         c.iter()
-            .filter(|&alert| alert.informed_entity.iter().any(|entity| {
+            .filter_map(|entity| Some((entity, entity.alert.as_ref()?)))
+            .filter(|(_, alert)| is_active(alert, time) && alert.informed_entity.iter().any(|entity| {
                 match entity.stop_id.as_ref() {
                     None => false,
                     Some(stop_id) => stop_id == code
                 }
             }))
-            .map(|alert| {
-                StopAlert {
-                    header: find_best_match(&alert.header_text),
-                    description: find_best_match(&alert.description_text),
-                    url: find_best_match(&alert.url)
-                }
-            }).collect_vec()
+            .map(|(entity, alert)| StopAlert::from_alert(entity.id.as_str(), alert))
+            .collect_vec()
     }).collect_vec()
 }
 
@@ -0,0 +1,112 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use axum::extract::{Query, State};
+use axum::http::StatusCode;
+use axum::Json;
+use axum::response::ErrorResponse;
+use chrono::{DateTime, TimeDelta, Utc};
+
+use BusBoardsServer::GTFSResponder;
+use BusBoardsServer::GTFSResponder::PASSENGER;
+
+use crate::api::util::{ServiceError, INTERNAL_ERROR};
+use crate::db::query_service;
+use crate::GTFSState;
+use crate::transit_realtime::{FeedEntity, Position};
+
+/// Trips seen since this many days ago are included in a vehicle's history
+const HISTORY_DAYS: i64 = 1;
+
+/// Find vehicles by fleet number, name or registration, with their current trip, position and recent trips
+pub async fn get_vehicle(Query(params): Query<HashMap<String, String>>, State(state): State<Arc<GTFSState>>) -> Result<Json<Vec<VehicleResponse>>, ErrorResponse> {
+    let id = params.get("id").map(|id| normalise(id)).filter(|id| !id.is_empty())
+        .or_error((StatusCode::BAD_REQUEST, "ID not provided"))?;
+    let since = (Utc::now() - TimeDelta::days(HISTORY_DAYS)).format("%Y%m%d").to_string();
+
+    let mut vehicles = Vec::new();
+    for (source, entity) in find_vehicles(&state, &id) {
+        let Some(vehicle) = entity.vehicle else { continue };
+        let descriptor = vehicle.vehicle.unwrap_or_default();
+        let history = match descriptor.id.as_ref() {
+            Some(vehicle_id) => state.observations.vehicle_trips(source, vehicle_id, since.as_str()).or_error(INTERNAL_ERROR)?
+                .into_iter().map(|trip| {
+                    let service = query_service(&state.db, trip.trip_id.as_str()).ok();
+                    VehicleTrip {
+                        route: service.as_ref().map(|service| service.code.clone()),
+                        dest: service.map(|service| service.dest),
+                        trip_id: trip.trip_id,
+                        date: trip.date,
+                        first_seen: trip.first_seen,
+                        last_seen: trip.last_seen
+                    }
+                }).collect(),
+            None => vec![]
+        };
+        let trip_id = vehicle.trip.and_then(|trip| trip.trip_id);
+        let service = trip_id.as_ref().and_then(|trip_id| query_service(&state.db, trip_id).ok());
+        vehicles.push(VehicleResponse {
+            source,
+            id: descriptor.id,
+            name: descriptor.label,
+            license: descriptor.license_plate,
+            trip_id,
+            route: service.as_ref().map(|service| service.code.clone()),
+            dest: service.map(|service| service.dest),
+            pos: vehicle.position,
+            timestamp: vehicle.timestamp.and_then(|timestamp| DateTime::from_timestamp(timestamp as i64, 0)),
+            history
+        });
+    }
+    Ok(Json(Some(vehicles).filter(|vehicles| !vehicles.is_empty()).or_error((StatusCode::NOT_FOUND, "Vehicle not found"))?))
+}
+
+/// Live vehicles whose id, name or registration matches the (normalised) query
+fn find_vehicles(state: &Arc<GTFSState>, id: &str) -> Vec<(GTFSResponder, FeedEntity)> {
+    state.vehicles.pin().iter().flat_map(|(source, entities)| {
+        entities.values().filter(|entity| {
+            entity.vehicle.as_ref().and_then(|vehicle| vehicle.vehicle.as_ref()).is_some_and(|descriptor| {
+                let fleet_number = descriptor.id.as_deref().map(|id| fleet_number(*source, id));
+                [descriptor.id.as_deref(), fleet_number, descriptor.label.as_deref(), descriptor.license_plate.as_deref()].into_iter().flatten()
+                    .any(|value| normalise(value) == id)
+            })
+        }).map(|entity| (*source, entity.clone())).collect::<Vec<_>>()
+    }).collect()
+}
+
+/// Fleet number of a vehicle id - Passenger ids are prefixed with their operator, as fleet numbers are only unique within an operator
+fn fleet_number(source: GTFSResponder, id: &str) -> &str {
+    match source {
+        PASSENGER => id.split_once('-').map_or(id, |(_, fleet_number)| fleet_number),
+        _ => id
+    }
+}
+
+/// Compare ids and registrations ignoring case and spacing
+fn normalise(id: &str) -> String {
+    id.chars().filter(|c| !c.is_whitespace()).flat_map(char::to_uppercase).collect()
+}
+
+#[derive(Serialize)]
+pub struct VehicleResponse {
+    source: GTFSResponder,
+    id: Option<String>,
+    name: Option<String>,
+    license: Option<String>,
+    trip_id: Option<String>,
+    route: Option<String>,
+    dest: Option<String>,
+    pos: Option<Position>,
+    timestamp: Option<DateTime<Utc>>,
+    history: Vec<VehicleTrip>
+}
+
+#[derive(Serialize)]
+pub struct VehicleTrip {
+    trip_id: String,
+    date: String,
+    route: Option<String>,
+    dest: Option<String>,
+    first_seen: DateTime<Utc>,
+    last_seen: DateTime<Utc>
+}
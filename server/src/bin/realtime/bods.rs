@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use std::io::Read;
 use std::sync::Arc;
 use geo_types::Point;
@@ -5,17 +6,27 @@ use itertools::Itertools;
 use log::error;
 use prost::Message;
 use tokio::sync::mpsc::Sender;
+use tokio::sync::watch;
 use tokio::time;
 use zip::ZipArchive;
 use BusBoardsServer::config::BBConfig;
-use crate::db::{get_bods_trip, get_line_segments, DBPool};
+use crate::bus_prediction::PositionHistory;
+use crate::db::{get_bods_trip, DBPool};
 use crate::GTFSResponder::BODS;
 use crate::{uw, GTFSResponse};
 use crate::api::util::map_feed_entities;
+use crate::siri::{download_siri_vm, SiriVm};
 use crate::transit_realtime::{FeedEntity, FeedMessage, VehiclePosition};
-use crate::util::{f64_cmp, get_geo_linepoint_distance};
+use crate::transit_realtime::vehicle_position::OccupancyStatus;
 
-pub async fn bods_listener(tx: Sender<GTFSResponse>, _: Arc<BBConfig>, db: Arc<DBPool>) {
+pub async fn bods_listener(tx: Sender<GTFSResponse>, config: Arc<BBConfig>, db: Arc<DBPool>) {
+    let Some(prefix) = config.realtime_prefix(BODS).map(str::to_string) else {
+        error!("No enabled GTFS source is configured with BODS realtime trips");
+        return
+    };
+    let (occupancy_tx, occupancy) = watch::channel(HashMap::new());
+    tokio::spawn(occupancy_listener(occupancy_tx));
+    let positions = PositionHistory::default();
     loop {
         let mut bods: FeedMessage = FeedMessage::default();
         // Download + decode BODS data
@@ -30,9 +41,14 @@ pub async fn bods_listener(tx: Sender<GTFSResponse>, _: Arc<BBConfig>, db: Arc<D
                 }
             }
         }
+        // Map BODS GTFS -> local by adding the prefix of the source with BODS trips
+        bods.entity.iter_mut()
+            .filter_map(|e| e.vehicle.as_mut()?.trip.as_mut()?.trip_id.as_mut())
+            .filter(|tid| !tid.is_empty())
+            .for_each(|tid| tid.insert_str(0, prefix.as_str()));
         // Include vehicles with an active journey
         if bods.header.timestamp.is_some() {
-            let filtered_entities: Vec<FeedEntity> = bods.entity.iter()
+            let mut filtered_entities: Vec<FeedEntity> = bods.entity.iter()
                 .filter(|e| uw!(e.vehicle.as_ref()?.trip.as_ref()?.trip_id.as_ref()).map_or(false, |tid| !tid.is_empty()))
                 .map(|e| {
                     if let Some(pos) = &e.vehicle.as_ref().unwrap().position {
@@ -41,14 +57,11 @@ pub async fn bods_listener(tx: Sender<GTFSResponse>, _: Arc<BBConfig>, db: Arc<D
                             let loc = Point::new(pos.longitude as f64, pos.latitude as f64);
                             let info = get_bods_trip(&db, trip.trip_id());
                             if let Some(info) = info {
-                                let points = get_line_segments(&db, info.route_id);
                                 let route = &info.trip_route;
                                 let seqs = &info.trip_seqs;
-                                let segments: Vec<geo_types::Line<f64>> = (0..route.len()-1).map(|i| {
-                                    geo_types::Line::new(points.get(&route[i]).copied().unwrap_or_default(), points.get(&route[i+1]).copied().unwrap_or_default())
-                                }).collect();
-                                let closest_segment = segments.iter().map(|s| get_geo_linepoint_distance(s, &loc))
-                                    .position_min_by(f64_cmp).unwrap_or(0);
+                                let vehicle_id = uw!(e.vehicle.as_ref()?.vehicle.as_ref()?.id.as_ref()).unwrap_or(&e.id);
+                                let vehicle = positions.observe(vehicle_id, loc, pos.bearing.map(|bearing| bearing as f64));
+                                let closest_segment = info.line.locate(&loc, vehicle.heading, vehicle.earlier.as_ref()).segment;
                                 return FeedEntity {
                                     vehicle: Some(VehiclePosition {
                                         current_stop_sequence: Some((seqs[closest_segment] + 1).min(*seqs.last().unwrap())),
@@ -64,10 +77,56 @@ pub async fn bods_listener(tx: Sender<GTFSResponse>, _: Arc<BBConfig>, db: Arc<D
                         e.clone()
                     }
                 }).collect();
+            positions.prune();
+            // Occupancy is only in SIRI-VM
+            {
+                let occupancy = occupancy.borrow();
+                filtered_entities.iter_mut().filter_map(|e| e.vehicle.as_mut()).for_each(|vehicle| {
+                    if vehicle.occupancy_status.is_none() && let Some(status) = vehicle.vehicle.as_ref().and_then(|v| v.id.as_ref()).and_then(|id| occupancy.get(id)) {
+                        vehicle.occupancy_status = Some(i32::from(*status));
+                    }
+                });
+            }
             // Send to main feed
-            tx.send((BODS, map_feed_entities(&filtered_entities), vec![])).await.unwrap_or_else(|err| error!("{}", err));
+            tx.send((BODS, map_feed_entities(&filtered_entities), vec![], HashMap::new())).await.unwrap_or_else(|err| error!("{}", err));
         }
         // Wait for next loop
         time::sleep(time::Duration::from_secs(60)).await
     }
-}
\ No newline at end of file
+}
+
+/// Refresh vehicle occupancy from BODS SIRI-VM.
+/// The SIRI-VM archive is much larger than the GTFS-RT feed, so it is downloaded less often
+async fn occupancy_listener(tx: watch::Sender<HashMap<String, OccupancyStatus>>) {
+    loop {
+        match download_siri_vm().await {
+            Ok(siri) => { tx.send_replace(get_occupancy(siri)); }
+            Err(err) => error!("Could not read BODS SIRI-VM: {err}")
+        }
+        time::sleep(time::Duration::from_secs(300)).await
+    }
+}
+
+/// GTFS-RT occupancy of each vehicle in BODS SIRI-VM, by vehicle ref.
+/// Refs used by more than one operator can't be told apart in the GTFS-RT feed, so are left out
+fn get_occupancy(siri: SiriVm) -> HashMap<String, OccupancyStatus> {
+    siri.service_delivery.vehicle_monitoring_delivery.vehicle_activity.into_iter()
+        .filter_map(|activity| {
+            let journey = activity.monitored_vehicle_journey;
+            Some((journey.vehicle_ref?, (journey.operator_ref, journey.occupancy)))
+        })
+        .into_group_map().into_iter()
+        .filter(|(_, vehicles)| vehicles.iter().map(|(operator, _)| operator).all_equal())
+        .filter_map(|(vehicle_ref, vehicles)| Some((vehicle_ref, siri_occupancy(vehicles[0].1.as_deref()?)?)))
+        .collect()
+}
+
+/// SIRI-VM occupancy -> GTFS-RT occupancy status
+fn siri_occupancy(occupancy: &str) -> Option<OccupancyStatus> {
+    match occupancy {
+        "seatsAvailable" => Some(OccupancyStatus::ManySeatsAvailable),
+        "standingAvailable" => Some(OccupancyStatus::StandingRoomOnly),
+        "full" => Some(OccupancyStatus::Full),
+        _ => None
+    }
+}
@@ -1,19 +1,17 @@
-use std::cmp::Ordering;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::ops::{Add, Sub};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
 use chrono::{DateTime, TimeDelta, Timelike, Utc};
-use config::Map;
-use geo::{Closest, GeodesicDistance, HaversineClosestPoint, LineLocatePoint};
+use geo::{Closest, GeodesicBearing, GeodesicDistance, HaversineClosestPoint};
 use geo_types::Point;
 use itertools::Itertools;
 
 use util::zero_day;
 
 use crate::db::DBPool;
+use crate::route_line::{RouteLine, RouteProgress};
 use crate::util;
-use crate::util::{f64_cmp, get_geo_linepoint_distance};
 
 type TripCandidateDBFunction = fn(&Arc<DBPool>, &DateTime<Utc>, i64, i64, &str) -> Vec<TripCandidate>;
 
@@ -42,18 +40,19 @@ pub fn get_trip_candidates(db: &Arc<DBPool>, specifier: &str, now_date: &DateTim
     }
 }
 
-/// For a given vehicle-trip combination, get its estimated next stop and how delayed the vehicle would be right now if running the route
-pub fn get_trip_info(candidate: &TripCandidate, candidate_i: usize, points: &Map<String, Point>, loc: &Point, now: &DateTime<Utc>) -> TripInfo {
-    // Find the closest route line segment to the vehicle
-    let route = &candidate.route;
-    let segments: Vec<geo_types::Line<f64>> = (0..route.len()-1).map(|i| {
-        geo_types::Line::new(points.get(&route[i]).copied().unwrap_or_default(), points.get(&route[i+1]).copied().unwrap_or_default())
-    }).collect();
-    let closest_segment = segments.iter().map(|s| get_geo_linepoint_distance(s, loc))
-        .position_min_by(f64_cmp).unwrap_or(0);
+/// Each degree a vehicle's heading disagrees with the trip's path (beyond the tolerance) costs as much as this many ms of delay
+const BEARING_MISMATCH_COST: f64 = 4000.0;
+/// Moving backwards along the trip's path since an earlier poll costs as much as this many ms of delay
+const BACKTRACK_COST: usize = 5 * 60 * 1000;
+/// Positions kept for each vehicle
+const MAX_POSITIONS: usize = 5;
+/// Vehicles must move at least this far (in metres) for their heading to be worked out from their positions
+const MIN_MOVEMENT: f64 = 20.0;
 
-    // Find how far along the line segment this point is
-    let pct = segments[closest_segment].line_locate_point(loc).unwrap_or(0.0);
+/// For a given vehicle-trip combination, get its estimated next stop and how delayed the vehicle would be right now if running the route
+pub fn get_trip_info(candidate: &TripCandidate, candidate_i: usize, vehicle: &VehicleObservation, now: &DateTime<Utc>) -> TripInfo {
+    // Find which stops the vehicle is between along the trip's path, and how far between them it is
+    let RouteProgress { segment: closest_segment, pct, bearing_mismatch, backtracked } = candidate.line.locate(&vehicle.loc, vehicle.heading, vehicle.earlier.as_ref());
 
     // Get departure times from the two ends of the line segment
     let from_time = candidate.times[closest_segment];
@@ -62,7 +61,9 @@ pub fn get_trip_info(candidate: &TripCandidate, candidate_i: usize, points: &Map
     // Interpolate to find the time the vehicle would be expected to be in that position
     let current_time = from_time.add(TimeDelta::milliseconds((to_time.signed_duration_since(from_time).num_milliseconds() as f64 * pct) as i64));
     // Find the difference between the expected time for the vehicle's position and the actual time
-    let diff = now.signed_duration_since(current_time).num_milliseconds().unsigned_abs() as usize;
+    let delay = now.signed_duration_since(current_time).num_milliseconds().unsigned_abs() as usize;
+    // Heading the wrong way or going backwards suggest this is the wrong trip (e.g. the other direction along the same road)
+    let diff = delay + (bearing_mismatch * BEARING_MISMATCH_COST) as usize + if backtracked { BACKTRACK_COST } else { 0 };
 
     TripInfo {
         candidate: candidate_i,
@@ -71,33 +72,171 @@ pub fn get_trip_info(candidate: &TripCandidate, candidate_i: usize, points: &Map
     }
 }
 
-/// Assign vehicles to GTFS trip candidates
-pub fn assign_vehicles(closeness: &mut Vec<TripCandidateList>, candidates: &[TripCandidate]) -> HashMap<usize, TripInfo> {
-    let mut assignments: HashMap<usize, TripInfo> = HashMap::new();
-    // Until empty
-    while !closeness.is_empty() {
-        // Find closest candidate for each vehicle
-        let per_vehicle = closeness.iter().map(|c| FinalTripCandidate {
-            vehicle: c.vehicle,
-            // Find closest matching remaining trip
-            trip: *c.cands.iter().min().unwrap(),
-        });
-
-        // Find closest match of these
-        let lowest = per_vehicle.min().unwrap();
-        // This is our assignment
-        assignments.insert(lowest.vehicle, lowest.trip);
-
-        // Now it's assigned, remove the vehicle from contention
-        closeness.remove(closeness.iter().position(|v| v.vehicle == lowest.vehicle).unwrap());
-        // Remove the trip from any other candidates
-        closeness.iter_mut().for_each(|v| {
-            v.cands.retain(|c| candidates[c.candidate].trip_id != candidates[lowest.trip.candidate].trip_id)
-        });
-        // And remove any vehicles without any further hope
-        closeness.retain(|v| !v.cands.is_empty());
-    }
-    assignments
+/// Cost of a vehicle-trip pair (in ms of delay) is reduced by this much if it was the vehicle's previous assignment,
+/// so vehicles running close together only swap trips when the evidence clearly changes
+const STICKY_MARGIN: i64 = 120 * 1000;
+/// Vehicles not seen for this long are forgotten
+const HISTORY_EXPIRY: TimeDelta = TimeDelta::minutes(10);
+/// Cost of matching a vehicle to a trip it is not a candidate for - more than any set of real matches could cost
+const UNMATCHABLE: i64 = 1 << 40;
+
+/// Assign vehicles to GTFS trips, minimising the total delay across all assignments.
+/// `previous` holds the trip ID each vehicle was assigned to on the last poll, by vehicle index
+pub fn assign_vehicles(closeness: &[TripCandidateList], candidates: &[TripCandidate], previous: &HashMap<usize, String>) -> HashMap<usize, TripInfo> {
+    // A trip may have several candidates (e.g. on consecutive days) - use each vehicle's closest candidate for each trip
+    let trip_ids = candidates.iter().map(|c| c.trip_id.as_str()).unique().collect_vec();
+    let best: Vec<HashMap<&str, TripInfo>> = closeness.iter().map(|list| {
+        let mut best: HashMap<&str, TripInfo> = HashMap::new();
+        for info in &list.cands {
+            best.entry(candidates[info.candidate].trip_id.as_str())
+                .and_modify(|b| if info.diff < b.diff { *b = *info })
+                .or_insert(*info);
+        }
+        best
+    }).collect();
+    let cost = |v: usize, trip_id: &str| match best[v].get(trip_id) {
+        Some(info) if previous.get(&closeness[v].vehicle).is_some_and(|prev| prev == trip_id) => info.diff as i64 - STICKY_MARGIN,
+        Some(info) => info.diff as i64,
+        None => UNMATCHABLE
+    };
+
+    // The assignment needs at least as many columns as rows, so match trips to vehicles if there are more vehicles
+    let pairs: Vec<(usize, usize)> = if closeness.len() <= trip_ids.len() {
+        let costs = (0..closeness.len()).map(|v| trip_ids.iter().map(|t| cost(v, t)).collect()).collect_vec();
+        min_cost_assignment(&costs).into_iter().enumerate().collect()
+    } else {
+        let costs = trip_ids.iter().map(|t| (0..closeness.len()).map(|v| cost(v, t)).collect()).collect_vec();
+        min_cost_assignment(&costs).into_iter().enumerate().map(|(t, v)| (v, t)).collect()
+    };
+    pairs.into_iter()
+        .filter_map(|(v, t)| Some((closeness[v].vehicle, *best[v].get(trip_ids[t])?)))
+        .collect()
+}
+
+/// Assign each row to a distinct column with the lowest total cost (Hungarian algorithm).
+/// There must be no more rows than columns - returns the column assigned to each row
+fn min_cost_assignment(costs: &[Vec<i64>]) -> Vec<usize> {
+    let rows = costs.len();
+    let cols = costs.first().map_or(0, |row| row.len());
+    // 1-indexed potentials and matches, with column 0 as the unmatched sentinel
+    let mut row_potential = vec![0i64; rows + 1];
+    let mut col_potential = vec![0i64; cols + 1];
+    let mut col_match = vec![0usize; cols + 1];
+    let mut way = vec![0usize; cols + 1];
+    for row in 1..=rows {
+        col_match[0] = row;
+        let mut col = 0;
+        let mut min_slack = vec![i64::MAX; cols + 1];
+        let mut used = vec![false; cols + 1];
+        // Grow an alternating path until it reaches an unmatched column
+        loop {
+            used[col] = true;
+            let matched_row = col_match[col];
+            let mut delta = i64::MAX;
+            let mut next_col = 0;
+            for j in 1..=cols {
+                if used[j] { continue }
+                let slack = costs[matched_row - 1][j - 1] - row_potential[matched_row] - col_potential[j];
+                if slack < min_slack[j] {
+                    min_slack[j] = slack;
+                    way[j] = col;
+                }
+                if min_slack[j] < delta {
+                    delta = min_slack[j];
+                    next_col = j;
+                }
+            }
+            for j in 0..=cols {
+                if used[j] {
+                    row_potential[col_match[j]] += delta;
+                    col_potential[j] -= delta;
+                } else {
+                    min_slack[j] -= delta;
+                }
+            }
+            col = next_col;
+            if col_match[col] == 0 { break }
+        }
+        // Flip the matches along the path
+        while col != 0 {
+            let prev_col = way[col];
+            col_match[col] = col_match[prev_col];
+            col = prev_col;
+        }
+    }
+
+    let mut assignment = vec![0; rows];
+    for (col, &row) in col_match.iter().enumerate().skip(1) {
+        if row != 0 {
+            assignment[row - 1] = col - 1;
+        }
+    }
+    assignment
+}
+
+/// Trips recently assigned to each vehicle (by a feed-specific vehicle ID), shared between polls of a listener
+#[derive(Default)]
+pub struct AssignmentHistory {
+    trips: Mutex<HashMap<String, (String, DateTime<Utc>)>>
+}
+
+impl AssignmentHistory {
+    /// Assign vehicles to trips, preferring each vehicle's previous trip, then remember the new assignments.
+    /// `vehicle_ids` are indexed by the vehicle indices of `closeness`
+    pub fn assign(&self, closeness: &[TripCandidateList], candidates: &[TripCandidate], vehicle_ids: &[String]) -> HashMap<usize, TripInfo> {
+        let now = Utc::now();
+        let mut trips = self.trips.lock().unwrap();
+        let previous = closeness.iter()
+            .filter_map(|list| Some((list.vehicle, trips.get(&vehicle_ids[list.vehicle])?.0.clone())))
+            .collect();
+        let assignments = assign_vehicles(closeness, candidates, &previous);
+        for (vehicle, trip) in &assignments {
+            trips.insert(vehicle_ids[*vehicle].clone(), (candidates[trip.candidate].trip_id.clone(), now));
+        }
+        trips.retain(|_, (_, seen)| now - *seen < HISTORY_EXPIRY);
+        assignments
+    }
+}
+
+/// A vehicle's position on this poll, with what is known about how it has been moving
+pub struct VehicleObservation {
+    pub loc: Point,
+    /// Degrees clockwise from north, from the feed or from the vehicle's last move
+    pub heading: Option<f64>,
+    /// The vehicle's oldest recent position
+    pub earlier: Option<Point>
+}
+
+/// Recent positions of each vehicle (by a feed-specific vehicle ID), shared between polls of a listener
+#[derive(Default)]
+pub struct PositionHistory {
+    positions: Mutex<HashMap<String, VecDeque<(DateTime<Utc>, Point)>>>
+}
+
+impl PositionHistory {
+    /// Observe a vehicle at a position, then remember the position for later polls.
+    /// The heading is worked out from the vehicle's last position if the feed does not give one
+    pub fn observe(&self, vehicle_id: &str, loc: Point, heading: Option<f64>) -> VehicleObservation {
+        let now = Utc::now();
+        let mut positions = self.positions.lock().unwrap();
+        let history = positions.entry(vehicle_id.to_string()).or_default();
+        history.retain(|(seen, _)| now - *seen < HISTORY_EXPIRY);
+        let earlier = history.front().map(|(_, point)| *point);
+        let heading = heading.or_else(|| history.back()
+            .filter(|(_, last)| last.geodesic_distance(&loc) >= MIN_MOVEMENT)
+            .map(|(_, last)| last.geodesic_bearing(loc)));
+        history.push_back((now, loc));
+        if history.len() > MAX_POSITIONS {
+            history.pop_front();
+        }
+        VehicleObservation { loc, heading, earlier }
+    }
+
+    /// Forget vehicles which have not been seen recently
+    pub fn prune(&self) {
+        let now = Utc::now();
+        self.positions.lock().unwrap().retain(|_, history| history.back().is_some_and(|(seen, _)| now - *seen < HISTORY_EXPIRY));
+    }
 }
 
 /// Potential GTFS trip candidate
@@ -105,9 +244,10 @@ pub fn assign_vehicles(closeness: &mut Vec<TripCandidateList>, candidates: &[Tri
 pub struct TripCandidate {
     pub trip_id: String,
     pub direction: Option<u8>,
-    pub route: Vec<String>,
+    pub route: Arc<Vec<String>>,
     pub times: Vec<DateTime<Utc>>,
-    pub seqs: Vec<u32>,
+    pub seqs: Arc<Vec<u32>>,
+    pub line: Arc<RouteLine>,
     pub date: usize
 }
 
@@ -115,62 +255,163 @@ pub struct TripCandidate {
 #[derive(Copy, Clone)]
 pub struct TripInfo {
     pub candidate: usize,
+    /// How poorly the vehicle fits the trip - its delay in ms, plus penalties for heading the wrong way or going backwards
     pub diff: usize,
     pub stop_index: usize
 }
 
-// For vehicle assignment .min() - order by diff
+/// Vehicle realtime index and a list of candidate GTFS trips
+pub struct TripCandidateList {
+    pub vehicle: usize,
+    pub cands: Vec<TripInfo>
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-impl Eq for TripInfo {}
+    const STOPS: usize = 5;
+    /// Minutes between each stop of the test route
+    const STOP_GAP: i64 = 5;
 
-impl PartialEq<Self> for TripInfo {
-    fn eq(&self, other: &Self) -> bool {
-        self.diff.eq(&other.diff)
+    fn start() -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339("2024-01-01T10:00:00Z").unwrap().to_utc()
     }
-}
 
-impl PartialOrd<Self> for TripInfo {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.cmp(other))
+    /// Trip calling at the given stops, which are spaced evenly eastwards along a line of latitude
+    fn trip_via(trip_id: &str, offset: i64, stops: &[usize]) -> TripCandidate {
+        TripCandidate {
+            trip_id: trip_id.to_string(),
+            direction: None,
+            route: Arc::new(stops.iter().map(|i| format!("S{i}")).collect()),
+            times: (0..stops.len()).map(|i| start() + TimeDelta::minutes(offset + STOP_GAP * i as i64)).collect(),
+            seqs: Arc::new((0..stops.len() as u32).collect()),
+            line: Arc::new(RouteLine::new(None, &stops.iter().map(|i| Some(Point::new(0.01 * *i as f64, 55.0))).collect_vec())),
+            date: 20240101
+        }
     }
-}
 
-impl Ord for TripInfo {
-    fn cmp(&self, other: &Self) -> Ordering {
-        self.diff.cmp(&other.diff)
+    /// Eastbound trip along the test route, departing its first stop the given minutes after 10:00
+    fn trip(trip_id: &str, offset: i64) -> TripCandidate {
+        trip_via(trip_id, offset, &(0..STOPS).collect_vec())
     }
-}
 
-/// Vehicle index and its assigned GTFS trip
-pub struct FinalTripCandidate {
-    vehicle: usize,
-    trip: TripInfo
-}
+    /// Vehicle on the test route, the given number of seconds after 10:00 along it at scheduled speed
+    fn position(secs: i64) -> Point {
+        Point::new(0.01 * secs as f64 / (STOP_GAP * 60) as f64, 55.0)
+    }
 
-// For vehicle assignment .min() - order by trip diff
+    fn closeness(candidates: &[TripCandidate], vehicles: &[Point], now: &DateTime<Utc>) -> Vec<TripCandidateList> {
+        vehicles.iter().enumerate().map(|(v, loc)| {
+            let vehicle = VehicleObservation { loc: *loc, heading: None, earlier: None };
+            TripCandidateList {
+                vehicle: v,
+                cands: candidates.iter().enumerate().map(|(c, candidate)| get_trip_info(candidate, c, &vehicle, now)).collect()
+            }
+        }).collect()
+    }
 
-impl Eq for FinalTripCandidate {}
+    fn trip_ids(assignments: &HashMap<usize, TripInfo>, candidates: &[TripCandidate], vehicles: usize) -> Vec<Option<String>> {
+        (0..vehicles).map(|v| assignments.get(&v).map(|info| candidates[info.candidate].trip_id.clone())).collect()
+    }
 
-impl PartialEq<Self> for FinalTripCandidate {
-    fn eq(&self, other: &Self) -> bool {
-        self.trip.eq(&other.trip)
+    fn list(vehicle: usize, diffs: &[(usize, usize)]) -> TripCandidateList {
+        TripCandidateList {
+            vehicle,
+            cands: diffs.iter().map(|&(candidate, diff)| TripInfo { candidate, diff, stop_index: 1 }).collect()
+        }
     }
-}
 
-impl PartialOrd<Self> for FinalTripCandidate {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.cmp(other))
+    #[test]
+    fn assignment_minimises_total_delay() {
+        // Greedily taking the closest pair (0 to X) would leave vehicle 1 on Y, 100 minutes out
+        let candidates = [trip("X", 0), trip("Y", 10)];
+        let closeness = [list(0, &[(0, 1), (1, 2)]), list(1, &[(0, 2), (1, 100)])];
+        let assignments = assign_vehicles(&closeness, &candidates, &HashMap::new());
+        assert_eq!(trip_ids(&assignments, &candidates, 2), [Some("Y".to_string()), Some("X".to_string())]);
     }
-}
 
-impl Ord for FinalTripCandidate {
-    fn cmp(&self, other: &Self) -> Ordering {
-        self.trip.cmp(&other.trip)
+    #[test]
+    fn vehicles_are_only_matched_to_their_candidates() {
+        let candidates = [trip("X", 0), trip("Y", 10)];
+        let closeness = [list(0, &[(0, 1)]), list(1, &[(0, 5)]), list(2, &[(1, 500)])];
+        let assignments = assign_vehicles(&closeness, &candidates, &HashMap::new());
+        assert_eq!(trip_ids(&assignments, &candidates, 3), [Some("X".to_string()), None, Some("Y".to_string())]);
     }
-}
 
-/// Vehicle realtime index and a list of candidate GTFS trips
-pub struct TripCandidateList {
-    pub vehicle: usize,
-    pub cands: Vec<TripInfo>
+    #[test]
+    fn more_trips_than_vehicles() {
+        let candidates = [trip("X", 0), trip("Y", 4), trip("Z", 8)];
+        let now = start() + TimeDelta::minutes(9);
+        // One vehicle on time for Z, one on time for X
+        let closeness = closeness(&candidates, &[position(60), position(9 * 60)], &now);
+        let assignments = assign_vehicles(&closeness, &candidates, &HashMap::new());
+        assert_eq!(trip_ids(&assignments, &candidates, 2), [Some("Z".to_string()), Some("X".to_string())]);
+    }
+
+    #[test]
+    fn bunched_vehicles_keep_their_trips() {
+        let candidates = [trip("X", 0), trip("Y", 4)];
+        let ids = ["bus-1".to_string(), "bus-2".to_string()];
+        let history = AssignmentHistory::default();
+
+        // Both vehicles on time
+        let now = start() + TimeDelta::minutes(6);
+        let assignments = history.assign(&closeness(&candidates, &[position(6 * 60), position(2 * 60)], &now), &candidates, &ids);
+        assert_eq!(trip_ids(&assignments, &candidates, 2), [Some("X".to_string()), Some("Y".to_string())]);
+
+        // X runs late and Y runs early until bus 2 is just ahead of bus 1 - without history, swapping trips looks slightly better
+        let now = start() + TimeDelta::minutes(7);
+        let bunched = closeness(&candidates, &[position(5 * 60 + 24), position(5 * 60 + 36)], &now);
+        let fresh = assign_vehicles(&bunched, &candidates, &HashMap::new());
+        assert_eq!(trip_ids(&fresh, &candidates, 2), [Some("Y".to_string()), Some("X".to_string())]);
+        let assignments = history.assign(&bunched, &candidates, &ids);
+        assert_eq!(trip_ids(&assignments, &candidates, 2), [Some("X".to_string()), Some("Y".to_string())]);
+    }
+
+    #[test]
+    fn clear_evidence_overrides_history() {
+        let candidates = [trip("X", 0), trip("Y", 4)];
+        let ids = ["bus-1".to_string(), "bus-2".to_string()];
+        let history = AssignmentHistory::default();
+
+        let now = start() + TimeDelta::minutes(6);
+        history.assign(&closeness(&candidates, &[position(6 * 60), position(2 * 60)], &now), &candidates, &ids);
+
+        // The vehicles were the other way round - each is now 4 minutes out on its previous trip
+        let now = start() + TimeDelta::minutes(7);
+        let assignments = history.assign(&closeness(&candidates, &[position(3 * 60), position(7 * 60)], &now), &candidates, &ids);
+        assert_eq!(trip_ids(&assignments, &candidates, 2), [Some("Y".to_string()), Some("X".to_string())]);
+    }
+
+    #[test]
+    fn heading_picks_the_direction_of_travel() {
+        // Both trips are due at the middle of the road at 10:10, in opposite directions
+        let candidates = [trip("East", 0), trip_via("West", 0, &(0..STOPS).rev().collect_vec())];
+        let now = start() + TimeDelta::minutes(10);
+        for (heading, expected) in [(90.0, "East"), (270.0, "West")] {
+            let vehicle = VehicleObservation { loc: position(10 * 60), heading: Some(heading), earlier: None };
+            let closeness = [TripCandidateList {
+                vehicle: 0,
+                cands: candidates.iter().enumerate().map(|(c, candidate)| get_trip_info(candidate, c, &vehicle, &now)).collect()
+            }];
+            let assignments = assign_vehicles(&closeness, &candidates, &HashMap::new());
+            assert_eq!(trip_ids(&assignments, &candidates, 1), [Some(expected.to_string())]);
+        }
+    }
+
+    #[test]
+    fn movement_history_picks_the_direction_of_travel() {
+        let candidates = [trip("East", 0), trip_via("West", 0, &(0..STOPS).rev().collect_vec())];
+        let positions = PositionHistory::default();
+        positions.observe("bus-1", position(12 * 60), None);
+        // The vehicle has moved west since the last poll - backwards for the eastbound trip
+        let vehicle = positions.observe("bus-1", position(10 * 60), None);
+        assert!(vehicle.heading.is_some_and(|heading| (heading.rem_euclid(360.0) - 270.0).abs() < 1.0));
+        let now = start() + TimeDelta::minutes(10);
+        let east = get_trip_info(&candidates[0], 0, &vehicle, &now);
+        let west = get_trip_info(&candidates[1], 1, &vehicle, &now);
+        assert!(west.diff < east.diff);
+        assert!(east.diff >= BACKTRACK_COST);
+    }
 }
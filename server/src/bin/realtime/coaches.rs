@@ -1,4 +1,5 @@
 use chrono::serde::{ts_seconds, ts_seconds_option};
+use std::collections::HashMap;
 use std::ops::{Add, Sub};
 use std::sync::Arc;
 use chrono::{DateTime, TimeDelta, Utc};
@@ -44,7 +45,7 @@ pub async fn coaches_listener(tx: Sender<GTFSResponse>, config: Arc<BBConfig>, d
             .collect::<Vec<FeedEntity>>().await;
 
         // Publish to main feed
-        tx.send((COACHES, map_feed_entities(&routes), vec![])).await.unwrap_or_else(|err| error!("Could not publish to main feed: {}", err));
+        tx.send((COACHES, map_feed_entities(&routes), vec![], HashMap::new())).await.unwrap_or_else(|err| error!("Could not publish to main feed: {}", err));
 
         // Wait for next loop
         time::sleep(time::Duration::from_secs(60)).await
@@ -100,7 +101,7 @@ async fn get_routes(db: &Arc<DBPool>, route: CoachRoute, api_url: &str, api_key:
                                     odometer: None,
                                     speed: None,
                                 }),
-                                current_stop_sequence: Some(trip.seqs[index] as u32),
+                                current_stop_sequence: Some(trip.seqs[index]),
                                 stop_id: Some(trip.route[index].to_string()),
                                 current_status: Some(i32::from(InTransitTo)),
                                 timestamp: Some(vehicle.last_update_time_unix.timestamp() as u64),
@@ -0,0 +1,189 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, RwLock};
+
+use chrono::{TimeDelta, Timelike, Utc};
+use itertools::Itertools;
+use log::{debug, error};
+use tokio::time;
+
+use BusBoardsServer::config::BBConfig;
+
+use crate::db::{get_started_trips, StartedTrip};
+use crate::GTFSResponder::{FIRST, LOTHIAN, STAGECOACH};
+use crate::GTFSState;
+use crate::util::adjust_timestamp;
+
+/// Trips without a vehicle this long after leaving their first stop are flagged
+const GRACE_SECS: i64 = 10 * 60;
+/// Coverage is measured over trips which started within this long
+const WINDOW_SECS: i64 = 60 * 60;
+/// Operators with fewer trips due in the window have no coverage estimate
+const MIN_TRIPS: usize = 5;
+/// Operators with at least this fraction of trips matched are considered fully tracked
+const TRACKED_COVERAGE: f64 = 0.8;
+/// Vehicles seen more recently than this count towards trips which are still running
+const SEEN_HOURS: i64 = 6;
+
+/// Why a trip which should have started has no vehicle
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Tracking {
+    /// The operator's tracking is patchy, so the trip may well be running
+    NoTracking,
+    /// The operator tracks almost every trip, so the trip is probably cancelled or very late
+    PossiblyCancelled
+}
+
+impl Tracking {
+    /// Status shown on boards and service pages
+    pub fn status(&self) -> &'static str {
+        match self {
+            Tracking::NoTracking => "No tracking",
+            Tracking::PossiblyCancelled => "Possibly cancelled"
+        }
+    }
+}
+
+/// How many of each tracked operator's trips are matched to a vehicle, and which trips have not been
+#[derive(Default)]
+pub struct TrackingCoverage {
+    unmatched: RwLock<HashMap<String, Tracking>>
+}
+
+impl TrackingCoverage {
+    /// Tracking state of a trip with no vehicle, if it should have one by now
+    pub fn tracking(&self, trip_id: &str) -> Option<Tracking> {
+        self.unmatched.read().unwrap().get(trip_id).copied()
+    }
+
+    /// Compare trips which should have started against the trips vehicles have been seen on
+    fn update(&self, state: &Arc<GTFSState>, agencies: &[String]) {
+        let now = Utc::now();
+        let seen = match state.observations.seen_trips(&(now - TimeDelta::hours(SEEN_HOURS))) {
+            Ok(seen) => seen,
+            Err(err) => {
+                error!("Could not read seen trips: {err}");
+                return
+            }
+        };
+        let live: HashSet<String> = state.vehicles.pin().iter().flat_map(|(_, entities)| entities.keys().cloned().collect_vec()).collect();
+
+        let local = adjust_timestamp(&now);
+        let trips = service_days(local.num_seconds_from_midnight() as i64).into_iter()
+            .flat_map(|(days_ago, secs)| get_started_trips(&state.db, agencies, &(local - TimeDelta::days(days_ago)), secs - WINDOW_SECS, secs - GRACE_SECS)
+                .into_iter().map(move |trip| (trip, secs)))
+            .collect_vec();
+        let is_matched = |trip: &StartedTrip| seen.contains(&trip.trip_id) || live.contains(&trip.trip_id);
+
+        let coverage = agency_coverage(&trips, is_matched);
+        debug!("Tracking coverage: {coverage:?}");
+        *self.unmatched.write().unwrap() = classify_unmatched(&trips, &coverage, is_matched);
+    }
+}
+
+/// Days ago of each service day which may have trips running now, with the current time in seconds after its midnight.
+/// Trips after midnight run on the previous day's service
+fn service_days(secs: i64) -> [(i64, i64); 2] {
+    [(0, secs), (1, secs + 86400)]
+}
+
+/// Fraction of each agency's trips started within the window which have been matched to a vehicle.
+/// Each trip is paired with the current time in seconds after midnight of its service day
+fn agency_coverage(trips: &[(StartedTrip, i64)], is_matched: impl Fn(&StartedTrip) -> bool) -> HashMap<String, f64> {
+    trips.iter()
+        .filter(|(trip, now_secs)| trip.start_time >= now_secs - WINDOW_SECS)
+        .into_group_map_by(|(trip, _)| trip.agency_id.clone()).into_iter()
+        .filter(|(_, due)| due.len() >= MIN_TRIPS)
+        .map(|(agency_id, due)| (agency_id, due.iter().filter(|(trip, _)| is_matched(trip)).count() as f64 / due.len() as f64))
+        .collect()
+}
+
+/// Tracking state of each trip with no vehicle, for agencies with a coverage estimate
+fn classify_unmatched(trips: &[(StartedTrip, i64)], coverage: &HashMap<String, f64>, is_matched: impl Fn(&StartedTrip) -> bool) -> HashMap<String, Tracking> {
+    trips.iter()
+        .filter(|(trip, _)| !is_matched(trip))
+        .filter_map(|(trip, _)| {
+            let tracking = if *coverage.get(trip.agency_id.as_str())? >= TRACKED_COVERAGE { Tracking::PossiblyCancelled } else { Tracking::NoTracking };
+            Some((trip.trip_id.clone(), tracking))
+        }).collect()
+}
+
+/// Agencies of the operators whose whole fleet is tracked by an enabled listener
+fn tracked_agencies(config: &BBConfig) -> Vec<String> {
+    let mut agencies = vec![];
+    if config.is_enabled(LOTHIAN) {
+        agencies.extend(config.lothian.operators.values().cloned());
+    }
+    if config.is_enabled(FIRST) {
+        agencies.extend(config.first.operators.values().cloned());
+    }
+    if config.is_enabled(STAGECOACH) {
+        agencies.extend(config.stagecoach.regional_operators.values().cloned());
+    }
+    agencies.into_iter().unique().collect()
+}
+
+/// Recalculate tracking coverage every minute
+pub async fn coverage_updater(state: Arc<GTFSState>, config: Arc<BBConfig>) {
+    let agencies = tracked_agencies(&config);
+    if agencies.is_empty() {
+        return
+    }
+    let mut interval = time::interval(time::Duration::from_secs(60));
+    loop {
+        interval.tick().await;
+        tokio::task::block_in_place(|| state.coverage.update(&state, &agencies));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Seconds after midnight of 10:00
+    const NOW: i64 = 10 * 3600;
+
+    /// Trip of agency A which left its first stop the given minutes before the current time
+    fn trip(trip_id: &str, minutes_ago: i64, now_secs: i64) -> (StartedTrip, i64) {
+        (StartedTrip { agency_id: "A".to_string(), trip_id: trip_id.to_string(), start_time: now_secs - minutes_ago * 60 }, now_secs)
+    }
+
+    fn classify(trips: &[(StartedTrip, i64)], matched: &[&str]) -> HashMap<String, Tracking> {
+        let is_matched = |trip: &StartedTrip| matched.contains(&trip.trip_id.as_str());
+        classify_unmatched(trips, &agency_coverage(trips, is_matched), is_matched)
+    }
+
+    #[test]
+    fn well_tracked_agency_flags_possibly_cancelled() {
+        let trips = (1..=5).map(|i| trip(&format!("T{i}"), 10 * i, NOW)).collect_vec();
+        let unmatched = classify(&trips, &["T1", "T2", "T3", "T4"]);
+        assert_eq!(unmatched, HashMap::from([("T5".to_string(), Tracking::PossiblyCancelled)]));
+    }
+
+    #[test]
+    fn patchy_agency_flags_no_tracking() {
+        let trips = (1..=5).map(|i| trip(&format!("T{i}"), 10 * i, NOW)).collect_vec();
+        let unmatched = classify(&trips, &["T1", "T2", "T3"]);
+        assert_eq!(unmatched.get("T4"), Some(&Tracking::NoTracking));
+        assert_eq!(unmatched.get("T5"), Some(&Tracking::NoTracking));
+    }
+
+    #[test]
+    fn agencies_with_few_trips_are_not_classified() {
+        let trips = (1..=4).map(|i| trip(&format!("T{i}"), 10 * i, NOW)).collect_vec();
+        assert!(classify(&trips, &[]).is_empty());
+    }
+
+    #[test]
+    fn trips_after_midnight_use_previous_day() {
+        // 00:10, when the previous day's late trips are still running
+        let [(_, today), (days_ago, yesterday)] = service_days(10 * 60);
+        assert_eq!((today, days_ago, yesterday), (600, 1, 87000));
+        let mut trips = (1..=5).map(|i| trip(&format!("T{i}"), 10 * i, yesterday)).collect_vec();
+        // started before the window, so still flagged but not counted towards coverage
+        trips.push(trip("T6", 2 * 60, yesterday));
+        let unmatched = classify(&trips, &["T1", "T2", "T3", "T4"]);
+        assert_eq!(unmatched.get("T5"), Some(&Tracking::PossiblyCancelled));
+        assert_eq!(unmatched.get("T6"), Some(&Tracking::PossiblyCancelled));
+    }
+}
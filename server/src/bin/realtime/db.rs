@@ -6,34 +6,171 @@ use std::rc::Rc;
 use std::str::FromStr;
 use std::sync::Arc;
 use std::thread::sleep;
+use std::time::SystemTime;
+use arc_swap::ArcSwap;
 use chrono::{Datelike, DateTime, Duration, DurationRound, TimeDelta, Utc};
+use geo::GeodesicDistance;
 use geo_types::{Coord, coord, Point};
 use itertools::Itertools;
-use log::{debug, info};
+use log::{debug, error, info, warn};
 use memoize::memoize;
+use polyline::decode_polyline;
 use r2d2::{Pool, PooledConnection};
 use r2d2_sqlite::SqliteConnectionManager;
 use rand::Rng;
-use rusqlite::{named_params, Params, params, CachedStatement};
+use rusqlite::{named_params, OptionalExtension, Params, params, CachedStatement};
 use rusqlite::types::Value;
 use serde::{de, Deserialize, Deserializer, Serializer};
 use serde_with::serde_as;
 use serde_with::DurationSeconds;
 
 use BusBoardsServer::config::BBConfig;
+use BusBoardsServer::patterns::unpack;
+use BusBoardsServer::segments::ALL_HOURS;
+use BusBoardsServer::SCHEMA_VERSION;
 
 use crate::bus_prediction::TripCandidate;
 use crate::passenger::PassengerDirectionInfo;
+use crate::route_line::RouteLine;
 use crate::util::{adjust_timestamp, gtfs_date, relative_to, zero_day, zero_time};
 
-pub type DBPool = Pool<SqliteConnectionManager>;
 pub type PooledConn = PooledConnection<SqliteConnectionManager>;
 
-/// Create database connection pool
-pub fn open_db() -> Pool<SqliteConnectionManager> {
-    let manager = SqliteConnectionManager::file("stops.sqlite")
+const DEFAULT_DB_PATH: &str = "stops.sqlite";
+
+/// Database connection pool which can be swapped out when the ingester replaces the database
+pub struct DBPool {
+    path: String,
+    pool: ArcSwap<Pool<SqliteConnectionManager>>,
+    modified: ArcSwap<Option<SystemTime>>
+}
+
+impl DBPool {
+    /// Get a connection to the current database
+    pub fn get(&self) -> Result<PooledConn, r2d2::Error> {
+        self.pool.load().get()
+    }
+
+    /// Swap to a new connection pool if the database file has been replaced, returning true if swapped
+    pub fn reload_if_changed(&self) -> bool {
+        let modified = get_modified(&self.path);
+        if modified.is_none() || modified == **self.modified.load() {
+            return false
+        }
+        info!("Database has been replaced - reopening {}", self.path);
+        let pool = open_pool(&self.path);
+        self.modified.store(Arc::new(modified));
+        if let Err(err) = check_schema(&pool) {
+            error!("Keeping the current database: {err}");
+            return false
+        }
+        self.pool.store(Arc::new(pool));
+        flush_memoized();
+        true
+    }
+}
+
+/// Get the path of the database file
+pub fn get_db_path() -> String {
+    std::env::var("BUSES_DB_PATH").unwrap_or(DEFAULT_DB_PATH.to_string())
+}
+
+fn get_modified(path: &str) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|md| md.modified()).ok()
+}
+
+/// Create database connection pool, exiting if the database uses a different schema version
+pub fn open_db() -> DBPool {
+    let path = get_db_path();
+    let pool = open_pool(&path);
+    if let Err(err) = check_schema(&pool) {
+        error!("Cannot use {path}: {err}");
+        std::process::exit(1);
+    }
+    DBPool {
+        modified: ArcSwap::from_pointee(get_modified(&path)),
+        pool: ArcSwap::from_pointee(pool),
+        path
+    }
+}
+
+/// Check that a database was built for the schema version this server expects.
+/// Databases built before versioning are allowed with a warning.
+fn check_schema(pool: &Pool<SqliteConnectionManager>) -> Result<(), String> {
+    let conn = pool.get().map_err(|e| e.to_string())?;
+    let version = conn.query_row("SELECT value FROM metadata WHERE key='schema_version'", [], |row| row.get::<_, String>(0))
+        .optional().ok().flatten();
+    match version.map(|v| v.parse::<u32>()) {
+        None => {
+            warn!("Database has no schema version - re-run the ingester to add one");
+            Ok(())
+        }
+        Some(Ok(version)) if version == SCHEMA_VERSION => Ok(()),
+        Some(Ok(version)) => Err(format!("database is schema version {version} but version {SCHEMA_VERSION} is required - re-run the ingester")),
+        Some(Err(err)) => Err(format!("invalid schema version: {err}"))
+    }
+}
+
+/// When the database was built and the version of each source it contains
+pub fn get_metadata(db: &Arc<DBPool>) -> rusqlite::Result<DatasetMetadata> {
+    let db = get_pool(db);
+    let mut metadata: HashMap<String, String> = db.prepare_cached("SELECT key, value FROM metadata")?
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<rusqlite::Result<_>>()?;
+    let sources = db.prepare_cached(r#"
+        SELECT name, downloaded, imported, hash, coalesce(routes, 0), coalesce(trips, 0), coalesce(services, 0)
+            FROM source_metadata ORDER BY name"#)?
+        .query_map([], |row| Ok(SourceMetadata {
+            name: row.get(0)?,
+            downloaded: row.get(1)?,
+            imported: row.get(2)?,
+            hash: row.get(3)?,
+            routes: row.get(4)?,
+            trips: row.get(5)?,
+            services: row.get(6)?
+        }))?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(DatasetMetadata {
+        built: metadata.remove("built"),
+        schema_version: metadata.remove("schema_version").and_then(|v| v.parse().ok()),
+        sources
+    })
+}
+
+#[derive(Serialize)]
+pub struct DatasetMetadata {
+    pub built: Option<String>,
+    pub schema_version: Option<u32>,
+    pub sources: Vec<SourceMetadata>
+}
+
+#[derive(Serialize)]
+pub struct SourceMetadata {
+    pub name: String,
+    /// When the source's file was downloaded - unknown for sources imported before metadata was recorded
+    pub downloaded: Option<String>,
+    pub imported: Option<String>,
+    pub hash: Option<String>,
+    pub routes: u64,
+    pub trips: u64,
+    pub services: u64
+}
+
+fn open_pool(path: &str) -> Pool<SqliteConnectionManager> {
+    let manager = SqliteConnectionManager::file(path)
         .with_init(|s| rusqlite::vtab::array::load_module(s));
-    Pool::new(manager).unwrap_or_else(|e| open_db())
+    Pool::new(manager).unwrap_or_else(|_| open_pool(path))
+}
+
+/// Clear every memoized database lookup
+fn flush_memoized() {
+    memoized_flush_get_agency();
+    memoized_flush_get_route();
+    memoized_flush_get_route_id();
+    memoized_flush_get_line_segments();
+    memoized_flush_get_lothian_route();
+    memoized_flush_get_pattern();
+    memoized_flush_get_route_line();
 }
 
 /// Get connection from database pool
@@ -73,71 +210,109 @@ pub fn get_route_id(db: &Arc<DBPool>, agency_id: String, route: String) -> Resul
     get_string(db, "SELECT route_id FROM routes WHERE agency_id=? AND upper(route_short_name)=upper(?)", (agency_id, route))
 }
 
+/// Stops and stop sequences shared by every trip with the same pattern
+#[derive(Clone)]
+pub struct TripPattern {
+    pub stops: Arc<Vec<String>>,
+    pub seqs: Arc<Vec<u32>>
+}
+
+/// Pattern ID -> stops and stop sequences, as built by the ingester
+#[memoize(Ignore: db)]
+pub fn get_pattern(db: &Arc<DBPool>, pattern_id: u64) -> Option<TripPattern> {
+    get_pool(db).prepare_cached("SELECT stops, seqs FROM patterns WHERE pattern_id=?").unwrap()
+        .query_row([pattern_id], |row| Ok(TripPattern {
+            stops: Arc::new(row.get::<_, String>(0)?.split(',').map(str::to_string).collect()),
+            seqs: Arc::new(unpack(&row.get::<_, Vec<u8>>(1)?))
+        })).ok()
+}
+
+/// Departure times of a trip from its start time and packed offsets, relative to `day_secs`
+fn pattern_times(day_secs: i64, start_time: i64, offsets: &[u8]) -> Vec<DateTime<Utc>> {
+    unpack(offsets).into_iter().map(|offset| DateTime::from_timestamp(day_secs + start_time + offset as i64, 0).unwrap()).collect()
+}
+
 /// Get trip candidates for realtime vehicle matching - query made specific to data provider
 fn trip_query(query: &str, db: &Arc<DBPool>, date: &DateTime<Utc>, start_before: i64, end_after: i64, specifier: &str) -> Vec<TripCandidate>  {
     let date_secs = zero_time(date).timestamp();
 
-    get_pool(db).prepare_cached(query).unwrap().query_map(named_params! {
+    let rows: Vec<(String, Option<u8>, usize, u64, Option<String>, i64, Vec<u8>)> = get_pool(db).prepare_cached(query).unwrap().query_map(named_params! {
         ":date": u64::from_str(date.format("%Y%m%d").to_string().as_str()).unwrap(),
         ":day": date.weekday().num_days_from_monday(),
         ":startTime": start_before,
         ":endTime": end_after,
         ":specifier": specifier
-    }, |row| {
-        let route_str: String = row.get("route")?;
-        let times_str: String = row.get("times")?;
-        let seqs_str: String = row.get("seqs")?;
-
-        Ok(TripCandidate {
-            trip_id: row.get("trip_id")?,
-            direction: row.get("direction").ok(),
-            route: route_str.split(',').map(|s| s.to_string()).collect(),
-            times: times_str.split(',').map(|s| DateTime::from_timestamp(i64::from_str(s).unwrap() + date_secs, 0).unwrap()).collect(),
-            seqs: seqs_str.split(',').map(|s| u32::from_str(s).unwrap()).collect(),
-            date: row.get("date")?,
+    }, |row| Ok((
+        row.get("trip_id")?,
+        row.get("direction").ok().flatten(),
+        row.get("date")?,
+        row.get("pattern_id")?,
+        row.get("shape_id")?,
+        row.get("start_time")?,
+        row.get("offsets")?
+    ))).unwrap().filter_map(|i| i.ok()).collect();
+
+    rows.into_iter().filter_map(|(trip_id, direction, date, pattern_id, shape_id, start_time, offsets)| {
+        let pattern = get_pattern(db, pattern_id)?;
+        Some(TripCandidate {
+            trip_id,
+            direction,
+            route: pattern.stops,
+            times: pattern_times(date_secs, start_time, &offsets),
+            seqs: pattern.seqs,
+            line: get_route_line(db, pattern_id, shape_id),
+            date,
         })
-    }).unwrap().filter_map(|i| i.ok()).collect()
+    }).collect()
 }
 
 /// Get trip candidates for Passenger realtime vehicle matching
 pub fn passenger_trip_query(db: &Arc<DBPool>, date: &DateTime<Utc>, start_before: i64, end_after: i64, route_id: &str) -> Vec<TripCandidate> {
-    trip_query(r#"SELECT trips.trip_id, p.direction, :date as date,
-                (SELECT group_concat(stop_id) FROM (SELECT stop_id FROM stop_times WHERE trip_id=trips.trip_id ORDER BY stop_sequence)) as route,
-                (SELECT group_concat(departure_time) FROM (SELECT departure_time FROM stop_times WHERE trip_id=trips.trip_id ORDER BY stop_sequence)) as times,
-                (SELECT group_concat(stop_sequence) FROM (SELECT stop_sequence FROM stop_times WHERE trip_id=trips.trip_id ORDER BY stop_sequence)) as seqs
+    trip_query(r#"SELECT trips.trip_id, p.direction, :date as date, tp.pattern_id, trips.shape_id, tp.start_time, tp.offsets
                  FROM trips
                           INNER JOIN routes r on r.route_id = trips.route_id
+                          INNER JOIN trip_patterns tp on tp.trip_id = trips.trip_id
                           LEFT OUTER JOIN calendar c on c.service_id = trips.service_id
                           LEFT OUTER JOIN calendar_dates d on (d.service_id = c.service_id AND d.date=:date)
                           LEFT OUTER JOIN polar p on trips.trip_id = p.gtfs
-                          INNER JOIN stop_times start on (start.trip_id=trips.trip_id AND start.stop_sequence=(SELECT min(stop_sequence) FROM stop_times WHERE trip_id=trips.trip_id))
-                          INNER JOIN stop_times finish on (finish.trip_id=trips.trip_id AND finish.stop_sequence=(SELECT max(stop_sequence) FROM stop_times WHERE trip_id=trips.trip_id))
                  WHERE r.route_id=:specifier
                    AND ((start_date <= :date AND end_date >= :date AND (validity & (1 << :day)) <> 0) OR exception_type=1)
                    AND NOT (exception_type IS NOT NULL AND exception_type = 2)
-                   AND +start.departure_time <= :startTime AND +finish.departure_time >= :endTime
+                   AND tp.start_time <= :startTime AND tp.end_time >= :endTime
     "#, db, date, start_before, end_after, route_id)
 }
 
 /// Get trip candidates for Lothian realtime vehicle matching
 pub fn lothian_trip_query(db: &Arc<DBPool>, date: &DateTime<Utc>, start_before: i64, end_after: i64, pattern: &str) -> Vec<TripCandidate> {
-    trip_query(r#"SELECT trips.trip_id, :date as date,
-                (SELECT group_concat(stop_id) FROM (SELECT stop_id FROM stop_times WHERE trip_id=trips.trip_id ORDER BY stop_sequence)) as route,
-                (SELECT group_concat(departure_time) FROM (SELECT departure_time FROM stop_times WHERE trip_id=trips.trip_id ORDER BY stop_sequence)) as times,
-                (SELECT group_concat(stop_sequence) FROM (SELECT stop_sequence FROM stop_times WHERE trip_id=trips.trip_id ORDER BY stop_sequence)) as seqs
+    trip_query(r#"SELECT trips.trip_id, :date as date, tp.pattern_id, trips.shape_id, tp.start_time, tp.offsets
                  FROM polar
                    INNER JOIN main.trips trips on polar.gtfs = trips.trip_id
+                   INNER JOIN trip_patterns tp on tp.trip_id = trips.trip_id
                    LEFT OUTER JOIN main.calendar c on c.service_id = trips.service_id
                    LEFT OUTER JOIN main.calendar_dates d on (d.service_id = c.service_id AND d.date=:date)
-                   INNER JOIN stop_times start on (start.trip_id=trips.trip_id AND start.stop_sequence=(SELECT min(stop_sequence) FROM stop_times WHERE trip_id=trips.trip_id))
-                   INNER JOIN stop_times finish on (finish.trip_id=trips.trip_id AND finish.stop_sequence=(SELECT max(stop_sequence) FROM stop_times WHERE trip_id=trips.trip_id))
                  WHERE direction IS NULL AND polar=:specifier
                    AND ((start_date <= :date AND end_date >= :date AND (validity & (1 << :day)) <> 0) OR exception_type=1)
                    AND NOT (exception_type IS NOT NULL AND exception_type = 2)
-                   AND +start.departure_time <= :startTime AND +finish.departure_time >= :endTime
+                   AND tp.start_time <= :startTime AND tp.end_time >= :endTime
     "#, db, date, start_before, end_after, pattern)
 }
 
+/// Trip pattern and shape -> path of the trip, with the distance along it of each stop
+#[memoize(Ignore: db)]
+pub fn get_route_line(db: &Arc<DBPool>, pattern_id: u64, shape_id: Option<String>) -> Arc<RouteLine> {
+    let stops = get_pattern(db, pattern_id).map(|pattern| pattern.stops).unwrap_or_default();
+    let conn = get_pool(db);
+    let codes = Rc::new(stops.iter().cloned().map(Value::from).collect::<Vec<Value>>());
+    let points: HashMap<String, Point> = conn.prepare_cached("SELECT code, long, lat FROM stances WHERE code IN (SELECT value FROM rarray(?1))").unwrap()
+        .query_map([codes], |row| Ok((row.get(0)?, Point::new(row.get(1)?, row.get(2)?)))).unwrap()
+        .filter_map(Result::ok).collect();
+    let shape = shape_id
+        .and_then(|shape_id| conn.prepare_cached("SELECT polyline FROM shapes WHERE shape_id=?").unwrap()
+            .query_row([shape_id], |row| row.get::<_, String>(0)).ok())
+        .and_then(|polyline| decode_polyline(polyline.as_str(), 5).ok());
+    Arc::new(RouteLine::new(shape, &stops.iter().map(|stop| points.get(stop).copied()).collect_vec()))
+}
+
 /// GTFS route -> coordinates for each stance on route
 #[memoize(Ignore: db)]
 pub fn get_line_segments(db: &Arc<DBPool>, route_id: String) -> HashMap<String, Point> {
@@ -177,24 +352,23 @@ pub struct StagecoachRoute {
     pub route_id: String,
     pub stop_seq: u64,
     pub stop_id: String,
-    pub trip_route: Vec<String>,
-    pub trip_seqs: Vec<u32>
+    pub trip_route: Arc<Vec<String>>,
+    pub trip_seqs: Arc<Vec<u32>>,
+    pub line: Arc<RouteLine>
 }
 
 /// Find GTFS trip match from Stagecoach realtime journey
 pub fn get_stagecoach_trip(db: &Arc<DBPool>, agency_id: &str, route_name: &str, next_stop: &str, departure: &DateTime<Utc>) -> Option<StagecoachRoute> {
     get_pool(db).prepare_cached(r#"
-        SELECT t.trip_id, r.route_id, stop_times.stop_sequence as stop_seq, stop_times.stop_id,
-            (SELECT group_concat(stop_id) FROM (SELECT stop_id FROM stop_times WHERE trip_id=t.trip_id ORDER BY stop_sequence)) as route,
-            (SELECT group_concat(stop_sequence) FROM (SELECT stop_sequence FROM stop_times WHERE trip_id=t.trip_id ORDER BY stop_sequence)) as seqs
+        SELECT t.trip_id, r.route_id, stop_times.stop_sequence as stop_seq, stop_times.stop_id, tp.pattern_id, t.shape_id
         FROM stop_times
             INNER JOIN trips t on t.trip_id = stop_times.trip_id
             INNER JOIN main.routes r on t.route_id = r.route_id
-            INNER JOIN stop_times origin on (origin.trip_id=t.trip_id AND origin.stop_sequence=(SELECT min(stop_sequence) FROM stop_times WHERE trip_id=t.trip_id))
+            INNER JOIN trip_patterns tp on tp.trip_id = t.trip_id
             LEFT OUTER JOIN main.calendar c on c.service_id = t.service_id
             LEFT OUTER JOIN main.calendar_dates d on (d.service_id = c.service_id AND d.date=:date)
         WHERE agency_id = :agency_id AND route_short_name = :route_name AND stop_times.stop_id=:next_stop
-          AND origin.departure_time = :dep_time
+          AND tp.start_time = :dep_time
           AND ((start_date <= :date AND end_date >= :date AND (validity & (1 << :day)) <> 0) OR exception_type=1)
           AND NOT (exception_type IS NOT NULL AND exception_type = 2)
     "#).unwrap().query_row(named_params! {
@@ -204,40 +378,33 @@ pub fn get_stagecoach_trip(db: &Arc<DBPool>, agency_id: &str, route_name: &str,
             ":agency_id": agency_id,
             ":route_name": route_name,
             ":next_stop": next_stop
-        }, |row| {
-        Ok(StagecoachRoute {
-            trip_id: row.get("trip_id")?,
-            route_id: row.get("route_id")?,
-            stop_seq: row.get("stop_seq")?,
-            stop_id: row.get("stop_id")?,
-            trip_route: row.get::<&str, String>("route")?.split(',').map(|s| s.to_string()).collect(),
-            trip_seqs: row.get::<&str, String>("seqs")?.split(',').map(|s| u32::from_str(s).unwrap()).collect()
-        })
-    }).ok()
+        }, |row| Ok((row.get("trip_id")?, row.get("route_id")?, row.get("stop_seq")?, row.get("stop_id")?, row.get("pattern_id")?, row.get("shape_id")?))
+    ).ok().and_then(|(trip_id, route_id, stop_seq, stop_id, pattern_id, shape_id)| {
+        let pattern = get_pattern(db, pattern_id)?;
+        let line = get_route_line(db, pattern_id, shape_id);
+        Some(StagecoachRoute { trip_id, route_id, stop_seq, stop_id, trip_route: pattern.stops, trip_seqs: pattern.seqs, line })
+    })
 }
 
 #[derive(Clone)]
 pub struct BODSRouteInfo {
-    pub route_id: String,
-    pub trip_route: Vec<String>,
-    pub trip_seqs: Vec<u32>
+    pub trip_route: Arc<Vec<String>>,
+    pub trip_seqs: Arc<Vec<u32>>,
+    pub line: Arc<RouteLine>
 }
 
 /// Get trip stop sequence info for BODS stop sequence numbering
 pub fn get_bods_trip(db: &Arc<DBPool>, trip_id: &str) -> Option<BODSRouteInfo> {
     get_pool(db).prepare_cached(r#"
-        SELECT route_id,
-            (SELECT group_concat(stop_id) FROM (SELECT stop_id FROM stop_times WHERE trip_id=trips.trip_id ORDER BY stop_sequence)) as route,
-            (SELECT group_concat(stop_sequence) FROM (SELECT stop_sequence FROM stop_times WHERE trip_id=trips.trip_id ORDER BY stop_sequence)) as seqs
+        SELECT tp.pattern_id, shape_id
         FROM trips
+            INNER JOIN trip_patterns tp on tp.trip_id = trips.trip_id
         WHERE trips.trip_id=?
-    "#).unwrap().query_row(params![trip_id], |row| {
-        Ok(BODSRouteInfo {
-            route_id: row.get("route_id")?,
-            trip_route: row.get::<&str, String>("route")?.split(',').map(|s| s.to_string()).collect(),
-            trip_seqs: row.get::<&str, String>("seqs")?.split(',').map(|s| u32::from_str(s).unwrap()).collect()
+    "#).unwrap().query_row(params![trip_id], |row| Ok((row.get("pattern_id")?, row.get("shape_id")?)))
+        .ok().and_then(|(pattern_id, shape_id)| {
+            let pattern = get_pattern(db, pattern_id)?;
+            Some(BODSRouteInfo { trip_route: pattern.stops, trip_seqs: pattern.seqs, line: get_route_line(db, pattern_id, shape_id) })
         })
-    }).ok()
 }
 
 /// GTFS route
@@ -265,9 +432,9 @@ pub fn get_coach_routes(db: &Arc<DBPool>, config: &Arc<BBConfig>) -> Vec<CoachRo
 /// GTFS trip info
 pub struct CoachTrip {
     pub trip_id: String,
-    pub route: Vec<String>,
-    pub times: Vec<usize>,
-    pub seqs: Vec<usize>,
+    pub route: Arc<Vec<String>>,
+    pub times: Vec<DateTime<Utc>>,
+    pub seqs: Arc<Vec<u32>>,
     pub std_loc: String,
     pub sta_loc: String
 }
@@ -275,18 +442,16 @@ pub struct CoachTrip {
 /// Coach realtime journey -> GTFS trip
 pub fn get_coach_trip(db: &Arc<DBPool>, route: &str, origin: &str, dest: &str, start: &DateTime<Utc>, end: &DateTime<Utc>) -> Option<CoachTrip> {
     get_pool(db).prepare_cached(
-        r#"SELECT trips.trip_id,
-            (SELECT group_concat(stop_id) FROM (SELECT stop_id FROM stop_times WHERE trip_id=trips.trip_id ORDER BY stop_sequence)) as route,
-            (SELECT group_concat(departure_time) FROM (SELECT departure_time FROM stop_times WHERE trip_id=trips.trip_id ORDER BY stop_sequence)) as times,
-            (SELECT group_concat(stop_sequence) FROM (SELECT stop_sequence FROM stop_times WHERE trip_id=trips.trip_id ORDER BY stop_sequence)) as seqs,
+        r#"SELECT trips.trip_id, tp.pattern_id, tp.start_time, tp.offsets,
             (SELECT locality_name FROM stances INNER JOIN main.stops s on s.id = stances.stop WHERE code=std.stop_id) as stdLoc,
             (SELECT locality_name FROM stances INNER JOIN main.stops s on s.id = stances.stop WHERE code=sta.stop_id) as staLoc
             FROM trips
+                INNER JOIN trip_patterns tp on tp.trip_id = trips.trip_id
                 INNER JOIN main.stop_times std on (trips.trip_id = std.trip_id AND std.stop_sequence=(SELECT min(stop_sequence) FROM stop_times WHERE trip_id=trips.trip_id))
                 INNER JOIN main.stop_times sta on (trips.trip_id = sta.trip_id AND sta.stop_sequence=(SELECT max(stop_sequence) FROM stop_times WHERE trip_id=trips.trip_id))
                 LEFT OUTER JOIN main.calendar c on c.service_id = trips.service_id
                 LEFT OUTER JOIN main.calendar_dates d on (d.service_id = c.service_id AND d.date=:date)
-            WHERE route_id=:route AND +std.departure_time=:startTime AND +sta.departure_time=:endTime
+            WHERE route_id=:route AND tp.start_time=:startTime AND tp.end_time=:endTime
                 AND stdLoc LIKE :depWildcard AND staLoc LIKE :arrWildcard
                 AND ((start_date <= :date AND end_date >= :date AND (validity & (1 << :day)) <> 0) OR exception_type=1)
                     AND NOT (exception_type IS NOT NULL AND exception_type = 2)"#).unwrap()
@@ -298,16 +463,24 @@ pub fn get_coach_trip(db: &Arc<DBPool>, route: &str, origin: &str, dest: &str, s
             ":arrWildcard": format!("{}%", dest.split_once('(').map(|s| s.0).unwrap_or(dest)),
             ":date": usize::from_str(gtfs_date(start).as_str()).unwrap(),
             ":day": start.weekday().num_days_from_monday()
-        }, |row| {
-            Ok(CoachTrip {
-                trip_id: row.get("trip_id")?,
-                route: row.get::<_, String>("route")?.split(',').map(str::to_string).collect_vec(),
-                times: row.get::<_, String>("times")?.split(',').filter_map(|s| usize::from_str(s).ok()).collect_vec(),
-                seqs: row.get::<_, String>("seqs")?.split(',').filter_map(|s| usize::from_str(s).ok()).collect_vec(),
-                std_loc: row.get("stdLoc")?,
-                sta_loc: row.get("staLoc")?,
+        }, |row| Ok((
+            row.get("trip_id")?,
+            row.get("pattern_id")?,
+            row.get("start_time")?,
+            row.get::<_, Vec<u8>>("offsets")?,
+            row.get("stdLoc")?,
+            row.get("staLoc")?
+        ))).ok().and_then(|(trip_id, pattern_id, start_time, offsets, std_loc, sta_loc)| {
+            let pattern = get_pattern(db, pattern_id)?;
+            Some(CoachTrip {
+                trip_id,
+                route: pattern.stops,
+                times: pattern_times(zero_time(start).timestamp(), start_time, &offsets),
+                seqs: pattern.seqs,
+                std_loc,
+                sta_loc,
             })
-        }).ok()
+        })
 }
 
 type TripID = String;
@@ -340,6 +513,31 @@ pub fn get_first_trip(db: &Arc<DBPool>, route: &str, agency_id: &str, start_stop
     ).ok()
 }
 
+/// SIRI-SX affected journey -> GTFS trip, by when it leaves its first stop on one of the affected routes.
+/// Journeys matching more than one trip (e.g. outbound and inbound trips leaving at the same time) are not resolved
+pub fn get_affected_trip(db: &Arc<DBPool>, route_ids: &[String], departure: &DateTime<Utc>) -> Option<TripID> {
+    let local = adjust_timestamp(departure);
+    let routes = Rc::new(route_ids.iter().cloned().map(Value::from).collect::<Vec<Value>>());
+    get_pool(db).prepare_cached(
+        r#"SELECT trips.trip_id FROM trips
+                INNER JOIN main.stop_times st on (trips.trip_id = st.trip_id AND stop_sequence=(SELECT min(stop_sequence) FROM stop_times WHERE trip_id=trips.trip_id))
+                LEFT OUTER JOIN main.calendar c on c.service_id = trips.service_id
+                LEFT OUTER JOIN main.calendar_dates d on (d.service_id = c.service_id AND d.date=:date)
+            WHERE trips.route_id IN (SELECT value FROM rarray(:routes)) AND st.departure_time=:startTime
+                AND ((start_date <= :date AND end_date >= :date AND (validity & (1 << :day)) <> 0) OR exception_type=1)
+                    AND NOT (exception_type IS NOT NULL AND exception_type = 2)
+            LIMIT 2"#
+    ).unwrap().query_map(
+        named_params![
+            ":routes": routes,
+            ":startTime": zero_day(&local).timestamp(),
+            ":date": u64::from_str(gtfs_date(&local).as_str()).unwrap(),
+            ":day": local.weekday().num_days_from_monday()
+        ],
+        |row| row.get("trip_id")
+    ).ok()?.filter_map(|trip| trip.ok()).exactly_one().ok()
+}
+
 /// Delete Lothian journey <-> GTFS trip mappings
 pub fn reset_lothian(db: &Arc<DBPool>) {
     get_pool(db).execute("DELETE FROM polar WHERE direction IS NULL", params![]).unwrap();
@@ -352,6 +550,39 @@ pub fn get_operator_routes(db: &Arc<DBPool>, agency_id: &str) -> Vec<(RouteID, R
         .unwrap().filter_map(|c| c.ok()).collect_vec()
 }
 
+/// A trip which is scheduled to have left its first stop
+pub struct StartedTrip {
+    pub agency_id: String,
+    pub trip_id: String,
+    /// Seconds after midnight of the trip's service day
+    pub start_time: i64
+}
+
+/// Trips of the given agencies running on a date which started between two times (seconds after midnight),
+/// or which started earlier and are still scheduled to be running at the later time
+pub fn get_started_trips(db: &Arc<DBPool>, agency_ids: &[String], date: &DateTime<Utc>, start_after: i64, start_before: i64) -> Vec<StartedTrip> {
+    let agencies = Rc::new(agency_ids.iter().cloned().map(Value::from).collect::<Vec<Value>>());
+    get_pool(db).prepare_cached(r#"
+        SELECT r.agency_id, trips.trip_id, tp.start_time
+        FROM routes r
+            INNER JOIN trips on trips.route_id = r.route_id
+            INNER JOIN trip_patterns tp on tp.trip_id = trips.trip_id
+            LEFT OUTER JOIN calendar c on c.service_id = trips.service_id
+            LEFT OUTER JOIN calendar_dates d on (d.service_id = c.service_id AND d.date=:date)
+        WHERE r.agency_id IN (SELECT value FROM rarray(:agencies))
+          AND tp.start_time <= :startBefore AND (tp.start_time >= :startAfter OR tp.end_time >= :startBefore)
+          AND ((start_date <= :date AND end_date >= :date AND (validity & (1 << :day)) <> 0) OR exception_type=1)
+          AND NOT (exception_type IS NOT NULL AND exception_type = 2)
+    "#).unwrap().query_map(named_params! {
+            ":agencies": agencies,
+            ":date": u64::from_str(gtfs_date(date).as_str()).unwrap(),
+            ":day": date.weekday().num_days_from_monday(),
+            ":startAfter": start_after,
+            ":startBefore": start_before
+        }, |row| Ok(StartedTrip { agency_id: row.get(0)?, trip_id: row.get(1)?, start_time: row.get(2)? })
+    ).unwrap().filter_map(|c| c.ok()).collect_vec()
+}
+
 /// GTFS trip info
 pub struct LothianGTFSTrip {
     pub min_stop_time: i64,
@@ -479,12 +710,19 @@ pub fn query_stops(db: &Arc<DBPool>, trip_id: &str) -> rusqlite::Result<Vec<Stop
     let mut stmt = db.prepare_cached(
         "SELECT stops.name, stops.name as display_name, stops.locality, indicator as ind, arrival_time as arr,
                     departure_time as dep, l.name as loc, timepoint as major, drop_off_type as doo, pickup_type as puo,
-                    stances.lat as lat, stances.long as long, stop_sequence as seq, stops.locality_name AS full_loc
+                    stances.lat as lat, stances.long as long, stop_sequence as seq, stops.locality_name AS full_loc,
+                    sa.parent_station as parent, sa.wheelchair_boarding as wheelchair,
+                    (SELECT json_group_array(json_object('to', tr.to_stop_id, 'type', tr.transfer_type, 'min_time', tr.min_transfer_time))
+                        FROM transfers tr WHERE tr.from_stop_id = stop_times.stop_id AND tr.to_stop_id <> tr.from_stop_id
+                            AND (tr.from_trip_id IS NULL OR tr.from_trip_id = stop_times.trip_id)) as transfers,
+                    h.headway_secs as headway
                 FROM stop_times
                     LEFT JOIN stances on stances.code = stop_times.stop_id
                     LEFT JOIN stops on stops.id = stances.stop
                     LEFT JOIN localities l on l.code = stops.locality
-                WHERE trip_id=? ORDER BY stop_sequence")?;
+                    LEFT JOIN stance_attributes sa on sa.code = stop_times.stop_id
+                    LEFT JOIN headways h on h.trip_id = stop_times.trip_id AND h.exact_times = 0
+                WHERE stop_times.trip_id=? ORDER BY stop_sequence")?;
     let result = Ok(stmt.query_map([trip_id], |row| Ok(StopsQuery {
         name: row.get(0).unwrap_or("Unknown".to_string()),
         display_name: row.get(1).unwrap_or("Unknown".to_string()),
@@ -500,7 +738,11 @@ pub fn query_stops(db: &Arc<DBPool>, trip_id: &str) -> rusqlite::Result<Vec<Stop
         long: row.get(11).ok(),
         seq: row.get(12)?,
         full_loc: row.get(13).unwrap_or("".to_string()),
-        status: None
+        status: None,
+        parent: row.get(14)?,
+        wheelchair: row.get(15)?,
+        transfers: parse_transfers(row.get(16)),
+        headway: row.get(17)?
     }))?.filter_map(Result::ok).collect_vec());
     result
 }
@@ -528,7 +770,29 @@ pub struct StopsQuery {
     pub seq: u64,
     #[serde(skip_serializing)]
     pub full_loc: String,
-    pub status: Option<String>
+    pub status: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wheelchair: Option<u8>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub transfers: Vec<StopTransfer>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub headway: Option<u32>
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct StopTransfer {
+    pub to: String,
+    #[serde(rename = "type")]
+    pub transfer_type: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_time: Option<u32>
+}
+
+/// Transfers from a stop, selected as a JSON array
+fn parse_transfers(json: rusqlite::Result<String>) -> Vec<StopTransfer> {
+    json.ok().and_then(|json| serde_json::from_str(json.as_str()).ok()).unwrap_or_default()
 }
 
 impl StopsQuery {
@@ -603,24 +867,111 @@ pub struct StopPosition {
     pub pos: Coord<f64>
 }
 
+/// Learned times for a stop of a trip, in seconds
+#[derive(Default, Clone, Copy)]
+pub struct LearnedTime {
+    /// Departure-to-departure running time from the previous stop
+    pub run_time: Option<i64>,
+    pub dwell: Option<i64>
+}
+
+/// Learned running and dwell times for each stop of a trip by stop sequence, falling back to the all-hours times
+pub fn get_learned_times(db: &Arc<DBPool>, trip_id: &str, day_type: u32) -> HashMap<u64, LearnedTime> {
+    let db = get_pool(db);
+    let result = db.prepare_cached(r#"
+        SELECT st.stop_sequence, coalesce(seg.run_time, seg_all.run_time), coalesce(dw.dwell, dw_all.dwell)
+            FROM (SELECT stop_sequence, stop_id, departure_time,
+                         lag(stop_id) OVER (ORDER BY stop_sequence) AS prev_stop,
+                         lag(departure_time) OVER (ORDER BY stop_sequence) AS prev_departure
+                      FROM stop_times WHERE trip_id=?1) st
+                LEFT OUTER JOIN segment_times seg ON seg.from_stop=st.prev_stop AND seg.to_stop=st.stop_id
+                    AND seg.day_type=?2 AND seg.hour=st.prev_departure / 3600 % 24
+                LEFT OUTER JOIN segment_times seg_all ON seg_all.from_stop=st.prev_stop AND seg_all.to_stop=st.stop_id
+                    AND seg_all.day_type=?2 AND seg_all.hour=?3
+                LEFT OUTER JOIN dwell_times dw ON dw.stop_id=st.stop_id AND dw.day_type=?2 AND dw.hour=st.departure_time / 3600 % 24
+                LEFT OUTER JOIN dwell_times dw_all ON dw_all.stop_id=st.stop_id AND dw_all.day_type=?2 AND dw_all.hour=?3"#)
+        .and_then(|mut stmt| stmt.query_map(params![trip_id, day_type, ALL_HOURS], |row| Ok((row.get(0)?, LearnedTime {
+            run_time: row.get(1)?,
+            dwell: row.get(2)?
+        })))?.collect::<rusqlite::Result<HashMap<u64, LearnedTime>>>());
+    result.unwrap_or_else(|err| {
+        error!("Could not get learned times for {trip_id}: {err}");
+        HashMap::new()
+    })
+}
+
 pub fn get_stop_info(db: &Arc<DBPool>, name: &str, locality: &str) -> rusqlite::Result<StopInfoQuery> {
     let db = get_pool(db);
-    let result = db.prepare_cached("SELECT id, name, locality_name, locality as locality_code FROM stops WHERE name=? AND locality=?")?
+    let result = db.prepare_cached("SELECT id, name, locality_name, locality as locality_code, stop_area FROM stops WHERE name=? AND locality=?")?
         .query_row([name, locality], |row| Ok(StopInfoQuery {
             id: row.get(0)?,
             name: row.get(1)?,
             locality_name: row.get(2)?,
             locality_code: row.get(3)?,
+            stop_area: row.get(4)?,
         }));
     result.inspect_err(|e| println!("{e}"))
 }
 
+/// Stops with a stance within `radius` metres of a point, nearest first.
+/// Stances are found with the spatial index, then filtered by their geodesic distance.
+pub fn get_nearby_stops(db: &Arc<DBPool>, lat: f64, lon: f64, radius: f64, limit: usize) -> rusqlite::Result<Vec<NearbyStop>> {
+    let lat_range = radius / 111_000.0;
+    let lon_range = lat_range / lat.to_radians().cos().max(0.01);
+    let origin = Point::new(lon, lat);
+    let stances = get_pool(db).prepare_cached(r#"
+        SELECT s.id, s.name, s.locality, s.locality_name, stances.code, stances.indicator, stances.lat, stances.long
+            FROM stances_rtree r
+                INNER JOIN stances ON stances.rowid = r.id
+                INNER JOIN main.stops s ON s.id = stances.stop
+            WHERE r.max_lat >= ?1 - ?3 AND r.min_lat <= ?1 + ?3 AND r.max_long >= ?2 - ?4 AND r.min_long <= ?2 + ?4"#)?
+        .query_map(params![lat, lon, lat_range, lon_range], |row| {
+            let (stance_lat, stance_long): (f64, f64) = (row.get(6)?, row.get(7)?);
+            Ok(NearbyStop {
+                id: row.get(0)?,
+                name: row.get(1)?,
+                locality_code: row.get(2)?,
+                locality_name: row.get(3)?,
+                stance: row.get(4)?,
+                indicator: row.get(5)?,
+                lat: stance_lat,
+                long: stance_long,
+                distance: origin.geodesic_distance(&Point::new(stance_long, stance_lat))
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(stances.into_iter()
+        .filter(|stance| stance.distance <= radius)
+        .sorted_by(|a, b| a.distance.total_cmp(&b.distance))
+        // sorted by distance, so this keeps each stop's nearest stance
+        .unique_by(|stance| stance.id)
+        .take(limit)
+        .collect())
+}
+
+#[derive(Serialize)]
+pub struct NearbyStop {
+    pub id: u64,
+    pub name: String,
+    pub locality_code: String,
+    pub locality_name: String,
+    /// Nearest stance of the stop
+    pub stance: String,
+    pub indicator: Option<String>,
+    pub lat: f64,
+    pub long: f64,
+    /// Geodesic distance to the nearest stance, in metres
+    pub distance: f64
+}
+
 #[derive(Serialize)]
 pub struct StopInfoQuery {
     pub id: u64,
     pub name: String,
     pub locality_name: String,
-    pub locality_code: String
+    pub locality_code: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop_area: Option<String>
 }
 
 pub fn get_stance_info(db: &Arc<DBPool>, id: u64) -> rusqlite::Result<Vec<StanceInfo>> {
@@ -664,10 +1015,16 @@ fn _get_services_between(db: &Arc<DBPool>, day: &DateTime<Utc>, from: &DateTime<
     let db = get_pool(db);
     let result = db.prepare_cached(r#"SELECT stop_times.trip_id,coalesce(stop_headsign,t.trip_headsign,'') as trip_headsign, departure_time,
                     s.indicator,r.route_short_name,a.agency_id as operator_id,a.agency_name as operator_name,stop_sequence as seq,
-                    (CASE WHEN l.show_then=0 THEN NULL ELSE tto.trip_headsign END) as then_headsign
+                    (CASE WHEN l.show_then=0 THEN NULL ELSE tto.trip_headsign END) as then_headsign,
+                    h.headway_secs as headway, sa.wheelchair_boarding as wheelchair, sa.parent_station as parent,
+                    (SELECT json_group_array(json_object('to', tr.to_stop_id, 'type', tr.transfer_type, 'min_time', tr.min_transfer_time))
+                        FROM transfers tr WHERE tr.from_stop_id = stop_times.stop_id AND tr.to_stop_id <> tr.from_stop_id
+                            AND (tr.from_trip_id IS NULL OR tr.from_trip_id = stop_times.trip_id)) as transfers
                 FROM stop_times
                     INNER JOIN trips t on stop_times.trip_id = t.trip_id
                     INNER JOIN stances s ON stop_times.stop_id = s.code
+                    LEFT OUTER JOIN main.headways h on h.trip_id = t.trip_id AND h.exact_times = 0
+                    LEFT OUTER JOIN main.stance_attributes sa on sa.code = stop_times.stop_id
                     INNER JOIN routes r on r.route_id = t.route_id
                     INNER JOIN main.agency a on r.agency_id = a.agency_id
                     LEFT OUTER JOIN main.calendar c on t.service_id = c.service_id
@@ -700,6 +1057,11 @@ fn _get_services_between(db: &Arc<DBPool>, day: &DateTime<Utc>, from: &DateTime<
             colour: "#777".to_string(),
             status: None,
             then_headsign: row.get(8).ok(),
+            headway: row.get(9)?,
+            wheelchair: row.get(10)?,
+            parent: row.get(11)?,
+            transfers: parse_transfers(row.get(12)),
+            occupancy: None,
         }))?.filter_map(Result::ok).collect_vec();
     Ok(result)
 }
@@ -734,7 +1096,17 @@ pub struct StopService {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub status: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub then_headsign: Option<String>
+    pub then_headsign: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub headway: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub wheelchair: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parent: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub transfers: Vec<StopTransfer>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub occupancy: Option<String>
 }
 
 fn serialize_as_hhmm<S>(date: &DateTime<Utc>, serializer: S) -> Result<S::Ok, S::Error>
@@ -747,7 +1119,7 @@ fn serialize_as_hhmm<S>(date: &DateTime<Utc>, serializer: S) -> Result<S::Ok, S:
 pub fn find_links(db: &Arc<DBPool>, trip_id: &str) -> Result<Connections, Box<dyn Error>> {
     let db = get_pool(db);
     let from = db.prepare_cached(r#"
-        SELECT t.trip_id, r.route_short_name, l.name, st.departure_time FROM links
+        SELECT t.trip_id, r.route_short_name, l.name, st.departure_time, links.confidence, links.observed FROM links
             INNER JOIN main.trips t on t.trip_id = links."from"
             INNER JOIN stop_times st on st.trip_id=t.trip_id AND st.stop_sequence=(SELECT min(stop_sequence) FROM stop_times WHERE trip_id=t.trip_id)
             INNER JOIN stances stance on stance.code=st.stop_id
@@ -757,7 +1129,7 @@ pub fn find_links(db: &Arc<DBPool>, trip_id: &str) -> Result<Connections, Box<dy
         WHERE "to"=?
     "#)?.get_linked_service(trip_id).ok();
     let to = db.prepare_cached(r#"
-        SELECT t.trip_id, r.route_short_name, t.trip_headsign, st.departure_time FROM links
+        SELECT t.trip_id, r.route_short_name, t.trip_headsign, st.departure_time, links.confidence, links.observed FROM links
             INNER JOIN main.trips t on t.trip_id = links."to"
             INNER JOIN main.routes r on t.route_id = r.route_id
             INNER JOIN stop_times st on st.trip_id=t.trip_id AND st.stop_sequence=(SELECT min(stop_sequence) FROM stop_times WHERE trip_id=t.trip_id)
@@ -780,6 +1152,8 @@ impl <'t> GetLinkedService for CachedStatement<'t> {
                 service_name: row.get(1)?,
                 location: row.get(2)?,
                 dep_time: DateTime::from_timestamp(row.get(3)?, 0).unwrap(),
+                confidence: row.get::<_, Option<f64>>(4)?.unwrap_or(1.0),
+                observed: row.get::<_, Option<bool>>(5)?.unwrap_or(false),
             }))
     }
 }
@@ -790,11 +1164,103 @@ pub struct LinkedService {
     pub service_name: String,
     pub location: String,
     #[serde(serialize_with = "serialize_as_hhmm")]
-    pub dep_time: DateTime<Utc>
+    pub dep_time: DateTime<Utc>,
+    /// How likely it is that the same vehicle runs both trips, from 0 to 1
+    #[serde(default = "default_confidence")]
+    pub confidence: f64,
+    /// Whether a vehicle has been seen running both trips, rather than the link being inferred from the timetable
+    #[serde(default)]
+    pub observed: bool
+}
+
+fn default_confidence() -> f64 {
+    1.0
 }
 
 #[derive(Serialize, Deserialize, Clone, Default)]
 pub struct Connections {
     pub from: Option<LinkedService>,
     pub to: Option<LinkedService>
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+
+    /// How trip candidates' stops, times and sequences were built from stop_times before trip_patterns
+    const GROUP_CONCAT_COLUMNS: &str = r#"
+        (SELECT group_concat(stop_id) FROM (SELECT stop_id FROM stop_times WHERE trip_id=trips.trip_id ORDER BY stop_sequence)) as route,
+        (SELECT group_concat(departure_time) FROM (SELECT departure_time FROM stop_times WHERE trip_id=trips.trip_id ORDER BY stop_sequence)) as times,
+        (SELECT group_concat(stop_sequence) FROM (SELECT stop_sequence FROM stop_times WHERE trip_id=trips.trip_id ORDER BY stop_sequence)) as seqs"#;
+
+    const GROUP_CONCAT_FILTERS: &str = r#"
+        INNER JOIN stop_times start on (start.trip_id=trips.trip_id AND start.stop_sequence=(SELECT min(stop_sequence) FROM stop_times WHERE trip_id=trips.trip_id))
+        INNER JOIN stop_times finish on (finish.trip_id=trips.trip_id AND finish.stop_sequence=(SELECT max(stop_sequence) FROM stop_times WHERE trip_id=trips.trip_id))
+        WHERE ((start_date <= :date AND end_date >= :date AND (validity & (1 << :day)) <> 0) OR exception_type=1)
+          AND NOT (exception_type IS NOT NULL AND exception_type = 2)
+          AND +start.departure_time <= :startTime AND +finish.departure_time >= :endTime"#;
+
+    /// Number of trip candidates found by a group_concat query, splitting the lists back into vectors as the old queries did
+    fn group_concat_query(db: &Arc<DBPool>, query: &str, date: &DateTime<Utc>, start_before: i64, end_after: i64, specifier: &str) -> usize {
+        get_pool(db).prepare(query).unwrap().query_map(named_params! {
+            ":date": u64::from_str(date.format("%Y%m%d").to_string().as_str()).unwrap(),
+            ":day": date.weekday().num_days_from_monday(),
+            ":startTime": start_before,
+            ":endTime": end_after,
+            ":specifier": specifier
+        }, |row| {
+            let route: Vec<String> = row.get::<_, String>("route")?.split(',').map(str::to_string).collect();
+            let times: Vec<i64> = row.get::<_, String>("times")?.split(',').map(|s| i64::from_str(s).unwrap()).collect();
+            let seqs: Vec<u32> = row.get::<_, String>("seqs")?.split(',').map(|s| u32::from_str(s).unwrap()).collect();
+            Ok((route, times, seqs))
+        }).unwrap().filter_map(|i| i.ok()).count()
+    }
+
+    /// Time trip candidate queries for every Lothian pattern and Passenger route against the group_concat queries they replaced.
+    /// Needs a built database at BUSES_DB_PATH with Lothian and Passenger mappings:
+    /// `cargo test --release --bin realtime trip_query_benchmark -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn trip_query_benchmark() {
+        let db = Arc::new(open_db());
+        let now = adjust_timestamp(&Utc::now());
+        let (start_before, end_after) = (zero_day(&(now + TimeDelta::hours(1))).timestamp(), zero_day(&(now - TimeDelta::hours(1))).timestamp());
+
+        let lothian: Vec<String> = get_lothian_patterns_tuples(&db).into_iter().map(|pattern| pattern.pattern).unique().collect();
+        let passenger: Vec<String> = get_pool(&db).prepare("SELECT DISTINCT route_id FROM trips INNER JOIN polar ON polar.gtfs = trips.trip_id WHERE direction IS NOT NULL").unwrap()
+            .query_map([], |row| row.get(0)).unwrap().filter_map(|route| route.ok()).collect();
+        let lothian_query = format!("SELECT trips.trip_id, :date as date, {GROUP_CONCAT_COLUMNS}
+            FROM polar
+                INNER JOIN main.trips trips on polar.gtfs = trips.trip_id
+                LEFT OUTER JOIN main.calendar c on c.service_id = trips.service_id
+                LEFT OUTER JOIN main.calendar_dates d on (d.service_id = c.service_id AND d.date=:date)
+                {GROUP_CONCAT_FILTERS} AND direction IS NULL AND polar=:specifier");
+        let passenger_query = format!("SELECT trips.trip_id, p.direction, :date as date, {GROUP_CONCAT_COLUMNS}
+            FROM trips
+                INNER JOIN routes r on r.route_id = trips.route_id
+                LEFT OUTER JOIN calendar c on c.service_id = trips.service_id
+                LEFT OUTER JOIN calendar_dates d on (d.service_id = c.service_id AND d.date=:date)
+                LEFT OUTER JOIN polar p on trips.trip_id = p.gtfs
+                {GROUP_CONCAT_FILTERS} AND r.route_id=:specifier");
+
+        for (name, specifiers, query, patterns_query) in [
+            ("Lothian", &lothian, &lothian_query, lothian_trip_query as fn(&Arc<DBPool>, &DateTime<Utc>, i64, i64, &str) -> Vec<TripCandidate>),
+            ("Passenger", &passenger, &passenger_query, passenger_trip_query)
+        ] {
+            let started = Instant::now();
+            let before: usize = specifiers.iter().map(|specifier| group_concat_query(&db, query, &now, start_before, end_after, specifier)).sum();
+            let before_time = started.elapsed();
+            // The first pass loads the memoized patterns, as the first matching pass after a database load does
+            let started = Instant::now();
+            let first: usize = specifiers.iter().map(|specifier| patterns_query(&db, &now, start_before, end_after, specifier).len()).sum();
+            let first_time = started.elapsed();
+            let started = Instant::now();
+            let after: usize = specifiers.iter().map(|specifier| patterns_query(&db, &now, start_before, end_after, specifier).len()).sum();
+            let after_time = started.elapsed();
+            println!("{name}: {} patterns/routes, group_concat {before} trips in {before_time:?}, trip_patterns {first} trips in {first_time:?} (first pass), {after} trips in {after_time:?}",
+                specifiers.len());
+            assert_eq!(before, after);
+        }
+    }
+}
@@ -5,16 +5,18 @@ use std::sync::Arc;
 use tokio::sync::mpsc::Sender;
 use tokio::{join, time};
 use crate::GTFSResponse;
+use crate::alerts::identify_alerts;
 
 use chrono::{TimeDelta, Utc};
 use tokio::sync::Mutex;
 use BusBoardsServer::config::{BBConfig, SourceURL};
-use crate::db::{DBPool, get_agency, get_route};
+use crate::db::{DBPool, get_affected_trip, get_agency, get_route};
 use crate::GTFSResponder::DISRUPTIONS;
 use crate::lothian::get_lothian_disruptions;
 use crate::passenger::get_passenger_disruptions;
-use crate::siri::{AffectedStopPoint, create_translated_string, download_siri, get_infolinks_url, Operators, SiriAffectedOperator};
-use crate::transit_realtime::{Alert, EntitySelector, TimeRange};
+use crate::siri::{AffectedStopPoint, Consequence, create_translated_string, download_siri, get_infolinks_url, Operators, SiriAffectedOperator};
+use crate::transit_realtime::{Alert, EntitySelector, TimeRange, TripDescriptor};
+use crate::util::{adjust_timestamp, gtfs_date};
 use crate::transit_realtime::alert::{Cause, Effect};
 
 
@@ -24,24 +26,25 @@ pub async fn disruptions_listener(tx: Sender<GTFSResponse>, config: Arc<BBConfig
         // Get provider disruptions
         let (bods_alerts, lothian_alerts, passenger_alerts) = join!(get_bods_disruptions(&db), get_lothian_disruptions(&db), get_passenger_disruptions(&db, &config, &passenger_alerts_cache));
 
-        // Flatten provider disruptions into one Vec
-        let mut alerts = Vec::with_capacity(bods_alerts.len() + lothian_alerts.len() + passenger_alerts.len());
-        alerts.extend(bods_alerts);
-        alerts.extend(lothian_alerts);
-        alerts.extend(passenger_alerts);
+        // Combine provider disruptions, preferring operators' own wording over BODS
+        let unreferenced = |alerts: Vec<Alert>| -> Vec<(Option<String>, Alert)> { alerts.into_iter().map(|alert| (None, alert)).collect() };
+        let (alerts, sources) = identify_alerts(vec![("lothian", unreferenced(lothian_alerts)), ("passenger", unreferenced(passenger_alerts)), ("bods", bods_alerts)]);
 
         // Publish to main feed
-        tx.send((DISRUPTIONS, HashMap::new(), alerts)).await.unwrap_or_else(|err| eprintln!("{}", err));
+        tx.send((DISRUPTIONS, HashMap::new(), alerts, sources)).await.unwrap_or_else(|err| eprintln!("{}", err));
 
         // Wait until next loop
         time::sleep(time::Duration::from_secs(60*15)).await
     }
 }
 
-/// Get BODS disruptions
-async fn get_bods_disruptions(db: &Arc<DBPool>) -> Vec<Alert> {
+/// Get BODS disruptions, with a reference for each alert made from the situation number
+async fn get_bods_disruptions(db: &Arc<DBPool>) -> Vec<(Option<String>, Alert)> {
     let siri = download_siri().await;
-    let alerts: Vec<Alert> = siri.siri.service_delivery.situation_exchange_delivery.situations.situations.iter().flat_map(|situation| {
+    let alerts: Vec<(Option<String>, Alert)> = siri.siri.service_delivery.situation_exchange_delivery.situations.situations.iter().flat_map(|situation| {
+        // A situation can have several consequences, each of which becomes an alert
+        let reference = |part: String| Some(situation.situation_number.trim()).filter(|number| !number.is_empty())
+            .map(|number| format!("{number}-{part}"));
         // Map time ranges to GTFS
         let time_ranges: Vec<TimeRange> = situation.validity_period.iter().map(|pw| {
             TimeRange {
@@ -54,12 +57,28 @@ async fn get_bods_disruptions(db: &Arc<DBPool>) -> Vec<Alert> {
         }).collect();
         // One generic alert for stops
         // Specific advice for each route
-        let mut alerts: Vec<Alert> = situation.consequences.consequences.iter().map(|con| {
-            // Route/operator-specific alert
-            Alert {
+        let mut alerts: Vec<(Option<String>, Alert)> = situation.consequences.consequences.iter().enumerate().map(|(i, con)| {
+            let routes: Vec<String> = con.affects.networks.affected_network.affected_line.iter().filter_map(|line| {
+                get_route(db, line.affected_operator.operator_ref.to_owned(), line.line_ref.as_ref().unwrap_or(&"".to_string()).to_owned()).ok()
+            }).collect();
+            // Journey-specific alert if the consequence names journeys, otherwise route/operator-specific alert
+            let trips = get_affected_trips(db, con, &routes);
+            let informed_entity = if !trips.is_empty() {
+                trips
+            } else {
+                routes.into_iter().map(|route| {
+                    EntitySelector { agency_id: None, route_id: Some(route), route_type: None, trip: None, stop_id: None, direction_id: None }
+                }).chain(
+                    compact_op(&con.affects.operators).map(|op| {
+                        let agency = get_agency(db, op.operator_ref.to_owned());
+                        EntitySelector { agency_id: agency.ok(), route_id: None, route_type: None, trip: None, stop_id: None, direction_id: None }
+                    })
+                ).collect()
+            };
+            (reference(i.to_string()), Alert {
                 active_period: time_ranges.clone(),
                 cause: Some(Cause::OtherCause as i32),
-                effect: Some(Effect::OtherEffect as i32),
+                effect: Some(condition_effect(con.condition.as_str()) as i32),
                 description_text: Some(create_translated_string(situation.description.to_string() + (if let Some(advice) = &con.advice { format!(" {}", advice.details) } else { "".to_string() }).as_str())),
                 tts_header_text: None,
                 tts_description_text: None,
@@ -69,18 +88,10 @@ async fn get_bods_disruptions(db: &Arc<DBPool>) -> Vec<Alert> {
                 cause_detail: None,
                 effect_detail: None,
                 header_text: Some(create_translated_string(situation.summary.to_string())),
-                informed_entity: con.affects.networks.affected_network.affected_line.iter().map(|line| {
-                    let route = get_route(db, line.affected_operator.operator_ref.to_owned(), line.line_ref.as_ref().unwrap_or(&"".to_string()).to_owned());
-                    EntitySelector { agency_id: None, route_id: route.ok(), route_type: None, trip: None, stop_id: None, direction_id: None }
-                }).chain(
-                    compact_op(&con.affects.operators).map(|op| {
-                        let agency = get_agency(db, op.operator_ref.to_owned());
-                        EntitySelector { agency_id: agency.ok(), route_id: None, route_type: None, trip: None, stop_id: None, direction_id: None }
-                    })
-                ).collect(),
+                informed_entity,
                 url: get_infolinks_url(&situation.info_links)
-            }
-        }).filter(|alert: &Alert| !alert.informed_entity.is_empty()).collect();
+            })
+        }).filter(|(_, alert)| !alert.informed_entity.is_empty()).collect();
         let stop_points: Vec<&AffectedStopPoint> = situation.consequences.consequences.iter().flat_map(
             |con| if let Some(sps) = con.affects.stop_points.as_ref() {
                 sps.stop_points.iter().collect::<Vec<_>>()
@@ -88,7 +99,7 @@ async fn get_bods_disruptions(db: &Arc<DBPool>) -> Vec<Alert> {
         ).collect();
         if !stop_points.is_empty() {
             // Generic alert for stops
-            alerts.push(Alert {
+            alerts.push((reference("stops".to_string()), Alert {
                 active_period: time_ranges.clone(),
                 cause: Some(Cause::OtherCause as i32),
                 effect: Some(Effect::OtherEffect as i32),
@@ -112,13 +123,55 @@ async fn get_bods_disruptions(db: &Arc<DBPool>) -> Vec<Alert> {
                 image_alternative_text: None,
                 cause_detail: None,
                 effect_detail: None
-            })
+            }))
         }
         alerts
     }).collect();
     alerts
 }
 
+/// Trips named by a consequence's affected journeys, matched to GTFS trips on the affected routes,
+/// or on the journey's own line if the consequence does not list the lines it affects
+fn get_affected_trips(db: &Arc<DBPool>, con: &Consequence, routes: &[String]) -> Vec<EntitySelector> {
+    let Some(journeys) = con.affects.vehicle_journeys.as_ref() else { return vec![] };
+    journeys.vehicle_journeys.iter().filter_map(|journey| {
+        let departure = journey.origin_aimed_departure_time.as_ref()?;
+        let journey_routes: Vec<String> = match (&journey.operator, &journey.line_ref) {
+            (Some(operator), Some(line_ref)) if routes.is_empty() => get_route(db, operator.operator_ref.to_owned(), line_ref.to_owned()).ok().into_iter().collect(),
+            _ => vec![]
+        };
+        let routes = if routes.is_empty() { journey_routes.as_slice() } else { routes };
+        let trip_id = get_affected_trip(db, routes, departure)?;
+        Some(EntitySelector {
+            agency_id: None,
+            route_id: None,
+            route_type: None,
+            trip: Some(TripDescriptor {
+                trip_id: Some(trip_id),
+                route_id: None,
+                direction_id: None,
+                start_time: None,
+                start_date: Some(gtfs_date(&adjust_timestamp(departure))),
+                schedule_relationship: None,
+            }),
+            stop_id: None,
+            direction_id: None
+        })
+    }).collect()
+}
+
+/// SIRI-SX consequence condition -> GTFS alert effect
+fn condition_effect(condition: &str) -> Effect {
+    match condition {
+        "cancelled" | "noService" => Effect::NoService,
+        "diverted" => Effect::Detour,
+        "delayed" | "disrupted" => Effect::SignificantDelays,
+        "altered" => Effect::ModifiedService,
+        "stopMoved" => Effect::StopMoved,
+        _ => Effect::OtherEffect
+    }
+}
+
 /// Convert Operators enum to an iterator of operators
 fn compact_op<'a>(obj: &'a Option<Operators>) -> Box<dyn Iterator<Item = &'a SiriAffectedOperator> + 'a> {
     return match obj {
@@ -5,6 +5,7 @@ use tokio::time;
 use BusBoardsServer::config::BBConfig;
 use crate::GTFSResponder::EMBER;
 use crate::{GTFSResponse, uw};
+use crate::alerts::identify_alerts;
 use crate::api::util::map_feed_entities;
 use crate::transit_realtime::{Alert, EntitySelector, FeedEntity, FeedMessage, TripDescriptor, TripUpdate, VehiclePosition};
 
@@ -60,7 +61,7 @@ pub async fn ember_listener(tx: Sender<GTFSResponse>, _: Arc<BBConfig>) {
         }).collect();
 
         // Separate alerts from vehicle data
-        let alerts: Vec<Alert> = entities.iter().filter_map(|e| e.alert.clone()).collect();
+        let (alerts, sources) = identify_alerts(vec![("ember", entities.iter().filter_map(|e| Some((Some(e.id.clone()).filter(|id| !id.is_empty()), e.alert.clone()?))).collect())]);
 
         // Partition into vehicle data, trip updates
         let (tus, mut vehicles): (Vec<FeedEntity>, Vec<FeedEntity>) = entities.iter_mut().map(|e| e.clone()).partition(|e| e.trip_update.is_some() && e.vehicle.is_none());
@@ -72,7 +73,7 @@ pub async fn ember_listener(tx: Sender<GTFSResponse>, _: Arc<BBConfig>) {
         });
 
         // Send to main feed
-        tx.send((EMBER, map_feed_entities(&vehicles), alerts)).await.unwrap_or_else(|err| eprintln!("{}", err));
+        tx.send((EMBER, map_feed_entities(&vehicles), alerts, sources)).await.unwrap_or_else(|err| eprintln!("{}", err));
 
         // Wait until next loop
         time::sleep(time::Duration::from_secs(30)).await
@@ -1,5 +1,6 @@
 use std::{fmt, fs};
 use std::cmp::min;
+use std::collections::HashMap;
 use std::fmt::{Display, Formatter};
 use std::fs::File;
 use std::io::BufReader;
@@ -33,8 +34,9 @@ use crate::api::util::map_feed_entities;
 use crate::db::{DBPool, get_first_trip};
 use crate::GTFSResponder::FIRST;
 use crate::GTFSResponse;
-use crate::transit_realtime::{FeedEntity, Position, TripDescriptor, VehiclePosition};
+use crate::transit_realtime::{FeedEntity, Position, TripDescriptor, VehicleDescriptor, VehiclePosition};
 use crate::transit_realtime::trip_descriptor::ScheduleRelationship::Scheduled;
+use crate::util::occupancy_status;
 
 const REGIONS_FILE: &str = "first-regions.json";
 
@@ -63,7 +65,7 @@ pub async fn first_listener(tx: Sender<GTFSResponse>, config: Arc<BBConfig>, db:
         }
 
         // Get vehicles and publish to main feed
-        tx.send((FIRST, map_feed_entities(&get_vehicles(&mut ws, &db, &config, &mut regions).await.unwrap_or(vec![])), vec![])).await.unwrap_or_else(|err| eprintln!("{}", err));
+        tx.send((FIRST, map_feed_entities(&get_vehicles(&mut ws, &db, &config, &mut regions).await.unwrap_or(vec![])), vec![], HashMap::new())).await.unwrap_or_else(|err| eprintln!("{}", err));
         // Wait until next loop
         time::sleep(Duration::from_secs(60)).await;
     }
@@ -154,10 +156,15 @@ fn map_to_feed_entity((v, trip_id): (&Member, String)) -> FeedEntity {
                 route_id: None,
                 direction_id: None,
                 start_time: Some(format!("{}:00", v.stops[0].time)),
-                start_date: Some(v.stops[0].date.to_string()),
+                start_date: Some(v.stops[0].date.replace('-', "")),
                 schedule_relationship: Some(i32::from(Scheduled)),
             }),
-            vehicle: None,
+            vehicle: Some(VehicleDescriptor {
+                id: Some(v.status.vehicle_id.to_string()),
+                label: None,
+                license_plate: None,
+                wheelchair_accessible: None,
+            }),
             position: Some(Position {
                 latitude: v.status.location.coordinates.y() as f32,
                 longitude: v.status.location.coordinates.x() as f32,
@@ -170,8 +177,8 @@ fn map_to_feed_entity((v, trip_id): (&Member, String)) -> FeedEntity {
             current_status: None,
             timestamp: Some(v.status.recorded_at_time.timestamp() as u64),
             congestion_level: None,
-            occupancy_status: None,
-            occupancy_percentage: None,
+            occupancy_status: v.status.occupancy.percentage().map(|pct| i32::from(occupancy_status(pct))),
+            occupancy_percentage: v.status.occupancy.percentage(),
             multi_carriage_details: vec![],
         }),
         alert: None,
@@ -461,6 +468,19 @@ pub struct Occupancy {
     types: Vec<Type>
 }
 
+impl Occupancy {
+    /// Percentage of the vehicle's total capacity (across seats, standing and wheelchair spaces) in use
+    fn percentage(&self) -> Option<u32> {
+        let capacity: isize = self.types.iter().map(|t| t.capacity.max(0)).sum();
+        let occupied: isize = self.types.iter().map(|t| t.occupied.max(0)).sum();
+        if capacity > 0 {
+            Some((occupied * 100 / capacity) as u32)
+        } else {
+            None
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Type {
     capacity: isize,
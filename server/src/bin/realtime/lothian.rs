@@ -1,7 +1,8 @@
+use std::collections::HashMap;
 use std::ops::Add;
 use std::str::FromStr;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use chrono::{DateTime, TimeDelta, Timelike, Utc};
 use chrono::serde::ts_seconds;
@@ -18,12 +19,12 @@ use tokio::time;
 
 use BusBoardsServer::config::BBConfig;
 use crate::api::util::map_feed_entities;
-use crate::bus_prediction::{assign_vehicles, get_trip_candidates, get_trip_info, TripCandidate, TripCandidateList, TripInfo};
-use crate::db::{DBPool, get_line_segments, get_lothian_patterns_tuples, get_lothian_route, get_lothian_timetabled_trips, get_operator_routes, lothian_trip_query, LothianDBPattern, reset_lothian, save_lothian_pattern_allocations};
+use crate::bus_prediction::{get_trip_candidates, get_trip_info, AssignmentHistory, PositionHistory, TripCandidate, TripCandidateList, TripInfo};
+use crate::db::{DBPool, get_lothian_patterns_tuples, get_lothian_route, get_lothian_timetabled_trips, get_operator_routes, lothian_trip_query, LothianDBPattern, reset_lothian, save_lothian_pattern_allocations};
 use crate::GTFSResponder::LOTHIAN;
 use crate::GTFSResponse;
 use crate::siri::create_translated_string;
-use crate::transit_realtime::{Alert, EntitySelector, FeedEntity, Position, TimeRange, TripDescriptor, VehiclePosition};
+use crate::transit_realtime::{Alert, EntitySelector, FeedEntity, Position, TimeRange, TripDescriptor, VehicleDescriptor, VehiclePosition};
 use crate::transit_realtime::vehicle_position::VehicleStopStatus;
 use crate::util::{adjust_timestamp, get_url, get_url_with_retries, gtfs_date, load_last_update, relative_to, save_last_update, URLParseError};
 
@@ -35,6 +36,8 @@ pub async fn lothian_listener(tx: Sender<GTFSResponse>, config: Arc<BBConfig>, d
 
     // Get route-directions to fetch
     let mut all_patterns = get_lothian_patterns_tuples(&db);
+    let history = AssignmentHistory::default();
+    let positions = PositionHistory::default();
 
     loop {
         // Perform route data updates on first run or at 3am at the configured interval
@@ -49,15 +52,18 @@ pub async fn lothian_listener(tx: Sender<GTFSResponse>, config: Arc<BBConfig>, d
         }
 
         // Match vehicles for each stored pattern
-        let p_map = |p: &LothianDBPattern| process_pattern(p.route.to_string(), p.pattern.to_string(), &http, &db);
+        let started = Instant::now();
+        let p_map = |p: &LothianDBPattern| process_pattern(p.route.to_string(), p.pattern.to_string(), &http, &db, &history, &positions);
         let entities = stream::iter(all_patterns.iter())
             .map(p_map)
             .buffer_unordered(10)
             .flat_map(stream::iter)
             .collect::<Vec<FeedEntity>>().await;
+        debug!("Matched {} Lothian vehicles in {:?}", entities.len(), started.elapsed());
+        positions.prune();
 
         // Publish to main feed
-        tx.send((LOTHIAN, map_feed_entities(&entities), vec![])).await.unwrap_or_else(|err| error!("Could not publish to main feed: {}", err));
+        tx.send((LOTHIAN, map_feed_entities(&entities), vec![], HashMap::new())).await.unwrap_or_else(|err| error!("Could not publish to main feed: {}", err));
 
         // Wait for next fetch
         time::sleep(Duration::from_secs(60)).await
@@ -79,7 +85,12 @@ fn to_feed_entity(trip: &TripInfo, vehicle: &LothianVehicle, candidate: &TripCan
                 start_date: Some(candidate.date.to_string()),
                 schedule_relationship: None,
             }),
-            vehicle: None,
+            vehicle: Some(VehicleDescriptor {
+                id: Some(vehicle.vehicle_id.to_string()),
+                label: None,
+                license_plate: None,
+                wheelchair_accessible: None,
+            }),
             position: Some(Position {
                 latitude: vehicle.latitude as f32,
                 longitude: vehicle.longitude as f32,
@@ -102,25 +113,25 @@ fn to_feed_entity(trip: &TripInfo, vehicle: &LothianVehicle, candidate: &TripCan
 }
 
 /// Match vehicles for a specific route direction
-async fn process_pattern(route: String, pattern: String, http: &Client, db: &Arc<DBPool>) -> Vec<FeedEntity> {
+async fn process_pattern(route: String, pattern: String, http: &Client, db: &Arc<DBPool>, history: &AssignmentHistory, positions: &PositionHistory) -> Vec<FeedEntity> {
     return match http.get(format!("https://tfeapp.com/api/website/vehicles_on_route.php?route_id={pattern}")).send().await {
         Ok(resp) => {
             return if resp.status().is_success() && let Ok(vehicles) = resp.json::<LothianLiveVehicles>().await {
                 let now_date = adjust_timestamp(&Utc::now());
                 // Get list of possible trips stored in GTFS that could match with a realtime vehicle
                 let candidates = get_trip_candidates(db, pattern.as_str(), &now_date, lothian_trip_query);
-                // Get route stance locations for vehicles to be matched to their nearest route line segment
-                let points = get_line_segments(db, route.to_string());
-                // For each vehicle, get a list of how delayed a vehicle would be on each trip at its current location
-                let mut closeness: Vec<TripCandidateList> = vehicles.vehicles.iter().enumerate().map(|(v_i, v)| {
+                // For each vehicle, get a list of how delayed a vehicle would be on each trip at its current location and heading
+                let closeness: Vec<TripCandidateList> = vehicles.vehicles.iter().enumerate().map(|(v_i, v)| {
+                    let vehicle = positions.observe(v.vehicle_id.as_str(), Point::new(v.longitude, v.latitude), Some(v.heading as f64));
                     TripCandidateList {
                         vehicle: v_i,
-                        cands: candidates.iter().enumerate().map(|(c_i, c)| get_trip_info(c, c_i, &points, &Point::new(v.longitude, v.latitude), &now_date)).collect(),
+                        cands: candidates.iter().enumerate().map(|(c_i, c)| get_trip_info(c, c_i, &vehicle, &now_date)).collect(),
                     }
                 }).filter(|v| !v.cands.is_empty()).collect();
 
-                // Match vehicles trip-by-trip using the most on-time matches first
-                let v: Vec<_> = assign_vehicles(&mut closeness, &candidates).iter().map(|(&i, trip)| to_feed_entity(trip, &vehicles.vehicles[i], &candidates[trip.candidate])).collect();
+                // Match vehicles to trips with the least total delay, keeping previous matches unless clearly wrong
+                let vehicle_ids = vehicles.vehicles.iter().map(|v| v.vehicle_id.to_string()).collect_vec();
+                let v: Vec<_> = history.assign(&closeness, &candidates, &vehicle_ids).iter().map(|(&i, trip)| to_feed_entity(trip, &vehicles.vehicles[i], &candidates[trip.candidate])).collect();
                 v
             } else {
                 vec![]
@@ -15,6 +15,11 @@ mod stagecoach;
 mod first;
 mod api;
 mod tfl;
+mod observations;
+mod route_line;
+mod alerts;
+mod notices;
+mod coverage;
 #[allow(dead_code)]
 mod tflapi;
 
@@ -29,10 +34,10 @@ use std::fs::File;
 use std::future::Future;
 use std::io::BufReader;
 use std::sync::{Arc, RwLock};
-use std::time::{SystemTime};
+use std::time::{Duration, SystemTime};
 use axum::extract::State;
 use axum::Router;
-use axum::routing::{get};
+use axum::routing::{get, post, put};
 use itertools::Itertools;
 use log::{debug, error, info};
 use nu_ansi_term::Color::{Green, Red};
@@ -44,18 +49,26 @@ use crate::bods::bods_listener;
 use crate::coaches::coaches_listener;
 use BusBoardsServer::config::{BBConfig, load_config};
 use BusBoardsServer::GTFSResponder;
+use crate::alerts::AlertSources;
+use crate::api::admin::{create_notice, expire_notice, list_notices, update_notice};
+use crate::api::alerts::get_alert_list;
+use crate::api::meta::get_meta;
 use crate::api::service::{get_service, OperatorColours, ServiceData};
-use crate::api::stop::{get_basic_stop_info, get_stop};
+use crate::api::stop::{get_basic_stop_info, get_nearby, get_stop};
+use crate::api::vehicle::get_vehicle;
+use crate::coverage::{coverage_updater, TrackingCoverage};
 use crate::db::{DBPool, open_db};
 use crate::disruptions::{disruptions_listener};
 use crate::ember::ember_listener;
 use crate::first::first_listener;
-use crate::GTFSResponder::{BODS, COACHES, DISRUPTIONS, EMBER, FIRST, LOTHIAN, PASSENGER, STAGECOACH};
+use crate::GTFSResponder::{BODS, COACHES, DISRUPTIONS, EMBER, FIRST, LOTHIAN, NOTICES, PASSENGER, STAGECOACH};
 use crate::lothian::lothian_listener;
+use crate::notices::{notices_listener, open_notices, Notices};
+use crate::observations::{open_observations, Observations};
 use crate::passenger::passenger_listener;
 use crate::siri::Operators;
 use crate::stagecoach::stagecoach_listener;
-use crate::transit_realtime::{Alert, FeedEntity, FeedMessage};
+use crate::transit_realtime::{FeedEntity, FeedMessage};
 
 #[cfg(not(target_env = "msvc"))]
 use tikv_jemallocator::Jemalloc;
@@ -70,17 +83,22 @@ pub mod transit_realtime {
     include!("transit_realtime.rs");
 }
 
-type GTFSResponse = (GTFSResponder, HashMap<String, FeedEntity>, Vec<Alert>);
+type GTFSResponse = (GTFSResponder, HashMap<String, FeedEntity>, Vec<FeedEntity>, AlertSources);
 type GTFSVehicles = papaya::HashMap<GTFSResponder, HashMap<String, FeedEntity>>;
-type GTFSAlerts = papaya::HashMap<GTFSResponder, Vec<Alert>>;
+type GTFSAlerts = papaya::HashMap<GTFSResponder, Vec<FeedEntity>>;
+type GTFSAlertSources = papaya::HashMap<GTFSResponder, AlertSources>;
 type RealtimeCache = papaya::HashMap<GTFSResponder, papaya::HashMap<String, ServiceData>>;
 
 struct GTFSState {
     vehicles: Arc<GTFSVehicles>,
     alerts: Arc<GTFSAlerts>,
+    alert_sources: Arc<GTFSAlertSources>,
     realtime_cache: Arc<RealtimeCache>,
     operators: OperatorColours,
-    db: Arc<DBPool>
+    db: Arc<DBPool>,
+    observations: Arc<Observations>,
+    notices: Arc<Notices>,
+    coverage: Arc<TrackingCoverage>
 }
 
 impl Default for GTFSState {
@@ -88,9 +106,13 @@ impl Default for GTFSState {
         GTFSState {
             vehicles: Arc::new(GTFSVehicles::new()),
             alerts: Arc::new(GTFSAlerts::new()),
+            alert_sources: Arc::new(GTFSAlertSources::new()),
             realtime_cache: Arc::new(RealtimeCache::new()),
             operators: serde_json::from_reader(BufReader::new(File::open("operators.json").unwrap())).unwrap(),
-            db: Arc::new(open_db())
+            db: Arc::new(open_db()),
+            observations: Arc::new(open_observations().expect("Could not open observations database")),
+            notices: Arc::new(open_notices().expect("Could not open notices database")),
+            coverage: Arc::new(TrackingCoverage::default())
         }
     }
 }
@@ -107,16 +129,28 @@ async fn main() {
     tokio::spawn(async move {
         while let Some(response) = rx.recv().await {
             debug!("Received from {}", response.0);
+            tokio::task::block_in_place(|| gtfs_ref.observations.record(response.0, &response.1));
             gtfs_ref.vehicles.pin().insert(response.0, response.1);
             gtfs_ref.alerts.pin().insert(response.0, response.2);
+            gtfs_ref.alert_sources.pin().insert(response.0, response.3);
             gtfs_ref.realtime_cache.pin().insert(response.0, papaya::HashMap::new());
         }
     });
 
-    // Initialise database
-    let db = open_db();
+    // Swap to a new database when the ingester replaces it
+    let reload_ref = gtfs_state.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(60));
+        loop {
+            interval.tick().await;
+            if reload_ref.db.reload_if_changed() {
+                reload_ref.realtime_cache.pin().clear();
+            }
+        }
+    });
+
     let arc_cfg = Arc::new(config);
-    let arc_db = Arc::new(db);
+    let arc_db = gtfs_state.db.clone();
 
     // Spawn data retrievers for each provider on a separate thread
     spawn_listener_db(&arc_cfg, BODS, &tx, &arc_db.clone(), bods_listener);
@@ -127,6 +161,10 @@ async fn main() {
     spawn_listener_db(&arc_cfg, STAGECOACH, &tx, &arc_db.clone(), stagecoach_listener);
     spawn_listener_db(&arc_cfg, COACHES, &tx, &arc_db.clone(), coaches_listener);
     spawn_listener_db(&arc_cfg, FIRST, &tx, &arc_db.clone(), first_listener);
+    spawn_listener(&arc_cfg, NOTICES, &tx, notices_listener);
+
+    // Flag trips of fully tracked operators which have no vehicle
+    tokio::spawn(coverage_updater(gtfs_state.clone(), arc_cfg.clone()));
 
     // Serve API endpoints
     let app = Router::new()
@@ -135,6 +173,13 @@ async fn main() {
         .route("/api/service", get(get_service))
         .route("/api/stop", get(get_stop))
         .route("/api/stop/preload", get(get_basic_stop_info))
+        .route("/api/stop/nearby", get(get_nearby))
+        .route("/api/meta", get(get_meta))
+        .route("/api/vehicle", get(get_vehicle))
+        .route("/api/alerts", get(get_alert_list))
+        .route("/api/admin/alerts", get(list_notices).post(create_notice))
+        .route("/api/admin/alerts/:id", put(update_notice))
+        .route("/api/admin/alerts/:id/expire", post(expire_notice))
         .with_state(gtfs_state)
         .layer(CompressionLayer::new());
 
@@ -176,24 +221,11 @@ fn generate_gtfs_message(state_lock: &Arc<GTFSState>) -> FeedMessage {
     let mut feed_msg: FeedMessage = Default::default();
     feed_msg.header.gtfs_realtime_version = "2.0".parse().unwrap();
     feed_msg.header.timestamp = Some(SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs());
-    {
-        let alert_entities: Vec<Vec<FeedEntity>> = state_lock.alerts.pin().iter().map(|(_, alerts)| {
-           return alerts.iter().map(|alert| {
-               FeedEntity {
-                   id: "".to_string(),
-                   is_deleted: None,
-                   trip_update: None,
-                   vehicle: None,
-                   alert: Some(alert.clone()),
-                   shape: None,
-               }
-           }).collect()
-        }).collect();
-        // Send combined feed with vehicles and alert entities
-        feed_msg.entity = state_lock.vehicles.pin().iter()
-            .flat_map(|(_, vehicles)| vehicles.values().cloned().collect_vec())
-            .chain(alert_entities.iter().cloned().flatten()).collect();
-    }
+    // Send combined feed with vehicles and alert entities
+    feed_msg.entity = state_lock.vehicles.pin().iter()
+        .flat_map(|(_, vehicles)| vehicles.values().cloned().collect_vec())
+        .chain(state_lock.alerts.pin().iter().flat_map(|(_, alerts)| alerts.iter().cloned().collect_vec()))
+        .collect();
 
     feed_msg
 }
@@ -0,0 +1,342 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Utc};
+use log::error;
+use rusqlite::{params, Connection, OptionalExtension};
+use tokio::sync::mpsc::Sender;
+use tokio::time;
+use uuid::Uuid;
+
+use BusBoardsServer::config::BBConfig;
+
+use crate::alerts::AlertSources;
+use crate::GTFSResponder::NOTICES;
+use crate::GTFSResponse;
+use crate::siri::create_translated_string;
+use crate::transit_realtime::{Alert, EntitySelector, FeedEntity, TimeRange, TripDescriptor};
+use crate::transit_realtime::alert::{Cause, Effect};
+
+const DEFAULT_NOTICES_PATH: &str = "notices.sqlite";
+
+/// Ad-hoc alerts posted by operators through the admin API.
+/// Kept in their own database so that they survive timetable imports
+pub struct Notices {
+    conn: Mutex<Connection>
+}
+
+/// An alert as posted to the admin API
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Notice {
+    pub header: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub url: Option<String>,
+    /// GTFS-RT effect name, e.g. DETOUR or NO_SERVICE
+    #[serde(default)]
+    pub effect: Option<String>,
+    #[serde(default)]
+    pub start: Option<DateTime<Utc>>,
+    /// Notices are published until they end, or until they are expired
+    #[serde(default)]
+    pub end: Option<DateTime<Utc>>,
+    pub informed_entity: Vec<NoticeSelector>
+}
+
+/// What a notice applies to, as in a GTFS-RT EntitySelector
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct NoticeSelector {
+    #[serde(default)]
+    pub agency_id: Option<String>,
+    #[serde(default)]
+    pub route_id: Option<String>,
+    #[serde(default)]
+    pub route_type: Option<i32>,
+    #[serde(default)]
+    pub trip_id: Option<String>,
+    #[serde(default)]
+    pub stop_id: Option<String>,
+    #[serde(default)]
+    pub direction_id: Option<u32>
+}
+
+/// A saved notice
+#[derive(Serialize)]
+pub struct StoredNotice {
+    pub id: String,
+    pub created: DateTime<Utc>,
+    pub updated: DateTime<Utc>,
+    #[serde(flatten)]
+    pub notice: Notice
+}
+
+impl Notice {
+    /// Reason the notice cannot be published, if any
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if self.header.trim().is_empty() {
+            return Err("A header is required")
+        }
+        if self.informed_entity.is_empty() {
+            return Err("At least one informed entity is required")
+        }
+        if self.informed_entity.iter().any(|entity| entity.agency_id.is_none() && entity.route_id.is_none()
+            && entity.route_type.is_none() && entity.trip_id.is_none() && entity.stop_id.is_none()) {
+            return Err("Each informed entity must select an agency, route, route type, trip or stop")
+        }
+        if self.effect.as_ref().is_some_and(|effect| Effect::from_str_name(effect).is_none()) {
+            return Err("Unknown effect")
+        }
+        if let (Some(start), Some(end)) = (self.start, self.end) && end < start {
+            return Err("The notice must end after it starts")
+        }
+        Ok(())
+    }
+
+    /// The notice ended at the given time, or when it was already due to end if that is earlier
+    pub fn expire(self, now: DateTime<Utc>) -> Notice {
+        Notice {
+            start: self.start.map(|start| start.min(now)),
+            end: Some(self.end.map_or(now, |end| end.min(now))),
+            ..self
+        }
+    }
+
+    fn to_alert(&self) -> Alert {
+        Alert {
+            active_period: if self.start.is_some() || self.end.is_some() {
+                vec![TimeRange {
+                    start: self.start.map(|start| start.timestamp() as u64),
+                    end: self.end.map(|end| end.timestamp() as u64)
+                }]
+            } else {
+                vec![]
+            },
+            informed_entity: self.informed_entity.iter().map(|entity| EntitySelector {
+                agency_id: entity.agency_id.clone(),
+                route_id: entity.route_id.clone(),
+                route_type: entity.route_type,
+                trip: entity.trip_id.as_ref().map(|trip_id| TripDescriptor {
+                    trip_id: Some(trip_id.to_string()),
+                    route_id: None,
+                    direction_id: None,
+                    start_time: None,
+                    start_date: None,
+                    schedule_relationship: None,
+                }),
+                stop_id: entity.stop_id.clone(),
+                direction_id: entity.direction_id
+            }).collect(),
+            cause: Some(Cause::OtherCause as i32),
+            effect: Some(self.effect.as_deref().and_then(Effect::from_str_name).unwrap_or(Effect::OtherEffect) as i32),
+            url: self.url.clone().map(create_translated_string),
+            header_text: Some(create_translated_string(self.header.to_string())),
+            description_text: self.description.clone().map(create_translated_string),
+            tts_header_text: None,
+            tts_description_text: None,
+            severity_level: None,
+            image: None,
+            image_alternative_text: None,
+            cause_detail: None,
+            effect_detail: None,
+        }
+    }
+}
+
+impl Notices {
+    /// Every notice, including expired ones, most recently updated first
+    pub fn list(&self) -> rusqlite::Result<Vec<StoredNotice>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare_cached("SELECT id, notice, created, updated FROM notices ORDER BY updated DESC")?;
+        let notices = stmt.query_map([], read_notice)?.filter_map(Result::ok).collect();
+        Ok(notices)
+    }
+
+    pub fn get(&self, id: &str) -> rusqlite::Result<Option<StoredNotice>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare_cached("SELECT id, notice, created, updated FROM notices WHERE id=?")?;
+        let notice = stmt.query_row([id], read_notice).optional();
+        notice
+    }
+
+    pub fn create(&self, notice: Notice) -> rusqlite::Result<StoredNotice> {
+        let now = Utc::now();
+        let id = Uuid::new_v4().to_string();
+        self.conn.lock().unwrap().execute("INSERT INTO notices (id, notice, created, updated) VALUES (?, ?, ?, ?)",
+            params![id, serde_json::to_string(&notice).unwrap(), now.timestamp(), now.timestamp()])?;
+        Ok(StoredNotice { id, created: now, updated: now, notice })
+    }
+
+    /// Replace a notice, returning None if it does not exist
+    pub fn update(&self, id: &str, notice: Notice) -> rusqlite::Result<Option<StoredNotice>> {
+        let now = Utc::now();
+        let changed = self.conn.lock().unwrap().execute("UPDATE notices SET notice=?, updated=? WHERE id=?",
+            params![serde_json::to_string(&notice).unwrap(), now.timestamp(), id])?;
+        if changed == 0 {
+            return Ok(None)
+        }
+        self.get(id)
+    }
+
+    /// Alert entities for the notices which have not ended
+    pub fn current_alerts(&self) -> (Vec<FeedEntity>, AlertSources) {
+        let now = Utc::now();
+        match self.list() {
+            Ok(notices) => {
+                let alerts: Vec<FeedEntity> = notices.iter()
+                    .filter(|stored| stored.notice.end.map_or(true, |end| end > now))
+                    .map(|stored| FeedEntity {
+                        id: format!("notice-{}", stored.id),
+                        is_deleted: None,
+                        trip_update: None,
+                        vehicle: None,
+                        alert: Some(stored.notice.to_alert()),
+                        shape: None,
+                    }).collect();
+                let sources = alerts.iter().map(|alert| (alert.id.clone(), vec!["notice".to_string()])).collect();
+                (alerts, sources)
+            }
+            Err(err) => {
+                error!("Could not read notices: {err}");
+                (vec![], AlertSources::new())
+            }
+        }
+    }
+}
+
+fn read_notice(row: &rusqlite::Row) -> rusqlite::Result<StoredNotice> {
+    let notice: String = row.get(1)?;
+    Ok(StoredNotice {
+        id: row.get(0)?,
+        notice: serde_json::from_str(notice.as_str())
+            .map_err(|err| rusqlite::Error::FromSqlConversionFailure(1, rusqlite::types::Type::Text, Box::new(err)))?,
+        created: DateTime::from_timestamp(row.get(2)?, 0).unwrap_or_default(),
+        updated: DateTime::from_timestamp(row.get(3)?, 0).unwrap_or_default()
+    })
+}
+
+/// Get the path of the notices database file
+fn get_notices_path() -> String {
+    std::env::var("BUSES_NOTICES_PATH").unwrap_or(DEFAULT_NOTICES_PATH.to_string())
+}
+
+/// Open (or create) the notices database
+pub fn open_notices() -> rusqlite::Result<Notices> {
+    let conn = Connection::open(get_notices_path())?;
+    conn.pragma_update(None, "journal_mode", "WAL")?;
+    init_notices(conn)
+}
+
+fn init_notices(conn: Connection) -> rusqlite::Result<Notices> {
+    conn.execute_batch(r#"
+        CREATE TABLE IF NOT EXISTS notices
+        (
+            id      TEXT PRIMARY KEY,
+            notice  TEXT NOT NULL,
+            created INTEGER NOT NULL,
+            updated INTEGER NOT NULL
+        );
+    "#)?;
+    Ok(Notices { conn: Mutex::new(conn) })
+}
+
+/// Republish notices regularly, so that they drop out of the feed once they end
+pub async fn notices_listener(tx: Sender<GTFSResponse>, _: Arc<BBConfig>) {
+    let notices = match open_notices() {
+        Ok(notices) => notices,
+        Err(err) => {
+            error!("Could not open notices database: {err}");
+            return
+        }
+    };
+    loop {
+        let (alerts, sources) = notices.current_alerts();
+        tx.send((NOTICES, HashMap::new(), alerts, sources)).await.unwrap_or_else(|err| error!("Could not publish to main feed: {}", err));
+        time::sleep(time::Duration::from_secs(60)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeDelta;
+
+    use super::*;
+
+    fn notice(header: &str, start: Option<DateTime<Utc>>, end: Option<DateTime<Utc>>) -> Notice {
+        Notice {
+            header: header.to_string(),
+            description: None,
+            url: None,
+            effect: Some("DETOUR".to_string()),
+            start,
+            end,
+            informed_entity: vec![NoticeSelector { route_id: Some("R1".to_string()), ..Default::default() }]
+        }
+    }
+
+    #[test]
+    fn notices_are_validated() {
+        let now = Utc::now();
+        assert!(notice("Diversion", None, None).validate().is_ok());
+        assert!(notice(" ", None, None).validate().is_err());
+        assert!(notice("Diversion", Some(now), Some(now - TimeDelta::hours(1))).validate().is_err());
+        assert!(Notice { effect: Some("NOT_AN_EFFECT".to_string()), ..notice("Diversion", None, None) }.validate().is_err());
+        assert!(Notice { informed_entity: vec![], ..notice("Diversion", None, None) }.validate().is_err());
+        assert!(Notice { informed_entity: vec![NoticeSelector { direction_id: Some(0), ..Default::default() }], ..notice("Diversion", None, None) }.validate().is_err());
+    }
+
+    #[test]
+    fn only_notices_which_have_not_ended_are_published() {
+        let notices = init_notices(Connection::open_in_memory().unwrap()).unwrap();
+        let now = Utc::now();
+        let open = notices.create(notice("Open", None, None)).unwrap();
+        let ending = notices.create(notice("Ending", Some(now - TimeDelta::hours(1)), Some(now + TimeDelta::hours(1)))).unwrap();
+        notices.create(notice("Ended", None, Some(now - TimeDelta::hours(1)))).unwrap();
+
+        let (alerts, sources) = notices.current_alerts();
+        let mut ids: Vec<_> = alerts.iter().map(|alert| alert.id.clone()).collect();
+        ids.sort();
+        let mut expected = vec![format!("notice-{}", open.id), format!("notice-{}", ending.id)];
+        expected.sort();
+        assert_eq!(ids, expected);
+        assert_eq!(sources.get(&format!("notice-{}", open.id)), Some(&vec!["notice".to_string()]));
+        assert_eq!(notices.list().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn notices_are_replaced() {
+        let notices = init_notices(Connection::open_in_memory().unwrap()).unwrap();
+        let stored = notices.create(notice("Diversion", None, None)).unwrap();
+
+        let updated = notices.update(stored.id.as_str(), notice("Updated diversion", None, None)).unwrap().unwrap();
+        assert_eq!(updated.id, stored.id);
+        assert_eq!(updated.created.timestamp(), stored.created.timestamp());
+        assert_eq!(notices.get(stored.id.as_str()).unwrap().unwrap().notice.header, "Updated diversion");
+        assert!(notices.update("missing", notice("Diversion", None, None)).unwrap().is_none());
+        assert!(notices.get("missing").unwrap().is_none());
+    }
+
+    #[test]
+    fn expired_notices_end_now() {
+        let now = Utc::now();
+        let (earlier, later) = (now - TimeDelta::hours(1), now + TimeDelta::hours(1));
+
+        let expired = notice("Open", None, None).expire(now);
+        assert_eq!((expired.start, expired.end), (None, Some(now)));
+        let expired = notice("Ending", Some(earlier), Some(later)).expire(now);
+        assert_eq!((expired.start, expired.end), (Some(earlier), Some(now)));
+        // notices which have not started yet become an empty period, and ended notices keep their end
+        let expired = notice("Upcoming", Some(later), None).expire(now);
+        assert_eq!((expired.start, expired.end), (Some(now), Some(now)));
+        let expired = notice("Ended", None, Some(earlier)).expire(now);
+        assert_eq!(expired.end, Some(earlier));
+    }
+
+    #[test]
+    fn expired_notices_are_no_longer_published() {
+        let notices = init_notices(Connection::open_in_memory().unwrap()).unwrap();
+        let stored = notices.create(notice("Diversion", None, None)).unwrap();
+        notices.update(stored.id.as_str(), stored.notice.expire(Utc::now() - TimeDelta::seconds(1))).unwrap();
+        assert!(notices.current_alerts().0.is_empty());
+    }
+}
@@ -0,0 +1,178 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+use chrono::{DateTime, TimeDelta, Utc};
+use log::error;
+use rusqlite::{params, Connection};
+
+use BusBoardsServer::GTFSResponder;
+use BusBoardsServer::observations::get_observations_path;
+
+use crate::transit_realtime::FeedEntity;
+use crate::transit_realtime::vehicle_position::VehicleStopStatus;
+
+/// Observations older than this are no longer used by the ingester
+const KEEP_DAYS: i64 = 28;
+
+/// Records the trips each vehicle runs and when it reaches each stop,
+/// so that the ingester can learn which trips are worked by the same vehicle and how long each segment takes
+pub struct Observations {
+    conn: Mutex<Connection>
+}
+
+impl Observations {
+    /// Record the vehicle, trip and stop progress of every entity in a listener's response
+    pub fn record(&self, responder: GTFSResponder, entities: &HashMap<String, FeedEntity>) {
+        if let Err(err) = self.try_record(responder, entities) {
+            error!("Could not record vehicle observations for {responder}: {err}");
+        }
+    }
+
+    fn try_record(&self, responder: GTFSResponder, entities: &HashMap<String, FeedEntity>) -> rusqlite::Result<()> {
+        let now = Utc::now();
+        let today = now.format("%Y%m%d").to_string();
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        {
+            let mut vehicle_stmt = tx.prepare_cached(r#"
+                INSERT INTO vehicle_trips (responder, vehicle_id, date, trip_id, first_seen, last_seen) VALUES (?1, ?2, ?3, ?4, ?5, ?5)
+                    ON CONFLICT DO UPDATE SET last_seen=excluded.last_seen"#)?;
+            // stopped_first and stopped_last are only set while the vehicle reports that it is stopped at the stop
+            let mut progress_stmt = tx.prepare_cached(r#"
+                INSERT INTO stop_progress (date, trip_id, stop_sequence, first_seen, last_seen, stopped_first, stopped_last)
+                    VALUES (?1, ?2, ?3, ?4, ?4, iif(?5, ?4, NULL), iif(?5, ?4, NULL))
+                    ON CONFLICT DO UPDATE SET first_seen=min(first_seen, excluded.first_seen), last_seen=max(last_seen, excluded.last_seen),
+                        stopped_first=coalesce(stopped_first, excluded.stopped_first),
+                        stopped_last=coalesce(excluded.stopped_last, stopped_last)"#)?;
+            for entity in entities.values() {
+                let Some(vehicle) = entity.vehicle.as_ref() else { continue };
+                let Some(trip) = vehicle.trip.as_ref() else { continue };
+                let Some(trip_id) = trip.trip_id.as_ref().filter(|id| !id.is_empty()) else { continue };
+                let date = trip.start_date.as_deref().map(normalise_date).unwrap_or_else(|| today.clone());
+                if let Some(vehicle_id) = vehicle.vehicle.as_ref().and_then(|v| v.id.as_ref()) {
+                    vehicle_stmt.execute(params![responder.to_string(), vehicle_id, date, trip_id, now.timestamp()])?;
+                }
+                if let Some(stop_sequence) = vehicle.current_stop_sequence {
+                    let seen = vehicle.timestamp.map_or(now.timestamp(), |timestamp| timestamp as i64);
+                    let stopped = vehicle.current_status == Some(VehicleStopStatus::StoppedAt.into());
+                    progress_stmt.execute(params![date, trip_id, stop_sequence, seen, stopped])?;
+                }
+            }
+        }
+        let cutoff = (now - TimeDelta::days(KEEP_DAYS)).format("%Y%m%d").to_string();
+        tx.execute("DELETE FROM vehicle_trips WHERE date < ?", [&cutoff])?;
+        tx.execute("DELETE FROM stop_progress WHERE date < ?", [&cutoff])?;
+        tx.commit()
+    }
+
+    /// Trips a vehicle has been seen running on or after the given date (YYYYMMDD), most recent first
+    pub fn vehicle_trips(&self, responder: GTFSResponder, vehicle_id: &str, since: &str) -> rusqlite::Result<Vec<ObservedTrip>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare_cached(r#"
+            SELECT trip_id, date, first_seen, last_seen FROM vehicle_trips
+                WHERE responder=? AND vehicle_id=? AND date >= ?
+                ORDER BY last_seen DESC"#)?;
+        let trips = stmt.query_map(params![responder.to_string(), vehicle_id, since], |row| Ok(ObservedTrip {
+            trip_id: row.get(0)?,
+            date: row.get(1)?,
+            first_seen: DateTime::from_timestamp(row.get(2)?, 0).unwrap_or_default(),
+            last_seen: DateTime::from_timestamp(row.get(3)?, 0).unwrap_or_default()
+        }))?.collect();
+        trips
+    }
+
+    /// Trips any vehicle has been seen running since the given time
+    pub fn seen_trips(&self, since: &DateTime<Utc>) -> rusqlite::Result<HashSet<String>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare_cached(r#"
+            SELECT trip_id FROM vehicle_trips WHERE date >= ?1 AND last_seen >= ?2
+            UNION
+            SELECT trip_id FROM stop_progress WHERE date >= ?1 AND last_seen >= ?2"#)?;
+        let trips = stmt.query_map(params![since.format("%Y%m%d").to_string(), since.timestamp()], |row| row.get(0))?.collect();
+        trips
+    }
+}
+
+/// A trip a vehicle was seen running, and when it was first and last seen on it
+pub struct ObservedTrip {
+    pub trip_id: String,
+    pub date: String,
+    pub first_seen: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>
+}
+
+/// Dates are stored as YYYYMMDD, as in GTFS, so that they can be compared as strings
+fn normalise_date(date: &str) -> String {
+    date.chars().filter(|c| c.is_ascii_digit()).collect()
+}
+
+/// Open (or create) the observations database
+pub fn open_observations() -> rusqlite::Result<Observations> {
+    let conn = Connection::open(get_observations_path())?;
+    conn.pragma_update(None, "journal_mode", "WAL")?;
+    init_observations(conn)
+}
+
+fn init_observations(conn: Connection) -> rusqlite::Result<Observations> {
+    conn.execute_batch(r#"
+        CREATE TABLE IF NOT EXISTS vehicle_trips
+        (
+            responder  TEXT,
+            vehicle_id TEXT,
+            date       TEXT,
+            trip_id    TEXT,
+            first_seen INTEGER,
+            last_seen  INTEGER,
+            PRIMARY KEY (responder, vehicle_id, date, trip_id)
+        );
+        CREATE INDEX IF NOT EXISTS vehicle_trips_date_index ON vehicle_trips (date);
+        CREATE TABLE IF NOT EXISTS stop_progress
+        (
+            date          TEXT,
+            trip_id       TEXT,
+            stop_sequence INTEGER,
+            first_seen    INTEGER,
+            last_seen     INTEGER,
+            stopped_first INTEGER,
+            stopped_last  INTEGER,
+            PRIMARY KEY (date, trip_id, stop_sequence)
+        );
+    "#)?;
+    Ok(Observations { conn: Mutex::new(conn) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transit_realtime::{TripDescriptor, VehicleDescriptor, VehiclePosition};
+    use BusBoardsServer::GTFSResponder::FIRST;
+
+    /// Vehicle on a trip, with the start date in the given format
+    fn entity(vehicle_id: &str, trip_id: &str, start_date: String) -> FeedEntity {
+        FeedEntity {
+            id: vehicle_id.to_string(),
+            vehicle: Some(VehiclePosition {
+                trip: Some(TripDescriptor { trip_id: Some(trip_id.to_string()), start_date: Some(start_date), ..Default::default() }),
+                vehicle: Some(VehicleDescriptor { id: Some(vehicle_id.to_string()), ..Default::default() }),
+                current_stop_sequence: Some(3),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn dashed_start_dates_are_kept() {
+        // First reports start dates as YYYY-MM-DD
+        let observations = init_observations(Connection::open_in_memory().unwrap()).unwrap();
+        let today = Utc::now();
+        let entities = HashMap::from([("F1".to_string(), entity("F1", "T1", today.format("%Y-%m-%d").to_string()))]);
+        observations.record(FIRST, &entities);
+
+        let trips = observations.vehicle_trips(FIRST, "F1", (today - TimeDelta::days(1)).format("%Y%m%d").to_string().as_str()).unwrap();
+        assert_eq!(trips.len(), 1);
+        assert_eq!(trips[0].trip_id, "T1");
+        assert_eq!(trips[0].date, today.format("%Y%m%d").to_string());
+        assert!(observations.seen_trips(&(today - TimeDelta::hours(1))).unwrap().contains("T1"));
+    }
+}
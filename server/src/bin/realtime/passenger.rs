@@ -2,6 +2,7 @@ use std::collections::HashMap;
 use std::ops::Add;
 use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Instant;
 
 use chrono::{DateTime, DurationRound, TimeDelta, Timelike, Utc};
 use config::Map;
@@ -16,13 +17,13 @@ use tokio::sync::mpsc::Sender;
 use tokio::sync::Mutex;
 use tokio::time;
 
-use bus_prediction::{assign_vehicles, get_trip_candidates};
+use bus_prediction::get_trip_candidates;
 use BusBoardsServer::config::{BBConfig, OperatorName, PassengerSource, SourceURL};
 
 use crate::{bus_prediction, GTFSResponse};
 use crate::api::util::map_feed_entities;
-use crate::bus_prediction::{TripCandidate, TripCandidateList, TripInfo};
-use crate::db::{DBPool, get_line_segments, get_operator_routes, get_passenger_route_trips, get_route_id, passenger_trip_query, PassengerRouteTrip, reset_passenger, RouteID, RouteName, save_passenger_trip_allocations};
+use crate::bus_prediction::{AssignmentHistory, PositionHistory, TripCandidate, TripCandidateList, TripInfo};
+use crate::db::{DBPool, get_operator_routes, get_passenger_route_trips, get_route_id, passenger_trip_query, PassengerRouteTrip, reset_passenger, RouteID, RouteName, save_passenger_trip_allocations};
 use crate::GTFSResponder::PASSENGER;
 use crate::siri::create_translated_string;
 use crate::transit_realtime::{Alert, EntitySelector, FeedEntity, Position, TimeRange, TripDescriptor, VehicleDescriptor, VehiclePosition};
@@ -34,6 +35,8 @@ const DIRECTIONS: [&str; 2] = ["inbound", "outbound"];
 
 pub async fn passenger_listener(tx: Sender<GTFSResponse>, config: Arc<BBConfig>, db: Arc<DBPool>) {
     let mut update_time = load_last_update(UPDATE_FILE);
+    let history = AssignmentHistory::default();
+    let positions = PositionHistory::default();
     loop {
         // Perform route updates on first run or at 2am on each interval
         if update_time.add(TimeDelta::days(config.update_interval_days as i64)) < Utc::now() {
@@ -46,11 +49,14 @@ pub async fn passenger_listener(tx: Sender<GTFSResponse>, config: Arc<BBConfig>,
         }
 
         // Get data for each operator
-        let entities_stream = stream::iter(config.passenger.iter()).then(|s| get_source_vehicles(s, &db));
+        let started = Instant::now();
+        let entities_stream = stream::iter(config.passenger.iter()).then(|s| get_source_vehicles(s, &db, &history, &positions));
         let entities = entities_stream.collect::<Vec<Vec<FeedEntity>>>().await.concat();
+        debug!("Matched {} Passenger vehicles in {:?}", entities.len(), started.elapsed());
+        positions.prune();
 
         // Publish to main feed
-        tx.send((PASSENGER, map_feed_entities(&entities), vec![])).await.unwrap_or_else(|err| error!("Could not publish to main feed: {}", err));
+        tx.send((PASSENGER, map_feed_entities(&entities), vec![], HashMap::new())).await.unwrap_or_else(|err| error!("Could not publish to main feed: {}", err));
 
         // Wait for next loop
         time::sleep(time::Duration::from_secs(60)).await
@@ -64,7 +70,7 @@ pub async fn get_passenger_disruptions(db: &Arc<DBPool>, config: &Arc<BBConfig>,
 }
 
 /// Get realtime data for a given Passenger operator(s) feed
-pub async fn get_source_vehicles((url, operators): (&SourceURL, &Map<OperatorName, PassengerSource>), db: &Arc<DBPool>) -> Vec<FeedEntity> {
+pub async fn get_source_vehicles((url, operators): (&SourceURL, &Map<OperatorName, PassengerSource>), db: &Arc<DBPool>, history: &AssignmentHistory, positions: &PositionHistory) -> Vec<FeedEntity> {
     // Fetch feed vehicle data
     if let Ok(vehicles_resp) = reqwest::get(format!("{url}/network/vehicles")).await {
         let vehicles_resp_str = vehicles_resp.text().await.unwrap();
@@ -74,7 +80,7 @@ pub async fn get_source_vehicles((url, operators): (&SourceURL, &Map<OperatorNam
             // Get results for each operator the feed contains
             return vehicle_features.into_iter().group_by(|v| (v.properties.operator.to_string(), v.properties.line.to_string())).into_iter()
                 .filter(|((operator, _line), _)| operators.contains_key(&operator.to_lowercase()))
-                .flat_map(|source| process_line_vehicles(db, operators, source.0, source.1))
+                .flat_map(|source| process_line_vehicles(db, operators, history, positions, source.0, source.1))
                 .collect()
         } else {
             error!("Error getting vehicles for {url}: {}", vehicles_result.err().unwrap());
@@ -84,7 +90,7 @@ pub async fn get_source_vehicles((url, operators): (&SourceURL, &Map<OperatorNam
 }
 
 /// Map vehicles on a given route to GTFS
-pub fn process_line_vehicles<FeatureIterator>(db: &Arc<DBPool>, operators: &Map<OperatorName, PassengerSource>, (operator, line): (String, String), vehicles_iter: FeatureIterator) -> Vec<FeedEntity>
+pub fn process_line_vehicles<FeatureIterator>(db: &Arc<DBPool>, operators: &Map<OperatorName, PassengerSource>, history: &AssignmentHistory, positions: &PositionHistory, (operator, line): (String, String), vehicles_iter: FeatureIterator) -> Vec<FeedEntity>
 where FeatureIterator: Iterator<Item = VehiclesFeature> {
     // Get GTFS route ID for the given route
     let operator_data = operators.get(&operator.to_lowercase()).unwrap();
@@ -98,31 +104,32 @@ where FeatureIterator: Iterator<Item = VehiclesFeature> {
 
     // Get list of possible trips stored in GTFS that could match with a realtime vehicle
     let candidates = get_trip_candidates(db, route_id.as_str(), &now_date, passenger_trip_query);
-    // Get route stance locations for vehicles to be matched to their nearest route line segment
-    let points = get_line_segments(db, route_id);
-    // For each vehicle, get a list of how delayed a vehicle would be on each trip at its current location
-    let mut closeness: Vec<TripCandidateList> = vehicles.iter().enumerate()
-        .map(|(i, vehicle)| gather_direction_candidates(&now_date, &candidates, &points, i, vehicle))
+    let vehicle_ids = vehicles.iter().map(|v| format!("{}-{}", v.properties.operator, v.properties.vehicle)).collect_vec();
+    // For each vehicle, get a list of how delayed a vehicle would be on each trip at its current location and heading
+    let closeness: Vec<TripCandidateList> = vehicles.iter().enumerate()
+        .map(|(i, vehicle)| gather_direction_candidates(&now_date, &candidates, positions, &vehicle_ids[i], i, vehicle))
         .filter(|v| !v.cands.is_empty())
         .collect();
-    // Match vehicles trip-by-trip using the most on-time matches first
-    assign_vehicles(&mut closeness, &candidates).iter()
+    // Match vehicles to trips with the least total delay, keeping previous matches unless clearly wrong
+    history.assign(&closeness, &candidates, &vehicle_ids).iter()
         // then map to GTFS feed entities
         .map(|(&i, trip)| to_feed_entity(trip, &vehicles[i], &candidates)).collect()
 }
 
 /// Get expected delay and next stop for each vehicle-trip combination in the specified direction
-fn gather_direction_candidates<'a>(now_date: &DateTime<Utc>, candidates: &'a [TripCandidate], points: &'a Map<String, Point>, i: usize, vehicle: &'a VehiclesFeature) -> TripCandidateList {
+fn gather_direction_candidates<'a>(now_date: &DateTime<Utc>, candidates: &'a [TripCandidate], positions: &PositionHistory, vehicle_id: &str, i: usize, vehicle: &'a VehiclesFeature) -> TripCandidateList {
     let direction: u8 = if vehicle.properties.direction == "inbound" { 0 } else { 1 }; // map to the database's encoding for direction
+    let observation = positions.observe(vehicle_id, vehicle.geometry.coordinates, vehicle.properties.bearing.map(|bearing| bearing as f64));
     TripCandidateList {
         vehicle: i,
         cands: candidates.iter().enumerate().filter(|(_i, c)| c.direction == Some(direction))
-            .map(|(i, c)| bus_prediction::get_trip_info(c, i, points, &vehicle.geometry.coordinates, now_date)).collect(),
+            .map(|(i, c)| bus_prediction::get_trip_info(c, i, &observation, now_date)).collect(),
     }
 }
 
 /// Map vehicle-trip assignment to GTFS
 fn to_feed_entity(trip: &TripInfo, vehicle: &VehiclesFeature, candidates: &[TripCandidate]) -> FeedEntity {
+    let meta = vehicle.properties.meta.as_ref();
     FeedEntity {
         id: format!("{}-{}-{}", vehicle.properties.operator, vehicle.properties.line, vehicle.properties.vehicle),
         is_deleted: None,
@@ -136,13 +143,12 @@ fn to_feed_entity(trip: &TripInfo, vehicle: &VehiclesFeature, candidates: &[Trip
                 start_date: Some(candidates[trip.candidate].date.to_string()),
                 schedule_relationship: None,
             }),
-            vehicle: vehicle.properties.meta.as_ref().map(|meta| {
-                VehicleDescriptor {
-                    id: Some(vehicle.properties.vehicle.clone()),
-                    label: meta.name.clone(),
-                    license_plate: meta.number_plate.clone(),
-                    wheelchair_accessible: meta.wheelchair_capacity.map(|num| num.max(1) as i32),
-                }
+            vehicle: Some(VehicleDescriptor {
+                // fleet numbers are only unique within an operator
+                id: Some(format!("{}-{}", vehicle.properties.operator, vehicle.properties.vehicle)),
+                label: meta.and_then(|meta| meta.name.clone()),
+                license_plate: meta.and_then(|meta| meta.number_plate.clone()),
+                wheelchair_accessible: meta.and_then(|meta| meta.wheelchair_capacity).map(|num| num.max(1) as i32),
             }),
             position: Some(Position {
                 latitude: vehicle.geometry.coordinates.y() as f32,
@@ -0,0 +1,255 @@
+use geo_types::{Coord, LineString, Point};
+
+/// Metres per degree of latitude
+const METRES_PER_DEGREE: f64 = 111_320.0;
+/// Headings within this many degrees of the path agree with it, allowing for GPS noise and corners
+const BEARING_TOLERANCE: f64 = 45.0;
+/// When choosing where a vehicle is on the path, each degree of heading disagreement beyond the tolerance counts as this many metres away
+const METRES_PER_DEGREE_MISMATCH: f64 = 2.0;
+/// Vehicles seen further back along the path than an earlier position by more than this (in metres) have moved backwards
+const BACKTRACK_TOLERANCE: f64 = 100.0;
+/// When choosing where a vehicle is on the path, moving backwards counts as this many metres away
+const BACKTRACK_METRES: f64 = 500.0;
+
+/// The path a trip takes, with the distance along it of each of the trip's stops.
+/// Coordinates are projected to metres around the start of the path, which is accurate enough over the length of a bus route
+pub struct RouteLine {
+    origin: Coord<f64>,
+    /// Metres per degree of longitude at the origin
+    x_scale: f64,
+    path: Vec<Coord<f64>>,
+    /// Distance along the path to each vertex
+    distances: Vec<f64>,
+    /// Distance along the path to each stop
+    stop_distances: Vec<f64>
+}
+
+/// How far a vehicle is along a trip
+pub struct RouteProgress {
+    /// Index of the last stop the vehicle has passed
+    pub segment: usize,
+    /// Fraction of the way from that stop to the next
+    pub pct: f64,
+    /// Degrees by which the vehicle's heading disagrees with the path, beyond the tolerance
+    pub bearing_mismatch: f64,
+    /// Whether the vehicle is further back along the path than its earlier position
+    pub backtracked: bool
+}
+
+/// Closest point on a path to a vehicle or stop
+struct PathPoint {
+    along: f64,
+    segment: usize,
+    bearing_mismatch: f64,
+    backtracked: bool
+}
+
+impl RouteLine {
+    /// Build from the trip's shape, or from straight lines between its stops if it has no shape.
+    /// Stops without a known location take the position of the stop before them
+    pub fn new(shape: Option<LineString<f64>>, stops: &[Option<Point<f64>>]) -> RouteLine {
+        let vertices = shape.map(|line| line.0).filter(|line| line.len() >= 2)
+            .unwrap_or_else(|| stops.iter().flatten().map(|stop| stop.0).collect());
+        let origin = vertices.first().copied().unwrap_or(Coord { x: 0.0, y: 0.0 });
+        let mut line = RouteLine {
+            origin,
+            x_scale: METRES_PER_DEGREE * origin.y.to_radians().cos(),
+            path: Vec::with_capacity(vertices.len()),
+            distances: Vec::with_capacity(vertices.len()),
+            stop_distances: Vec::with_capacity(stops.len())
+        };
+        line.path = vertices.iter().map(|vertex| line.project(vertex)).collect();
+        let mut distance = 0.0;
+        for (i, vertex) in line.path.iter().enumerate() {
+            if i > 0 {
+                distance += length(line.path[i - 1], *vertex);
+            }
+            line.distances.push(distance);
+        }
+
+        // Match stops in order, so a stop is not placed on a later pass of a looping route
+        let mut from_segment = 0;
+        let mut last_distance = 0.0;
+        for stop in stops {
+            if let Some(stop) = stop {
+                let closest = line.closest(line.project(&stop.0), from_segment, None, None);
+                from_segment = closest.segment;
+                last_distance = closest.along.max(last_distance);
+            }
+            line.stop_distances.push(last_distance);
+        }
+        line
+    }
+
+    /// Locate a vehicle along the route, between the two stops either side of its closest point on the path.
+    /// Where the path passes the vehicle more than once, its heading (in degrees clockwise from north) and an earlier position
+    /// are used to choose the part of the path it is travelling along
+    pub fn locate(&self, loc: &Point<f64>, heading: Option<f64>, earlier: Option<&Point<f64>>) -> RouteProgress {
+        let previous = earlier.map(|earlier| self.closest(self.project(&earlier.0), 0, None, None).along);
+        let PathPoint { along, bearing_mismatch, backtracked, .. } = self.closest(self.project(&loc.0), 0, heading, previous);
+        if self.stop_distances.len() < 2 {
+            return RouteProgress { segment: 0, pct: 0.0, bearing_mismatch, backtracked }
+        }
+        let next_stop = self.stop_distances.iter().position(|distance| *distance > along)
+            .unwrap_or(self.stop_distances.len() - 1).max(1);
+        let (from, to) = (self.stop_distances[next_stop - 1], self.stop_distances[next_stop]);
+        let pct = if to > from { ((along - from) / (to - from)).clamp(0.0, 1.0) } else { 0.0 };
+        RouteProgress { segment: next_stop - 1, pct, bearing_mismatch, backtracked }
+    }
+
+    fn project(&self, coord: &Coord<f64>) -> Coord<f64> {
+        Coord {
+            x: (coord.x - self.origin.x) * self.x_scale,
+            y: (coord.y - self.origin.y) * METRES_PER_DEGREE
+        }
+    }
+
+    /// Closest point on the path to a projected point, searching from the given segment onwards.
+    /// Disagreeing with the heading or being behind the previous distance along the path count against a segment
+    fn closest(&self, point: Coord<f64>, from_segment: usize, heading: Option<f64>, previous: Option<f64>) -> PathPoint {
+        let mut best = PathPoint { along: 0.0, segment: from_segment, bearing_mismatch: 0.0, backtracked: false };
+        let mut best_cost = f64::INFINITY;
+        for segment in from_segment..self.path.len().saturating_sub(1) {
+            let (start, end) = (self.path[segment], self.path[segment + 1]);
+            let (dx, dy) = (end.x - start.x, end.y - start.y);
+            let length_squared = dx * dx + dy * dy;
+            let t = if length_squared > 0.0 {
+                (((point.x - start.x) * dx + (point.y - start.y) * dy) / length_squared).clamp(0.0, 1.0)
+            } else {
+                0.0
+            };
+            let along = self.distances[segment] + t * length_squared.sqrt();
+            let bearing_mismatch = heading.filter(|_| length_squared > 0.0)
+                .map_or(0.0, |heading| (bearing_difference(heading, dx.atan2(dy).to_degrees()) - BEARING_TOLERANCE).max(0.0));
+            let backtracked = previous.is_some_and(|previous| along < previous - BACKTRACK_TOLERANCE);
+            let cost = length(Coord { x: start.x + t * dx, y: start.y + t * dy }, point)
+                + bearing_mismatch * METRES_PER_DEGREE_MISMATCH
+                + if backtracked { BACKTRACK_METRES } else { 0.0 };
+            if cost < best_cost {
+                best = PathPoint { along, segment, bearing_mismatch, backtracked };
+                best_cost = cost;
+            }
+        }
+        best
+    }
+}
+
+fn length(a: Coord<f64>, b: Coord<f64>) -> f64 {
+    (a.x - b.x).hypot(a.y - b.y)
+}
+
+/// Smallest angle between two bearings, in degrees
+fn bearing_difference(a: f64, b: f64) -> f64 {
+    let difference = (a - b).rem_euclid(360.0);
+    difference.min(360.0 - difference)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Out along the equator for 0.01° (about 1113m), north by 0.001°, then back again
+    const OUT_AND_BACK: [(f64, f64); 4] = [(0.0, 0.0), (0.01, 0.0), (0.01, 0.001), (0.0, 0.001)];
+
+    fn route(shape: &[(f64, f64)], stops: &[(f64, f64)]) -> RouteLine {
+        RouteLine::new(Some(LineString::from(shape.to_vec())), &stops.iter().map(|stop| Some(Point::from(*stop))).collect::<Vec<_>>())
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() < 1e-6, "{actual} != {expected}");
+    }
+
+    #[test]
+    fn vehicles_are_projected_onto_the_closest_segment() {
+        let line = route(&[(0.0, 0.0), (0.01, 0.0), (0.01, 0.01)], &[(0.0, 0.0), (0.01, 0.0), (0.01, 0.01)]);
+        assert_close(line.stop_distances[1], 1113.2);
+        assert_close(line.stop_distances[2], 2226.4);
+
+        let progress = line.locate(&Point::new(0.005, -0.001), None, None);
+        assert_eq!(progress.segment, 0);
+        assert_close(progress.pct, 0.5);
+
+        let progress = line.locate(&Point::new(0.011, 0.0025), None, None);
+        assert_eq!(progress.segment, 1);
+        assert_close(progress.pct, 0.25);
+
+        let progress = line.locate(&Point::new(0.02, 0.02), None, None);
+        assert_eq!(progress.segment, 1);
+        assert_close(progress.pct, 1.0);
+    }
+
+    #[test]
+    fn stops_are_joined_without_a_shape() {
+        let line = RouteLine::new(None, &[Some(Point::new(0.0, 0.0)), None, Some(Point::new(0.01, 0.0))]);
+        assert_close(line.stop_distances[0], 0.0);
+        assert_close(line.stop_distances[1], 0.0);
+        assert_close(line.stop_distances[2], 1113.2);
+    }
+
+    #[test]
+    fn heading_chooses_the_direction_of_travel() {
+        let line = route(&OUT_AND_BACK, &OUT_AND_BACK);
+        // closer to the outbound side, but heading west
+        let loc = Point::new(0.005, 0.0004);
+
+        let progress = line.locate(&loc, None, None);
+        assert_eq!(progress.segment, 0);
+
+        let progress = line.locate(&loc, Some(270.0), None);
+        assert_eq!(progress.segment, 2);
+        assert_close(progress.pct, 0.5);
+        assert_close(progress.bearing_mismatch, 0.0);
+
+        let progress = line.locate(&loc, Some(90.0), None);
+        assert_eq!(progress.segment, 0);
+        assert_close(progress.bearing_mismatch, 0.0);
+    }
+
+    #[test]
+    fn heading_mismatch_is_reported_beyond_the_tolerance() {
+        let line = route(&OUT_AND_BACK[..2], &OUT_AND_BACK[..2]);
+        assert_close(line.locate(&Point::new(0.005, 0.0), Some(90.0 + BEARING_TOLERANCE), None).bearing_mismatch, 0.0);
+        assert_close(line.locate(&Point::new(0.005, 0.0), Some(180.0), None).bearing_mismatch, 90.0 - BEARING_TOLERANCE);
+        assert_close(line.locate(&Point::new(0.005, 0.0), Some(270.0), None).bearing_mismatch, 180.0 - BEARING_TOLERANCE);
+    }
+
+    #[test]
+    fn earlier_position_avoids_moving_backwards() {
+        let line = route(&OUT_AND_BACK, &OUT_AND_BACK);
+        let earlier = Point::new(0.009, 0.0008);
+
+        let progress = line.locate(&Point::new(0.005, 0.0004), None, Some(&earlier));
+        assert_eq!(progress.segment, 2);
+        assert!(!progress.backtracked);
+
+        // small movements backwards are GPS noise
+        let progress = line.locate(&Point::new(0.0095, 0.001), None, Some(&earlier));
+        assert!(!progress.backtracked);
+    }
+
+    #[test]
+    fn backtracking_is_reported_without_an_alternative() {
+        let line = route(&OUT_AND_BACK[..2], &OUT_AND_BACK[..2]);
+        let progress = line.locate(&Point::new(0.002, 0.0), None, Some(&Point::new(0.008, 0.0)));
+        assert_eq!(progress.segment, 0);
+        assert_close(progress.pct, 0.2);
+        assert!(progress.backtracked);
+    }
+
+    #[test]
+    fn looping_shapes_place_stops_in_order() {
+        // round a square and back through the start, then south
+        let shape = [(0.0, 0.0), (0.01, 0.0), (0.01, 0.01), (0.0, 0.01), (0.0, 0.0), (0.0, -0.01)];
+        let stops = [(0.0, 0.0), (0.01, 0.005), (0.005, 0.01), (0.0, 0.005), (0.0, 0.0), (0.0, -0.01)];
+        let line = route(&shape, &stops);
+        for (distance, expected) in line.stop_distances.iter().zip([0.0, 1669.8, 2783.0, 3896.2, 4452.8, 5566.0]) {
+            assert!((distance - expected).abs() < 1e-3, "{distance} != {expected}");
+        }
+
+        let start = Point::new(0.0, 0.0);
+        assert_eq!(line.locate(&start, None, None).segment, 0);
+        assert_eq!(line.locate(&start, Some(90.0), None).segment, 0);
+        assert_eq!(line.locate(&start, Some(180.0), None).segment, 4);
+        assert_eq!(line.locate(&start, None, Some(&Point::new(0.0, 0.005))).segment, 4);
+    }
+}
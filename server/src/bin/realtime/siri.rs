@@ -1,3 +1,4 @@
+use std::error::Error;
 use std::io::Read;
 
 use chrono::{DateTime, Utc};
@@ -149,6 +150,23 @@ pub struct Affects {
     pub networks: Networks,
     pub stop_points: Option<StopPoints>,
     pub operators: Option<Operators>,
+    pub vehicle_journeys: Option<VehicleJourneys>,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub struct VehicleJourneys {
+    #[serde(rename = "AffectedVehicleJourney", default)]
+    pub vehicle_journeys: Vec<AffectedVehicleJourney>
+}
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub struct AffectedVehicleJourney {
+    pub vehicle_journey_ref: Option<String>,
+    pub operator: Option<SiriAffectedOperator>,
+    pub line_ref: Option<String>,
+    pub origin_aimed_departure_time: Option<DateTime<Utc>>,
 }
 
 #[derive(Serialize, Deserialize, Default, Debug)]
@@ -232,6 +250,52 @@ pub struct Source {
     pub time_of_communication: String,
 }
 
+/// SIRI-VM vehicle positions - only the fields needed to add occupancy to the BODS GTFS-RT feed
+#[derive(Serialize, Deserialize, Default, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub struct SiriVm {
+    pub service_delivery: VmServiceDelivery,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub struct VmServiceDelivery {
+    pub vehicle_monitoring_delivery: VehicleMonitoringDelivery,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub struct VehicleMonitoringDelivery {
+    #[serde(default)]
+    pub vehicle_activity: Vec<VehicleActivity>,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub struct VehicleActivity {
+    pub monitored_vehicle_journey: MonitoredVehicleJourney,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub struct MonitoredVehicleJourney {
+    pub operator_ref: Option<String>,
+    pub vehicle_ref: Option<String>,
+    /// full, seatsAvailable or standingAvailable
+    pub occupancy: Option<String>,
+}
+
+/// Download BODS SIRI-VM vehicle positions
+pub async fn download_siri_vm() -> Result<SiriVm, Box<dyn Error + Send + Sync>> {
+    let bytes = reqwest::get("https://data.bus-data.dft.gov.uk/avl/download/bulk_archive").await?.bytes().await?;
+    // the national archive is large, so unzip and parse it without holding up other tasks
+    tokio::task::spawn_blocking(move || -> Result<SiriVm, Box<dyn Error + Send + Sync>> {
+        let mut xml = Vec::new();
+        ZipArchive::new(std::io::Cursor::new(bytes))?.by_name("siri_vm.xml")?.read_to_end(&mut xml)?;
+        Ok(serde_xml_rust::from_reader(xml.as_slice()).map_err(|err| err.to_string())?)
+    }).await?
+}
+
 /// Download Siri disruptions data
 pub async fn download_siri() -> SiriSx {
     if let Ok(result) = reqwest::get("https://data.bus-data.dft.gov.uk/disruptions/download/bulk_archive").await {
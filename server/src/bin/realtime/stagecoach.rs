@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use std::str::FromStr;
 use std::sync::Arc;
 use chrono::{DateTime, Utc};
@@ -10,28 +11,31 @@ use tokio::sync::mpsc::Sender;
 use tokio::time;
 use BusBoardsServer::config::BBConfig;
 use crate::api::util::map_feed_entities;
-use crate::db::{DBPool, get_stagecoach_trip, get_line_segments};
+use crate::bus_prediction::PositionHistory;
+use crate::db::{DBPool, get_stagecoach_trip};
 use crate::GTFSResponder::{STAGECOACH};
 use crate::GTFSResponse;
-use crate::transit_realtime::{FeedEntity, Position, TripDescriptor, VehiclePosition};
+use crate::transit_realtime::{FeedEntity, Position, TripDescriptor, VehicleDescriptor, VehiclePosition};
 use crate::transit_realtime::trip_descriptor::ScheduleRelationship;
-use crate::util::{adjust_timestamp, f64_cmp, get_geo_linepoint_distance, gtfs_date, gtfs_time};
+use crate::util::{adjust_timestamp, gtfs_date, gtfs_time};
 
 pub async fn stagecoach_listener(tx: Sender<GTFSResponse>, config: Arc<BBConfig>, db: Arc<DBPool>) {
+    let positions = PositionHistory::default();
     loop {
         // Get entities for each Stagecoach operator
         let entities = stream::iter(config.stagecoach.regional_operators.iter())
-            .then(|(c, gtfs)| get_region(c.as_str(), gtfs.as_str(), &db)).collect::<Vec<Vec<FeedEntity>>>().await.concat();
+            .then(|(c, gtfs)| get_region(c.as_str(), gtfs.as_str(), &db, &positions)).collect::<Vec<Vec<FeedEntity>>>().await.concat();
+        positions.prune();
 
         // Send to main feed
-        tx.send((STAGECOACH, map_feed_entities(&entities), vec![])).await.unwrap_or_else(|err| error!("Could not publish to main feed: {}", err));
+        tx.send((STAGECOACH, map_feed_entities(&entities), vec![], HashMap::new())).await.unwrap_or_else(|err| error!("Could not publish to main feed: {}", err));
         // Wait for next loop
         time::sleep(time::Duration::from_secs(60)).await
     }
 }
 
 /// Map journeys for the given Stagecoach region
-pub async fn get_region(region: &str, gtfs: &str, db: &Arc<DBPool>) -> Vec<FeedEntity> {
+pub async fn get_region(region: &str, gtfs: &str, db: &Arc<DBPool>, positions: &PositionHistory) -> Vec<FeedEntity> {
     match reqwest::get(format!("https://api.stagecoach-technology.net/vehicle-tracking/v1/vehicles?services=:{region}:::")).await {
         Ok(resp) => {
             if resp.status().is_success() {
@@ -45,14 +49,10 @@ pub async fn get_region(region: &str, gtfs: &str, db: &Arc<DBPool>) -> Vec<FeedE
                                     sc.line_number.as_str(), sc.next_stop_code.as_str(), &adjust_timestamp(&sc.origin_std?)
                                 ).map(|trip| {
                                     let loc = Point::new(sc.longitude, sc.latitude);
-                                    let points = get_line_segments(db, trip.route_id.to_string());
                                     let route = &trip.trip_route;
                                     let seqs = &trip.trip_seqs;
-                                    let segments: Vec<geo_types::Line<f64>> = (0..route.len()-1).map(|i| {
-                                        geo_types::Line::new(points.get(&route[i]).copied().unwrap_or_default(), points.get(&route[i+1]).copied().unwrap_or_default())
-                                    }).collect();
-                                    let closest_segment = segments.iter().map(|s| get_geo_linepoint_distance(s, &loc))
-                                        .position_min_by(f64_cmp).unwrap_or(0);
+                                    let vehicle = positions.observe(format!("{}-{}", sc.regional_operator, sc.fleet_number).as_str(), loc, Some(sc.heading as f64));
+                                    let closest_segment = trip.line.locate(&loc, vehicle.heading, vehicle.earlier.as_ref()).segment;
                                     
                                     FeedEntity {
                                         id: sc.trip_id.to_string(),
@@ -67,7 +67,12 @@ pub async fn get_region(region: &str, gtfs: &str, db: &Arc<DBPool>) -> Vec<FeedE
                                                 start_date: Some(gtfs_date(&sc.origin_std.unwrap())),
                                                 schedule_relationship: Some(i32::from(if sc.cancelled { ScheduleRelationship::Canceled } else { ScheduleRelationship::Scheduled })),
                                             }),
-                                            vehicle: None,
+                                            vehicle: Some(VehicleDescriptor {
+                                                id: Some(sc.fleet_number.to_string()),
+                                                label: None,
+                                                license_plate: None,
+                                                wheelchair_accessible: None,
+                                            }),
                                             position: Some(Position {
                                                 latitude: sc.latitude as f32,
                                                 longitude: sc.longitude as f32,
@@ -12,6 +12,7 @@ use tokio::sync::mpsc::Sender;
 use tokio::time;
 use BusBoardsServer::config::BBConfig;
 use BusBoardsServer::GTFSResponder::TFL;
+use crate::alerts::identify_alerts;
 use crate::db::{DBPool, get_line_segments, get_route_id};
 use crate::{GTFSResponse, tflapi};
 use crate::passenger::ActivePeriod;
@@ -25,9 +26,10 @@ pub async fn tfl_listener(tx: Sender<GTFSResponse>, config: Arc<BBConfig>, db: A
         let alerts = get_tube_alerts(&db).await
             .inspect_err(|e| error!("{}", e))
             .unwrap_or(vec![]);
+        let (alerts, sources) = identify_alerts(vec![("tfl", alerts.into_iter().map(|alert| (None, alert)).collect())]);
         
         // Send to main feed
-        tx.send((TFL, HashMap::new(), alerts)).await.unwrap_or_else(|err| eprintln!("{}", err));
+        tx.send((TFL, HashMap::new(), alerts, sources)).await.unwrap_or_else(|err| eprintln!("{}", err));
         
         // Wait until next loop
         time::sleep(time::Duration::from_secs(60)).await
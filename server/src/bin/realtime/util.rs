@@ -21,6 +21,7 @@ use serde::de::{SeqAccess, Visitor};
 use tokio::time::sleep;
 use rand::{Rng, thread_rng};
 
+use crate::transit_realtime::vehicle_position::OccupancyStatus;
 use crate::util::URLParseError::{DownloadError, ParsingError, StatusCodeError};
 
 pub fn zero_day(date: &DateTime<Utc>) -> DateTime<Utc> {
@@ -155,4 +156,15 @@ pub fn haversine_closest_point(s: &Line<f64>, loc: &Point<f64>) -> Point<f64> {
 
 pub fn get_geo_linepoint_distance(s: &Line<f64>, loc: &Point<f64>) -> f64 {
     loc.geodesic_distance(&haversine_closest_point(s, loc))
-}
\ No newline at end of file
+}
+
+/// GTFS-RT occupancy status for the percentage of a vehicle's capacity in use
+pub fn occupancy_status(pct: u32) -> OccupancyStatus {
+    match pct {
+        0..=10 => OccupancyStatus::Empty,
+        11..=50 => OccupancyStatus::ManySeatsAvailable,
+        51..=75 => OccupancyStatus::FewSeatsAvailable,
+        76..=95 => OccupancyStatus::StandingRoomOnly,
+        _ => OccupancyStatus::Full
+    }
+}
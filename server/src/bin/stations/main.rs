@@ -1,6 +1,6 @@
 use std::error::Error;
 use spex::parsing::XmlReader;
-use BusBoardsServer::download_if_old;
+use BusBoardsServer::download_naptan_xml;
 
 const NAPTAN_NS: &str = "http://www.naptan.org.uk/";
 
@@ -9,7 +9,7 @@ fn main() -> Result<(), Box<dyn Error>> {
     let mut csv = csv::Writer::from_path("crs.csv")?;
     csv.write_record(&["ATCOCode", "CrsRef"])?;
 
-    let naptan_file = download_if_old("https://naptan.api.dft.gov.uk/v1/access-nodes?dataFormat=xml", "NaPTAN.xml")?;
+    let naptan_file = download_naptan_xml()?;
     let xml = XmlReader::parse_auto(naptan_file)?;
 
     xml.root().pre_ns(NAPTAN_NS).all("StopPoints").all("StopPoint")
@@ -16,13 +16,85 @@ pub struct BBConfig {
     pub stagecoach: StagecoachConfig,
     pub coaches: CoachesConfig,
     pub first: FirstConfig,
-    pub lothian: LothianConfig
+    pub lothian: LothianConfig,
+    pub gtfs: Vec<GTFSSource>
 }
 
 impl BBConfig {
     pub fn is_enabled(&self, resp: GTFSResponder) -> bool {
         self.listeners.contains(&resp)
     }
+
+    /// Prefix of the enabled source whose trip IDs a realtime feed uses
+    pub fn realtime_prefix(&self, resp: GTFSResponder) -> Option<&str> {
+        self.gtfs.iter().find(|source| source.enabled && source.realtime == Some(resp)).map(|source| source.prefix.as_str())
+    }
+}
+
+/// Static GTFS feed imported by the ingester
+#[derive(Serialize, Deserialize)]
+pub struct GTFSSource {
+    pub name: String,
+    /// Prefix added to IDs from this source - must be unique across sources
+    #[serde(default)]
+    pub prefix: String,
+    /// URL to download the zip from - if not set, `path` must already exist
+    pub url: Option<String>,
+    pub path: String,
+    #[serde(default)]
+    pub format: SourceFormat,
+    #[serde(default)]
+    pub add_stops: StopAddType,
+    /// Default values for empty fields, by GTFS file name then field name
+    #[serde(default)]
+    pub defaults: Map<String, Map<String, String>>,
+    #[serde(default = "enabled_default")]
+    pub enabled: bool,
+    /// Realtime feed whose trip IDs are this source's trip IDs without the prefix
+    #[serde(default)]
+    pub realtime: Option<GTFSResponder>
+}
+
+fn enabled_default() -> bool {
+    true
+}
+
+/// Matches the serde defaults, so that sources are enabled unless they say otherwise
+impl Default for GTFSSource {
+    fn default() -> Self {
+        GTFSSource {
+            name: String::new(),
+            prefix: String::new(),
+            url: None,
+            path: String::new(),
+            format: SourceFormat::default(),
+            add_stops: StopAddType::default(),
+            defaults: Map::new(),
+            enabled: enabled_default(),
+            realtime: None
+        }
+    }
+}
+
+/// File format of a timetable source
+#[derive(Serialize, Deserialize, Default, Eq, PartialEq, Debug)]
+pub enum SourceFormat {
+    #[default]
+    GTFS,
+    /// Zip of TransXChange XML files (or zips of them), e.g. the BODS/TNDS bulk archives
+    TransXChange
+}
+
+/// How stops from a GTFS source should be matched to NaPTAN stops
+#[derive(Serialize, Deserialize, Default, Eq, PartialEq, Debug)]
+pub enum StopAddType {
+    /// Source uses NaPTAN ATCO codes
+    #[default]
+    None,
+    /// Use a nearby NaPTAN stop if one exists, otherwise create a stop
+    MatchStopBeforeAdd,
+    /// Create stops which are not already in the database
+    AddIfMissing
 }
 
 #[derive(Serialize, Deserialize, Default)]
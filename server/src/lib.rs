@@ -9,12 +9,18 @@ use strum_macros::{Display, EnumIter};
 use tempfile::NamedTempFile;
 
 pub mod config;
+pub mod observations;
+pub mod patterns;
+pub mod segments;
+
+/// Version of the database schema written by the ingester - the realtime server refuses databases of any other version
+pub const SCHEMA_VERSION: u32 = 5;
 
 #[derive(Copy, Clone, Display, EnumIter)]
 #[derive(Eq, Hash, PartialEq)]
 #[derive(Serialize, Deserialize)]
 pub enum GTFSResponder {
-    BODS, DISRUPTIONS, EMBER, PASSENGER, LOTHIAN, STAGECOACH, COACHES, FIRST, TFL
+    BODS, DISRUPTIONS, EMBER, PASSENGER, LOTHIAN, STAGECOACH, COACHES, FIRST, TFL, NOTICES
 }
 
 #[derive(Serialize, Deserialize, Debug, Copy, Clone)]
@@ -53,4 +59,9 @@ pub fn download_if_old(url: &str, dst: &str) -> Result<File, Box<dyn Error>> {
         }
     }
     download(url, dst)
-}
\ No newline at end of file
+}
+
+/// Full NaPTAN XML, shared by the stations binary (CRS codes) and the ingester (stop areas) so it is only downloaded once a day
+pub fn download_naptan_xml() -> Result<File, Box<dyn Error>> {
+    download_if_old("https://naptan.api.dft.gov.uk/v1/access-nodes?dataFormat=xml", "NaPTAN.xml")
+}
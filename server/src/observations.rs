@@ -0,0 +1,13 @@
+use std::path::Path;
+
+const DEFAULT_OBSERVATIONS_PATH: &str = "observations.sqlite";
+
+/// Get the path of the observations database written by the realtime server and read by the ingester
+pub fn get_observations_path() -> String {
+    std::env::var("BUSES_OBSERVATIONS_PATH").unwrap_or(DEFAULT_OBSERVATIONS_PATH.to_string())
+}
+
+/// Whether the realtime server has recorded any vehicle observations
+pub fn has_observations() -> bool {
+    Path::new(get_observations_path().as_str()).exists()
+}
@@ -0,0 +1,9 @@
+/// Pack a list of integers into a blob of little-endian u32s, as stored in the trip_patterns tables
+pub fn pack(values: &[u32]) -> Vec<u8> {
+    values.iter().flat_map(|value| value.to_le_bytes()).collect()
+}
+
+/// Unpack a blob of little-endian u32s
+pub fn unpack(blob: &[u8]) -> Vec<u32> {
+    blob.chunks_exact(4).map(|chunk| u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]])).collect()
+}
@@ -0,0 +1,13 @@
+use chrono::{Datelike, NaiveDate, Weekday};
+
+/// Hour bucket holding the learned time across every hour of the day, used when an hour has too few samples
+pub const ALL_HOURS: u32 = 24;
+
+/// Day type of a date for learned segment times - 0 for weekdays, 1 for Saturdays and 2 for Sundays
+pub fn day_type(date: NaiveDate) -> u32 {
+    match date.weekday() {
+        Weekday::Sat => 1,
+        Weekday::Sun => 2,
+        _ => 0
+    }
+}